@@ -17,6 +17,8 @@ use tauri::{
     tray::TrayIconBuilder,
     Emitter, Manager, State, WindowEvent,
 };
+use tauri_plugin_clipboard_manager::ClipboardExt;
+use tauri_plugin_notification::NotificationExt;
 use tokio::process::Command as TokioCommand;
 use tokio::time::timeout;
 
@@ -40,28 +42,127 @@ fn decode_bytes(bytes: &[u8]) -> String {
     String::from_utf8_lossy(bytes).to_string()
 }
 
+fn now_unix_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// メッセージカタログ（言語 → キー → 文字列）
+type MessageCatalog = std::collections::HashMap<String, std::collections::HashMap<String, String>>;
+
+/// messages.toml を埋め込みリソースとして読み込み
+fn message_catalog() -> &'static MessageCatalog {
+    static CATALOG: std::sync::OnceLock<MessageCatalog> = std::sync::OnceLock::new();
+    CATALOG.get_or_init(|| {
+        toml::from_str(include_str!("../messages.toml")).unwrap_or_else(|e| {
+            eprintln!("[Nexus] Warning: messages.toml parse error: {}", e);
+            MessageCatalog::new()
+        })
+    })
+}
+
+/// ユーザー向けメッセージを取得（未翻訳キーは日本語にフォールバック、{name} を置換）
+fn tr(lang: &str, key: &str, args: &[(&str, &str)]) -> String {
+    let catalog = message_catalog();
+    let template = catalog
+        .get(lang)
+        .and_then(|m| m.get(key))
+        .or_else(|| catalog.get("ja").and_then(|m| m.get(key)))
+        .map(|t| t.as_str())
+        .unwrap_or(key);
+    args.iter().fold(template.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{}}}", name), value)
+    })
+}
+
+/// アプリデータディレクトリ内のファイルパス（ディレクトリがなければ作成）
+fn app_data_path(app_handle: &tauri::AppHandle, file_name: &str) -> Result<PathBuf, String> {
+    let dir = app_handle
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("アプリデータディレクトリ取得エラー: {}", e))?;
+    std::fs::create_dir_all(&dir).map_err(|e| format!("ディレクトリ作成エラー: {}", e))?;
+    Ok(dir.join(file_name))
+}
+
+/// JSON Lines ファイルに1レコード追記
+fn append_jsonl<T: Serialize>(path: &std::path::Path, record: &T) -> Result<(), String> {
+    use std::io::Write;
+    let line = serde_json::to_string(record).map_err(|e| format!("JSON変換エラー: {}", e))?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("ファイルオープンエラー: {}", e))?;
+    writeln!(file, "{}", line).map_err(|e| format!("書き込みエラー: {}", e))
+}
+
+/// JSON Lines ファイルを読み込み（壊れた行はスキップ）
+fn read_jsonl<T: serde::de::DeserializeOwned>(path: &std::path::Path) -> Vec<T> {
+    std::fs::read_to_string(path)
+        .map(|content| {
+            content
+                .lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
 // ========================================
 // Anthropic API Types (Tool Use対応)
 // ========================================
 
-/// 履歴用メッセージ（テキストのみ保持、トークン節約）
-#[derive(Serialize, Clone)]
+/// 履歴用メッセージ（APIへはテキストのみ送信、トークン節約）
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct HistoryMessage {
     role: String,
     content: String,
+    /// このターンで実行されたツール（エクスポート用、APIには送らない）
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_executions: Vec<ToolExecution>,
+    /// 後処理で前置き等を除去する前の応答（除去が起きたときのみ）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw_content: Option<String>,
+    /// ピン留め（コンテキストのトリム対象から除外）
+    #[serde(default)]
+    pinned: bool,
+    /// お気に入り（list_starred / お気に入りのみのエクスポート用）
+    #[serde(default)]
+    starred: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// API に送る形での概算トークン数（0 は未計算。内容を変えたら 0 に戻す）
+    #[serde(default)]
+    estimated_tokens: u64,
+}
+
+impl HistoryMessage {
+    /// 概算トークン数（未計算なら計算して保持）
+    fn token_estimate(&mut self) -> u64 {
+        if self.estimated_tokens == 0 {
+            self.estimated_tokens = estimate_tokens(&history_to_api_message(self).to_string());
+        }
+        self.estimated_tokens
+    }
 }
 
 /// API送信用リクエスト（tools / system / stream 対応）
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct ApiRequest {
     model: String,
     max_tokens: u32,
+    /// 文字列、またはプロンプトキャッシュ指定付きのブロック配列（cached_system_prompt）
     #[serde(skip_serializing_if = "Option::is_none")]
-    system: Option<String>,
+    system: Option<serde_json::Value>,
     messages: Vec<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tools: Option<Vec<serde_json::Value>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    tool_choice: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
 }
 
@@ -75,8 +176,52 @@ struct ApiResponse {
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug)]
 struct UsageInfo {
+    /// キャッシュを使わなかった入力（キャッシュ分は下の2つに別計上される）
     input_tokens: u64,
     output_tokens: u64,
+    /// プロンプトキャッシュへの書き込み・読み取りに回った入力
+    #[serde(default)]
+    cache_creation_input_tokens: u64,
+    #[serde(default)]
+    cache_read_input_tokens: u64,
+}
+
+impl UsageInfo {
+    fn add(&mut self, other: &UsageInfo) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+    }
+
+    /// キャッシュ分を含めた入力の総量（コンテキスト使用率の計算用）
+    fn prompt_tokens(&self) -> u64 {
+        self.input_tokens + self.cache_creation_input_tokens + self.cache_read_input_tokens
+    }
+}
+
+/// プロンプトキャッシュ（cache_control）を有効にするヘッダ値
+const PROMPT_CACHING_BETA: &str = "prompt-caching-2024-07-31";
+
+/// システムプロンプトをキャッシュ指定付きのブロックにする（tools とシステムプロンプトが毎回同じ前置きとして再利用される）
+/// キャッシュの最小長に満たない短いプロンプトは API 側で単にキャッシュされない
+/// 末尾の現在日時（以降）は送信ごとに変わるので、キャッシュ対象の後ろに別ブロックとして置く
+fn cached_system_prompt(system: &str) -> serde_json::Value {
+    match system.find(&format!("\n\n{}", CURRENT_DATETIME_LABEL)) {
+        Some(pos) => serde_json::json!([
+            {
+                "type": "text",
+                "text": &system[..pos],
+                "cache_control": { "type": "ephemeral" }
+            },
+            { "type": "text", "text": system[pos..].trim_start() }
+        ]),
+        None => serde_json::json!([{
+            "type": "text",
+            "text": system,
+            "cache_control": { "type": "ephemeral" }
+        }]),
+    }
 }
 
 #[derive(Deserialize)]
@@ -90,18 +235,129 @@ struct ApiErrorDetail {
 }
 
 /// ツール実行結果（フロントエンドに返す）
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct ToolExecution {
+    /// 出力フィルタ等で実行結果を参照するためのID
+    execution_id: String,
     machine_name: String,
     command: String,
     stdout: String,
     stderr: String,
     success: bool,
+    /// 適用されたタイムアウト（秒）とその決定元（"machine" | "os_profile" | "default"）
+    timeout_secs: u64,
+    timeout_source: String,
+    /// 大きな出力を要約して Claude に渡した場合の要約（全文は stdout/stderr に保持）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    summary: Option<String>,
+    /// 定番コマンド（df / free / systemctl status 等）の出力を構造化したもの
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parsed: Option<serde_json::Value>,
+    /// Claude が申告した実行目的（investigate / change / verify）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    purpose: Option<ToolPurpose>,
+    /// 1リクエスト内の実行順（1始まり、ツールループをまたいで単調増加）
+    sequence: u64,
+    /// ユーザーが実行中にキャンセルした
+    cancelled: bool,
+    /// 実際に接続したホスト（hosts に複数の候補があるマシンで、どれに繋がったか）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connected_host: Option<String>,
+    /// 実行せずに読み取り結果のキャッシュから返した
+    #[serde(default)]
+    cached: bool,
+    /// Claude に渡す本文を行数・文字数の上限で切り詰めた（全文は stdout/stderr に保持）
+    #[serde(default)]
+    truncated: bool,
+}
+
+impl ToolExecution {
+    /// コマンドを実行できなかった（または実行前に止めた）場合の記録
+    fn failed(machine_name: &str, command: &str, stderr: String, timeout_secs: u64, timeout_source: &str) -> Self {
+        Self {
+            execution_id: next_execution_id(),
+            machine_name: machine_name.to_string(),
+            command: command.to_string(),
+            stdout: String::new(),
+            stderr,
+            success: false,
+            timeout_secs,
+            timeout_source: timeout_source.to_string(),
+            summary: None,
+            parsed: None,
+            purpose: None,
+            sequence: 0,
+            cancelled: false,
+            connected_host: None,
+            cached: false,
+            truncated: false,
+        }
+    }
+}
+
+/// 実行IDを採番（起動時刻＋連番、再起動後も重複しない）
+fn next_execution_id() -> String {
+    static SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    static STARTED: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+    let started = *STARTED.get_or_init(now_unix_secs);
+    let seq = SEQ.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    format!("exec-{}-{}", started, seq)
+}
+
+/// 送信1回の終了をトレースIDとともにログに残す
+fn log_request_finished(trace_id: &str, tool_count: usize, usage: &UsageInfo, error: Option<&str>) {
+    eprintln!(
+        "[Nexus] [{}] Request finished ({} tool call(s), {} in / {} out tokens{})",
+        trace_id,
+        tool_count,
+        usage.prompt_tokens(),
+        usage.output_tokens,
+        error.map(|e| format!(", partial: {}", split_error_detail(e).0)).unwrap_or_default()
+    );
+}
+
+/// 送信1回分のトレースID（UUID v4 形式）。イベント・ログ・監査記録に付けて後から突き合わせる
+fn new_trace_id() -> String {
+    use std::hash::{BuildHasher, Hasher};
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    // RandomState は生成のたびに異なる鍵を持つので、乱数の代わりに使う
+    let random = |salt: u64| {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u64(salt);
+        hasher.write_u128(nanos);
+        hasher.finish()
+    };
+    let hi = (random(0) & 0xffff_ffff_ffff_0fff) | 0x4000;
+    let lo = (random(1) & 0x3fff_ffff_ffff_ffff) | 0x8000_0000_0000_0000;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        hi >> 32,
+        (hi >> 16) & 0xffff,
+        hi & 0xffff,
+        lo >> 48,
+        lo & 0xffff_ffff_ffff
+    )
+}
+
+/// エラーの詳細欄にトレースIDを付ける（不具合報告のときにログを特定できるように）
+fn with_trace_id(error: String, trace_id: &str) -> String {
+    let line = format!("trace_id: {}", trace_id);
+    match split_error_detail(&error) {
+        (message, Some(detail)) => with_error_detail(message.to_string(), &format!("{}\n{}", detail, line)),
+        (message, None) => with_error_detail(message.to_string(), &line),
+    }
 }
 
 /// ツール実行中イベント（Tauriイベント経由でフロントへ）
 #[derive(Serialize, Clone, Debug)]
 struct ToolExecutingEvent {
+    session_id: String,
+    trace_id: String,
+    sequence: u64,
+    /// cancel_tool_execution で対象を指定するためのID（完了後の ToolExecution と同じ）
+    execution_id: String,
     machine_name: String,
     command: String,
 }
@@ -109,6 +365,10 @@ struct ToolExecutingEvent {
 /// ツール実行完了イベント
 #[derive(Serialize, Clone, Debug)]
 struct ToolCompletedEvent {
+    session_id: String,
+    trace_id: String,
+    sequence: u64,
+    execution_id: String,
     machine_name: String,
     command: String,
     success: bool,
@@ -118,35 +378,548 @@ struct ToolCompletedEvent {
 // App State
 // ========================================
 
-#[derive(Serialize, Clone, Default)]
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+#[serde(default)]
 struct TokenStats {
     last_input_tokens: u64,
     last_output_tokens: u64,
     total_input_tokens: u64,
     total_output_tokens: u64,
     request_count: u32,
+    /// ツール出力・会話履歴の要約に使ったトークン（要約モデル分、上の累計とは別）
+    summary_input_tokens: u64,
+    summary_output_tokens: u64,
+    /// プロンプトキャッシュへの書き込み・読み取りに回った入力の累計（total_input_tokens とは別枠、ヒット率の表示用）
+    total_cache_creation_input_tokens: u64,
+    total_cache_read_input_tokens: u64,
+    /// 履歴の要約圧縮で削った概算トークン（落としたやり取り − 要約）
+    history_summary_saved_tokens: u64,
+    /// モデルID別の累計（要約モデル分も含む）。途中でモデルを切り替えても各モデルの単価でコストを出すため
+    by_model: std::collections::HashMap<String, UsageInfo>,
+}
+
+impl TokenStats {
+    fn add_model_usage(&mut self, model: &str, usage: &UsageInfo) {
+        self.by_model.entry(model.to_string()).or_default().add(usage);
+    }
 }
 
 struct ChatState {
     history: Vec<HistoryMessage>,
     model: String,
+    /// 1回の API 呼び出しの出力上限（モデルの上限を超える分は呼び出し時に切り詰める）
+    max_tokens: u32,
+    /// 1回の送信で API を呼び出す（ツール結果を返して続ける）最大回数
+    max_tool_loops: u32,
     token_stats: TokenStats,
+    /// このセッションの予算上限（USD、None で無制限）。累計推定コストが達すると送信しない
+    budget_limit_usd: Option<f64>,
+    /// 上限の一定割合に達したことを budget-warning で通知済み（上限の変更・リセットで戻す）
+    budget_warned: bool,
+    /// undo / redo 用の操作前の状態（古い順、UNDO_MAX_SNAPSHOTS まで）
+    undo_stack: Vec<ChatSnapshot>,
+    redo_stack: Vec<ChatSnapshot>,
+}
+
+/// トークン統計の変更を token-stats-updated で通知（フロントはポーリングせずにこれを購読する）
+/// reason: "request"（送信・要約で加算） | "reset"（コスト累計のリセット） | "clear"（履歴クリア） | "restore"（undo / redo）
+fn notify_token_stats(app_handle: &tauri::AppHandle, session_id: &str, stats: &TokenStats, reason: &str) {
+    let _ = app_handle.emit("token-stats-updated", serde_json::json!({
+        "session_id": session_id,
+        "token_stats": stats,
+        "reason": reason
+    }));
+}
+
+const DEFAULT_SESSION_ID: &str = "default";
+const DEFAULT_SESSION_TITLE: &str = "General";
+
+/// 会話セッション（履歴・統計と送信の逐次化をセッション単位で持つ）
+struct Session {
+    id: String,
+    /// 一覧に表示する名前
+    title: Mutex<String>,
+    created_at: u64,
+    chat: Mutex<ChatState>,
+    gate: RequestGate,
+    /// 実行中のツール（execution_id → キャンセル・タイムアウト延長の送り口）
+    running_tools: Mutex<std::collections::HashMap<String, ToolControl>>,
+    /// 承認待ちのツール（execution_id → 承認/拒否の通知先）
+    pending_approvals: Mutex<std::collections::HashMap<String, tokio::sync::oneshot::Sender<ApprovalDecision>>>,
+    /// まとめて承認を待っているバッチ（batch_id → 承認した項目の通知先）
+    pending_batch_approvals: Mutex<std::collections::HashMap<String, tokio::sync::oneshot::Sender<BatchApprovalReply>>>,
+    /// 現在のターンでバッチ承認済みの結果（(マシン名, コマンド) → 実行可否）。ターンのツール実行が終わったら消す
+    batch_approval_results: Mutex<std::collections::HashMap<(String, String), Result<(), String>>>,
+    /// stop_generation で立てる中断フラグ（送信開始時に戻す）。stop_notify で受信待ちを起こす
+    stop_requested: std::sync::atomic::AtomicBool,
+    stop_notify: tokio::sync::Notify,
+}
+
+impl Session {
+    fn new(id: &str) -> Self {
+        let title = if id == DEFAULT_SESSION_ID { DEFAULT_SESSION_TITLE } else { id };
+        Self {
+            id: id.to_string(),
+            title: Mutex::new(title.to_string()),
+            created_at: now_unix_secs(),
+            chat: Mutex::new(ChatState::default()),
+            gate: RequestGate::default(),
+            running_tools: Mutex::new(std::collections::HashMap::new()),
+            pending_approvals: Mutex::new(std::collections::HashMap::new()),
+            pending_batch_approvals: Mutex::new(std::collections::HashMap::new()),
+            batch_approval_results: Mutex::new(std::collections::HashMap::new()),
+            stop_requested: std::sync::atomic::AtomicBool::new(false),
+            stop_notify: tokio::sync::Notify::new(),
+        }
+    }
+
+    fn stop_requested(&self) -> bool {
+        self.stop_requested.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+/// セッション一覧。ロックはセッションごとなので、異なるセッションへの送信は並列に処理される
+/// default セッションは起動時に必ず作られ、削除できない（単一履歴だった頃の会話の移行先）
+struct Sessions {
+    map: Mutex<std::collections::HashMap<String, std::sync::Arc<Session>>>,
+    /// session_id 未指定のコマンドが対象にするセッション（switch_session で切り替え）
+    active: Mutex<String>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        let mut map = std::collections::HashMap::new();
+        map.insert(DEFAULT_SESSION_ID.to_string(), std::sync::Arc::new(Session::new(DEFAULT_SESSION_ID)));
+        Self {
+            map: Mutex::new(map),
+            active: Mutex::new(DEFAULT_SESSION_ID.to_string()),
+        }
+    }
+}
+
+impl Sessions {
+    /// 対象セッションのID（未指定・空文字はアクティブセッション）
+    fn key(&self, id: Option<&str>) -> Result<String, String> {
+        match id.map(str::trim).filter(|id| !id.is_empty()) {
+            Some(id) => Ok(id.to_string()),
+            None => self.active.lock().map(|a| a.clone()).map_err(|e| format!("Lock error: {}", e)),
+        }
+    }
+
+    fn get(&self, id: Option<&str>) -> Result<std::sync::Arc<Session>, String> {
+        let id = self.key(id)?;
+        let map = self.map.lock().map_err(|e| format!("Lock error: {}", e))?;
+        map.get(&id).cloned().ok_or_else(|| format!("セッション '{}' が見つかりません", id))
+    }
+
+    /// 送信時は未知のIDなら新しいセッションを作る
+    fn get_or_create(&self, id: Option<&str>) -> Result<std::sync::Arc<Session>, String> {
+        let id = self.key(id)?;
+        let mut map = self.map.lock().map_err(|e| format!("Lock error: {}", e))?;
+        Ok(map
+            .entry(id.clone())
+            .or_insert_with(|| std::sync::Arc::new(Session::new(&id)))
+            .clone())
+    }
+}
+
+/// 実行中ツールへのユーザー操作の送り口（Session.running_tools に登録）
+struct ToolControl {
+    cancel: tokio::sync::watch::Sender<bool>,
+    /// タイムアウト後の「さらに待つ」(true) /「中止」(false)
+    timeout_decisions: tokio::sync::mpsc::UnboundedSender<bool>,
+}
+
+/// 実行中ツール1件の識別とユーザー操作の受け口
+struct ToolRun {
+    execution_id: String,
+    sequence: u64,
+    cancel: tokio::sync::watch::Receiver<bool>,
+    timeout_decisions: tokio::sync::mpsc::UnboundedReceiver<bool>,
+}
+
+fn tool_run_channel(execution_id: &str, sequence: u64) -> (ToolControl, ToolRun) {
+    let (cancel_tx, cancel_rx) = tokio::sync::watch::channel(false);
+    let (decision_tx, decision_rx) = tokio::sync::mpsc::unbounded_channel();
+    (
+        ToolControl { cancel: cancel_tx, timeout_decisions: decision_tx },
+        ToolRun {
+            execution_id: execution_id.to_string(),
+            sequence,
+            cancel: cancel_rx,
+            timeout_decisions: decision_rx,
+        },
+    )
+}
+
+/// モデル能力テーブル（エイリアス → 実ID の解決もここで行う）
+struct ModelSpec {
+    alias: &'static str,
+    id: &'static str,
+    label: &'static str,
+    /// 単価（USD / 100万トークン、フロントの MODEL_PRICING と揃える）
+    input_usd_per_mtok: f64,
+    output_usd_per_mtok: f64,
+    /// コンテキストウィンドウ（トークン）
+    context_window: u64,
+    /// 1回の応答で指定できる max_tokens の上限（set_max_tokens の指定はここまでに収める）
+    max_output_tokens: u32,
+}
+
+const MODELS: &[ModelSpec] = &[
+    ModelSpec {
+        alias: "sonnet",
+        id: "claude-sonnet-4-5-20250929",
+        label: "Sonnet 4.5",
+        input_usd_per_mtok: 3.0,
+        output_usd_per_mtok: 15.0,
+        context_window: 200_000,
+        max_output_tokens: 64_000,
+    },
+    ModelSpec {
+        alias: "haiku",
+        id: "claude-haiku-4-5-20251001",
+        label: "Haiku 4.5",
+        input_usd_per_mtok: 0.80,
+        output_usd_per_mtok: 4.0,
+        context_window: 200_000,
+        max_output_tokens: 64_000,
+    },
+    ModelSpec {
+        alias: "opus",
+        id: "claude-opus-4-1-20250805",
+        label: "Opus 4.1",
+        input_usd_per_mtok: 15.0,
+        output_usd_per_mtok: 75.0,
+        context_window: 200_000,
+        max_output_tokens: 32_000,
+    },
+];
+
+const DEFAULT_MODEL_ALIAS: &str = "sonnet";
+
+/// エイリアス（大文字小文字無視）または実IDからモデルを引く
+fn find_model(name: &str) -> Option<&'static ModelSpec> {
+    let name = name.trim();
+    MODELS
+        .iter()
+        .find(|m| m.id == name || m.alias.eq_ignore_ascii_case(name))
+}
+
+/// 指定された出力上限をモデルの範囲に収める（不明なモデルは既定値を上限とする）
+fn clamp_max_tokens(max_tokens: u32, model: &str) -> u32 {
+    let upper = find_model(model).map_or(DEFAULT_CALL_MAX_TOKENS, |m| m.max_output_tokens);
+    max_tokens.clamp(MIN_CALL_MAX_TOKENS, upper)
 }
 
 impl Default for ChatState {
     fn default() -> Self {
         Self {
             history: Vec::new(),
-            model: "claude-sonnet-4-5-20250929".to_string(),
+            model: find_model(DEFAULT_MODEL_ALIAS).map(|m| m.id).unwrap_or_default().to_string(),
+            max_tokens: DEFAULT_CALL_MAX_TOKENS,
+            max_tool_loops: DEFAULT_MAX_TOOL_LOOPS,
             token_stats: TokenStats::default(),
+            budget_limit_usd: None,
+            budget_warned: false,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+        }
+    }
+}
+
+/// ツール実行の通し番号（1始まり。ツールループ・並列実行をまたいで1リクエスト内で共有する）
+#[derive(Default)]
+struct ToolSequence(std::sync::atomic::AtomicU64);
+
+impl ToolSequence {
+    fn next(&self) -> u64 {
+        self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1
+    }
+}
+
+/// ツール実行に必要な設定のスナップショット（リクエスト開始時に確定）
+#[derive(Clone)]
+struct ToolContext {
+    machines: Vec<SshMachineConfig>,
+    tools: Vec<serde_json::Value>, // 入力検証に使うツール定義（build_tools の結果）
+    settings: AppSettings,
+    app_handle: tauri::AppHandle,
+    api_key: String, // ツール結果の要約に使う
+    session: std::sync::Arc<Session>,
+    /// ツール実行の通し番号（並列実行でもイベントを実行順に並べられるようにする）
+    tool_sequence: std::sync::Arc<ToolSequence>,
+    /// この送信のトレースID（new_trace_id）
+    trace_id: String,
+}
+
+impl ToolContext {
+    fn next_tool_sequence(&self) -> u64 {
+        self.tool_sequence.next()
+    }
+
+    /// このリクエストのセッションID・トレースIDを付けてイベントを通知（フロントが振り分けに使う）
+    fn emit(&self, event: &str, mut payload: serde_json::Value) {
+        if let Some(object) = payload.as_object_mut() {
+            object.insert("session_id".to_string(), serde_json::json!(self.session.id));
+            object.insert("trace_id".to_string(), serde_json::json!(self.trace_id));
+        }
+        let _ = self.app_handle.emit(event, payload);
+    }
+
+    fn api_caller(&self) -> ApiCaller<'_> {
+        ApiCaller {
+            api_key: &self.api_key,
+            lang: &self.settings.language,
+            app_handle: &self.app_handle,
+            session_id: &self.session.id,
+            trace_id: &self.trace_id,
+        }
+    }
+}
+
+/// ユーザーメッセージを履歴に追加（直前も user なら結合して role の連続を防ぐ）
+fn push_user_message(chat: &mut ChatState, content: &str) {
+    if let Some(last) = chat.history.last_mut() {
+        if last.role == "user" {
+            last.content = format!("{}\n\n{}", last.content, content);
+            last.estimated_tokens = 0;
+            return;
+        }
+    }
+    chat.history.push(HistoryMessage {
+        role: "user".to_string(),
+        content: content.to_string(),
+        tool_executions: Vec::new(),
+        raw_content: None,
+        pinned: false,
+        starred: false,
+        tags: Vec::new(),
+        estimated_tokens: 0,
+    });
+}
+
+/// モデル不明時のコンテキスト上限
+const DEFAULT_CONTEXT_WINDOW_TOKENS: u64 = 200_000;
+
+/// 履歴に使えるトークン予算（モデルのコンテキスト上限 × 割合）
+/// 割合は [context] model_budget_ratios（モデル別）→ NEXUS_CONTEXT_BUDGET_RATIO → [context] budget_ratio の順
+fn context_budget_tokens(model: &str, settings: &ContextSettings) -> u64 {
+    let valid = |r: &f64| *r > 0.0 && *r <= 1.0;
+    let spec = find_model(model);
+    let model_ratio = settings
+        .model_budget_ratios
+        .iter()
+        .find(|(key, _)| find_model(key).zip(spec).is_some_and(|(a, b)| a.id == b.id) || key.as_str() == model)
+        .map(|(_, ratio)| *ratio)
+        .filter(valid);
+    let ratio = model_ratio
+        .or_else(|| {
+            std::env::var("NEXUS_CONTEXT_BUDGET_RATIO")
+                .ok()
+                .and_then(|v| v.trim().parse::<f64>().ok())
+                .filter(valid)
+        })
+        .or(Some(settings.budget_ratio).filter(valid))
+        .unwrap_or(DEFAULT_CONTEXT_BUDGET_RATIO);
+    let window = spec.map_or(DEFAULT_CONTEXT_WINDOW_TOKENS, |m| m.context_window);
+    (window as f64 * ratio) as u64
+}
+
+/// 概算トークン数（ASCII は約4文字で1トークン、日本語等は1文字1トークンとして見積もる）
+fn estimate_tokens(text: &str) -> u64 {
+    let (ascii, other) = text
+        .chars()
+        .fold((0u64, 0u64), |(a, o), c| if c.is_ascii() { (a + 1, o) } else { (a, o + 1) });
+    ascii.div_ceil(4) + other
+}
+
+/// 履歴をトークン予算に収める（古い順にピン留め以外を落とす、直近のやり取りは残す）
+/// user とそれに続く assistant を1組として落とし、応答だけが残ったり質問だけが残ったりしないようにする
+/// 落としたメッセージ（古い順）と残りの概算トークン数を返す
+fn trim_history_to_budget(history: &mut Vec<HistoryMessage>, budget: u64) -> (Vec<HistoryMessage>, u64) {
+    let mut total: u64 = history.iter_mut().map(HistoryMessage::token_estimate).sum();
+    let mut removed = Vec::new();
+    let mut index = 0;
+    while total > budget && index < history.len() {
+        // index から次の user の手前までが1組（先頭が assistant なら対応する user を失った応答）
+        let end = history[index + 1..]
+            .iter()
+            .position(|m| m.role == "user")
+            .map_or(history.len(), |offset| index + 1 + offset);
+        if end == history.len() {
+            break;
+        }
+        if history[index..end].iter().any(|m| m.pinned) {
+            index = end;
+            continue;
+        }
+        for message in history.drain(index..end) {
+            total -= message.estimated_tokens;
+            removed.push(message);
+        }
+    }
+    // 先頭が assistant のままだと API に拒否されるため、対応する user を失った応答も落とす
+    while !removed.is_empty() && history.len() > 1 && history[0].role == "assistant" && !history[0].pinned {
+        let message = history.remove(0);
+        total -= message.estimated_tokens;
+        removed.push(message);
+    }
+    (removed, total)
+}
+
+/// 履歴要約メッセージの目印（次に落とすときは前回の要約ごと要約し直す）
+const HISTORY_SUMMARY_PREFIX: &str = "[これまでの会話の要約]";
+/// 要約の直後に置く応答（user / assistant の交互を保つ）
+const HISTORY_SUMMARY_ACK: &str = "了解しました。この要約を踏まえて会話を続けます。";
+/// 履歴要約の出力上限（要約する場合は概算の誤差も見込んでこの2倍を予算から空けてトリムする）
+const HISTORY_SUMMARY_MAX_TOKENS: u32 = 1024;
+
+/// 送信前に履歴をトークン予算に収め、API メッセージ配列を返す
+/// 落とすやり取りは要約モデルで要約して履歴の先頭に残し、要約に失敗したらそのまま落とす（どちらも context-trimmed で通知）
+async fn prepare_api_messages(ctx: &ToolContext) -> Result<Vec<serde_json::Value>, String> {
+    let state = &ctx.session.chat;
+    let config = &ctx.settings.context;
+    let (dropped, budget) = {
+        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        let budget = context_budget_tokens(&chat.model, config);
+        let reserve = if config.summarize_trimmed { HISTORY_SUMMARY_MAX_TOKENS as u64 * 2 } else { 0 };
+        let (dropped, _) = trim_history_to_budget(&mut chat.history, budget.saturating_sub(reserve));
+        if dropped.is_empty() {
+            return Ok(chat.history.iter().map(history_to_api_message).collect());
+        }
+        (dropped, budget)
+    };
+
+    // 要約中はロックを持たない（送信は実行権で逐次化済み）
+    let summary = if config.summarize_trimmed { summarize_history(&dropped, ctx).await } else { None };
+
+    let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    let dropped_tokens: u64 = dropped.iter().map(|m| m.estimated_tokens).sum();
+    let mut saved_tokens = 0;
+    if let Some(summary) = &summary {
+        let summary_message = |role: &str, content: String| HistoryMessage {
+            role: role.to_string(),
+            content,
+            tool_executions: Vec::new(),
+            raw_content: None,
+            pinned: false,
+            starred: false,
+            tags: Vec::new(),
+            estimated_tokens: 0,
+        };
+        // 先頭がピン留めの assistant なら応答を挟まずに置く
+        if chat.history.first().is_none_or(|m| m.role == "user") {
+            chat.history.insert(0, summary_message("assistant", HISTORY_SUMMARY_ACK.to_string()));
         }
+        chat.history.insert(0, summary_message("user", format!("{}\n{}", HISTORY_SUMMARY_PREFIX, summary)));
+        let summary_tokens: u64 = chat.history.iter_mut().take(2).map(HistoryMessage::token_estimate).sum();
+        saved_tokens = dropped_tokens.saturating_sub(summary_tokens);
+        chat.token_stats.history_summary_saved_tokens += saved_tokens;
+        notify_token_stats(&ctx.app_handle, &ctx.session.id, &chat.token_stats, "request");
     }
+    let remaining_tokens: u64 = chat.history.iter_mut().map(HistoryMessage::token_estimate).sum();
+    eprintln!(
+        "[Nexus] Context trimmed: {} messages {} (~{} / {} tokens)",
+        dropped.len(),
+        if summary.is_some() { "summarized" } else { "removed" },
+        remaining_tokens,
+        budget
+    );
+    ctx.emit("context-trimmed", serde_json::json!({
+        "removed": dropped.len(),
+        "summarized": summary.is_some(),
+        "saved_tokens": saved_tokens,
+        "remaining_messages": chat.history.len(),
+        "estimated_tokens": remaining_tokens,
+        "budget_tokens": budget
+    }));
+    Ok(chat.history.iter().map(history_to_api_message).collect())
+}
+const DEFAULT_MAX_TOOL_LOOPS: u32 = 5; // Tool Use最大ループ回数の既定値（set_max_tool_loops で変更可）
+const HARD_MAX_TOOL_LOOPS: u32 = 20; // set_max_tool_loops でも超えられない上限（暴走防止）
+
+/// 指定されたツールループ回数を 1〜HARD_MAX_TOOL_LOOPS に収める
+fn clamp_max_tool_loops(max_tool_loops: u32) -> u32 {
+    max_tool_loops.clamp(1, HARD_MAX_TOOL_LOOPS)
 }
 
-const MAX_HISTORY: usize = 20; // 直近20メッセージを保持
-const MAX_TOOL_LOOPS: usize = 5; // Tool Use最大ループ回数（暴走防止）
+/// ループ上限で打ち切ったことを応答の末尾に残す（何回で止めたかを含める）
+fn tool_loop_limit_notice(max_tool_loops: usize) -> String {
+    format!("\n⚠️ ツール実行回数が上限に達しました（{}回で打ち切り）。", max_tool_loops)
+}
+const GENERATION_STOPPED_NOTICE: &str = "\n⏹ ユーザーが生成を中断しました。";
+const TOOL_CANCELLED_NOTICE: &str = "\n⚠️ ツール実行がユーザーによりキャンセルされたため、処理を中断しました。";
+/// 複数マシンに同じコマンドを並列実行するツール（1回のツール呼び出し＝ループ1回分として数える）
+const MULTI_MACHINE_TOOL: &str = "execute_on_machines";
+const READ_FILE_TOOL: &str = "read_remote_file";
+const LOCAL_COMMAND_TOOL: &str = "execute_local_command";
+const SERVICE_TOOL: &str = "manage_service";
+/// read_remote_file の max_bytes 既定値と上限
+const READ_FILE_DEFAULT_MAX_BYTES: u64 = 64 * 1024;
+const READ_FILE_MAX_BYTES_LIMIT: u64 = 1024 * 1024;
 const API_URL: &str = "https://api.anthropic.com/v1/messages";
 
+// ========================================
+// 空応答・不完全な応答の自動再生成
+// ========================================
+
+const RESPONSE_RETRY_LOG_FILE: &str = "response_retries.jsonl";
+
+/// 自動再生成1回分の記録（response_retries.jsonl に追記し、発生頻度を追えるようにする）
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ResponseRetryRecord {
+    timestamp: u64,
+    session_id: String,
+    #[serde(default)]
+    trace_id: String,
+    model: String,
+    /// empty / tool_use_without_call / missing_stop_reason / unexpected_stop_reason
+    reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_reason: Option<String>,
+    /// 何回目の API 呼び出しで起きたか（0 始まり、ツール実行を挟むたびに増える）
+    loop_count: usize,
+}
+
+/// 応答が不完全なら理由を返す（ここまでの応答テキストが空でツール呼び出しもない、ツールを呼ぶと言って呼ばない等）
+fn incomplete_response_reason(text_so_far: &str, has_tool_uses: bool, stop_reason: Option<&str>) -> Option<&'static str> {
+    match stop_reason {
+        None => Some("missing_stop_reason"),
+        Some("tool_use") if !has_tool_uses => Some("tool_use_without_call"),
+        Some("end_turn" | "tool_use" | "max_tokens" | "stop_sequence") => {
+            (text_so_far.trim().is_empty() && !has_tool_uses).then_some("empty")
+        }
+        Some(_) => Some("unexpected_stop_reason"),
+    }
+}
+
+/// 再生成したことをログとフロントに残す（discard_chars はフロントが表示済みの途中テキストを取り消す長さ）
+fn notify_response_retry(ctx: &ToolContext, model: &str, reason: &str, stop_reason: Option<&str>, loop_count: usize, discard_chars: usize) {
+    eprintln!(
+        "[Nexus] Incomplete response ({}, stop_reason={}), regenerating once",
+        reason,
+        stop_reason.unwrap_or("none")
+    );
+    ctx.emit("response-retrying", serde_json::json!({
+        "reason": reason,
+        "discard_chars": discard_chars
+    }));
+    let record = ResponseRetryRecord {
+        timestamp: now_unix_secs(),
+        session_id: ctx.session.id.clone(),
+        trace_id: ctx.trace_id.clone(),
+        model: model.to_string(),
+        reason: reason.to_string(),
+        stop_reason: stop_reason.map(str::to_string),
+        loop_count,
+    };
+    let result = app_data_path(&ctx.app_handle, RESPONSE_RETRY_LOG_FILE).and_then(|path| append_jsonl(&path, &record));
+    if let Err(e) = result {
+        eprintln!("[Nexus] Failed to record response retry: {}", e);
+    }
+}
+
 // ========================================
 // Tauri Commands
 // ========================================
@@ -157,96 +930,215 @@ struct SendMessageResponse {
     text: String,
     token_stats: TokenStats,
     tool_executions: Vec<ToolExecution>,
+    grounding: &'static str, // "verified"（実機で確認） | "inferred"（推測）
+    /// 後処理前の応答（除去が起きたときのみ）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_text: Option<String>,
+    /// 途中でエラーになり部分応答を返した場合のエラー内容（利用者向け）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// error の元になった技術的な詳細（折りたたみ表示用）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_detail: Option<String>,
+    /// text 中の URL・パス・マシン名・コマンド（文字単位の範囲）
+    annotations: Vec<TextAnnotation>,
+    /// この送信のトレースID（イベント・ログ・監査記録と共通）
+    trace_id: String,
+}
+
+/// 応答テキストから設定された前置き・後置きを除去する
+/// 除去が起きた場合は (除去後, Some(元の応答))、除去で空になる場合は元の応答を残す
+fn postprocess_response(text: String, settings: &PostprocessSettings) -> (String, Option<String>) {
+    if !settings.enabled {
+        return (text, None);
+    }
+    let compile = |pattern: &str, anchored: String| match regex::Regex::new(&anchored) {
+        Ok(re) => Some(re),
+        Err(e) => {
+            eprintln!("[Nexus] Warning: invalid postprocess pattern '{}': {}", pattern, e);
+            None
+        }
+    };
+    let prefixes = settings.strip_prefixes.iter().filter_map(|p| compile(p, format!(r"\A\s*(?:{})", p)));
+    let suffixes = settings.strip_suffixes.iter().filter_map(|p| compile(p, format!(r"(?:{})\s*\z", p)));
+
+    let mut stripped = text.clone();
+    for re in prefixes.chain(suffixes) {
+        stripped = re.replace(&stripped, "").into_owned();
+    }
+    let stripped = stripped.trim().to_string();
+    if stripped.is_empty() || stripped == text.trim() {
+        return (text, None);
+    }
+    (stripped, Some(text))
+}
+
+/// 回答の根拠区分: 成功したツール実行を伴えば verified、なければ inferred
+fn grounding_of(tool_executions: &[ToolExecution]) -> &'static str {
+    if tool_executions.iter().any(|e| e.success) {
+        "verified"
+    } else {
+        "inferred"
+    }
 }
 
 // ========================================
 // Notion API — ソフトウェア情報フェッチ
 // ========================================
 
-/// Notionページからプレーンテキストを抽出（軽量: ブロック取得のみ）
-async fn fetch_notion_page_text(page_id: &str, api_key: &str) -> Result<String, String> {
-    let url = format!(
-        "https://api.notion.com/v1/blocks/{}/children?page_size=100",
-        page_id
-    );
+/// ネストしたブロック（toggle の中身・入れ子のリスト等）をたどる深さの上限
+const NOTION_MAX_DEPTH: usize = 3;
+/// Notion API の呼び出し間隔（平均 3 リクエスト/秒のレート制限に収める）
+const NOTION_REQUEST_INTERVAL_MS: u64 = 350;
+/// 1ページで取得するブロック数の上限（子ブロック含む。巨大なページで取得が終わらないのを防ぐ）
+const NOTION_MAX_BLOCKS: usize = 500;
+
+/// ブロックの子要素を next_cursor をたどって取得（limit 件に達したらそこで打ち切り、残りがあったかを返す）
+async fn fetch_notion_children(
+    client: &reqwest::Client,
+    block_id: &str,
+    api_key: &str,
+    limit: usize,
+) -> Result<(Vec<serde_json::Value>, bool), String> {
+    let mut blocks = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let mut url = format!(
+            "https://api.notion.com/v1/blocks/{}/children?page_size=100",
+            block_id
+        );
+        if let Some(cursor) = &cursor {
+            url.push_str(&format!("&start_cursor={}", cursor));
+            tokio::time::sleep(Duration::from_millis(NOTION_REQUEST_INTERVAL_MS)).await;
+        }
 
-    let client = reqwest::Client::new();
-    let resp = client
-        .get(&url)
-        .header("Authorization", format!("Bearer {}", api_key))
-        .header("Notion-Version", "2022-06-28")
-        .send()
-        .await
-        .map_err(|e| format!("Notion API error: {}", e))?;
+        let resp = client
+            .get(&url)
+            .header("Authorization", format!("Bearer {}", api_key))
+            .header("Notion-Version", "2022-06-28")
+            .send()
+            .await
+            .map_err(|e| format!("Notion API error: {}", e))?;
+
+        if !resp.status().is_success() {
+            return Err(format!("Notion API HTTP {}", resp.status()));
+        }
+
+        let body: serde_json::Value = resp
+            .json()
+            .await
+            .map_err(|e| format!("Notion parse error: {}", e))?;
+
+        let results = body["results"].as_array().map(Vec::as_slice).unwrap_or_default();
+        let room = limit - blocks.len();
+        blocks.extend(results.iter().take(room).cloned());
+        let has_more = body["has_more"].as_bool() == Some(true);
+        match body["next_cursor"].as_str() {
+            Some(next) if has_more && blocks.len() < limit => cursor = Some(next.to_string()),
+            _ => return Ok((blocks, results.len() > room || has_more)),
+        }
+    }
+}
 
-    if !resp.status().is_success() {
-        return Err(format!("Notion API HTTP {}", resp.status()));
+/// ブロック1つ分のテキスト（見出し・リストは記号付き）。テキストを持たないブロックは None
+fn notion_block_line(block: &serde_json::Value) -> Option<String> {
+    let block_type = block["type"].as_str().unwrap_or("");
+    let rich_text_path = match block_type {
+        "paragraph" => "paragraph",
+        "heading_1" => "heading_1",
+        "heading_2" => "heading_2",
+        "heading_3" => "heading_3",
+        "bulleted_list_item" => "bulleted_list_item",
+        "numbered_list_item" => "numbered_list_item",
+        "toggle" => "toggle",
+        "callout" => "callout",
+        _ => return None,
+    };
+
+    let text: String = block[rich_text_path]["rich_text"]
+        .as_array()?
+        .iter()
+        .filter_map(|rt| rt["plain_text"].as_str())
+        .collect::<Vec<&str>>()
+        .join("");
+    if text.is_empty() {
+        return None;
     }
+    let prefix = match block_type {
+        "heading_1" => "# ",
+        "heading_2" => "## ",
+        "heading_3" => "### ",
+        "bulleted_list_item" => "- ",
+        "numbered_list_item" => "• ",
+        _ => "",
+    };
+    Some(format!("{}{}", prefix, text))
+}
 
-    let body: serde_json::Value = resp
-        .json()
-        .await
-        .map_err(|e| format!("Notion parse error: {}", e))?;
+/// Notionページからプレーンテキストを抽出（子ブロックは NOTION_MAX_DEPTH までインデント付きで含める）
+/// 取得したブロックが NOTION_MAX_BLOCKS に達したら残りは取らず、打ち切ったことを末尾に記す
+async fn fetch_notion_page_text(page_id: &str, api_key: &str) -> Result<String, String> {
+    let client = reqwest::Client::new();
+    let (top, mut truncated) = fetch_notion_children(&client, page_id, api_key, NOTION_MAX_BLOCKS).await?;
+    let mut fetched = top.len();
 
-    // ブロックからテキストを抽出
+    // 深さ優先でたどり、本文の並び順のまま出力する（子ページ・子データベースは別ページなので入らない）
     let mut lines = Vec::new();
-    if let Some(results) = body["results"].as_array() {
-        for block in results {
-            let block_type = block["type"].as_str().unwrap_or("");
-            let rich_text_path = match block_type {
-                "paragraph" => Some("paragraph"),
-                "heading_1" => Some("heading_1"),
-                "heading_2" => Some("heading_2"),
-                "heading_3" => Some("heading_3"),
-                "bulleted_list_item" => Some("bulleted_list_item"),
-                "numbered_list_item" => Some("numbered_list_item"),
-                "toggle" => Some("toggle"),
-                "callout" => Some("callout"),
-                _ => None,
-            };
+    let mut stack: Vec<(std::vec::IntoIter<serde_json::Value>, usize)> = vec![(top.into_iter(), 0)];
+    while let Some((blocks, depth)) = stack.last_mut() {
+        let depth = *depth;
+        let Some(block) = blocks.next() else {
+            stack.pop();
+            continue;
+        };
+        if let Some(line) = notion_block_line(&block) {
+            lines.push(format!("{}{}", "  ".repeat(depth), line));
+        }
 
-            if let Some(path) = rich_text_path {
-                if let Some(rich_text) = block[path]["rich_text"].as_array() {
-                    let text: String = rich_text
-                        .iter()
-                        .filter_map(|rt| rt["plain_text"].as_str())
-                        .collect::<Vec<&str>>()
-                        .join("");
-                    if !text.is_empty() {
-                        let prefix = match block_type {
-                            "heading_1" => "# ",
-                            "heading_2" => "## ",
-                            "heading_3" => "### ",
-                            "bulleted_list_item" => "- ",
-                            "numbered_list_item" => "• ",
-                            _ => "",
-                        };
-                        lines.push(format!("{}{}", prefix, text));
-                    }
-                }
+        let block_type = block["type"].as_str().unwrap_or("");
+        let has_children = block["has_children"].as_bool() == Some(true)
+            && !matches!(block_type, "child_page" | "child_database");
+        if !has_children || depth >= NOTION_MAX_DEPTH {
+            continue;
+        }
+        if fetched >= NOTION_MAX_BLOCKS {
+            truncated = true;
+            continue;
+        }
+        let Some(block_id) = block["id"].as_str() else {
+            continue;
+        };
+        tokio::time::sleep(Duration::from_millis(NOTION_REQUEST_INTERVAL_MS)).await;
+        match fetch_notion_children(&client, block_id, api_key, NOTION_MAX_BLOCKS - fetched).await {
+            Ok((children, more)) => {
+                fetched += children.len();
+                truncated |= more;
+                stack.push((children.into_iter(), depth + 1));
             }
+            // 子ブロックの取得失敗はその部分だけ欠ける
+            Err(e) => eprintln!("[Nexus] Notion child blocks fetch failed ({}): {}", block_id, e),
         }
     }
 
     if lines.is_empty() {
-        Err("Notionページにテキストなし".to_string())
-    } else {
-        Ok(lines.join("\n"))
+        return Err("Notionページにテキストなし".to_string());
     }
+    if truncated {
+        eprintln!("[Nexus] Notion page {} reached the {} block limit", page_id, NOTION_MAX_BLOCKS);
+        lines.push(format!("…（ブロック数の上限 {} に達したため以降は省略）", NOTION_MAX_BLOCKS));
+    }
+    Ok(lines.join("\n"))
 }
 
-/// 全マシンのNotion情報を一括フェッチ
+/// 全マシンのNotion情報を一括フェッチ（NOTION_API_KEY 未設定ならエラー）
 async fn fetch_all_notion_info(
     machines: &[SshMachineConfig],
-) -> std::collections::HashMap<String, String> {
+) -> Result<std::collections::HashMap<String, String>, String> {
     let mut info = std::collections::HashMap::new();
 
     let api_key = match std::env::var("NOTION_API_KEY") {
         Ok(key) if !key.is_empty() => key,
-        _ => {
-            eprintln!("[Nexus] NOTION_API_KEY not set, skipping Notion fetch");
-            return info;
-        }
+        _ => return Err("NOTION_API_KEY が未設定です".to_string()),
     };
 
     for machine in machines {
@@ -263,26 +1155,191 @@ async fn fetch_all_notion_info(
         }
     }
 
-    info
+    Ok(info)
 }
 
-// ========================================
-// Tool Use — ヘルパー関数
-// ========================================
+/// Notion情報のディスクキャッシュ（起動のたびに全ページを取得しないため）
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct NotionSettings {
+    /// キャッシュの有効期間（時間）。0 なら毎回取得する
+    cache_ttl_hours: u64,
+}
 
-/// 利用可能なマシンからツール定義を動的生成
-fn build_tools(machines: &[SshMachineConfig]) -> Vec<serde_json::Value> {
+impl Default for NotionSettings {
+    fn default() -> Self {
+        Self { cache_ttl_hours: 24 }
+    }
+}
+
+const NOTION_CACHE_FILE: &str = "notion_cache.json";
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+struct NotionCache {
+    /// 取得時刻（UNIX秒）
+    fetched_at: u64,
+    /// マシン名 → ページのテキスト
+    info: std::collections::HashMap<String, String>,
+}
+
+fn load_notion_cache(path: &std::path::Path) -> Option<NotionCache> {
+    let content = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+fn save_notion_cache(path: &std::path::Path, cache: &NotionCache) -> Result<(), String> {
+    let json = serde_json::to_string(cache).map_err(|e| format!("JSON変換エラー: {}", e))?;
+    std::fs::write(path, json).map_err(|e| format!("書き込みエラー: {}", e))
+}
+
+fn notion_cache_fresh(cache: &NotionCache, ttl_hours: u64, now: u64) -> bool {
+    now.saturating_sub(cache.fetched_at) < ttl_hours.saturating_mul(3600)
+}
+
+/// refresh_notion_info の結果
+#[derive(Serialize)]
+struct NotionInfoStatus {
+    /// 情報を取得した時刻（UNIX秒）
+    fetched_at: u64,
+    machines: usize,
+    /// API を呼ばずにキャッシュを使った
+    from_cache: bool,
+}
+
+/// Notion情報を用意する: TTL 内のキャッシュがあればそれを使い、なければ取得してキャッシュに保存
+/// force なら TTL に関係なく取得する。取得できなかった場合（キー未設定・全ページ失敗）は古くてもキャッシュを使う
+async fn load_notion_info(
+    app_handle: &tauri::AppHandle,
+    force: bool,
+) -> Result<(NotionCache, bool), String> {
+    let ttl_hours = app_handle.state::<Mutex<AppSettings>>().lock().map(|s| s.notion.cache_ttl_hours).unwrap_or(24);
+    let machines = {
+        let ssh_state = app_handle.state::<Mutex<SshState>>();
+        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        state.machines.clone()
+    };
+    let path = app_data_path(app_handle, NOTION_CACHE_FILE)?;
+    let cached = load_notion_cache(&path);
+
+    if let Some(cache) = cached.as_ref().filter(|c| !force && notion_cache_fresh(c, ttl_hours, now_unix_secs())) {
+        return Ok((cache.clone(), true));
+    }
+    let fetched = fetch_all_notion_info(&machines).await.and_then(|info| {
+        if info.is_empty() {
+            Err("Notion情報を取得できませんでした（ページ未設定または取得失敗）".to_string())
+        } else {
+            Ok(info)
+        }
+    });
+    match (fetched, cached) {
+        (Ok(info), _) => {
+            let cache = NotionCache { fetched_at: now_unix_secs(), info };
+            if let Err(e) = save_notion_cache(&path, &cache) {
+                eprintln!("[Nexus] Warning: Notion cache save failed: {}", e);
+            }
+            Ok((cache, false))
+        }
+        (Err(e), Some(cache)) => {
+            eprintln!("[Nexus] Notion fetch skipped ({}), using cache from {}", e, cache.fetched_at);
+            Ok((cache, true))
+        }
+        (Err(e), None) => Err(e),
+    }
+}
+
+/// Notion情報を用意して SshState に反映する
+async fn apply_notion_info(app_handle: &tauri::AppHandle, force: bool) -> Result<NotionInfoStatus, String> {
+    let (cache, from_cache) = load_notion_info(app_handle, force).await?;
+    let status = NotionInfoStatus { fetched_at: cache.fetched_at, machines: cache.info.len(), from_cache };
+    let ssh_state = app_handle.state::<Mutex<SshState>>();
+    let mut state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+    state.notion_info = cache.info;
+    Ok(status)
+}
+
+/// Notion情報を強制的に取得し直す（取得できなければ既存のキャッシュを使う）
+#[tauri::command]
+async fn refresh_notion_info(app_handle: tauri::AppHandle) -> Result<NotionInfoStatus, String> {
+    apply_notion_info(&app_handle, true).await
+}
+
+// ========================================
+// Tool Use — ヘルパー関数
+// ========================================
+
+/// 利用可能なマシンからツール定義を動的生成
+fn build_tools(machines: &[SshMachineConfig]) -> Vec<serde_json::Value> {
     let machine_names: Vec<String> = machines
         .iter()
         .filter(|m| m.enabled && m.role != "Commander")
         .map(|m| m.name.clone())
         .collect();
+    let local_tool = local_machine(machines).map(|local| {
+        serde_json::json!({
+            "name": LOCAL_COMMAND_TOOL,
+            "description": format!(
+                "このアプリが動いているマシン（{}）でシェルコマンドを直接実行する（SSH は使わない）。{}。自分自身の状態確認に使い、リモートマシンには execute_remote_command を使う。",
+                local.name,
+                if cfg!(windows) { "シェルは cmd。PowerShell が必要なら powershell -Command \"...\" を使う" } else { "シェルは sh" }
+            ),
+            "input_schema": {
+                "type": "object",
+                "properties": {
+                    "command": {
+                        "type": "string",
+                        "description": "ローカルで実行するシェルコマンド"
+                    },
+                    "skip_syntax_check": {
+                        "type": "boolean",
+                        "description": "実行前のクォート・括弧チェックを省略する。チェックで止められたが意図どおりのコマンドである場合のみ true"
+                    },
+                    "purpose": {
+                        "type": "string",
+                        "enum": ToolPurpose::ALL.map(ToolPurpose::name),
+                        "description": "実行目的。investigate=調査（情報収集）、change=変更（設定・ファイル・サービス状態を変える）、verify=確認（変更後の検証）。迷ったら change"
+                    }
+                },
+                "required": ["command", "purpose"]
+            }
+        })
+    });
 
     if machine_names.is_empty() {
-        return vec![];
+        return local_tool.into_iter().collect();
     }
 
-    vec![serde_json::json!({
+    let multi_machine_tool = serde_json::json!({
+        "name": MULTI_MACHINE_TOOL,
+        "description": "複数のリモートマシンで同じシェルコマンドを並列実行する。複数台の状態をまとめて確認するときは execute_remote_command を繰り返さずこちらを使う。結果はマシンごとに返り、一部が失敗しても他の結果は得られる。マシンごとにOSやシェルが違う場合は、全台で有効なコマンドかに注意する。",
+        "input_schema": {
+            "type": "object",
+            "properties": {
+                "machine_names": {
+                    "type": "array",
+                    "description": format!("対象マシン名の配列。利用可能: {}", machine_names.join(", ")),
+                    "items": { "type": "string", "enum": machine_names },
+                    "minItems": 1,
+                    "uniqueItems": true
+                },
+                "command": {
+                    "type": "string",
+                    "description": "全マシンで実行するシェルコマンド（例: df -h）"
+                },
+                "skip_syntax_check": {
+                    "type": "boolean",
+                    "description": "実行前のクォート・括弧チェックを省略する。チェックで止められたが意図どおりのコマンドである場合のみ true"
+                },
+                "purpose": {
+                    "type": "string",
+                    "enum": ToolPurpose::ALL.map(ToolPurpose::name),
+                    "description": "実行目的。investigate=調査（情報収集）、change=変更（設定・ファイル・サービス状態を変える）、verify=確認（変更後の検証）。迷ったら change"
+                }
+            },
+            "required": ["machine_names", "command", "purpose"]
+        }
+    });
+
+    let single_machine_tool = serde_json::json!({
         "name": "execute_remote_command",
         "description": "リモートマシンでシェルコマンドを実行する。ディスク容量、プロセス確認、サービス状態など、システム情報の取得や管理タスクに使用。",
         "input_schema": {
@@ -296,15 +1353,190 @@ fn build_tools(machines: &[SshMachineConfig]) -> Vec<serde_json::Value> {
                 "command": {
                     "type": "string",
                     "description": "実行するシェルコマンド（例: df -h, free -m, systemctl status nginx）"
+                },
+                "skip_syntax_check": {
+                    "type": "boolean",
+                    "description": "実行前のクォート・括弧チェックを省略する。チェックで止められたが意図どおりのコマンドである場合のみ true"
+                },
+                "purpose": {
+                    "type": "string",
+                    "enum": ToolPurpose::ALL.map(ToolPurpose::name),
+                    "description": "実行目的。investigate=調査（情報収集）、change=変更（設定・ファイル・サービス状態を変える）、verify=確認（変更後の検証）。迷ったら change"
+                }
+            },
+            "required": ["machine_name", "command", "purpose"]
+        }
+    });
+    let read_file_tool = serde_json::json!({
+        "name": READ_FILE_TOOL,
+        "description": "リモートマシンのテキストファイルを読み取る（読み取り専用）。設定ファイルやログの内容確認には cat / type を execute_remote_command で実行せずこちらを使う。パスのエスケープはアプリ側で行う。max_bytes を超える分は切り詰められ、バイナリファイルは読み取れない。",
+        "input_schema": {
+            "type": "object",
+            "properties": {
+                "machine_name": {
+                    "type": "string",
+                    "description": format!("対象マシン名。利用可能: {}", machine_names.join(", ")),
+                    "enum": machine_names
+                },
+                "path": {
+                    "type": "string",
+                    "minLength": 1,
+                    "description": "読み取るファイルの絶対パス（例: /etc/nginx/nginx.conf, C:\\ProgramData\\app\\config.ini）"
+                },
+                "max_bytes": {
+                    "type": "integer",
+                    "minimum": 1,
+                    "maximum": READ_FILE_MAX_BYTES_LIMIT,
+                    "description": format!("読み取る最大バイト数（省略時 {}）", READ_FILE_DEFAULT_MAX_BYTES)
+                }
+            },
+            "required": ["machine_name", "path"]
+        }
+    });
+    let service_tool = serde_json::json!({
+        "name": SERVICE_TOOL,
+        "description": "リモートマシンのサービスの状態確認・起動・停止・再起動を行う（Linux は systemctl、Windows は sc / net）。サービス操作は execute_remote_command で systemctl 等を組み立てずこちらを使う。start / stop / restart はユーザーの承認を得てから実行され、操作後のサービスの状態を構造化して返す。",
+        "input_schema": {
+            "type": "object",
+            "properties": {
+                "machine_name": {
+                    "type": "string",
+                    "description": format!("対象マシン名。利用可能: {}", machine_names.join(", ")),
+                    "enum": machine_names
+                },
+                "service_name": {
+                    "type": "string",
+                    "pattern": "^[A-Za-z0-9@._:+-]+$",
+                    "description": "サービス名（例: nginx, docker.service, Spooler）。Windows はサービス名（表示名ではない）"
+                },
+                "action": {
+                    "type": "string",
+                    "enum": ServiceAction::ALL.map(ServiceAction::name),
+                    "description": "status=状態確認、start=起動、stop=停止、restart=再起動"
                 }
             },
-            "required": ["machine_name", "command"]
+            "required": ["machine_name", "service_name", "action"]
+        }
+    });
+    let mut tools = vec![single_machine_tool, multi_machine_tool, read_file_tool, service_tool];
+    tools.extend(local_tool);
+    tools
+}
+
+/// read_remote_file 用のコマンドをマシンのシェルに合わせて組み立てる（パスはクォート済み）
+/// bash は max_bytes+1 バイトだけ読み、超過の有無を判定できるようにする
+fn read_file_command(machine: &SshMachineConfig, path: &str, max_bytes: u64) -> Result<String, String> {
+    if path.contains(['\n', '\r', '\0']) {
+        return Err(format!("パスに改行・NUL は使えません: {}", path.escape_debug()));
+    }
+    Ok(match machine_shell(machine) {
+        ShellKind::Bash => format!("head -c {} -- {}", max_bytes + 1, escape_for_bash(path)),
+        ShellKind::PowerShell => format!(
+            "$c = Get-Content -LiteralPath {} -Raw -ErrorAction Stop; if ($c) {{ $c.Substring(0, [Math]::Min($c.Length, {})) }}",
+            escape_for_powershell(path),
+            max_bytes + 1
+        ),
+        ShellKind::Cmd => {
+            if path.contains('"') {
+                return Err(format!("パスに \" は使えません: {}", path));
+            }
+            format!("type {}", escape_for_cmd(path))
+        }
+    })
+}
+
+/// 読み取ったファイル内容の後処理：バイナリなら失敗にし、max_bytes を超える分は切り詰める
+fn apply_file_read_limit(exec: &mut ToolExecution, max_bytes: usize, lang: &str) {
+    if !exec.success {
+        return;
+    }
+    // 上限で切った位置が複数バイト文字の途中だと末尾が置換文字になるため、末尾のものは判定から除く
+    let sample: Vec<char> = exec.stdout.trim_end_matches('\u{FFFD}').chars().take(8192).collect();
+    let control_chars = sample
+        .iter()
+        .filter(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x0c' | '\x1b'))
+        .count();
+    if sample.contains(&'\0') || sample.contains(&'\u{FFFD}') || control_chars * 10 > sample.len() {
+        exec.success = false;
+        exec.stdout.clear();
+        exec.stderr = tr(lang, "file_not_text", &[]);
+        return;
+    }
+    if exec.stdout.len() > max_bytes {
+        let mut end = max_bytes;
+        while !exec.stdout.is_char_boundary(end) {
+            end -= 1;
         }
-    })]
+        exec.stdout.truncate(end);
+        exec.stdout.push_str("\n...(truncated)");
+    }
+}
+
+/// ロール/タグ別ポリシーのうち、ツールで操作可能なマシンに該当するものだけを指示行にする
+fn role_policy_lines(
+    machines: &[SshMachineConfig],
+    role_policies: &std::collections::BTreeMap<String, String>,
+) -> Vec<String> {
+    role_policies
+        .iter()
+        .filter_map(|(key, instruction)| {
+            let targets: Vec<&str> = machines
+                .iter()
+                .filter(|m| m.enabled && m.role != "Commander")
+                .filter(|m| m.role.eq_ignore_ascii_case(key) || m.tags.iter().any(|t| t.eq_ignore_ascii_case(key)))
+                .map(|m| m.name.as_str())
+                .collect();
+            if targets.is_empty() {
+                None
+            } else {
+                Some(format!("- [{}] {}（対象: {}）", key, instruction.trim(), targets.join(", ")))
+            }
+        })
+        .collect()
+}
+
+/// システムプロンプトの詳細度
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum PromptStyle {
+    Detailed, // ルールを理由・例つきで説明（Sonnet / Opus 向け）
+    Concise,  // ルールを要点だけに絞りトークンを節約（Haiku 向け）
+}
+
+/// モデル1つ分の調整（未指定の style はモデルごとの既定）
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+struct ModelPromptSettings {
+    style: Option<PromptStyle>,
+    /// システムプロンプト末尾に追加する指示（トーン等）
+    extra_instructions: String,
+}
+
+/// モデル別のシステムプロンプト調整（settings.toml の [prompts.sonnet] 等）
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+struct PromptSettings {
+    sonnet: ModelPromptSettings,
+    haiku: ModelPromptSettings,
+    opus: ModelPromptSettings,
+}
+
+impl PromptSettings {
+    /// モデルIDに対応する (詳細度, 追加指示)。未知のモデルは詳細版
+    fn for_model(&self, model_id: &str) -> (PromptStyle, &str) {
+        let (settings, default_style) = match find_model(model_id).map(|m| m.alias) {
+            Some("haiku") => (&self.haiku, PromptStyle::Concise),
+            Some("opus") => (&self.opus, PromptStyle::Detailed),
+            _ => (&self.sonnet, PromptStyle::Detailed),
+        };
+        (settings.style.unwrap_or(default_style), settings.extra_instructions.trim())
+    }
 }
 
-/// システムプロンプト生成（マシン情報を注入）
-fn build_system_prompt(machines: &[SshMachineConfig], notion_info: &std::collections::HashMap<String, String>) -> String {
+/// システムプロンプト生成（マシン情報・ロール別ポリシーを注入し、モデルに合わせて詳細度を変える）
+fn build_system_prompt(ssh: &SshState, model_id: &str, prompts: &PromptSettings) -> String {
+    let machines = &ssh.machines;
+    let notion_info = &ssh.notion_info;
     let machine_info: Vec<String> = machines
         .iter()
         .map(|m| {
@@ -320,1093 +1552,10746 @@ fn build_system_prompt(machines: &[SshMachineConfig], notion_info: &std::collect
             } else {
                 format!(" — {}", m.notes)
             };
+            let shell_part = m.shell.map_or(String::new(), |sh| format!(" shell={}", sh.name()));
+            let tags_part = if m.tags.is_empty() {
+                String::new()
+            } else {
+                format!(" tags={}", m.tags.join(","))
+            };
+            // 許可リストを知らせて、拒否されるコマンドを最初から選ばせない
+            let allowlist_part = m.allowed_command_prefixes.as_ref().map_or(String::new(), |prefixes| {
+                format!(" 許可コマンド(前方一致、連結不可)={}", prefixes.join(","))
+            });
             let notion_part = notion_info.get(&m.name).map_or(String::new(), |info| {
                 format!("\n  ソフトウェア情報:\n  {}", info.replace('\n', "\n  "))
             });
-            format!("- {} ({}): OS={}, {} [{}]{}{}", m.name, m.role, m.os, status, m.host, notes_part, notion_part)
+            format!(
+                "- {} ({}): OS={}{}, {} [{}]{}{}{}{}",
+                m.name, m.role, m.os, shell_part, status, m.host, tags_part, allowlist_part, notes_part, notion_part
+            )
         })
         .collect();
 
-    format!(
-        "あなたはProject Nexusのシステム管理アシスタントです。\n\
-         管理対象マシン:\n{}\n\n\
-         重要なルール:\n\
+    let policies = role_policy_lines(machines, &ssh.role_policies);
+    let policy_part = if policies.is_empty() {
+        String::new()
+    } else {
+        format!("\n\nマシン別の運用ポリシー（該当マシンを操作するときは必ず従うこと）:\n{}", policies.join("\n"))
+    };
+
+    let (style, extra_instructions) = prompts.for_model(model_id);
+    let rules = match style {
+        PromptStyle::Detailed => "重要なルール:\n\
          - 各マシンのOSに対応したコマンドを使うこと（WindowsならPowerShell/cmd、Linuxならbash）\n\
          - Windowsマシンではdu/find等のLinuxコマンドは使わず、dir/powershell/Get-ChildItem等を使う\n\
          - SSHでのWindows接続はcmd.exeシェルで実行される。PowerShellが必要なら powershell -Command \"...\" を使う\n\
+         - shell= の指定があるマシンでは、そのシェル（cmd / powershell / bash）の構文でコマンドを書く（ラップとエスケープはアプリ側で行う）\n\
          - コマンドは1回で正確に実行し、試行錯誤を最小限にする\n\
          - 結果は日本語で簡潔に説明する\n\
          - コマンド実行が不要な質問には通常通り回答する",
-        machine_info.join("\n")
+        PromptStyle::Concise => "ルール:\n\
+         - OSに合うコマンドを使う（Windowsは既定cmd、PowerShellは powershell -Command \"...\"）\n\
+         - shell= 指定のマシンはそのシェルの構文で書く\n\
+         - 試行錯誤を避け、日本語で簡潔に答える",
+    };
+    let extra_part = if extra_instructions.is_empty() {
+        String::new()
+    } else {
+        format!("\n\n{}", extra_instructions)
+    };
+
+    format!(
+        "あなたはProject Nexusのシステム管理アシスタントです。\n\
+         管理対象マシン:\n{}\n\n{}{}{}\n\n{}",
+        machine_info.join("\n"),
+        rules,
+        policy_part,
+        extra_part,
+        current_datetime_line(chrono::Local::now())
+    )
+}
+
+const CURRENT_DATETIME_LABEL: &str = "現在日時: ";
+
+/// Commander の現在日時とタイムゾーン（送信のたびに組み立て直すので常に最新）
+fn current_datetime_line<Tz: chrono::TimeZone>(now: chrono::DateTime<Tz>) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let offset = now.format("%:z").to_string();
+    let zone = if offset == "+09:00" { "JST".to_string() } else { format!("UTC{}", offset) };
+    format!(
+        "{}{} {}（ログの日付や「昨日」「最近」等の相対的な表現はこの日時を基準に解釈すること）",
+        CURRENT_DATETIME_LABEL,
+        now.format("%Y-%m-%d %H:%M"),
+        zone
     )
 }
 
+/// コマンドのクォート・括弧の対応を検査（純粋関数、誤検知を避けるため保守的）
+/// 不整合があればメッセージカタログのキーと問題の文字を返す。
+/// ヒアドキュメント/ヒアストリングや case 文など、単純な走査で判断できない構文は検査しない
+fn check_command_syntax(command: &str, os: &str) -> Result<(), (&'static str, char)> {
+    if command.contains("<<") || command.contains("@'") || command.contains("@\"") {
+        return Ok(());
+    }
+    let check_brackets = !command.split_whitespace().any(|w| w == "case");
+    // エスケープ文字: PowerShell はバッククォート、それ以外はバックスラッシュ
+    let escape = if os.eq_ignore_ascii_case("windows") { '`' } else { '\\' };
+
+    let mut quote: Option<char> = None;
+    let mut brackets: Vec<char> = Vec::new();
+    let mut prev = ' ';
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match quote {
+            // シングルクォート内はエスケープなし
+            Some('\'') => {
+                if c == '\'' {
+                    quote = None;
+                }
+            }
+            Some(q) => {
+                if c == escape {
+                    chars.next();
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None => match c {
+                _ if c == escape => {
+                    chars.next();
+                }
+                '\'' | '"' => quote = Some(c),
+                // 行頭・空白直後の # 以降はコメント
+                '#' if prev.is_whitespace() => break,
+                '(' | '[' | '{' if check_brackets => brackets.push(c),
+                ')' | ']' | '}' if check_brackets => {
+                    let open = match c {
+                        ')' => '(',
+                        ']' => '[',
+                        _ => '{',
+                    };
+                    if brackets.pop() != Some(open) {
+                        return Err(("syntax_unexpected_closer", c));
+                    }
+                }
+                _ => {}
+            },
+        }
+        prev = c;
+    }
+
+    if let Some(q) = quote {
+        return Err(("syntax_unclosed_quote", q));
+    }
+    if let Some(open) = brackets.pop() {
+        return Err(("syntax_unclosed_bracket", open));
+    }
+    Ok(())
+}
+
+/// whoami の結果が期待ユーザーと一致するか（Windowsの "domain\\user" 形式はユーザー部分でも比較）
+fn remote_user_matches(actual: &str, expected: &str) -> bool {
+    let actual = actual.trim().to_lowercase();
+    let expected = expected.trim().to_lowercase();
+    let actual_user = actual.rsplit('\\').next().unwrap_or(&actual);
+    actual == expected || actual_user == expected
+}
+
+/// 実行ユーザーを whoami で確認（不一致・確認失敗時は理由を返す）
+async fn verify_remote_user(machine: &SshMachineConfig, expected: &str, lang: &str) -> Result<(), String> {
+    let output = run_ssh_command(machine, "whoami", SSH_TIMEOUT_SECS, lang)
+        .await
+        .map_err(|e| tr(lang, "user_check_failed", &[("error", &e)]))?;
+    let actual = output.stdout.trim();
+    if !output.success || actual.is_empty() {
+        return Err(tr(lang, "user_check_failed", &[("error", output.stderr.trim())]));
+    }
+    if remote_user_matches(actual, expected) {
+        Ok(())
+    } else {
+        eprintln!("[Nexus] User guard mismatch on {}: expected {}, got {}", machine.name, expected, actual);
+        Err(tr(lang, "user_mismatch", &[("expected", expected), ("actual", actual)]))
+    }
+}
+
+/// 実行ユーザー不一致のときの実行可否（approval は承認を求めた結果、None は承認を求められなかった）
+/// 拒否時は不一致の内容も Claude に伝わるよう、拒否理由の前に付ける
+fn user_guard_outcome(reason: &str, approval: Option<Result<(), String>>, lang: &str) -> Result<(), String> {
+    match approval {
+        Some(Ok(())) => {
+            eprintln!("[Nexus] User guard mismatch approved by user: {}", reason);
+            Ok(())
+        }
+        Some(Err(rejection)) => Err(format!("{}: {}", reason, rejection)),
+        None => Err(tr(lang, "user_guard_blocked", &[("reason", reason)])),
+    }
+}
+
+/// 許可リストのモードでは連結・置換・リダイレクトで別コマンドを紛れ込ませられないよう、これらを含むコマンドは拒否
+const ALLOWLIST_FORBIDDEN_TOKENS: &[&str] = &[";", "&", "|", "`", "$(", ">", "<", "\n", "\r"];
+
+/// コマンドが許可リストのいずれかに前方一致するか（空リストはすべて拒否）
+/// 接頭辞が英数字で終わる場合は単語境界まで一致を求める（"df" は "df -h" に一致し "dfx" には一致しない）
+/// Windows のコマンドは大文字小文字を区別しない
+fn is_command_allowed(command: &str, prefixes: &[String], os: &str) -> bool {
+    let command = command.trim();
+    if ALLOWLIST_FORBIDDEN_TOKENS.iter().any(|token| command.contains(token)) {
+        return false;
+    }
+    let ignore_case = os.eq_ignore_ascii_case("windows");
+    prefixes.iter().map(|p| p.trim()).filter(|p| !p.is_empty()).any(|prefix| {
+        let Some(head) = command.get(..prefix.len()) else {
+            return false;
+        };
+        let matched = if ignore_case { head.eq_ignore_ascii_case(prefix) } else { head == prefix };
+        let at_boundary = !prefix.ends_with(|c: char| c.is_alphanumeric())
+            || !command[prefix.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '-' || c == '_');
+        matched && at_boundary
+    })
+}
+
 /// ツール実行（SSH経由）
 async fn execute_tool_ssh(
     machine_name: &str,
     command: &str,
-    machines: &[SshMachineConfig],
+    skip_syntax_check: bool,
+    purpose: Option<ToolPurpose>,
+    mut run: ToolRun,
+    ctx: &ToolContext,
 ) -> ToolExecution {
-    let machine = machines
+    let lang = ctx.settings.language.as_str();
+    let machine = ctx
+        .machines
         .iter()
         .find(|m| m.name == machine_name && m.enabled && m.role != "Commander");
 
     let Some(machine) = machine else {
-        return ToolExecution {
-            machine_name: machine_name.to_string(),
-            command: command.to_string(),
-            stdout: String::new(),
-            stderr: format!("マシン '{}' が見つからないか無効です", machine_name),
-            success: false,
-        };
+        let stderr = tr(lang, "machine_unavailable", &[("machine", machine_name)]);
+        return ToolExecution::failed(machine_name, command, stderr, 0, "");
     };
 
-    let result = timeout(
-        Duration::from_secs(30),
-        TokioCommand::new("ssh")
-            .args([
-                "-o", "BatchMode=yes",
-                "-o", "ConnectTimeout=5",
-                "-o", "ServerAliveInterval=30",
-                "-o", "ServerAliveCountMax=3",
-                &machine.host,
-                command,
-            ])
-            .output(),
-    )
-    .await;
+    let (timeout_secs, timeout_source) = resolve_command_timeout(machine, &ctx.settings);
 
-    match result {
-        Ok(Ok(output)) => ToolExecution {
-            machine_name: machine_name.to_string(),
-            command: command.to_string(),
-            stdout: decode_bytes(&output.stdout),
-            stderr: decode_bytes(&output.stderr),
-            success: output.status.success(),
-        },
-        Ok(Err(e)) => ToolExecution {
-            machine_name: machine_name.to_string(),
-            command: command.to_string(),
-            stdout: String::new(),
-            stderr: format!("SSH実行エラー: {}", e),
-            success: false,
-        },
-        Err(_) => ToolExecution {
-            machine_name: machine_name.to_string(),
-            command: command.to_string(),
-            stdout: String::new(),
-            stderr: "タイムアウト（30秒）".to_string(),
-            success: false,
-        },
+    // 許可リスト（allowed_command_prefixes）設定時は、一致しないコマンドを送らない
+    if let Some(prefixes) = &machine.allowed_command_prefixes {
+        if !is_command_allowed(command, prefixes, &machine.os) {
+            let allowed = if prefixes.is_empty() { "(なし)".to_string() } else { prefixes.join(", ") };
+            let stderr = tr(lang, "command_not_allowed", &[("machine", machine_name), ("prefixes", &allowed)]);
+            return ToolExecution::failed(machine_name, command, stderr, timeout_secs, timeout_source);
+        }
     }
-}
 
-/// Anthropic API呼び出し（共通）
-async fn call_anthropic(
-    api_key: &str,
-    model: &str,
-    system: &str,
-    tools: &[serde_json::Value],
-    messages: &[serde_json::Value],
-) -> Result<ApiResponse, String> {
-    let client = reqwest::Client::new();
+    // 明らかな構文エラー（クォート・括弧の不整合）は送らずに差し戻す
+    if !skip_syntax_check {
+        if let Err((key, ch)) = check_command_syntax(command, &machine.os) {
+            let detail = tr(lang, key, &[("char", &ch.to_string())]);
+            let stderr = tr(lang, "syntax_check_failed", &[("detail", &detail)]);
+            return ToolExecution::failed(machine_name, command, stderr, timeout_secs, timeout_source);
+        }
+    }
 
-    let body = ApiRequest {
-        model: model.to_string(),
-        max_tokens: 4096,
-        system: Some(system.to_string()),
-        messages: messages.to_vec(),
-        tools: if tools.is_empty() {
-            None
-        } else {
-            Some(tools.to_vec())
-        },
-        stream: None,
-    };
+    // 読み取り系は期限内のキャッシュを返し、書き込み系は実行前後にそのマシンのキャッシュを無効化する
+    let cache = &ctx.settings.tool_cache;
+    let cache_use = classify_for_tool_cache(command, purpose, cache);
+    if cache.enabled {
+        match cache_use {
+            ToolCacheUse::Read => {
+                if let Some(hit) = lookup_tool_cache(machine_name, command, cache) {
+                    return hit;
+                }
+            }
+            ToolCacheUse::Write { whole_machine } => invalidate_tool_cache(machine_name, command, whole_machine, cache),
+            ToolCacheUse::Fresh => {}
+        }
+    }
 
-    let response = client
-        .post(API_URL)
-        .header("x-api-key", api_key)
-        .header("anthropic-version", "2023-06-01")
-        .header("content-type", "application/json")
-        .json(&body)
-        .send()
-        .await
-        .map_err(|e| format!("API接続エラー: {}", e))?;
+    // 短時間の連続実行からマシンを守る（超過時は待機、待ちきれなければ拒否）
+    if !acquire_rate_limit(machine, &ctx.app_handle).await {
+        let limit = machine.rate_limit.clone().unwrap_or_default();
+        let stderr = tr(lang, "rate_limited", &[
+            ("machine", machine_name),
+            ("calls", &limit.max_calls.to_string()),
+            ("secs", &limit.per_secs.to_string()),
+        ]);
+        return ToolExecution::failed(machine_name, command, stderr, timeout_secs, timeout_source);
+    }
 
-    let status = response.status();
-    let response_text = response
-        .text()
-        .await
-        .map_err(|e| format!("レスポンス読み取りエラー: {}", e))?;
-
-    if !status.is_success() {
-        if let Ok(err) = serde_json::from_str::<ApiError>(&response_text) {
-            if let Some(detail) = err.error {
-                return Err(format!(
-                    "API Error ({}): {}",
-                    status,
-                    detail.message.unwrap_or_default()
-                ));
+    // 同時実行枠を待つ（ユーザー起点の対話ツールは定期ジョブより優先）
+    let _permit = ctx
+        .app_handle
+        .state::<ToolQueue>()
+        .acquire(ToolPriority::High, machine_name, command)
+        .await;
+
+    // 接続候補（hosts）が複数あれば、直近に繋がったホスト → 優先順の疎通確認で接続先を決める
+    let failover = host_candidates(machine).len() > 1;
+    let resolved;
+    let machine = if failover {
+        let host = match cached_host(&machine.name) {
+            Some(host) => Some(host),
+            None => find_reachable_host(machine).await,
+        };
+        let Some(host) = host else {
+            let stderr = tr(lang, "hosts_unreachable", &[("machine", machine_name), ("hosts", &machine.hosts.join(", "))]);
+            return ToolExecution::failed(machine_name, command, stderr, timeout_secs, timeout_source);
+        };
+        resolved = with_host(machine, &host);
+        &resolved
+    } else {
+        machine
+    };
+
+    // 実行ユーザー確認ガード（expected_user 設定時のみ、SSH往復が1回増える）
+    // 不一致なら警告して承認を求め、承認されたときだけ実行する（承認機能が無効なら実行しない）
+    if let Some(expected) = &machine.expected_user {
+        if let Err(reason) = verify_remote_user(machine, expected, lang).await {
+            let approval = if ctx.settings.approval.enabled {
+                Some(request_tool_approval(&run, machine_name, command, purpose, ApprovalKind::UserMismatch, &reason, ctx).await)
+            } else {
+                ctx.emit("tool-user-mismatch", serde_json::json!({
+                    "machine_name": machine_name,
+                    "command": command,
+                    "reason": reason
+                }));
+                None
+            };
+            if let Err(stderr) = user_guard_outcome(&reason, approval, lang) {
+                return ToolExecution::failed(machine_name, command, stderr, timeout_secs, timeout_source);
             }
         }
-        return Err(format!("API Error ({}): {}", status, response_text));
     }
 
-    serde_json::from_str(&response_text)
-        .map_err(|e| format!("レスポンスパースエラー: {} / body: {}", e, &response_text[..200.min(response_text.len())]))
-}
+    // 順番待ちの間にキャンセルされていれば送らない
+    if *run.cancel.borrow() {
+        return ToolExecution::failed(machine_name, command, tr(lang, "tool_cancelled", &[]), timeout_secs, timeout_source);
+    }
 
-// ========================================
-// Streaming SSE Parser
-// ========================================
-
-/// SSEストリーミングでAnthropic APIを呼び出し、Tauriイベントでフロントに配信
-/// Tool Use発生時はツール実行後に再ストリームするループ構造
-async fn call_anthropic_stream(
-    api_key: &str,
-    model: &str,
-    system: &str,
-    tools: &[serde_json::Value],
-    messages: &[serde_json::Value],
-    app_handle: &tauri::AppHandle,
-    machines: &[SshMachineConfig],
-) -> Result<(String, Vec<ToolExecution>, UsageInfo, u64), String> {
-    let client = reqwest::Client::new();
-    let mut api_messages = messages.to_vec();
-    let mut all_text_parts: Vec<String> = Vec::new();
-    let mut all_tool_executions: Vec<ToolExecution> = Vec::new();
-    let mut total_usage = UsageInfo::default();
-    let mut last_call_input_tokens: u64 = 0;
-
-    for _loop_count in 0..MAX_TOOL_LOOPS {
-        let body = ApiRequest {
-            model: model.to_string(),
-            max_tokens: 4096,
-            system: Some(system.to_string()),
-            messages: api_messages.clone(),
-            tools: if tools.is_empty() {
-                None
-            } else {
-                Some(tools.to_vec())
-            },
-            stream: Some(true),
-        };
-
-        let response = client
-            .post(API_URL)
-            .header("x-api-key", api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&body)
-            .send()
-            .await
-            .map_err(|e| format!("API接続エラー: {}", e))?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let text = response.text().await.unwrap_or_default();
-            return Err(format!("API Error ({}): {}", status, &text[..200.min(text.len())]));
-        }
-
-        // SSEパース状態
-        let mut current_text = String::new();
-        let mut content_blocks: Vec<serde_json::Value> = Vec::new();
-        // tool_use蓄積用: index → (id, name, input_json_str)
-        let mut tool_use_map: std::collections::HashMap<u64, (String, String, String)> =
-            std::collections::HashMap::new();
-        let mut stop_reason: Option<String> = None;
-        let mut line_buf = String::new();
-
-        let mut byte_stream = response.bytes_stream();
-        while let Some(chunk_result) = byte_stream.next().await {
-            let chunk = chunk_result.map_err(|e| format!("Stream error: {}", e))?;
-            let chunk_str = String::from_utf8_lossy(&chunk);
-
-            line_buf.push_str(&chunk_str);
-
-            // 改行で分割してSSEイベントを処理
-            while let Some(newline_pos) = line_buf.find('\n') {
-                let line = line_buf[..newline_pos].trim_end_matches('\r').to_string();
-                line_buf = line_buf[newline_pos + 1..].to_string();
-
-                if !line.starts_with("data: ") {
-                    continue;
-                }
-                let data = &line[6..];
-                if data == "[DONE]" {
-                    continue;
-                }
-
-                let Ok(event) = serde_json::from_str::<serde_json::Value>(data) else {
-                    continue;
-                };
-
-                let event_type = event.get("type").and_then(|t| t.as_str()).unwrap_or("");
-
-                match event_type {
-                    "message_start" => {
-                        // input_tokens取得
-                        if let Some(usage) = event.pointer("/message/usage") {
-                            if let Some(it) = usage.get("input_tokens").and_then(|v| v.as_u64()) {
-                                last_call_input_tokens = it;
-                                total_usage.input_tokens += it;
-                            }
-                        }
-                    }
-                    "content_block_start" => {
-                        let index = event.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
-                        if let Some(block) = event.get("content_block") {
-                            let block_type = block.get("type").and_then(|t| t.as_str()).unwrap_or("");
-                            if block_type == "tool_use" {
-                                let id = block.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                                let name = block.get("name").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                                tool_use_map.insert(index, (id, name, String::new()));
-                            }
-                        }
-                    }
-                    "content_block_delta" => {
-                        let index = event.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
-                        if let Some(delta) = event.get("delta") {
-                            let delta_type = delta.get("type").and_then(|t| t.as_str()).unwrap_or("");
-                            match delta_type {
-                                "text_delta" => {
-                                    if let Some(text) = delta.get("text").and_then(|t| t.as_str()) {
-                                        current_text.push_str(text);
-                                        // フロントエンドにデルタ送信
-                                        let _ = app_handle.emit("stream-delta", serde_json::json!({ "text": text }));
-                                    }
-                                }
-                                "input_json_delta" => {
-                                    if let Some(partial) = delta.get("partial_json").and_then(|t| t.as_str()) {
-                                        if let Some(entry) = tool_use_map.get_mut(&index) {
-                                            entry.2.push_str(partial);
-                                        }
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    "message_delta" => {
-                        if let Some(delta) = event.get("delta") {
-                            if let Some(sr) = delta.get("stop_reason").and_then(|v| v.as_str()) {
-                                stop_reason = Some(sr.to_string());
-                            }
-                        }
-                        if let Some(usage) = event.get("usage") {
-                            if let Some(ot) = usage.get("output_tokens").and_then(|v| v.as_u64()) {
-                                total_usage.output_tokens += ot;
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-            }
+    // 実行前フック（非ゼロ終了・タイムアウトなら本コマンドは送らない）
+    if let Some(record) = run_command_hook(HookPhase::Pre, machine, command, purpose, None, &ctx.app_handle, &ctx.trace_id).await {
+        if !record.succeeded() {
+            let stderr = tr(lang, "pre_hook_failed", &[("detail", &pre_hook_failure_detail(&record))]);
+            return ToolExecution::failed(machine_name, command, stderr, timeout_secs, timeout_source);
         }
+    }
 
-        // テキスト部分を保存
-        if !current_text.is_empty() {
-            all_text_parts.push(current_text.clone());
+    // 出力は行ごとに tool-output-line で逐次通知（長時間コマンドの途中経過を見せる）
+    // タイムアウト時に確認するモードでは tool-timeout-pending で「さらに待つ / 中止」を選ばせる
+    let (execution_id, sequence) = (run.execution_id.clone(), run.sequence);
+    let on_event = |event: StreamEvent| match event {
+        StreamEvent::Line(line, stream) => ctx.emit("tool-output-line", serde_json::json!({
+            "sequence": sequence,
+            "machine_name": machine_name,
+            "command": command,
+            "line": line,
+            "stream": stream.name()
+        })),
+        StreamEvent::TimeoutPending { elapsed_secs, decision_wait_secs } => {
+            ctx.emit("tool-timeout-pending", serde_json::json!({
+                "execution_id": execution_id,
+                "sequence": sequence,
+                "machine_name": machine_name,
+                "command": command,
+                "elapsed_secs": elapsed_secs,
+                "extend_secs": ctx.settings.timeouts.extend_secs,
+                "decision_wait_secs": decision_wait_secs
+            }));
+            notify_if_hidden(
+                &ctx.app_handle,
+                true,
+                format!("{} のコマンドが{}秒を超えました: {}", machine_name, elapsed_secs, notification_excerpt(command)),
+            );
         }
-
-        // content_blocksを再構築（履歴用）
-        if !current_text.is_empty() {
-            content_blocks.push(serde_json::json!({ "type": "text", "text": current_text }));
+    };
+    let ask = ctx.settings.timeouts.ask_policy();
+    let streaming = run_ssh_command_streaming(machine, command, timeout_secs, ask, lang, &mut run, on_event);
+    // 負荷モニタリング: コマンドが終わった時点で打ち切る（取得中の ssh も kill_on_drop で止まる）
+    let result = if ctx.settings.load_monitor.enabled {
+        tokio::select! {
+            result = streaming => result,
+            never = monitor_machine_load(machine, &execution_id, sequence, ctx) => match never {},
         }
-        for (_, (id, name, input_json)) in &tool_use_map {
-            let input: serde_json::Value = serde_json::from_str(input_json).unwrap_or(serde_json::json!({}));
-            content_blocks.push(serde_json::json!({
-                "type": "tool_use",
-                "id": id,
-                "name": name,
-                "input": input
-            }));
+    } else {
+        streaming.await
+    };
+    let execution = match result {
+        Ok(mut output) => {
+            // 公開鍵認証の失敗は、agent の状態から具体的な対処を添える（パスフレーズ付き鍵は agent 経由でしか使えない）
+            if output.exit_code == 255 && output.stderr.to_lowercase().contains("permission denied") {
+                if let Some(hint) = ssh_agent_hint(&check_ssh_agent().await, lang) {
+                    output.stderr = format!("{}\n{}", output.stderr.trim_end(), hint);
+                }
+            }
+            if output.success {
+                record_command_metrics(&ctx.app_handle, machine_name, command, &output.stdout);
+            }
+            // ssh 自体の失敗（255）なら次回はキャッシュを使わず候補を順に試し直す
+            if failover && output.exit_code == 255 {
+                forget_cached_host(&machine.name);
+            }
+            let parsed = output.success.then(|| parse_tool_output(command, &output.stdout)).flatten();
+            ToolExecution {
+                execution_id: next_execution_id(),
+                machine_name: machine_name.to_string(),
+                command: command.to_string(),
+                stdout: output.stdout,
+                stderr: output.stderr,
+                success: output.success,
+                timeout_secs,
+                timeout_source: timeout_source.to_string(),
+                summary: None,
+                parsed,
+                purpose: None,
+                sequence: 0,
+                cancelled: false,
+                connected_host: failover.then(|| machine.host.clone()),
+                cached: false,
+                truncated: false,
+            }
         }
+        Err(e) => ToolExecution::failed(machine_name, command, e, timeout_secs, timeout_source),
+    };
 
-        // アシスタント応答をメッセージ配列に追加
-        api_messages.push(serde_json::json!({
-            "role": "assistant",
-            "content": content_blocks
-        }));
-
-        // ツール呼び出しがなければ終了
-        if tool_use_map.is_empty() || stop_reason.as_deref() != Some("tool_use") {
-            break;
+    // 実行中に並行した読み取りが古い結果を入れた可能性があるため、書き込み後にもう一度無効化する
+    if cache.enabled {
+        match cache_use {
+            ToolCacheUse::Read if execution.success => store_tool_cache(machine_name, command, &execution, cache),
+            ToolCacheUse::Write { whole_machine } => invalidate_tool_cache(machine_name, command, whole_machine, cache),
+            _ => {}
         }
+    }
 
-        // ツール実行
-        let mut tool_results: Vec<serde_json::Value> = Vec::new();
-        // indexでソートして順番に実行
-        let mut sorted_tools: Vec<_> = tool_use_map.into_iter().collect();
-        sorted_tools.sort_by_key(|(idx, _)| *idx);
-
-        for (_, (tool_id, tool_name, input_json)) in sorted_tools {
-            let input: serde_json::Value = serde_json::from_str(&input_json).unwrap_or(serde_json::json!({}));
-            let machine_name = input.get("machine_name").and_then(|v| v.as_str()).unwrap_or("unknown");
-            let command = input.get("command").and_then(|v| v.as_str()).unwrap_or("");
+    // 実行後フック（結果は audit ログのみ、ツール結果には影響しない）
+    run_command_hook(HookPhase::Post, machine, command, purpose, Some(execution.success), &ctx.app_handle, &ctx.trace_id).await;
+    execution
+}
 
-            let _ = app_handle.emit("tool-executing", ToolExecutingEvent {
-                machine_name: machine_name.to_string(),
-                command: command.to_string(),
-            });
+/// ツール入力を build_tools の input_schema で検証（全ツール共通）
+fn validate_tool_input(
+    tools: &[serde_json::Value],
+    tool_name: &str,
+    input: &serde_json::Value,
+    lang: &str,
+) -> Result<(), String> {
+    let schema = tools
+        .iter()
+        .find(|t| t.get("name").and_then(|n| n.as_str()) == Some(tool_name))
+        .and_then(|t| t.get("input_schema"))
+        .ok_or_else(|| tr(lang, "unknown_tool", &[("tool", tool_name)]))?;
+    let validator = jsonschema::validator_for(schema).map_err(|e| format!("ツール定義のスキーマが不正です: {}", e))?;
+
+    let errors: Vec<String> = validator
+        .iter_errors(input)
+        .map(|e| {
+            let path = e.instance_path.to_string();
+            format!("- {}: {}", if path.is_empty() { "(root)" } else { path.as_str() }, e)
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(tr(lang, "tool_input_invalid", &[("errors", &errors.join("\n"))]))
+    }
+}
 
-            if tool_name == "execute_remote_command" {
-                let exec_result = execute_tool_ssh(machine_name, command, machines).await;
+/// ツール呼び出し1件を実行し、tool_result とツール実行記録を返す（ストリーム/非ストリーム共通）
+/// 呼び出し内容と成否は分析用に tool_decisions.jsonl に記録する
+async fn run_tool_call(
+    tool_id: &str,
+    tool_name: &str,
+    input: &serde_json::Value,
+    ctx: &ToolContext,
+) -> (serde_json::Value, Vec<ToolExecution>) {
+    let (tool_result, executions) = dispatch_tool_call(tool_id, tool_name, input, ctx).await;
+    record_tool_decision(&ctx.app_handle, tool_name, input, &tool_result, &executions, &ctx.trace_id);
+    (tool_result, executions)
+}
 
-                let _ = app_handle.emit("tool-completed", ToolCompletedEvent {
-                    machine_name: machine_name.to_string(),
-                    command: command.to_string(),
-                    success: exec_result.success,
-                });
+/// ツール名に応じて実行を振り分ける
+async fn dispatch_tool_call(
+    tool_id: &str,
+    tool_name: &str,
+    input: &serde_json::Value,
+    ctx: &ToolContext,
+) -> (serde_json::Value, Vec<ToolExecution>) {
+    // 定義済みスキーマに適合しない入力は実行せず、具体的な違反内容を返す
+    if let Err(message) = validate_tool_input(&ctx.tools, tool_name, input, &ctx.settings.language) {
+        eprintln!("[Nexus] Tool input rejected ({}): {}", tool_name, message);
+        let tool_result = serde_json::json!({
+            "type": "tool_result",
+            "tool_use_id": tool_id,
+            "content": message,
+            "is_error": true
+        });
+        return (tool_result, Vec::new());
+    }
 
-                let result_text = if exec_result.success {
-                    if exec_result.stdout.is_empty() { "(コマンド成功・出力なし)".to_string() } else { exec_result.stdout.clone() }
-                } else {
-                    format!("エラー: {}{}", exec_result.stderr, if !exec_result.stdout.is_empty() { format!("\nstdout: {}", exec_result.stdout) } else { String::new() })
-                };
+    let command = input.get("command").and_then(|v| v.as_str()).unwrap_or("");
+    let skip_syntax_check = input.get("skip_syntax_check").and_then(|v| v.as_bool()).unwrap_or(false);
+    let purpose = ToolPurpose::from_input(input);
 
-                tool_results.push(serde_json::json!({
-                    "type": "tool_result",
-                    "tool_use_id": tool_id,
-                    "content": result_text,
-                    "is_error": !exec_result.success
-                }));
-                all_tool_executions.push(exec_result);
-            } else {
-                tool_results.push(serde_json::json!({
+    if tool_name == READ_FILE_TOOL {
+        let machine_name = input.get("machine_name").and_then(|v| v.as_str()).unwrap_or("unknown");
+        let path = input.get("path").and_then(|v| v.as_str()).unwrap_or("");
+        let max_bytes = input.get("max_bytes").and_then(|v| v.as_u64()).unwrap_or(READ_FILE_DEFAULT_MAX_BYTES);
+        let command = ctx
+            .machines
+            .iter()
+            .find(|m| m.name == machine_name && m.enabled && m.role != "Commander")
+            .ok_or_else(|| tr(&ctx.settings.language, "machine_unavailable", &[("machine", machine_name)]))
+            .and_then(|machine| read_file_command(machine, path, max_bytes));
+        let command = match command {
+            Ok(command) => command,
+            Err(message) => {
+                let tool_result = serde_json::json!({
                     "type": "tool_result",
                     "tool_use_id": tool_id,
-                    "content": format!("未知のツール: {}", tool_name),
+                    "content": message,
                     "is_error": true
-                }));
+                });
+                return (tool_result, Vec::new());
             }
-        }
+        };
+        // アプリが組み立てたコマンドなので構文チェックは不要。読み取りのみなので目的は調査
+        let sequence = ctx.next_tool_sequence();
+        let (content, exec_result) = run_machine_command(
+            machine_name,
+            &command,
+            true,
+            Some(ToolPurpose::Investigate),
+            sequence,
+            MachineCommandOptions { read_limit: Some(max_bytes as usize), ..Default::default() },
+            ctx,
+        )
+        .await;
+        let tool_result = serde_json::json!({
+            "type": "tool_result",
+            "tool_use_id": tool_id,
+            "content": content,
+            "is_error": !exec_result.success
+        });
+        return (tool_result, vec![exec_result]);
+    }
 
-        // ツール結果をuserメッセージとして追加して次のループへ
-        api_messages.push(serde_json::json!({
-            "role": "user",
-            "content": tool_results
-        }));
+    if tool_name == SERVICE_TOOL {
+        return run_service_tool(tool_id, input, ctx).await;
+    }
 
-        // 次のストリームループ開始をフロントに通知
-        let _ = app_handle.emit("stream-tool-continue", serde_json::json!({}));
+    if tool_name == LOCAL_COMMAND_TOOL {
+        // 承認・監査・イベントはリモート実行と同じ経路（run_machine_command が Commander ならローカルで実行する）
+        let machine_name = local_machine(&ctx.machines).map_or("local", |m| m.name.as_str());
+        let sequence = ctx.next_tool_sequence();
+        let (content, exec_result) =
+            run_machine_command(machine_name, command, skip_syntax_check, purpose, sequence, MachineCommandOptions::default(), ctx).await;
+        let tool_result = serde_json::json!({
+            "type": "tool_result",
+            "tool_use_id": tool_id,
+            "content": content,
+            "is_error": !exec_result.success
+        });
+        return (tool_result, vec![exec_result]);
     }
 
-    let final_text = all_text_parts.join("");
-    let final_text = if final_text.is_empty() {
-        "(空の応答が返されました)".to_string()
-    } else {
-        final_text
-    };
+    if tool_name == MULTI_MACHINE_TOOL {
+        let machine_names: Vec<&str> = input
+            .get("machine_names")
+            .and_then(|v| v.as_array())
+            .map(|names| names.iter().filter_map(|n| n.as_str()).collect())
+            .unwrap_or_default();
+        // 通し番号は並列実行の前に配列順で振る。各マシンの実行は同時実行枠（ToolQueue）の範囲で並列に進む
+        let results = futures_util::future::join_all(machine_names.iter().map(|machine_name| {
+            let sequence = ctx.next_tool_sequence();
+            run_machine_command(machine_name, command, skip_syntax_check, purpose, sequence, MachineCommandOptions::default(), ctx)
+        }))
+        .await;
+        let content = results
+            .iter()
+            .map(|(content, exec)| {
+                format!("## {} {}\n{}", exec.machine_name, if exec.success { "✓" } else { "✗" }, content)
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        // 一部でも成功していればエラー扱いにしない（失敗分は本文に残る）
+        let tool_result = serde_json::json!({
+            "type": "tool_result",
+            "tool_use_id": tool_id,
+            "content": content,
+            "is_error": results.iter().all(|(_, exec)| !exec.success)
+        });
+        return (tool_result, results.into_iter().map(|(_, exec)| exec).collect());
+    }
 
-    Ok((final_text, all_tool_executions, total_usage, last_call_input_tokens))
+    let machine_name = input.get("machine_name").and_then(|v| v.as_str()).unwrap_or("unknown");
+    let sequence = ctx.next_tool_sequence();
+    let (content, exec_result) =
+        run_machine_command(machine_name, command, skip_syntax_check, purpose, sequence, MachineCommandOptions::default(), ctx).await;
+    let tool_result = serde_json::json!({
+        "type": "tool_result",
+        "tool_use_id": tool_id,
+        "content": content,
+        "is_error": !exec_result.success
+    });
+    (tool_result, vec![exec_result])
 }
 
-// ========================================
-// Tauri Commands
-// ========================================
+/// run_machine_command の追加指定
+#[derive(Default)]
+struct MachineCommandOptions {
+    /// ファイル読み取り（read_remote_file）時の上限バイト数。出力をファイル内容として検査・切り詰める
+    read_limit: Option<usize>,
+    /// danger_patterns に関係なく承認を求める理由（manage_service の変更系等）
+    approval_reason: Option<String>,
+}
 
-/// Send a message via streaming SSE (primary)
-#[tauri::command]
-async fn send_message_stream(
-    message: String,
-    state: State<'_, Mutex<ChatState>>,
-    ssh_state: State<'_, Mutex<SshState>>,
-    app_handle: tauri::AppHandle,
-) -> Result<SendMessageResponse, String> {
-    let api_key = std::env::var("ANTHROPIC_API_KEY")
-        .map_err(|_| "ANTHROPIC_API_KEY 環境変数が設定されていません".to_string())?;
+/// 1台分のコマンド実行（イベント通知・監査記録・要約まで）。Claude に返す本文と実行記録を返す
+async fn run_machine_command(
+    machine_name: &str,
+    command: &str,
+    skip_syntax_check: bool,
+    purpose: Option<ToolPurpose>,
+    sequence: u64,
+    options: MachineCommandOptions,
+    ctx: &ToolContext,
+) -> (String, ToolExecution) {
+    // 実行中にキャンセル・延長できるよう、IDを先に採番して登録しておく
+    let execution_id = next_execution_id();
+    let (control, run) = tool_run_channel(&execution_id, sequence);
+    let cancel_rx = run.cancel.clone();
+    if let Ok(mut running) = ctx.session.running_tools.lock() {
+        running.insert(execution_id.clone(), control);
+    }
 
-    let (tools, system_prompt, machines) = {
-        let ssh = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
-        (build_tools(&ssh.machines), build_system_prompt(&ssh.machines, &ssh.notion_info), ssh.machines.clone())
+    // フロントエンドに実行中イベントを送信
+    let _ = ctx.app_handle.emit("tool-executing", ToolExecutingEvent {
+        session_id: ctx.session.id.clone(),
+        trace_id: ctx.trace_id.clone(),
+        sequence,
+        execution_id: execution_id.clone(),
+        machine_name: machine_name.to_string(),
+        command: command.to_string(),
+    });
+
+    // 破壊的コマンドはユーザーの承認を得てから実行する
+    let approval = await_tool_approval(&run, machine_name, command, purpose, options.approval_reason.as_deref(), ctx).await;
+    let local = local_machine(&ctx.machines).filter(|m| m.name == machine_name);
+    let mut exec_result = match (approval, local) {
+        (Ok(()), Some(local)) => execute_tool_local(local, command, skip_syntax_check, run, ctx).await,
+        (Ok(()), None) => execute_tool_ssh(machine_name, command, skip_syntax_check, purpose, run, ctx).await,
+        (Err(reason), _) => ToolExecution::failed(machine_name, command, reason, 0, ""),
     };
+    if let Ok(mut running) = ctx.session.running_tools.lock() {
+        running.remove(&execution_id);
+    }
+    exec_result.execution_id = execution_id;
+    exec_result.cancelled = *cancel_rx.borrow() && !exec_result.success;
+    if let Some(max_bytes) = options.read_limit {
+        apply_file_read_limit(&mut exec_result, max_bytes, &ctx.settings.language);
+    }
+    exec_result.purpose = purpose;
+    exec_result.sequence = sequence;
+    record_tool_audit(&ctx.app_handle, &exec_result, &ctx.trace_id);
+
+    // 実行完了イベント
+    let _ = ctx.app_handle.emit("tool-completed", ToolCompletedEvent {
+        session_id: ctx.session.id.clone(),
+        trace_id: ctx.trace_id.clone(),
+        sequence,
+        execution_id: exec_result.execution_id.clone(),
+        machine_name: machine_name.to_string(),
+        command: command.to_string(),
+        success: exec_result.success,
+    });
+
+    if !exec_result.success && !exec_result.cancelled {
+        notify_if_hidden(
+            &ctx.app_handle,
+            true,
+            format!("{} でコマンドが失敗しました: {}", machine_name, notification_excerpt(command)),
+        );
+    }
 
-    let api_messages: Vec<serde_json::Value> = {
-        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
-        chat.history.push(HistoryMessage { role: "user".to_string(), content: message.clone() });
-        if chat.history.len() > MAX_HISTORY {
-            let drain_count = chat.history.len() - MAX_HISTORY;
-            chat.history.drain(..drain_count);
+    let full_text = tool_result_full_text(&exec_result, &ctx.settings.language);
+    let content = match summarize_if_large(&exec_result, &full_text, ctx).await {
+        Some(summary) => {
+            let content = format!(
+                "{}\n{}",
+                tr(&ctx.settings.language, "output_summarized", &[("chars", &full_text.chars().count().to_string())]),
+                summary
+            );
+            exec_result.summary = Some(summary);
+            content
+        }
+        None => {
+            let content = tool_result_text(&exec_result, &full_text, &ctx.settings);
+            exec_result.truncated = content != full_text;
+            content
         }
-        chat.history.iter().map(|m| serde_json::json!({ "role": m.role, "content": m.content })).collect()
-    };
-
-    let model = {
-        let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
-        chat.model.clone()
     };
 
-    // stream-start イベント
-    let _ = app_handle.emit("stream-start", serde_json::json!({}));
+    // Claude に渡す結果（要約・切り詰め後）が確定した
+    ctx.emit("tool-result-ready", serde_json::json!({
+        "sequence": sequence,
+        "execution_id": exec_result.execution_id,
+        "machine_name": machine_name,
+        "command": command,
+        "success": exec_result.success,
+        "summarized": exec_result.summary.is_some(),
+        "truncated": exec_result.truncated
+    }));
+    (content, exec_result)
+}
 
-    let (final_text, tool_executions, total_usage, last_call_input_tokens) =
-        call_anthropic_stream(&api_key, &model, &system_prompt, &tools, &api_messages, &app_handle, &machines).await?;
+/// 要約に渡す出力の上限（超過分は中央を省略）
+const SUMMARY_INPUT_MAX_CHARS: usize = 100_000;
 
-    // 履歴とトークン統計を更新
-    let current_stats = {
-        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
-        chat.token_stats.last_input_tokens = last_call_input_tokens;
-        chat.token_stats.last_output_tokens = total_usage.output_tokens;
-        chat.token_stats.total_input_tokens += total_usage.input_tokens;
-        chat.token_stats.total_output_tokens += total_usage.output_tokens;
-        chat.token_stats.request_count += 1;
-        chat.history.push(HistoryMessage { role: "assistant".to_string(), content: final_text.clone() });
-        chat.token_stats.clone()
+/// 閾値を超える出力を要約モデルで要約する（無効・閾値以下・失敗時は None）
+/// 使用トークンは要約用としてトークン統計に計上する
+async fn summarize_if_large(exec: &ToolExecution, full_text: &str, ctx: &ToolContext) -> Option<String> {
+    let config = &ctx.settings.summarize;
+    let char_count = full_text.chars().count();
+    if !config.enabled || char_count <= config.threshold_chars {
+        return None;
+    }
+    let model = find_model(&config.model)?;
+
+    let input = elide_middle(full_text, SUMMARY_INPUT_MAX_CHARS);
+    let system = "あなたはサーバー運用のログ・コマンド出力を要約するアシスタントです。\
+         出力の要点と異常を抽出し、後続のアシスタントが判断に使える簡潔な要約を作ってください。\
+         数値・エラーメッセージ・警告・異常値・失敗したサービス名は省略せず、原文どおり必ず保持すること。\
+         推測や出力にない情報は加えないこと。";
+    let messages = [serde_json::json!({
+        "role": "user",
+        "content": format!("マシン: {}\nコマンド: {}\n\n出力:\n{}", exec.machine_name, exec.command, input)
+    })];
+
+    let resp = match call_anthropic(&ctx.api_caller(), model.id, system, &[], &messages, CallLimits::default()).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            eprintln!("[Nexus] Tool output summarization failed, sending truncated output: {}", e);
+            return None;
+        }
     };
 
-    // stream-end イベント
-    let _ = app_handle.emit("stream-end", serde_json::json!({
-        "token_stats": current_stats,
-        "tool_executions": tool_executions
-    }));
+    if let Some(usage) = &resp.usage {
+        if let Ok(mut chat) = ctx.session.chat.lock() {
+            chat.token_stats.summary_input_tokens += usage.input_tokens;
+            chat.token_stats.summary_output_tokens += usage.output_tokens;
+            chat.token_stats.add_model_usage(model.id, usage);
+            notify_token_stats(&ctx.app_handle, &ctx.session.id, &chat.token_stats, "request");
+        };
+        record_usage(&ctx.app_handle, model.id, usage);
+    }
 
-    Ok(SendMessageResponse {
-        text: final_text,
-        token_stats: current_stats,
-        tool_executions,
-    })
+    let summary: String = resp
+        .content
+        .iter()
+        .filter_map(|b| b.get("text").and_then(|t| t.as_str()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if summary.trim().is_empty() {
+        None
+    } else {
+        Some(summary)
+    }
 }
 
-/// Send a message via Anthropic API (non-streaming fallback)
-#[tauri::command]
-async fn send_message(
-    message: String,
-    state: State<'_, Mutex<ChatState>>,
-    ssh_state: State<'_, Mutex<SshState>>,
-    app_handle: tauri::AppHandle,
-) -> Result<SendMessageResponse, String> {
-    let api_key = std::env::var("ANTHROPIC_API_KEY")
-        .map_err(|_| "ANTHROPIC_API_KEY 環境変数が設定されていません".to_string())?;
+/// 上限を超える文章の中央を省略する（要約モデルへの入力用）
+fn elide_middle(text: &str, max_chars: usize) -> String {
+    let char_count = text.chars().count();
+    if char_count <= max_chars {
+        return text.to_string();
+    }
+    let half = max_chars / 2;
+    let head: String = text.chars().take(half).collect();
+    let tail: String = text.chars().skip(char_count - half).collect();
+    format!("{}\n...（中略）...\n{}", head, tail)
+}
 
-    // マシン情報からツール定義とシステムプロンプトを生成
-    let (tools, system_prompt, machines) = {
-        let ssh = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
-        (
-            build_tools(&ssh.machines),
-            build_system_prompt(&ssh.machines, &ssh.notion_info),
-            ssh.machines.clone(),
-        )
+/// トリムで落とすやり取りを要約モデルで要約する（失敗時は None、呼び出し側はそのまま落とす）
+/// 使用トークンは要約用としてトークン統計に計上する
+async fn summarize_history(dropped: &[HistoryMessage], ctx: &ToolContext) -> Option<String> {
+    let model = find_model(&ctx.settings.context.summary_model)?;
+    let transcript: String = dropped
+        .iter()
+        .map(|m| {
+            let content = history_to_api_message(m)["content"].as_str().unwrap_or_default().to_string();
+            let speaker = if m.role == "user" { "ユーザー" } else { "アシスタント" };
+            format!("{}: {}", speaker, content)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    let system = "あなたはサーバー運用アシスタントとユーザーの会話を要約するアシスタントです。\
+         後続のアシスタントが会話を引き継げるよう、ユーザーの目的・決定事項・未解決の課題と、\
+         実行したコマンドの結果（マシン名・数値・エラーメッセージ・変更した設定）を簡潔にまとめてください。\
+         会話の先頭に前回までの要約があれば、その内容も引き継ぐこと。推測や会話にない情報は加えないこと。";
+    let messages = [serde_json::json!({
+        "role": "user",
+        "content": format!("以下の会話を要約してください。\n\n{}", elide_middle(&transcript, SUMMARY_INPUT_MAX_CHARS))
+    })];
+    let limits = CallLimits { max_tokens: HISTORY_SUMMARY_MAX_TOKENS, ..CallLimits::default() };
+
+    let resp = match call_anthropic(&ctx.api_caller(), model.id, system, &[], &messages, limits).await {
+        Ok(resp) => resp,
+        Err(e) => {
+            eprintln!("[Nexus] History summarization failed, dropping old messages: {}", e);
+            return None;
+        }
     };
 
-    // 履歴からAPIメッセージ配列を構築
-    let mut api_messages: Vec<serde_json::Value> = {
-        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    if let Some(usage) = &resp.usage {
+        if let Ok(mut chat) = ctx.session.chat.lock() {
+            chat.token_stats.summary_input_tokens += usage.input_tokens;
+            chat.token_stats.summary_output_tokens += usage.output_tokens;
+            chat.token_stats.add_model_usage(model.id, usage);
+        };
+        record_usage(&ctx.app_handle, model.id, usage);
+    }
 
-        // ユーザーメッセージを履歴に追加
-        chat.history.push(HistoryMessage {
-            role: "user".to_string(),
-            content: message.clone(),
-        });
+    let summary: String = resp
+        .content
+        .iter()
+        .filter_map(|b| b.get("text").and_then(|t| t.as_str()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let summary = summary.trim();
+    (!summary.is_empty()).then(|| summary.to_string())
+}
 
-        // 履歴をトリム
-        if chat.history.len() > MAX_HISTORY {
-            let drain_count = chat.history.len() - MAX_HISTORY;
-            chat.history.drain(..drain_count);
+/// tool_result の本文（切り詰め前、エラー文言は設定言語に揃える）
+fn tool_result_full_text(exec: &ToolExecution, lang: &str) -> String {
+    if exec.success {
+        if let Some(parsed) = &exec.parsed {
+            // 構造化できた出力は整形済みの JSON として渡す（生の出力はユーザー側に表示）
+            let parser = find_output_parser(&exec.command).map_or("", |p| p.name);
+            format!("{}\n{}", tr(lang, "output_parsed", &[("parser", parser)]), parsed)
+        } else if exec.stdout.is_empty() {
+            tr(lang, "tool_success_no_output", &[])
+        } else {
+            exec.stdout.clone()
         }
+    } else {
+        let mut text = tr(lang, "tool_error", &[("stderr", &exec.stderr)]);
+        if !exec.stdout.is_empty() {
+            text.push_str(&format!("\nstdout: {}", exec.stdout));
+        }
+        text
+    }
+}
 
-        // 履歴を API メッセージ形式に変換
-        chat.history
-            .iter()
-            .map(|m| {
-                serde_json::json!({
-                    "role": m.role,
-                    "content": m.content
-                })
-            })
-            .collect()
-    };
-
-    let model = {
-        let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
-        chat.model.clone()
+/// Claude に返す tool_result の本文（行数上限で切り詰めたうえで、文字数上限を超えれば中間を省略）
+fn tool_result_text(exec: &ToolExecution, full_text: &str, settings: &AppSettings) -> String {
+    let lang = settings.language.as_str();
+    let tail_first = match settings.output.truncate_mode {
+        TruncateMode::Head => false,
+        TruncateMode::Tail => true,
+        TruncateMode::Auto => prefers_tail(&exec.command),
     };
+    let text = truncate_lines(full_text, settings.output.max_lines, tail_first, lang);
+    truncate_chars(&text, settings.output.max_chars, lang)
+}
 
-    // ========================================
-    // Tool Use ループ
-    // ========================================
+/// 文字数上限を超えた出力の先頭と末尾を残し、中間を省略する（max_chars = 0 なら無制限）
+/// 長い1行（ls -R を1行に連結した出力等）は行数上限では削れないため、文字数でも抑える
+fn truncate_chars(text: &str, max_chars: usize, lang: &str) -> String {
+    let char_count = text.chars().count();
+    if max_chars == 0 || char_count <= max_chars {
+        return text.to_string();
+    }
+    let head_chars = max_chars / 2;
+    let tail_chars = max_chars - head_chars;
+    let omitted = (char_count - max_chars).to_string();
+    let head: String = text.chars().take(head_chars).collect();
+    let tail: String = text.chars().skip(char_count - tail_chars).collect();
+    format!("{}\n{}\n{}", head, tr(lang, "output_middle_omitted", &[("count", &omitted)]), tail)
+}
+
+/// ログ系コマンドは最新行（末尾）が重要なので末尾優先にする
+const TAIL_PRIORITY_COMMANDS: &[&str] = &[
+    "tail", "journalctl", "dmesg", "get-eventlog", "get-winevent", "docker logs", "kubectl logs",
+];
+
+fn prefers_tail(command: &str) -> bool {
+    let lower = command.to_lowercase();
+    lower.contains("-tail ")
+        || lower.split(['|', ';', '&']).any(|part| {
+            let part = part.trim();
+            TAIL_PRIORITY_COMMANDS.iter().any(|c| {
+                part.strip_prefix(c)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+            })
+        })
+}
+
+/// 行数上限を超えた出力を切り詰め、省略箇所を明示する（max_lines = 0 なら無制限）
+fn truncate_lines(text: &str, max_lines: usize, tail_first: bool, lang: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    if max_lines == 0 || lines.len() <= max_lines {
+        return text.to_string();
+    }
+    let omitted = (lines.len() - max_lines).to_string();
+    if tail_first {
+        format!(
+            "{}\n{}",
+            tr(lang, "output_head_omitted", &[("count", &omitted)]),
+            lines[lines.len() - max_lines..].join("\n")
+        )
+    } else {
+        format!(
+            "{}\n{}",
+            lines[..max_lines].join("\n"),
+            tr(lang, "output_tail_omitted", &[("count", &omitted)])
+        )
+    }
+}
+
+/// ツールループ各段のテキストを連結（段落区切りを保ち、文が癒着しないようにする）
+fn join_text_parts(parts: &[String]) -> String {
+    parts
+        .iter()
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<&str>>()
+        .join("\n\n")
+}
+
+/// 最終テキストを確定（途中でエラーになった場合はここまでの内容にエラーを付記）
+fn finalize_text(parts: &[String], error: Option<&str>) -> String {
+    let text = join_text_parts(parts);
+    match error.map(|e| split_error_detail(e).0) {
+        Some(e) => format!("{}\n\n⚠️ 応答の途中でエラーが発生したため、ここまでの内容を保存しました: {}", text, e)
+            .trim_start()
+            .to_string(),
+        None if text.is_empty() => "(空の応答が返されました)".to_string(),
+        None => text,
+    }
+}
+
+/// 出力上限で切れた応答の続きを依頼する（自動継続時に user として送る）
+const AUTO_CONTINUE_PROMPT: &str = "出力上限で応答が途中で切れました。直前の応答の続きを、繰り返しや前置きなしでそのまま書いてください。";
+
+/// 出力上限で応答が切れたことを response-truncated で通知（フロントは続きの依頼を促す）
+fn notify_response_truncated(ctx: &ToolContext, model: &str, max_tokens: u32) {
+    eprintln!("[Nexus] Response truncated at max_tokens={} ({})", max_tokens, model);
+    ctx.emit("response-truncated", serde_json::json!({
+        "model": model,
+        "max_tokens": max_tokens
+    }));
+}
+
+/// 応答を得られずに終わったユーザー発言を履歴から外す（user/assistant の交互を保つ）
+fn discard_unanswered_message(state: &Mutex<ChatState>) {
+    if let Ok(mut chat) = state.lock() {
+        if chat.history.last().is_some_and(|m| m.role == "user") {
+            chat.history.pop();
+        }
+    }
+}
+
+const HISTORY_TOOL_OUTPUT_PREVIEW: usize = 300;
+
+/// 履歴メッセージをAPI形式に変換
+/// ツール実行を伴った応答には実行コマンドと結果の要旨を付記し、次ターンでも文脈を保つ
+fn history_to_api_message(m: &HistoryMessage) -> serde_json::Value {
+    if m.tool_executions.is_empty() {
+        return serde_json::json!({ "role": m.role, "content": m.content });
+    }
+
+    let records: Vec<String> = m
+        .tool_executions
+        .iter()
+        .map(|e| {
+            let output = if e.success { &e.stdout } else { &e.stderr };
+            let preview: String = output.trim().chars().take(HISTORY_TOOL_OUTPUT_PREVIEW).collect();
+            let ellipsis = if output.trim().chars().count() > HISTORY_TOOL_OUTPUT_PREVIEW { "…" } else { "" };
+            format!(
+                "- {}: `{}` → {}\n  {}{}",
+                e.machine_name,
+                e.command,
+                if e.success { "成功" } else { "失敗" },
+                preview.replace('\n', "\n  "),
+                ellipsis
+            )
+        })
+        .collect();
+
+    serde_json::json!({
+        "role": m.role,
+        "content": format!("{}\n\n[このターンで実行したコマンド]\n{}", m.content, records.join("\n"))
+    })
+}
+
+/// 利用者向けメッセージと元エラーの区切り（フロントは後半を折りたたみの詳細として表示する）
+const ERROR_DETAIL_SEPARATOR: &str = "\n--- detail ---\n";
+
+fn with_error_detail(message: String, detail: &str) -> String {
+    format!("{}{}{}", message, ERROR_DETAIL_SEPARATOR, detail)
+}
+
+/// エラー文字列を利用者向けメッセージと詳細に分ける（詳細のないエラーはそのまま）
+fn split_error_detail(error: &str) -> (&str, Option<&str>) {
+    match error.split_once(ERROR_DETAIL_SEPARATOR) {
+        Some((message, detail)) => (message, Some(detail)),
+        None => (error, None),
+    }
+}
+
+/// reqwest のエラーを種別（タイムアウト・接続・デコード）に応じた平易なメッセージにする
+fn describe_request_error(e: &reqwest::Error, lang: &str) -> String {
+    let key = if e.is_timeout() {
+        "api_timeout"
+    } else if e.is_connect() {
+        "api_connect"
+    } else if e.is_decode() || e.is_body() {
+        "api_decode"
+    } else {
+        "api_request_failed"
+    };
+    // reqwest の Display は原因（DNS 解決失敗等）を含まないため source をたどる
+    let mut detail = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        detail.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    with_error_detail(tr(lang, key, &[]), &detail)
+}
+
+/// API のエラー応答（HTTP ステータス）を平易なメッセージにする
+fn describe_api_status(status: reqwest::StatusCode, body: &str, lang: &str) -> String {
+    let key = match status.as_u16() {
+        401 | 403 => "api_unauthorized",
+        429 => "api_rate_limited",
+        529 => "api_overloaded",
+        500..=599 => "api_server_error",
+        _ => "api_bad_request",
+    };
+    let detail = match serde_json::from_str::<ApiError>(body).ok().and_then(|e| e.error).and_then(|d| d.message) {
+        Some(message) => format!("API Error ({}): {}", status, message),
+        None => format!("API Error ({}): {}", status, body.chars().take(200).collect::<String>()),
+    };
+    with_error_detail(tr(lang, key, &[("status", &status.as_u16().to_string())]), &detail)
+}
+
+/// API呼び出しの共通情報（認証・エラーメッセージの言語・再試行の通知先）
+struct ApiCaller<'a> {
+    api_key: &'a str,
+    lang: &'a str,
+    app_handle: &'a tauri::AppHandle,
+    session_id: &'a str,
+    trace_id: &'a str,
+}
+
+/// 過負荷・レート制限時の再試行回数（初回を除く）
+const API_MAX_RETRIES: u32 = 3;
+const API_RETRY_BASE_DELAY_MS: u64 = 1000;
+const API_RETRY_MAX_DELAY_MS: u64 = 30_000;
+
+/// 時間をおけば通る可能性があるステータス（400 / 401 等はリクエスト自体の問題なので即エラー）
+fn is_retryable_api_status(status: reqwest::StatusCode) -> bool {
+    matches!(status.as_u16(), 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+/// 再試行までの待ち時間: Retry-After（秒）があれば優先し、なければ指数バックオフ＋ジッタ
+fn api_retry_delay_ms(attempt: u32, retry_after: Option<&str>) -> u64 {
+    if let Some(secs) = retry_after.and_then(|v| v.trim().parse::<f64>().ok()).filter(|s| s.is_finite() && *s >= 0.0) {
+        return ((secs * 1000.0) as u64).min(API_RETRY_MAX_DELAY_MS * 2);
+    }
+    let base = (API_RETRY_BASE_DELAY_MS << attempt.min(10)).min(API_RETRY_MAX_DELAY_MS);
+    // 同時に失敗した複数リクエストが同じ間隔で再送し合わないよう、最大で base の半分をずらす
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() as u64);
+    base + nanos % (base / 2 + 1)
+}
+
+/// API にリクエストを送る（429 / 529 等は最大 API_MAX_RETRIES 回まで待って再送し、api-retrying で通知）
+async fn post_api_request(client: &reqwest::Client, body: &ApiRequest, caller: &ApiCaller<'_>) -> Result<reqwest::Response, String> {
+    let mut attempt = 0;
+    loop {
+        let response = client
+            .post(API_URL)
+            .header("x-api-key", caller.api_key)
+            .header("anthropic-version", "2023-06-01")
+            .header("anthropic-beta", PROMPT_CACHING_BETA)
+            .header("content-type", "application/json")
+            .json(body)
+            .send()
+            .await
+            .map_err(|e| describe_request_error(&e, caller.lang))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        if !is_retryable_api_status(status) || attempt >= API_MAX_RETRIES {
+            let text = response.text().await.unwrap_or_default();
+            return Err(describe_api_status(status, &text, caller.lang));
+        }
+
+        attempt += 1;
+        let retry_after = response.headers().get("retry-after").and_then(|v| v.to_str().ok());
+        let delay_ms = api_retry_delay_ms(attempt - 1, retry_after);
+        eprintln!(
+            "[Nexus] [{}] API returned {}, retrying in {}ms ({}/{})",
+            caller.trace_id, status, delay_ms, attempt, API_MAX_RETRIES
+        );
+        let _ = caller.app_handle.emit("api-retrying", serde_json::json!({
+            "session_id": caller.session_id,
+            "trace_id": caller.trace_id,
+            "attempt": attempt,
+            "max_attempts": API_MAX_RETRIES,
+            "delay_ms": delay_ms,
+            "status": status.as_u16()
+        }));
+        tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+    }
+}
+
+/// Anthropic API呼び出し（共通）
+async fn call_anthropic(
+    caller: &ApiCaller<'_>,
+    model: &str,
+    system: &str,
+    tools: &[serde_json::Value],
+    messages: &[serde_json::Value],
+    limits: CallLimits,
+) -> Result<ApiResponse, String> {
+    let client = reqwest::Client::new();
+    let lang = caller.lang;
+
+    let body = ApiRequest {
+        model: model.to_string(),
+        max_tokens: limits.max_tokens,
+        system: Some(cached_system_prompt(system)),
+        messages: messages.to_vec(),
+        tools: if tools.is_empty() {
+            None
+        } else {
+            Some(tools.to_vec())
+        },
+        // 履歴に tool_use がある場合は tools を外せないため、tool_choice で使用だけ止める
+        tool_choice: (!limits.allow_tools && !tools.is_empty()).then(|| serde_json::json!({ "type": "none" })),
+        stream: None,
+    };
+
+    let response = post_api_request(&client, &body, caller).await?;
+    let response_text = response
+        .text()
+        .await
+        .map_err(|e| describe_request_error(&e, lang))?;
+
+    serde_json::from_str(&response_text).map_err(|e| {
+        let body: String = response_text.chars().take(200).collect();
+        with_error_detail(tr(lang, "api_decode", &[]), &format!("レスポンスパースエラー: {} / body: {}", e, body))
+    })
+}
+
+// ========================================
+// Streaming SSE Parser
+// ========================================
+
+/// 1回分のSSEストリームの解析結果
+#[derive(Default)]
+struct StreamTurn {
+    text: String,
+    /// tool_use蓄積用: index → (id, name, input_json_str)
+    tool_uses: std::collections::HashMap<u64, (String, String, String)>,
+    stop_reason: Option<String>,
+    usage: UsageInfo,
+}
+
+enum StreamReadError {
+    /// ping もデルタも一定時間届かず停止したとみなした（has_tool_uses はツール呼び出しの受信途中だったか）
+    Stalled { partial_text: String, usage: UsageInfo, has_tool_uses: bool },
+    /// 受信の途中で接続が切れた
+    Disconnected { message: String, partial_text: String, usage: UsageInfo, has_tool_uses: bool },
+    /// stop_generation で中断された（接続は切る）
+    Stopped { partial_text: String, usage: UsageInfo },
+    /// ストリーム中に event: error が届いた（overloaded_error 等。途中まで受信したテキストは残す）
+    ApiError { message: String, partial_text: String, usage: UsageInfo },
+}
+
+/// SSE の error イベント（{"type":"error","error":{"type":..,"message":..}}）をユーザー向けのエラーに変換
+fn describe_stream_error_event(error_type: &str, message: &str, lang: &str) -> String {
+    let user_message = match error_type {
+        "overloaded_error" => tr(lang, "api_overloaded", &[]),
+        "rate_limit_error" => tr(lang, "api_rate_limited", &[]),
+        _ => tr(lang, "stream_error_event", &[("type", error_type)]),
+    };
+    with_error_detail(user_message, &format!("SSE error ({}): {}", error_type, message))
+}
+
+/// バッファから改行までの完全な行を取り出して UTF-8 としてデコードする（未完成の行はバッファに残す）
+/// 改行（0x0A）はマルチバイト文字の途中に現れないので、行単位なら文字が分断されない
+fn drain_sse_lines(buf: &mut Vec<u8>) -> Vec<String> {
+    let mut lines = Vec::new();
+    while let Some(newline_pos) = buf.iter().position(|&b| b == b'\n') {
+        let raw: Vec<u8> = buf.drain(..=newline_pos).collect();
+        match std::str::from_utf8(&raw[..newline_pos]) {
+            Ok(line) => lines.push(line.trim_end_matches('\r').to_string()),
+            Err(e) => eprintln!("[Nexus] Skipped SSE line with invalid UTF-8: {}", e),
+        }
+    }
+    lines
+}
+
+/// 切断されたストリームを受信済みテキストの続きから再開するときの prefill（None なら最初からやり直す）
+/// ツール呼び出しの受信途中は入力 JSON を prefill で続けられないため再開しない
+/// API は末尾が空白の assistant prefill を受け付けないので、末尾の空白は落として続きの生成に任せる
+fn resume_prefill(partial_text: &str, has_tool_uses: bool) -> Option<String> {
+    if has_tool_uses {
+        return None;
+    }
+    let prefill = partial_text.trim_end();
+    (!prefill.is_empty()).then(|| prefill.to_string())
+}
+
+/// 末尾に assistant の prefill を足したリクエスト（応答はこのテキストの続きから始まる）
+fn with_assistant_prefill(body: &ApiRequest, prefill: &str) -> ApiRequest {
+    let mut resumed = body.clone();
+    resumed.messages.push(serde_json::json!({
+        "role": "assistant",
+        "content": prefill
+    }));
+    resumed
+}
+
+/// SSE のイベントのうち、呼び出し側が反応するもの（それ以外は StreamTurn に蓄積するだけ）
+#[derive(Debug, PartialEq)]
+enum SseEvent {
+    Ping,
+    /// フロントに流すテキストのデルタ
+    TextDelta(String),
+    /// event: error（overloaded_error 等）。これ以降のイベントは読まない
+    Error { error_type: String, message: String },
+}
+
+/// 受信したチャンクを SSE イベントとして解釈し、StreamTurn に蓄積する
+struct SseParser {
+    /// チャンクはマルチバイト文字の途中で切れることがあるため、バイト列のまま貯めて行単位でデコードする
+    line_buf: Vec<u8>,
+    turn: StreamTurn,
+}
+
+impl SseParser {
+    /// prefill は切断からの再開時に送った受信済みテキスト。続きのデルタはその後ろに連結される
+    fn new(prefill: &str) -> Self {
+        Self {
+            line_buf: Vec::new(),
+            turn: StreamTurn { text: prefill.to_string(), ..Default::default() },
+        }
+    }
+
+    fn feed(&mut self, chunk: &[u8]) -> Vec<SseEvent> {
+        self.line_buf.extend_from_slice(chunk);
+        let turn = &mut self.turn;
+        let mut events = Vec::new();
+
+        // 改行で分割してSSEイベントを処理（改行の後ろの未完成の行は次のチャンクと結合する）
+        for line in drain_sse_lines(&mut self.line_buf) {
+            if !line.starts_with("data: ") {
+                continue;
+            }
+            let data = &line[6..];
+            if data == "[DONE]" {
+                continue;
+            }
+
+            let Ok(event) = serde_json::from_str::<serde_json::Value>(data) else {
+                continue;
+            };
+
+            let event_type = event.get("type").and_then(|t| t.as_str()).unwrap_or("");
+
+            match event_type {
+                "ping" => events.push(SseEvent::Ping),
+                "message_start" => {
+                    // 入力トークン（キャッシュの書き込み・読み取り分を含む）
+                    if let Some(usage) = event.pointer("/message/usage") {
+                        let tokens = |key: &str| usage.get(key).and_then(|v| v.as_u64()).unwrap_or(0);
+                        turn.usage.input_tokens = tokens("input_tokens");
+                        turn.usage.cache_creation_input_tokens = tokens("cache_creation_input_tokens");
+                        turn.usage.cache_read_input_tokens = tokens("cache_read_input_tokens");
+                    }
+                }
+                "content_block_start" => {
+                    let index = event.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+                    if let Some(block) = event.get("content_block") {
+                        let block_type = block.get("type").and_then(|t| t.as_str()).unwrap_or("");
+                        if block_type == "tool_use" {
+                            let id = block.get("id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+                            let name = block.get("name").and_then(|v| v.as_str()).unwrap_or("").to_string();
+                            turn.tool_uses.insert(index, (id, name, String::new()));
+                        }
+                    }
+                }
+                "content_block_delta" => {
+                    let index = event.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+                    if let Some(delta) = event.get("delta") {
+                        let delta_type = delta.get("type").and_then(|t| t.as_str()).unwrap_or("");
+                        match delta_type {
+                            "text_delta" => {
+                                if let Some(text) = delta.get("text").and_then(|t| t.as_str()) {
+                                    turn.text.push_str(text);
+                                    events.push(SseEvent::TextDelta(text.to_string()));
+                                }
+                            }
+                            "input_json_delta" => {
+                                if let Some(partial) = delta.get("partial_json").and_then(|t| t.as_str()) {
+                                    if let Some(entry) = turn.tool_uses.get_mut(&index) {
+                                        entry.2.push_str(partial);
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                "message_delta" => {
+                    if let Some(delta) = event.get("delta") {
+                        if let Some(sr) = delta.get("stop_reason").and_then(|v| v.as_str()) {
+                            turn.stop_reason = Some(sr.to_string());
+                        }
+                    }
+                    if let Some(usage) = event.get("usage") {
+                        if let Some(ot) = usage.get("output_tokens").and_then(|v| v.as_u64()) {
+                            turn.usage.output_tokens += ot;
+                        }
+                    }
+                }
+                "error" => {
+                    // HTTP 200 で始まったストリームの途中でも API はエラーを返すことがある（混雑時の overloaded_error 等）
+                    let field = |pointer: &str| event.pointer(pointer).and_then(|v| v.as_str()).map(str::to_string);
+                    events.push(SseEvent::Error {
+                        error_type: field("/error/type").unwrap_or_else(|| "unknown_error".to_string()),
+                        message: field("/error/message").unwrap_or_default(),
+                    });
+                    break;
+                }
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+/// SSEストリームを読み切る（ping・デルタが idle_timeout_secs 途絶えたら Stalled）
+/// prefill は切断からの再開時に送った受信済みテキスト。続きのデルタはその後ろに連結される
+/// 中断は session の stop_requested で受け取り、stream-delta・stream-error は emit で通知する
+async fn read_sse_stream(
+    response: reqwest::Response,
+    session: &Session,
+    stream_settings: &StreamSettings,
+    lang: &str,
+    prefill: &str,
+    emit: impl Fn(&str, serde_json::Value),
+) -> Result<StreamTurn, StreamReadError> {
+    let mut parser = SseParser::new(prefill);
+    let mut last_ping: Option<std::time::Instant> = None;
+    let idle_timeout = Duration::from_secs(stream_settings.idle_timeout_secs);
+
+    let mut byte_stream = response.bytes_stream();
+    loop {
+        // 中断されたら受信途中でもストリームを捨てて接続を切る
+        if session.stop_requested() {
+            return Err(StreamReadError::Stopped {
+                partial_text: parser.turn.text,
+                usage: parser.turn.usage,
+            });
+        }
+        // どのデータ（ping含む）を受信してもアイドルタイマーはリセットされる
+        let next = tokio::select! {
+            next = timeout(idle_timeout, byte_stream.next()) => next,
+            _ = session.stop_notify.notified() => continue,
+        };
+        let chunk_result = match next {
+            Ok(Some(chunk_result)) => chunk_result,
+            Ok(None) => break,
+            Err(_) => {
+                return Err(StreamReadError::Stalled {
+                    has_tool_uses: !parser.turn.tool_uses.is_empty(),
+                    partial_text: parser.turn.text,
+                    usage: parser.turn.usage,
+                })
+            }
+        };
+        let chunk = match chunk_result {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return Err(StreamReadError::Disconnected {
+                    message: with_error_detail(tr(lang, "stream_interrupted", &[]), &format!("Stream error: {}", e)),
+                    has_tool_uses: !parser.turn.tool_uses.is_empty(),
+                    partial_text: parser.turn.text,
+                    usage: parser.turn.usage,
+                });
+            }
+        };
+
+        for event in parser.feed(&chunk) {
+            match event {
+                SseEvent::Ping => {
+                    // 接続生存の指標: 受信間隔をログに出す
+                    let now = std::time::Instant::now();
+                    if let Some(prev) = last_ping.replace(now) {
+                        eprintln!("[Nexus] SSE ping interval: {}ms", now.duration_since(prev).as_millis());
+                    }
+                }
+                // フロントエンドにデルタ送信
+                SseEvent::TextDelta(text) => emit("stream-delta", serde_json::json!({ "text": text })),
+                SseEvent::Error { error_type, message } => {
+                    eprintln!("[Nexus] SSE error event: {} ({})", error_type, message);
+                    emit("stream-error", serde_json::json!({
+                        "error_type": error_type,
+                        "message": message,
+                        "partial_chars": parser.turn.text.chars().count()
+                    }));
+                    return Err(StreamReadError::ApiError {
+                        message: describe_stream_error_event(&error_type, &message, lang),
+                        partial_text: parser.turn.text,
+                        usage: parser.turn.usage,
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(parser.turn)
+}
+
+/// SSEストリーミングでAnthropic APIを呼び出し、Tauriイベントでフロントに配信
+/// Tool Use発生時はツール実行後に再ストリームするループ構造
+async fn call_anthropic_stream(
+    model: &str,
+    max_tokens: u32,
+    max_tool_loops: usize,
+    system: &str,
+    tools: &[serde_json::Value],
+    messages: &[serde_json::Value],
+    ctx: &ToolContext,
+) -> Result<StreamOutcome, String> {
+    let client = reqwest::Client::new();
+    let mut api_messages = messages.to_vec();
     let mut all_text_parts: Vec<String> = Vec::new();
     let mut all_tool_executions: Vec<ToolExecution> = Vec::new();
     let mut total_usage = UsageInfo::default();
-    let mut last_call_input_tokens: u64 = 0; // コンテキスト使用率計算用（最後のAPIコールのみ）
+    let mut last_call_input_tokens: u64 = 0;
+    let mut error: Option<String> = None;
+    let mut response_retried = false;
+    let mut auto_continues: u32 = 0;
+    let mut continuing = false; // 直前の呼び出しが出力上限で切れ、続きを依頼した
+    let mut stopped = false;
+    let budget = RequestBudget::new(&ctx.settings.budget, max_tokens);
+    // ツールを実行して結果を返した回数（自動継続・不完全な応答の再生成はツールループに数えない）
+    let mut tool_loops: usize = 0;
+
+    loop {
+        if ctx.session.stop_requested() {
+            stopped = true;
+            all_text_parts.push(GENERATION_STOPPED_NOTICE.to_string());
+            break;
+        }
+        let used = total_usage.input_tokens + total_usage.output_tokens;
+        if budget.phase(used) == BudgetPhase::Exhausted {
+            all_text_parts.push("\n⚠️ このリクエストのトークン予算に達したため、ツール実行を打ち切りました。".to_string());
+            break;
+        }
+        let limits = budget.call_limits(used);
+        let body = ApiRequest {
+            model: model.to_string(),
+            max_tokens: limits.max_tokens,
+            system: Some(cached_system_prompt(&budget.system_prompt(system, used))),
+            messages: api_messages.clone(),
+            tools: if tools.is_empty() {
+                None
+            } else {
+                Some(tools.to_vec())
+            },
+            tool_choice: (!limits.allow_tools && !tools.is_empty()).then(|| serde_json::json!({ "type": "none" })),
+            stream: Some(true),
+        };
+
+        // ストリーム停止（アイドルタイムアウト）・切断時は再送する
+        // resume_on_disconnect なら受信済みテキストを assistant の prefill にして続きから生成させる
+        let stream_settings = &ctx.settings.stream;
+        let mut stall_retries: u32 = 0;
+        let mut prefill = String::new();
+        let turn_result = loop {
+            let resumed_body;
+            let request_body = if prefill.is_empty() {
+                &body
+            } else {
+                resumed_body = with_assistant_prefill(&body, &prefill);
+                &resumed_body
+            };
+            let response = match post_api_request(&client, request_body, &ctx.api_caller()).await {
+                Ok(response) => response,
+                Err(e) => break Err(e),
+            };
+
+            let (partial_text, usage, has_tool_uses, give_up_error) = match read_sse_stream(
+                response,
+                &ctx.session,
+                stream_settings,
+                &ctx.settings.language,
+                &prefill,
+                |event, payload| ctx.emit(event, payload),
+            )
+            .await
+            {
+                Ok(turn) => break Ok(turn),
+                Err(StreamReadError::Stalled { partial_text, usage, has_tool_uses }) => {
+                    let error = format!(
+                        "ストリームが停止しました（{}秒間データなし、{}回再試行済み）",
+                        stream_settings.idle_timeout_secs, stall_retries
+                    );
+                    (partial_text, usage, has_tool_uses, error)
+                }
+                Err(StreamReadError::Disconnected { message, partial_text, usage, has_tool_uses }) => {
+                    if !stream_settings.resume_on_disconnect {
+                        total_usage.add(&usage);
+                        break Err(message);
+                    }
+                    (partial_text, usage, has_tool_uses, message)
+                }
+                // 中断時は受信済みの分を通常の応答と同じ経路で確定させる
+                Err(StreamReadError::Stopped { partial_text, usage }) => {
+                    stopped = true;
+                    break Ok(StreamTurn { text: partial_text, usage, ..Default::default() });
+                }
+                // 表示済みの途中テキストは部分応答として残し、エラーと一緒に返す
+                Err(StreamReadError::ApiError { message, partial_text, usage }) => {
+                    total_usage.add(&usage);
+                    all_text_parts.push(partial_text);
+                    break Err(message);
+                }
+            };
+
+            total_usage.add(&usage);
+            if stall_retries >= stream_settings.max_stall_retries {
+                // 表示済みの途中テキストは部分応答として残す
+                all_text_parts.push(partial_text);
+                break Err(give_up_error);
+            }
+            stall_retries += 1;
+            let resumable = if stream_settings.resume_on_disconnect {
+                resume_prefill(&partial_text, has_tool_uses)
+            } else {
+                None
+            };
+            match resumable {
+                Some(next_prefill) => {
+                    eprintln!(
+                        "[Nexus] Stream interrupted, resuming after {} chars ({}/{})",
+                        next_prefill.chars().count(), stall_retries, stream_settings.max_stall_retries
+                    );
+                    // フロントは prefill に含めなかった末尾の空白だけ取り消し、続きのデルタをそのまま連結する
+                    ctx.emit("stream-resuming", serde_json::json!({
+                        "attempt": stall_retries,
+                        "discard_chars": partial_text.encode_utf16().count() - next_prefill.encode_utf16().count()
+                    }));
+                    prefill = next_prefill;
+                }
+                None => {
+                    eprintln!(
+                        "[Nexus] Stream interrupted, retrying from scratch ({}/{})",
+                        stall_retries, stream_settings.max_stall_retries
+                    );
+                    // フロントは途中まで表示したテキストを取り消してから再受信する（JSの文字列長=UTF-16単位）
+                    ctx.emit("stream-stalled", serde_json::json!({
+                        "attempt": stall_retries,
+                        "discard_chars": partial_text.encode_utf16().count()
+                    }));
+                    prefill.clear();
+                }
+            }
+        };
+
+        // 何も得られていなければエラーのみ返し、途中まで進んでいれば部分応答として確定する
+        let turn = match turn_result {
+            Ok(turn) => turn,
+            Err(e) if join_text_parts(&all_text_parts).is_empty() && all_tool_executions.is_empty() => {
+                return Err(e);
+            }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+
+        last_call_input_tokens = turn.usage.prompt_tokens();
+        total_usage.add(&turn.usage);
+        budget.emit_progress(ctx, total_usage.input_tokens + total_usage.output_tokens, tool_loops);
+
+        // 空応答・不完全な応答は同じリクエストで1回だけ再生成する（2回目も不完全ならそのまま返す）
+        let text_so_far = join_text_parts(&all_text_parts) + &turn.text;
+        let incomplete = if stopped {
+            None
+        } else {
+            incomplete_response_reason(&text_so_far, !turn.tool_uses.is_empty(), turn.stop_reason.as_deref())
+        };
+        if let Some(reason) = incomplete {
+            if ctx.settings.requests.retry_incomplete_response && !response_retried {
+                response_retried = true;
+                notify_response_retry(ctx, model, reason, turn.stop_reason.as_deref(), tool_loops, turn.text.encode_utf16().count());
+                continue;
+            }
+            if response_retried {
+                eprintln!("[Nexus] Response still incomplete after regenerating ({})", reason);
+            }
+        }
+        let current_text = turn.text;
+        let tool_use_map = turn.tool_uses;
+        let stop_reason = turn.stop_reason;
+        let mut content_blocks: Vec<serde_json::Value> = Vec::new();
+
+        // テキスト部分を保存（自動継続の続きは直前の部分にそのまま連結する）
+        if !current_text.is_empty() {
+            match all_text_parts.last_mut() {
+                Some(last) if continuing => last.push_str(&current_text),
+                _ => all_text_parts.push(current_text.clone()),
+            }
+        }
+        continuing = false;
+
+        // 中断された呼び出しのツール呼び出しは実行しない（履歴には確定したテキストと実行済みツールだけが残る）
+        if stopped {
+            all_text_parts.push(GENERATION_STOPPED_NOTICE.to_string());
+            break;
+        }
+
+        // content_blocksを再構築（履歴用）
+        if !current_text.is_empty() {
+            content_blocks.push(serde_json::json!({ "type": "text", "text": current_text }));
+        }
+        for (_, (id, name, input_json)) in &tool_use_map {
+            let input: serde_json::Value = serde_json::from_str(input_json).unwrap_or(serde_json::json!({}));
+            content_blocks.push(serde_json::json!({
+                "type": "tool_use",
+                "id": id,
+                "name": name,
+                "input": input
+            }));
+        }
+
+        // アシスタント応答をメッセージ配列に追加
+        api_messages.push(serde_json::json!({
+            "role": "assistant",
+            "content": content_blocks
+        }));
+
+        if stop_reason.as_deref() == Some("max_tokens") {
+            // テキストの途中で切れただけなら続きを依頼して同じ応答として受け取る
+            let requests = &ctx.settings.requests;
+            if requests.auto_continue && auto_continues < requests.max_auto_continues && tool_use_map.is_empty() && !current_text.is_empty() {
+                auto_continues += 1;
+                continuing = true;
+                eprintln!(
+                    "[Nexus] Response hit max_tokens={}, auto-continuing ({}/{})",
+                    limits.max_tokens, auto_continues, requests.max_auto_continues
+                );
+                ctx.emit("response-continuing", serde_json::json!({
+                    "attempt": auto_continues,
+                    "max_attempts": requests.max_auto_continues
+                }));
+                api_messages.push(serde_json::json!({
+                    "role": "user",
+                    "content": AUTO_CONTINUE_PROMPT
+                }));
+                continue;
+            }
+            notify_response_truncated(ctx, model, limits.max_tokens);
+        }
+
+        // ツール呼び出しがなければ終了
+        if tool_use_map.is_empty() || stop_reason.as_deref() != Some("tool_use") {
+            break;
+        }
+
+        // ループ上限チェック（ツールを実行しても結果を返す呼び出しが残っていない）
+        if tool_loops >= max_tool_loops - 1 {
+            eprintln!("[Nexus] [{}] Tool loop limit reached ({})", ctx.trace_id, max_tool_loops);
+            all_text_parts.push(tool_loop_limit_notice(max_tool_loops));
+            break;
+        }
+
+        // ツール実行
+        let mut tool_results: Vec<serde_json::Value> = Vec::new();
+        // indexでソートして順番に実行
+        let mut sorted_tools: Vec<_> = tool_use_map.into_iter().collect();
+        sorted_tools.sort_by_key(|(idx, _)| *idx);
+
+        let tool_calls: Vec<(String, String, serde_json::Value)> = sorted_tools
+            .into_iter()
+            .map(|(_, (tool_id, tool_name, input_json))| {
+                (tool_id, tool_name, serde_json::from_str(&input_json).unwrap_or(serde_json::json!({})))
+            })
+            .collect();
+        preflight_batch_approval(&tool_calls, ctx).await;
+
+        let mut cancelled = false;
+        for (tool_id, tool_name, input) in &tool_calls {
+            // バッチ承認の待機中などに中断されたら、まだ始めていないツールは実行しない
+            if ctx.session.stop_requested() {
+                cancelled = true;
+                break;
+            }
+            let (tool_result, exec_result) = run_tool_call(tool_id, tool_name, input, ctx).await;
+            tool_results.push(tool_result);
+            cancelled = exec_result.iter().any(|e| e.cancelled);
+            all_tool_executions.extend(exec_result);
+            if cancelled {
+                break;
+            }
+        }
+        clear_batch_approvals(ctx);
+
+        // ユーザーがキャンセルしたら残りのツールも次の呼び出しも行わない
+        if cancelled {
+            stopped = ctx.session.stop_requested();
+            all_text_parts.push(if stopped { GENERATION_STOPPED_NOTICE } else { TOOL_CANCELLED_NOTICE }.to_string());
+            break;
+        }
+
+        // ツール結果をuserメッセージとして追加して次のループへ
+        api_messages.push(serde_json::json!({
+            "role": "user",
+            "content": tool_results
+        }));
+
+        // 次のストリームループ開始をフロントに通知
+        ctx.emit("stream-tool-continue", serde_json::json!({}));
+        tool_loops += 1;
+    }
+
+    Ok(StreamOutcome {
+        text: finalize_text(&all_text_parts, error.as_deref()),
+        tool_executions: all_tool_executions,
+        usage: total_usage,
+        last_call_input_tokens,
+        error,
+        stopped,
+    })
+}
+
+/// ストリーミング Tool Use ループの結果（error があれば途中までの部分応答、stopped は stop_generation で中断）
+struct StreamOutcome {
+    text: String,
+    tool_executions: Vec<ToolExecution>,
+    usage: UsageInfo,
+    last_call_input_tokens: u64,
+    error: Option<String>,
+    stopped: bool,
+}
+
+// ========================================
+// Tauri Commands
+// ========================================
+
+/// 会話リクエストの逐次化（同じセッションへの同時送信で履歴が壊れないようにする）
+#[derive(Default)]
+struct RequestGate {
+    lock: std::sync::Arc<tokio::sync::Mutex<()>>,
+    queued: std::sync::atomic::AtomicUsize,
+}
+
+/// 処理中の間だけ保持する実行権（drop で解放し busy-changed を通知）
+struct RequestSlot {
+    _guard: tokio::sync::OwnedMutexGuard<()>,
+    app_handle: tauri::AppHandle,
+    session_id: String,
+}
+
+impl Drop for RequestSlot {
+    fn drop(&mut self) {
+        let _ = self.app_handle.emit("busy-changed", serde_json::json!({ "busy": false, "session_id": self.session_id }));
+    }
+}
+
+/// 実行権を取得。処理中なら設定に応じて拒否（busy エラー）するか、順番を待つ
+async fn acquire_request_slot(
+    session: &Session,
+    mode: BusyMode,
+    app_handle: &tauri::AppHandle,
+) -> Result<RequestSlot, String> {
+    use std::sync::atomic::Ordering;
+    let gate = &session.gate;
+    let guard = match gate.lock.clone().try_lock_owned() {
+        Ok(guard) => guard,
+        Err(_) if mode == BusyMode::Reject => {
+            return Err("別のリクエストを処理中です。完了してから送信してください".to_string());
+        }
+        Err(_) => {
+            gate.queued.fetch_add(1, Ordering::SeqCst);
+            eprintln!("[Nexus] Request queued (waiting: {})", gate.queued.load(Ordering::SeqCst));
+            let guard = gate.lock.clone().lock_owned().await;
+            gate.queued.fetch_sub(1, Ordering::SeqCst);
+            guard
+        }
+    };
+    let _ = app_handle.emit("busy-changed", serde_json::json!({ "busy": true, "session_id": session.id }));
+    Ok(RequestSlot {
+        _guard: guard,
+        app_handle: app_handle.clone(),
+        session_id: session.id.clone(),
+    })
+}
+
+#[derive(Serialize)]
+struct BusyStatus {
+    busy: bool,
+    /// 順番待ちのリクエスト数（queue モード時）
+    queued: usize,
+}
+
+/// セッションが現在リクエストを処理中か（未作成のセッションは処理中でない）
+#[tauri::command]
+fn is_busy(session_id: Option<String>, sessions: State<'_, Sessions>) -> BusyStatus {
+    match sessions.get(session_id.as_deref()) {
+        Ok(session) => BusyStatus {
+            busy: session.gate.lock.try_lock().is_err(),
+            queued: session.gate.queued.load(std::sync::atomic::Ordering::SeqCst),
+        },
+        Err(_) => BusyStatus { busy: false, queued: 0 },
+    }
+}
+
+/// 実行中のツールをキャンセル（SSHプロセスを止め、そのリクエストの Tool Use ループも打ち切る）
+/// execution_id 未指定ならセッション内の実行中ツールをすべて止める。止めた件数を返す
+#[tauri::command]
+fn cancel_tool_execution(
+    session_id: Option<String>,
+    execution_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<usize, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let running = session.running_tools.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let targets: Vec<&ToolControl> = match execution_id.as_deref() {
+        Some(id) => vec![running
+            .get(id)
+            .ok_or_else(|| format!("実行中のツール '{}' が見つかりません（既に完了した可能性があります）", id))?],
+        None => running.values().collect(),
+    };
+    for control in &targets {
+        control.cancel.send_replace(true);
+    }
+    eprintln!("[Nexus] Cancelled {} tool execution(s) in session {}", targets.len(), session.id);
+    Ok(targets.len())
+}
+
+/// ストリーミング中の生成を中断（受信中のストリームを切り、実行中のツールも止める）
+/// 受信済みのテキストは履歴に残り、stream-end に cancelled: true が付く。処理中でなければ false を返す
+#[tauri::command]
+fn stop_generation(session_id: Option<String>, sessions: State<'_, Sessions>) -> Result<bool, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    // 処理中でなければ何もしない（次の送信を止めてしまわないように）
+    if session.gate.lock.try_lock().is_ok() {
+        return Ok(false);
+    }
+    session.stop_requested.store(true, std::sync::atomic::Ordering::SeqCst);
+    session.stop_notify.notify_waiters();
+    let running = session.running_tools.lock().map_err(|e| format!("Lock error: {}", e))?;
+    for control in running.values() {
+        control.cancel.send_replace(true);
+    }
+    eprintln!("[Nexus] Generation stop requested in session {} ({} running tool(s))", session.id, running.len());
+    Ok(true)
+}
+
+/// タイムアウトに達したツール実行（tool-timeout-pending）への応答。wait=true で延長、false で打ち切り
+#[tauri::command]
+fn resolve_tool_timeout(
+    execution_id: String,
+    wait: bool,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<(), String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let running = session.running_tools.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let control = running
+        .get(&execution_id)
+        .ok_or_else(|| format!("実行中のツール '{}' が見つかりません（既に完了した可能性があります）", execution_id))?;
+    control
+        .timeout_decisions
+        .send(wait)
+        .map_err(|_| format!("実行中のツール '{}' は既に終了しています", execution_id))?;
+    eprintln!("[Nexus] Tool timeout {}: {}", if wait { "extended" } else { "aborted" }, execution_id);
+    Ok(())
+}
+
+fn busy_mode(settings_state: &Mutex<AppSettings>) -> Result<BusyMode, String> {
+    Ok(settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.requests.on_busy)
+}
+
+/// Send a message via streaming SSE (primary)
+#[tauri::command]
+async fn send_message_stream(
+    message: String,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<SendMessageResponse, String> {
+    let session = sessions.get_or_create(session_id.as_deref())?;
+    let mode = busy_mode(&settings_state)?;
+    let _slot = acquire_request_slot(&session, mode, &app_handle).await?;
+    run_send(&session, &app_handle, stream_message(message, session.clone(), ssh_state, settings_state, app_handle.clone())).await
+}
+
+/// 送信の前後処理を挟んで送信本体を実行する（呼び出し側で実行権を取得済みであること）
+/// 前: 前の送信に向けた中断要求を戻す / 後: 完了通知と、再起動後も会話を続けられるようセッションを保存
+/// send は await されるまで動かないため、中断要求を戻してから始まる
+async fn run_send(
+    session: &Session,
+    app_handle: &tauri::AppHandle,
+    send: impl std::future::Future<Output = Result<SendMessageResponse, String>>,
+) -> Result<SendMessageResponse, String> {
+    session.stop_requested.store(false, std::sync::atomic::Ordering::SeqCst);
+    let result = send.await;
+    notify_send_result(app_handle, result.as_ref().map_err(String::as_str));
+    // 保存に失敗しても応答には影響させない
+    if let Err(e) = save_session_to_disk(app_handle, session) {
+        eprintln!("[Nexus] Warning: session save failed: {}", e);
+    }
+    result
+}
+
+/// ストリーミング送信の本体（呼び出し側で実行権を取得済みであること）
+async fn stream_message(
+    message: String,
+    session: std::sync::Arc<Session>,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<SendMessageResponse, String> {
+    let state = &session.chat;
+    let api_key = std::env::var("ANTHROPIC_API_KEY")
+        .map_err(|_| "ANTHROPIC_API_KEY 環境変数が設定されていません".to_string())?;
+
+    // システムプロンプトはモデルに合わせて毎回組み立てる（モデル切り替えが次の送信から反映される）
+    let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+    check_budget_limit(&session, &settings.pricing)?;
+    let (model, max_tokens, max_tool_loops) = {
+        let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        (chat.model.clone(), clamp_max_tokens(chat.max_tokens, &chat.model), clamp_max_tool_loops(chat.max_tool_loops) as usize)
+    };
+    let (tools, system_prompt, machines) = {
+        let ssh = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        (build_tools(&ssh.machines), build_system_prompt(&ssh, &model, &settings.prompts), ssh.machines.clone())
+    };
+    let ctx = ToolContext {
+        machines,
+        tools: tools.clone(),
+        settings,
+        app_handle: app_handle.clone(),
+        api_key: api_key.clone(),
+        session: session.clone(),
+        tool_sequence: Default::default(),
+        trace_id: new_trace_id(),
+    };
+    eprintln!("[Nexus] [{}] Request started (session {}, model {})", ctx.trace_id, session.id, model);
+
+    // 送信前の状態（応答を履歴に残せたら undo の対象にする）
+    let undo_point = {
+        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        let snapshot = chat.snapshot("send");
+        push_user_message(&mut chat, &message);
+        snapshot
+    };
+    let api_messages = prepare_api_messages(&ctx).await.map_err(|e| with_trace_id(e, &ctx.trace_id))?;
+
+    // stream-start イベント
+    ctx.emit("stream-start", serde_json::json!({}));
+
+    let outcome =
+        match call_anthropic_stream(&model, max_tokens, max_tool_loops, &system_prompt, &tools, &api_messages, &ctx).await {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                eprintln!("[Nexus] [{}] Request failed: {}", ctx.trace_id, e);
+                discard_unanswered_message(state);
+                return Err(with_trace_id(e, &ctx.trace_id));
+            }
+        };
+    let StreamOutcome { text, tool_executions, usage: total_usage, last_call_input_tokens, error, stopped } = outcome;
+    record_usage(&app_handle, &model, &total_usage);
+    let (final_text, raw_text) = postprocess_response(text, &ctx.settings.postprocess);
+
+    // 履歴とトークン統計を更新
+    let current_stats = {
+        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        chat.token_stats.last_input_tokens = last_call_input_tokens;
+        chat.token_stats.last_output_tokens = total_usage.output_tokens;
+        chat.token_stats.total_input_tokens += total_usage.input_tokens;
+        chat.token_stats.total_output_tokens += total_usage.output_tokens;
+        chat.token_stats.total_cache_creation_input_tokens += total_usage.cache_creation_input_tokens;
+        chat.token_stats.total_cache_read_input_tokens += total_usage.cache_read_input_tokens;
+        chat.token_stats.request_count += 1;
+        chat.token_stats.add_model_usage(&model, &total_usage);
+        notify_budget_warning(&app_handle, &session.id, &mut chat, &ctx.settings.pricing);
+        chat.record_undo(undo_point);
+        chat.history.push(HistoryMessage {
+            role: "assistant".to_string(),
+            content: final_text.clone(),
+            tool_executions: tool_executions.clone(),
+            raw_content: raw_text.clone(),
+            pinned: false,
+            starred: false,
+            tags: Vec::new(),
+            estimated_tokens: 0,
+        });
+        chat.token_stats.clone()
+    };
+    notify_token_stats(&app_handle, &session.id, &current_stats, "request");
+
+    // stream-end イベント
+    ctx.emit("stream-end", serde_json::json!({
+        "token_stats": current_stats,
+        "tool_executions": tool_executions,
+        "cancelled": stopped
+    }));
+
+    log_request_finished(&ctx.trace_id, tool_executions.len(), &total_usage, error.as_deref());
+    let machine_names: Vec<&str> = ctx.machines.iter().map(|m| m.name.as_str()).collect();
+    Ok(SendMessageResponse {
+        annotations: annotate_response(&final_text, &machine_names),
+        text: final_text,
+        token_stats: current_stats,
+        grounding: grounding_of(&tool_executions),
+        tool_executions,
+        raw_text,
+        error_detail: error.as_deref().and_then(|e| split_error_detail(e).1).map(str::to_string),
+        error: error.as_deref().map(|e| split_error_detail(e).0.to_string()),
+        trace_id: ctx.trace_id.clone(),
+    })
+}
+
+/// Send a message via Anthropic API (non-streaming fallback)
+#[tauri::command]
+async fn send_message(
+    message: String,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<SendMessageResponse, String> {
+    let session = sessions.get_or_create(session_id.as_deref())?;
+    let mode = busy_mode(&settings_state)?;
+    let _slot = acquire_request_slot(&session, mode, &app_handle).await?;
+    run_send(&session, &app_handle, blocking_message(message, session.clone(), ssh_state, settings_state, app_handle.clone())).await
+}
+
+/// 非ストリーミング送信の本体（呼び出し側で実行権を取得済みであること）
+async fn blocking_message(
+    message: String,
+    session: std::sync::Arc<Session>,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<SendMessageResponse, String> {
+    let state = &session.chat;
+    let api_key = std::env::var("ANTHROPIC_API_KEY")
+        .map_err(|_| "ANTHROPIC_API_KEY 環境変数が設定されていません".to_string())?;
+
+    // マシン情報からツール定義とシステムプロンプトを生成（プロンプトは現在のモデル向け）
+    let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+    check_budget_limit(&session, &settings.pricing)?;
+    let (model, max_tokens, max_tool_loops) = {
+        let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        (chat.model.clone(), clamp_max_tokens(chat.max_tokens, &chat.model), clamp_max_tool_loops(chat.max_tool_loops) as usize)
+    };
+    let (tools, system_prompt, machines) = {
+        let ssh = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        (
+            build_tools(&ssh.machines),
+            build_system_prompt(&ssh, &model, &settings.prompts),
+            ssh.machines.clone(),
+        )
+    };
+    let ctx = ToolContext {
+        machines,
+        tools: tools.clone(),
+        settings,
+        app_handle: app_handle.clone(),
+        api_key: api_key.clone(),
+        session: session.clone(),
+        tool_sequence: Default::default(),
+        trace_id: new_trace_id(),
+    };
+    eprintln!("[Nexus] [{}] Request started (session {}, model {})", ctx.trace_id, session.id, model);
+
+    // ユーザーメッセージを履歴に追加（送信前の状態は応答を残せたら undo の対象にする）
+    let undo_point = {
+        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        let snapshot = chat.snapshot("send");
+        push_user_message(&mut chat, &message);
+        snapshot
+    };
+
+    // 履歴をトークン予算に収めて API メッセージ形式に変換
+    let mut api_messages = prepare_api_messages(&ctx).await.map_err(|e| with_trace_id(e, &ctx.trace_id))?;
+
+    // ========================================
+    // Tool Use ループ
+    // ========================================
+    let mut all_text_parts: Vec<String> = Vec::new();
+    let mut all_tool_executions: Vec<ToolExecution> = Vec::new();
+    let mut total_usage = UsageInfo::default();
+    let mut last_call_input_tokens: u64 = 0; // コンテキスト使用率計算用（最後のAPIコールのみ）
+    let mut error: Option<String> = None;
+    let mut response_retried = false;
+    let mut auto_continues: u32 = 0;
+    let mut continuing = false; // 直前の呼び出しが出力上限で切れ、続きを依頼した
+    let budget = RequestBudget::new(&ctx.settings.budget, max_tokens);
+    // ツールを実行して結果を返した回数（自動継続・不完全な応答の再生成はツールループに数えない）
+    let mut tool_loops: usize = 0;
+
+    loop {
+        let used = total_usage.input_tokens + total_usage.output_tokens;
+        if budget.phase(used) == BudgetPhase::Exhausted {
+            all_text_parts.push("\n⚠️ このリクエストのトークン予算に達したため、ツール実行を打ち切りました。".to_string());
+            break;
+        }
+        let system = budget.system_prompt(&system_prompt, used);
+        let limits = budget.call_limits(used);
+
+        // 何も得られていなければエラーのみ返し、途中まで進んでいれば部分応答として確定する
+        let api_resp = match call_anthropic(&ctx.api_caller(), &model, &system, &tools, &api_messages, limits).await {
+            Ok(resp) => resp,
+            Err(e) if join_text_parts(&all_text_parts).is_empty() && all_tool_executions.is_empty() => {
+                eprintln!("[Nexus] [{}] Request failed: {}", ctx.trace_id, e);
+                discard_unanswered_message(state);
+                return Err(with_trace_id(e, &ctx.trace_id));
+            }
+            Err(e) => {
+                error = Some(e);
+                break;
+            }
+        };
+
+        // トークン使用量を累積
+        if let Some(usage) = &api_resp.usage {
+            total_usage.add(usage);
+            last_call_input_tokens = usage.prompt_tokens(); // 最新のAPIコールの入力（キャッシュ分を含む）を記録
+        }
+        budget.emit_progress(&ctx, total_usage.input_tokens + total_usage.output_tokens, tool_loops);
+
+        // 空応答・不完全な応答は同じリクエストで1回だけ再生成する（2回目も不完全ならそのまま返す）
+        let block_type = |block: &serde_json::Value, ty: &str| block.get("type").and_then(|t| t.as_str()) == Some(ty);
+        let turn_text: String = api_resp
+            .content
+            .iter()
+            .filter(|block| block_type(block, "text"))
+            .filter_map(|block| block.get("text").and_then(|t| t.as_str()))
+            .collect();
+        let has_tool_uses = api_resp.content.iter().any(|block| block_type(block, "tool_use"));
+        let text_so_far = join_text_parts(&all_text_parts) + &turn_text;
+        if let Some(reason) = incomplete_response_reason(&text_so_far, has_tool_uses, api_resp.stop_reason.as_deref()) {
+            if ctx.settings.requests.retry_incomplete_response && !response_retried {
+                response_retried = true;
+                notify_response_retry(&ctx, &model, reason, api_resp.stop_reason.as_deref(), tool_loops, 0);
+                continue;
+            }
+            if response_retried {
+                eprintln!("[Nexus] Response still incomplete after regenerating ({})", reason);
+            }
+        }
+
+        // レスポンスのcontentブロックを解析
+        let mut tool_uses: Vec<(String, String, serde_json::Value)> = Vec::new(); // (id, name, input)
+
+        for block in &api_resp.content {
+            if let Some(block_type) = block.get("type").and_then(|t| t.as_str()) {
+                match block_type {
+                    "text" => {
+                        // 自動継続の続きは直前の部分にそのまま連結する
+                        if let Some(text) = block.get("text").and_then(|t| t.as_str()) {
+                            match all_text_parts.last_mut() {
+                                Some(last) if continuing => last.push_str(text),
+                                _ => all_text_parts.push(text.to_string()),
+                            }
+                            continuing = false;
+                        }
+                    }
+                    "tool_use" => {
+                        let id = block
+                            .get("id")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("")
+                            .to_string();
+                        let name = block
+                            .get("name")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("")
+                            .to_string();
+                        let input = block
+                            .get("input")
+                            .cloned()
+                            .unwrap_or(serde_json::json!({}));
+                        tool_uses.push((id, name, input));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // アシスタント応答をメッセージ配列に追加（tool_useブロック含む）
+        api_messages.push(serde_json::json!({
+            "role": "assistant",
+            "content": api_resp.content
+        }));
+
+        continuing = false;
+
+        if api_resp.stop_reason.as_deref() == Some("max_tokens") {
+            // テキストの途中で切れただけなら続きを依頼して同じ応答として受け取る
+            let requests = &ctx.settings.requests;
+            if requests.auto_continue && auto_continues < requests.max_auto_continues && tool_uses.is_empty() && !turn_text.is_empty() {
+                auto_continues += 1;
+                continuing = true;
+                eprintln!(
+                    "[Nexus] Response hit max_tokens={}, auto-continuing ({}/{})",
+                    limits.max_tokens, auto_continues, requests.max_auto_continues
+                );
+                api_messages.push(serde_json::json!({
+                    "role": "user",
+                    "content": AUTO_CONTINUE_PROMPT
+                }));
+                continue;
+            }
+            notify_response_truncated(&ctx, &model, limits.max_tokens);
+        }
+
+        // ツール呼び出しがなければ終了
+        if tool_uses.is_empty() || api_resp.stop_reason.as_deref() != Some("tool_use") {
+            break;
+        }
+
+        // ループ上限チェック
+        if tool_loops >= max_tool_loops - 1 {
+            eprintln!("[Nexus] [{}] Tool loop limit reached ({})", ctx.trace_id, max_tool_loops);
+            all_text_parts.push(tool_loop_limit_notice(max_tool_loops));
+            break;
+        }
+
+        // ツール実行
+        let mut tool_results: Vec<serde_json::Value> = Vec::new();
+
+        preflight_batch_approval(&tool_uses, &ctx).await;
+
+        let mut cancelled = false;
+        for (tool_id, tool_name, tool_input) in &tool_uses {
+            let (tool_result, exec_result) = run_tool_call(tool_id, tool_name, tool_input, &ctx).await;
+            tool_results.push(tool_result);
+            cancelled = exec_result.iter().any(|e| e.cancelled);
+            all_tool_executions.extend(exec_result);
+            if cancelled {
+                break;
+            }
+        }
+        clear_batch_approvals(&ctx);
+
+        // ユーザーがキャンセルしたら残りのツールも次の呼び出しも行わない
+        if cancelled {
+            all_text_parts.push(TOOL_CANCELLED_NOTICE.to_string());
+            break;
+        }
+
+        // ツール結果をuserメッセージとして追加
+        api_messages.push(serde_json::json!({
+            "role": "user",
+            "content": tool_results
+        }));
+        tool_loops += 1;
+    }
+
+    record_usage(&app_handle, &model, &total_usage);
+
+    // 最終テキスト
+    let final_text = finalize_text(&all_text_parts, error.as_deref());
+    let (final_text, raw_text) = postprocess_response(final_text, &ctx.settings.postprocess);
+
+    // 履歴とトークン統計を更新（最終テキストのみ保存）
+    let current_stats = {
+        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+
+        chat.token_stats.last_input_tokens = last_call_input_tokens; // コンテキスト%用: 最後のAPIコールのみ
+        chat.token_stats.last_output_tokens = total_usage.output_tokens;
+        chat.token_stats.total_input_tokens += total_usage.input_tokens; // コスト計算用: 全ループ合計
+        chat.token_stats.total_output_tokens += total_usage.output_tokens;
+        chat.token_stats.total_cache_creation_input_tokens += total_usage.cache_creation_input_tokens;
+        chat.token_stats.total_cache_read_input_tokens += total_usage.cache_read_input_tokens;
+        chat.token_stats.request_count += 1;
+        chat.token_stats.add_model_usage(&model, &total_usage);
+        notify_token_stats(&app_handle, &session.id, &chat.token_stats, "request");
+        notify_budget_warning(&app_handle, &session.id, &mut chat, &ctx.settings.pricing);
+
+        // アシスタント応答を履歴に追加（テキストのみ）
+        chat.record_undo(undo_point);
+        chat.history.push(HistoryMessage {
+            role: "assistant".to_string(),
+            content: final_text.clone(),
+            tool_executions: all_tool_executions.clone(),
+            raw_content: raw_text.clone(),
+            pinned: false,
+            starred: false,
+            tags: Vec::new(),
+            estimated_tokens: 0,
+        });
+
+        chat.token_stats.clone()
+    };
+
+    log_request_finished(&ctx.trace_id, all_tool_executions.len(), &total_usage, error.as_deref());
+    let machine_names: Vec<&str> = ctx.machines.iter().map(|m| m.name.as_str()).collect();
+    Ok(SendMessageResponse {
+        annotations: annotate_response(&final_text, &machine_names),
+        text: final_text,
+        token_stats: current_stats,
+        grounding: grounding_of(&all_tool_executions),
+        tool_executions: all_tool_executions,
+        raw_text,
+        error_detail: error.as_deref().and_then(|e| split_error_detail(e).1).map(str::to_string),
+        error: error.as_deref().map(|e| split_error_detail(e).0.to_string()),
+        trace_id: ctx.trace_id.clone(),
+    })
+}
+
+/// メッセージのピン留めを切り替え（ピン留めしたメッセージはコンテキストのトリムで落とさない）
+#[tauri::command]
+fn pin_message(
+    index: usize,
+    pinned: bool,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<(), String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    let message = chat
+        .history
+        .get_mut(index)
+        .ok_or_else(|| format!("メッセージ #{} が見つかりません", index))?;
+    message.pinned = pinned;
+    Ok(())
+}
+
+/// 会話履歴を取得（編集・コピー対象のインデックス確認用）
+#[tauri::command]
+fn get_history(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<Vec<HistoryMessage>, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    Ok(chat.history.clone())
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct FilteredLine {
+    line_no: usize, // 1始まり
+    text: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct FilteredOutput {
+    execution_id: String,
+    lines: Vec<FilteredLine>,
+    total_lines: usize,
+}
+
+/// 保持しているツール実行結果を正規表現で絞り込む（フロント表示用、元の出力は変更しない）
+/// stdout が空なら stderr を対象にする
+#[tauri::command]
+fn filter_tool_output(
+    execution_id: String,
+    pattern: String,
+    invert: bool,
+    ignore_case: Option<bool>,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<FilteredOutput, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let regex = regex::RegexBuilder::new(&pattern)
+        .case_insensitive(ignore_case.unwrap_or(true))
+        .build()
+        .map_err(|e| format!("正規表現エラー: {}", e))?;
+
+    let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    let exec = chat
+        .history
+        .iter()
+        .flat_map(|m| m.tool_executions.iter())
+        .find(|e| e.execution_id == execution_id)
+        .ok_or_else(|| format!("実行結果 '{}' が見つかりません", execution_id))?;
+
+    let output = if exec.stdout.is_empty() { &exec.stderr } else { &exec.stdout };
+    let lines: Vec<FilteredLine> = output
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| regex.is_match(line) != invert)
+        .map(|(i, line)| FilteredLine { line_no: i + 1, text: line.to_string() })
+        .collect();
+
+    Ok(FilteredOutput {
+        execution_id,
+        lines,
+        total_lines: output.lines().count(),
+    })
+}
+
+/// 過去のユーザーメッセージを編集し、以降の履歴を破棄して会話をやり直す
+/// resend=true なら編集内容をそのまま送信、false なら履歴の書き換えのみ
+#[tauri::command]
+async fn edit_message(
+    index: usize,
+    new_content: String,
+    resend: Option<bool>,
+    session_id: Option<String>,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<Option<SendMessageResponse>, String> {
+    if new_content.trim().is_empty() {
+        return Err("編集後のメッセージが空です".to_string());
+    }
+    let resend = resend.unwrap_or(true);
+    let session = app_handle.state::<Sessions>().get(session_id.as_deref())?;
+
+    // 処理中の応答と履歴の書き換えが競合しないよう、編集も実行権を取ってから行う
+    let _slot = acquire_request_slot(&session, BusyMode::Reject, &app_handle).await?;
+
+    {
+        let mut chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+        let target = chat
+            .history
+            .get(index)
+            .ok_or_else(|| format!("メッセージ #{} が見つかりません", index))?;
+        if target.role != "user" {
+            return Err("アシスタントの応答は編集できません".to_string());
+        }
+
+        // 編集地点以降を破棄（送信時は send 側で再追加する）
+        chat.history.truncate(index);
+        if !resend {
+            push_user_message(&mut chat, &new_content);
+        }
+    }
+
+    if !resend {
+        if let Err(e) = save_session_to_disk(&app_handle, &session) {
+            eprintln!("[Nexus] Warning: session save failed: {}", e);
+        }
+        return Ok(None);
+    }
+    run_send(&session, &app_handle, stream_message(new_content, session.clone(), ssh_state, settings_state, app_handle.clone()))
+        .await
+        .map(Some)
+}
+
+/// Clear conversation history (コスト累計は保持)
+#[tauri::command]
+fn clear_history(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let session = sessions.get(session_id.as_deref())?;
+    {
+        let mut chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+        let snapshot = chat.snapshot("clear");
+        chat.record_undo(snapshot);
+        chat.history.clear();
+        // コンテキスト関連のみリセット、コスト累計は保持
+        chat.token_stats.last_input_tokens = 0;
+        chat.token_stats.last_output_tokens = 0;
+        notify_token_stats(&app_handle, &session.id, &chat.token_stats, "clear");
+    }
+    // 保存済みの履歴も空にしておかないと、再起動で消したはずの会話が戻る
+    if let Err(e) = save_session_to_disk(&app_handle, &session) {
+        eprintln!("[Nexus] Warning: session save failed: {}", e);
+    }
+    Ok(())
+}
+
+/// コスト累計をリセット
+#[tauri::command]
+fn reset_cost(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    let snapshot = chat.snapshot("reset_cost");
+    chat.record_undo(snapshot);
+    chat.token_stats = TokenStats::default();
+    chat.budget_warned = false;
+    notify_token_stats(&app_handle, &session.id, &chat.token_stats, "reset");
+    Ok(())
+}
+
+/// Get current token usage statistics
+#[tauri::command]
+fn get_token_stats(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<TokenStats, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    Ok(chat.token_stats.clone())
+}
+
+/// モデルを切り替えて model-changed を通知
+fn apply_model(session: &Session, chat: &mut ChatState, spec: &ModelSpec, app_handle: &tauri::AppHandle) -> String {
+    if chat.model != spec.id {
+        let snapshot = chat.snapshot("model");
+        chat.record_undo(snapshot);
+    }
+    chat.model = spec.id.to_string();
+    let _ = app_handle.emit("model-changed", serde_json::json!({
+        "session_id": session.id,
+        "model": spec.id,
+        "alias": spec.alias,
+        "label": spec.label
+    }));
+    format!("モデルを {} に変更しました", spec.label)
+}
+
+/// Switch model（実ID または "sonnet" | "haiku" | "opus" のエイリアス）
+#[tauri::command]
+fn set_model(
+    model_id: String,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let spec = find_model(&model_id).ok_or_else(|| format!("無効なモデル: {}", model_id))?;
+    let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    Ok(apply_model(&session, &mut chat, spec, &app_handle))
+}
+
+/// Sonnet と Haiku を交互に切り替え（それ以外のモデルからは Haiku へ）
+#[tauri::command]
+fn toggle_model(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    let is_haiku = find_model(&chat.model).is_some_and(|m| m.alias == "haiku");
+    let next = if is_haiku { "sonnet" } else { "haiku" };
+    let spec = find_model(next).ok_or_else(|| format!("無効なモデル: {}", next))?;
+    Ok(apply_model(&session, &mut chat, spec, &app_handle))
+}
+
+/// Get current model info
+#[tauri::command]
+fn get_current_model(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<String, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+    Ok(chat.model.clone())
+}
+
+/// 1回の応答の出力上限（max_tokens）を変更。モデルの範囲に収めた実際の値を返す
+#[tauri::command]
+fn set_max_tokens(
+    max_tokens: u32,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<u32, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let applied = {
+        let mut chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+        chat.max_tokens = clamp_max_tokens(max_tokens, &chat.model);
+        chat.max_tokens
+    };
+    if applied != max_tokens {
+        eprintln!("[Nexus] max_tokens {} clamped to {}", max_tokens, applied);
+    }
+    if let Err(e) = save_session_to_disk(&app_handle, &session) {
+        eprintln!("[Nexus] Warning: session save failed: {}", e);
+    }
+    Ok(applied)
+}
+
+/// 1回の送信でツールを実行しながら API を呼び出す最大回数を変更。1〜HARD_MAX_TOOL_LOOPS に収めた実際の値を返す
+#[tauri::command]
+fn set_max_tool_loops(
+    max_tool_loops: u32,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<u32, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let applied = clamp_max_tool_loops(max_tool_loops);
+    session.chat.lock().map_err(|e| format!("State lock error: {}", e))?.max_tool_loops = applied;
+    if applied != max_tool_loops {
+        eprintln!("[Nexus] max_tool_loops {} clamped to {}", max_tool_loops, applied);
+    }
+    if let Err(e) = save_session_to_disk(&app_handle, &session) {
+        eprintln!("[Nexus] Warning: session save failed: {}", e);
+    }
+    Ok(applied)
+}
+
+#[derive(Serialize)]
+struct MachineStatus {
+    name: String,
+    role: String,
+    online: bool,
+    /// 接続できたホスト（オンライン時のみ）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connected_host: Option<String>,
+    /// SSH 接続の確立から応答までの所要時間（オンライン時のみ）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+}
+
+// ========================================
+// メッセージのタグ・お気に入り
+// ========================================
+
+const MESSAGE_BOOKMARKS_FILE: &str = "message_bookmarks.json";
+/// 一覧・検索結果に保存する本文抜粋の最大文字数
+const BOOKMARK_EXCERPT_CHARS: usize = 200;
+
+/// タグ・お気に入りを付けたメッセージの記録（セッションをまたいで永続化）
+/// 元のメッセージは履歴のクリア・編集・セッション削除で消えたり位置がずれたりするため、本文の抜粋（マスク済み）を合わせて保存する
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MessageBookmark {
+    session_id: String,
+    index: usize, // 記録時の履歴インデックス（以降の編集・トリムでずれることがある）
+    role: String,
+    excerpt: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    starred: bool,
+    updated_at: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct TagCount {
+    tag: String,
+    count: usize,         // タグが付いたメッセージ数
+    session_count: usize, // タグが使われているセッション数
+}
+
+fn load_message_bookmarks(path: &std::path::Path) -> Vec<MessageBookmark> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_message_bookmarks(path: &std::path::Path, bookmarks: &[MessageBookmark]) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(bookmarks).map_err(|e| format!("JSON変換エラー: {}", e))?;
+    std::fs::write(path, json).map_err(|e| format!("書き込みエラー: {}", e))
+}
+
+/// タグを正規化（前後の空白と先頭の # を除去、空は捨て、大文字小文字を区別せず重複除去）
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut seen = std::collections::HashSet::new();
+    tags.into_iter()
+        .map(|t| t.trim().trim_start_matches('#').trim().to_string())
+        .filter(|t| !t.is_empty() && seen.insert(t.to_lowercase()))
+        .collect()
+}
+
+fn bookmark_excerpt(content: &str) -> String {
+    let masked = mask_secrets(content);
+    let mut excerpt: String = masked.chars().take(BOOKMARK_EXCERPT_CHARS).collect();
+    if masked.chars().count() > BOOKMARK_EXCERPT_CHARS {
+        excerpt.push('…');
+    }
+    excerpt
+}
+
+/// 履歴のメッセージにタグ・お気に入りを反映し、永続化ファイルの記録も更新する
+/// タグもお気に入りもなくなった記録はファイルから削除する
+fn update_message_marks(
+    app_handle: &tauri::AppHandle,
+    session_id: Option<&str>,
+    index: usize,
+    apply: impl FnOnce(&mut HistoryMessage),
+) -> Result<MessageBookmark, String> {
+    // 同時に複数のコマンドが来てもファイルの読み書きが交互にならないようにする
+    static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+    let session = app_handle.state::<Sessions>().get(session_id)?;
+    let bookmark = {
+        let mut chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+        let message = chat
+            .history
+            .get_mut(index)
+            .ok_or_else(|| format!("メッセージ #{} が見つかりません", index))?;
+        apply(message);
+        MessageBookmark {
+            session_id: session.id.clone(),
+            index,
+            role: message.role.clone(),
+            excerpt: bookmark_excerpt(&message.content),
+            tags: message.tags.clone(),
+            starred: message.starred,
+            updated_at: now_unix_secs(),
+        }
+    };
+
+    let _guard = FILE_LOCK.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let path = app_data_path(app_handle, MESSAGE_BOOKMARKS_FILE)?;
+    let mut bookmarks = load_message_bookmarks(&path);
+    // 同じメッセージかはセッション・インデックス・本文で判定（編集でインデックスが再利用されても取り違えない）
+    bookmarks.retain(|b| {
+        !(b.session_id == bookmark.session_id && b.index == bookmark.index && b.excerpt == bookmark.excerpt)
+    });
+    if bookmark.starred || !bookmark.tags.is_empty() {
+        bookmarks.push(bookmark.clone());
+    }
+    save_message_bookmarks(&path, &bookmarks)?;
+    Ok(bookmark)
+}
+
+/// メッセージのタグを設定（既存のタグは置き換え、空配列で全削除）。正規化後の記録を返す
+#[tauri::command]
+fn tag_message(
+    index: usize,
+    tags: Vec<String>,
+    session_id: Option<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<MessageBookmark, String> {
+    let tags = normalize_tags(tags);
+    update_message_marks(&app_handle, session_id.as_deref(), index, |m| m.tags = tags)
+}
+
+/// メッセージのお気に入りを切り替え
+#[tauri::command]
+fn star_message(
+    index: usize,
+    starred: bool,
+    session_id: Option<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<MessageBookmark, String> {
+    update_message_marks(&app_handle, session_id.as_deref(), index, |m| m.starred = starred)
+}
+
+/// お気に入りの一覧（新しい順）。session_id 未指定なら全セッション
+#[tauri::command]
+fn list_starred(
+    session_id: Option<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<MessageBookmark>, String> {
+    let session_id = session_id.map(|id| app_handle.state::<Sessions>().key(Some(&id))).transpose()?;
+    let path = app_data_path(&app_handle, MESSAGE_BOOKMARKS_FILE)?;
+    let mut starred: Vec<MessageBookmark> = load_message_bookmarks(&path)
+        .into_iter()
+        .filter(|b| b.starred && session_id.as_ref().is_none_or(|id| b.session_id == *id))
+        .collect();
+    starred.sort_by_key(|b| std::cmp::Reverse(b.updated_at));
+    Ok(starred)
+}
+
+/// タグでメッセージを検索（大文字小文字・先頭の # は区別しない、新しい順）。session_id 未指定なら全セッション
+#[tauri::command]
+fn search_by_tag(
+    tag: String,
+    session_id: Option<String>,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<MessageBookmark>, String> {
+    let tag = normalize_tags(vec![tag])
+        .pop()
+        .ok_or("検索するタグを指定してください")?
+        .to_lowercase();
+    let session_id = session_id.map(|id| app_handle.state::<Sessions>().key(Some(&id))).transpose()?;
+    let path = app_data_path(&app_handle, MESSAGE_BOOKMARKS_FILE)?;
+    let mut found: Vec<MessageBookmark> = load_message_bookmarks(&path)
+        .into_iter()
+        .filter(|b| b.tags.iter().any(|t| t.to_lowercase() == tag))
+        .filter(|b| session_id.as_ref().is_none_or(|id| b.session_id == *id))
+        .collect();
+    found.sort_by_key(|b| std::cmp::Reverse(b.updated_at));
+    Ok(found)
+}
+
+/// 全セッションのタグを集計（使用数の多い順）
+#[tauri::command]
+fn list_tags(app_handle: tauri::AppHandle) -> Result<Vec<TagCount>, String> {
+    let path = app_data_path(&app_handle, MESSAGE_BOOKMARKS_FILE)?;
+    // 小文字化したタグ → (最初に見つかった表記, メッセージ数, セッション集合)
+    let mut counts: std::collections::HashMap<String, (String, usize, std::collections::HashSet<String>)> =
+        std::collections::HashMap::new();
+    for bookmark in load_message_bookmarks(&path) {
+        for tag in &bookmark.tags {
+            let entry = counts
+                .entry(tag.to_lowercase())
+                .or_insert_with(|| (tag.clone(), 0, std::collections::HashSet::new()));
+            entry.1 += 1;
+            entry.2.insert(bookmark.session_id.clone());
+        }
+    }
+    let mut tags: Vec<TagCount> = counts
+        .into_values()
+        .map(|(tag, count, sessions)| TagCount { tag, count, session_count: sessions.len() })
+        .collect();
+    tags.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+    Ok(tags)
+}
+
+// ========================================
+// SSH Remote Management (Phase 3-A)
+// ========================================
+// machines.toml 設定構造体
+// ========================================
+
+#[derive(Deserialize, Debug)]
+struct MachinesFileConfig {
+    ssh: Option<SshFileConfig>,
+    machines: Vec<MachineEntry>,
+    /// ロール名またはタグ → システムプロンプトに追加する指示
+    #[serde(default)]
+    role_policies: std::collections::BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct SshFileConfig {
+    timeout_secs: Option<u64>,
+    keepalive_interval: Option<u32>,
+    keepalive_count_max: Option<u32>,
+    bind_address: Option<String>,
+    pre_command_hook: Option<String>,
+    post_command_hook: Option<String>,
+    hook_timeout_secs: Option<u64>,
+    allowed_command_prefixes: Option<Vec<String>>,
+    connection_reuse: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MachineEntry {
+    name: String,
+    host: Option<String>,
+    #[serde(default)]
+    hosts: Vec<String>,
+    port: Option<u16>,
+    user: Option<String>,
+    identity_file: Option<PathBuf>,
+    role: String,
+    enabled: bool,
+    os: String,
+    notes: Option<String>,
+    notion_page_id: Option<String>,
+    command_timeout_secs: Option<u64>,
+    expected_user: Option<String>,
+    #[serde(default)]
+    watch_commands: Vec<String>,
+    watch_interval_secs: Option<u64>,
+    rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    tags: Vec<String>,
+    shell: Option<ShellKind>,
+    bind_address: Option<String>,
+    pre_command_hook: Option<String>,
+    post_command_hook: Option<String>,
+    hook_timeout_secs: Option<u64>,
+    allowed_command_prefixes: Option<Vec<String>>,
+}
+
+/// SSH接続維持設定（グローバル）
+struct SshGlobalConfig {
+    timeout_secs: u64,
+    keepalive_interval: u32,
+    keepalive_count_max: u32,
+    /// ControlMaster による接続再利用（対応プラットフォームのみ）
+    connection_reuse: bool,
+}
+
+impl Default for SshGlobalConfig {
+    fn default() -> Self {
+        Self {
+            timeout_secs: 5,
+            keepalive_interval: 30,
+            keepalive_count_max: 3,
+            connection_reuse: true,
+        }
+    }
+}
+
+// ========================================
+
+const SSH_TIMEOUT_SECS: u64 = 5;
+/// ControlMaster 接続を最後の利用から維持する秒数
+const SSH_CONTROL_PERSIST_SECS: u64 = 60;
+
+/// ControlMaster のソケットを置くディレクトリ（本人のみアクセス可）
+/// Windows の ssh.exe は ControlMaster 非対応（Unix ドメインソケット・fork 前提）のため None
+fn ssh_control_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        return None;
+    }
+    // ソケットパスは約104バイトが上限のため、長くなりがちな TMPDIR（macOS）ではなく /tmp を使う
+    let user = std::env::var("USER").unwrap_or_else(|_| "user".to_string());
+    let dir = PathBuf::from("/tmp").join(format!("nexus-ssh-{}", user));
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    match builder.create(&dir) {
+        Ok(()) => Some(dir),
+        Err(e) => {
+            eprintln!("[Nexus] Warning: SSH connection reuse disabled ({}): {}", dir.display(), e);
+            None
+        }
+    }
+}
+
+/// マシンごとに ControlMaster のソケットパスを割り当てる
+/// %C（接続先・ポート・ユーザーのハッシュ）を含めるので、host を変えても古い接続は使い回さない
+fn assign_control_paths(machines: &mut [SshMachineConfig]) {
+    let Some(dir) = ssh_control_dir() else {
+        return;
+    };
+    for machine in machines.iter_mut().filter(|m| m.role != "Commander") {
+        let name: String = machine
+            .name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .take(20)
+            .collect();
+        machine.control_path = Some(dir.join(format!("{}-%C", name)).to_string_lossy().into_owned());
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SshMachineConfig {
+    name: String,
+    host: String,       // ~/.ssh/config の Host名 or IPアドレス（hosts の先頭。表示・フック等で使う代表ホスト）
+    #[serde(default)]
+    hosts: Vec<String>, // 接続候補（優先順）。複数あれば順に試し、最初に繋がったものを使う
+    #[serde(default)]
+    port: Option<u16>,  // SSHポート（未指定なら ~/.ssh/config / 22）
+    #[serde(default)]
+    user: Option<String>, // 接続ユーザー（未指定なら ~/.ssh/config / ローカルのユーザー名）
+    #[serde(default)]
+    identity_file: Option<PathBuf>, // 秘密鍵（ssh -i、~ はホームディレクトリに展開。未指定なら agent / ~/.ssh/config）
+    role: String,       // "Commander" | "Remote"
+    enabled: bool,      // 接続試行するか
+    os: String,         // "Windows" | "Linux"
+    notes: String,      // マシン用途・特記事項
+    #[serde(default)]
+    notion_page_id: Option<String>,  // Notionページ（ソフトウェア情報）
+    #[serde(default)]
+    command_timeout_secs: Option<u64>, // コマンド実行タイムアウト（未指定ならOSプロファイル）
+    #[serde(default)]
+    expected_user: Option<String>, // 設定時のみ実行前に whoami で実行ユーザーを確認
+    #[serde(default)]
+    watch_commands: Vec<String>, // 構成変化を監視するコマンド（出力を前回と比較）
+    #[serde(default)]
+    watch_interval_secs: Option<u64>, // 監視間隔（未指定なら1時間）
+    #[serde(default)]
+    rate_limit: Option<RateLimitConfig>, // ツール呼び出しのレート制限（未指定なら20回/60秒）
+    #[serde(default)]
+    tags: Vec<String>, // 任意のタグ（"production" 等、role_policies の対象指定に使う）
+    #[serde(default)]
+    shell: Option<ShellKind>, // コマンドを解釈させるシェル（未指定ならログインシェル: Windows=cmd, それ以外=bash）
+    #[serde(default)]
+    bind_address: Option<String>, // 接続元アドレス（ssh -b、複数NICで経路を選ぶ。未指定なら [ssh] の設定）
+    #[serde(default)]
+    pre_command_hook: Option<String>, // ツール実行前にローカルで実行（非ゼロ終了なら本コマンドを中止。未指定なら [ssh] の設定）
+    #[serde(default)]
+    post_command_hook: Option<String>, // ツール実行後にローカルで実行（結果に影響しない。未指定なら [ssh] の設定）
+    #[serde(default)]
+    hook_timeout_secs: Option<u64>, // フック自体のタイムアウト（未指定なら [ssh] の設定、それもなければ10秒）
+    #[serde(default)]
+    allowed_command_prefixes: Option<Vec<String>>, // ツール実行を許可するコマンドの前方一致リスト（未指定なら [ssh] の設定、それもなければ全許可）
+    #[serde(skip)]
+    control_path: Option<String>, // ControlMaster のソケットパス（SshState が割り当て。非対応プラットフォーム・無効時は None）
+}
+
+impl Default for SshMachineConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            host: String::new(),
+            hosts: Vec::new(),
+            port: None,
+            user: None,
+            identity_file: None,
+            role: "Remote".to_string(),
+            enabled: true,
+            os: "Windows".to_string(),
+            notes: String::new(),
+            notion_page_id: None,
+            command_timeout_secs: None,
+            expected_user: None,
+            watch_commands: Vec::new(),
+            watch_interval_secs: None,
+            rate_limit: None,
+            tags: Vec::new(),
+            shell: None,
+            bind_address: None,
+            pre_command_hook: None,
+            post_command_hook: None,
+            hook_timeout_secs: None,
+            allowed_command_prefixes: None,
+            control_path: None,
+        }
+    }
+}
+
+struct SshState {
+    machines: Vec<SshMachineConfig>,
+    global_config: SshGlobalConfig,
+    /// Notion APIから取得したソフトウェア情報（マシン名 → 情報テキスト）
+    notion_info: std::collections::HashMap<String, String>,
+    /// 接続状態のデバウンス（マシン名 → 確定状態・候補状態）
+    availability: std::collections::HashMap<String, AvailabilityTracker>,
+    /// ツール呼び出しのレート制限（マシン名 → トークンバケット）
+    rate_limits: std::collections::HashMap<String, TokenBucket>,
+    /// ロール/タグ別の追加指示（machines.toml の [role_policies]）
+    role_policies: std::collections::BTreeMap<String, String>,
+}
+
+/// 設定ファイルのパスを解決（実行ファイルからの相対パス対応）
+fn resolve_config_path(file_name: &str) -> Option<PathBuf> {
+    // 1. カレントディレクトリ
+    let cwd_path = std::path::Path::new(file_name);
+    if cwd_path.exists() {
+        return Some(cwd_path.to_path_buf());
+    }
+    // 2. 実行ファイルの親ディレクトリ
+    if let Ok(exe_path) = std::env::current_exe() {
+        if let Some(exe_dir) = exe_path.parent() {
+            let path = exe_dir.join(file_name);
+            if path.exists() {
+                return Some(path);
+            }
+            // 3. 開発時: src-tauri/../../<file> (nexus-app/直下)
+            let dev_path = exe_dir
+                .join("..")
+                .join("..")
+                .join("..")
+                .join(file_name);
+            if dev_path.exists() {
+                return Some(dev_path);
+            }
+        }
+    }
+    None
+}
+
+/// machines.tomlのパスを解決
+fn resolve_machines_toml_path() -> Option<PathBuf> {
+    resolve_config_path("machines.toml")
+}
+
+/// machines.toml の内容をパース・検証して SshState を組み立てる
+/// 接続候補（host を先頭に hosts を優先順で、空・重複は除く）。単数の host だけの旧形式もそのまま扱う
+fn machine_entry_hosts(entry: &MachineEntry) -> Vec<String> {
+    let mut hosts: Vec<String> = Vec::new();
+    for host in entry.host.iter().chain(entry.hosts.iter()) {
+        let host = host.trim();
+        if !host.is_empty() && !hosts.iter().any(|h| h == host) {
+            hosts.push(host.to_string());
+        }
+    }
+    hosts
+}
+
+fn parse_machines_config(content: &str) -> Result<SshState, String> {
+    let mut table = toml::from_str::<toml::Table>(content).map_err(|e| format!("TOML構文エラー: {}", e))?;
+    expand_machine_templates(&mut table)?;
+    let config: MachinesFileConfig = table.try_into().map_err(|e| format!("設定エラー: {}", e))?;
+
+    let mut seen = std::collections::HashSet::new();
+    for (i, m) in config.machines.iter().enumerate() {
+        if m.name.trim().is_empty() {
+            return Err(format!("machines[{}]: name が空です", i));
+        }
+        if machine_entry_hosts(m).is_empty() {
+            return Err(format!("machines[{}] ({}): host（または hosts）が空です", i, m.name));
+        }
+        if !seen.insert(m.name.as_str()) {
+            return Err(format!("machines[{}]: マシン名 '{}' が重複しています", i, m.name));
+        }
+        if let Some(addr) = m.bind_address.as_deref() {
+            validate_bind_address(addr).map_err(|e| format!("machines[{}] ({}): {}", i, m.name, e))?;
+        }
+        if m.port == Some(0) {
+            return Err(format!("machines[{}] ({}): port は 1〜65535 で指定してください", i, m.name));
+        }
+        if let Some(user) = m.user.as_deref() {
+            if user.trim().is_empty() || user.contains('@') || user.contains(char::is_whitespace) {
+                return Err(format!("machines[{}] ({}): user '{}' が不正です（空白や @ は含められません）", i, m.name, user));
+            }
+        }
+    }
+    let global_bind_address = config.ssh.as_ref().and_then(|s| s.bind_address.clone());
+    let global_pre_hook = config.ssh.as_ref().and_then(|s| s.pre_command_hook.clone());
+    let global_post_hook = config.ssh.as_ref().and_then(|s| s.post_command_hook.clone());
+    let global_hook_timeout = config.ssh.as_ref().and_then(|s| s.hook_timeout_secs);
+    let global_allowed_prefixes = config.ssh.as_ref().and_then(|s| s.allowed_command_prefixes.clone());
+    if let Some(addr) = global_bind_address.as_deref() {
+        validate_bind_address(addr).map_err(|e| format!("[ssh]: {}", e))?;
+    }
+
+    let global = config.ssh.as_ref().map_or(SshGlobalConfig::default(), |s| {
+        SshGlobalConfig {
+            timeout_secs: s.timeout_secs.unwrap_or(5),
+            keepalive_interval: s.keepalive_interval.unwrap_or(30),
+            keepalive_count_max: s.keepalive_count_max.unwrap_or(3),
+            connection_reuse: s.connection_reuse.unwrap_or(true),
+        }
+    });
+
+    let machines = config
+        .machines
+        .into_iter()
+        .map(|m| SshMachineConfig {
+            host: machine_entry_hosts(&m)[0].clone(),
+            hosts: machine_entry_hosts(&m),
+            name: m.name,
+            port: m.port,
+            user: m.user,
+            identity_file: m.identity_file,
+            role: m.role,
+            enabled: m.enabled,
+            os: m.os,
+            notes: m.notes.unwrap_or_default(),
+            notion_page_id: m.notion_page_id,
+            command_timeout_secs: m.command_timeout_secs,
+            expected_user: m.expected_user,
+            watch_commands: m.watch_commands,
+            watch_interval_secs: m.watch_interval_secs,
+            rate_limit: m.rate_limit,
+            tags: m.tags,
+            shell: m.shell,
+            bind_address: m.bind_address.or_else(|| global_bind_address.clone()),
+            pre_command_hook: m.pre_command_hook.or_else(|| global_pre_hook.clone()),
+            post_command_hook: m.post_command_hook.or_else(|| global_post_hook.clone()),
+            hook_timeout_secs: m.hook_timeout_secs.or(global_hook_timeout),
+            allowed_command_prefixes: m.allowed_command_prefixes.or_else(|| global_allowed_prefixes.clone()),
+            control_path: None,
+        })
+        .collect();
+
+    let mut state = SshState::new(machines, global);
+    state.role_policies = config.role_policies;
+    Ok(state)
+}
+
+/// bind_address はこのPCのNICに割り当てられたIPアドレス（ホスト名不可）
+fn validate_bind_address(addr: &str) -> Result<(), String> {
+    addr.trim()
+        .parse::<std::net::IpAddr>()
+        .map(|_| ())
+        .map_err(|_| format!("bind_address '{}' はIPアドレスとして不正です（例: \"192.168.1.10\"。NIC名やホスト名は指定できません）", addr))
+}
+
+/// [[machine_templates]] を各マシンに展開する（template = "名前" で参照、マシン個別の指定が優先）
+/// テンプレートも template で別のテンプレートを継承できる。未使用のテンプレートも循環参照を検査する
+fn expand_machine_templates(config: &mut toml::Table) -> Result<(), String> {
+    let mut templates: std::collections::HashMap<String, toml::Table> = std::collections::HashMap::new();
+    if let Some(value) = config.remove("machine_templates") {
+        let list = value.as_array().ok_or("machine_templates は [[machine_templates]] の配列で指定してください")?;
+        for (i, template) in list.iter().enumerate() {
+            let mut template = template
+                .as_table()
+                .cloned()
+                .ok_or_else(|| format!("machine_templates[{}]: テーブルではありません", i))?;
+            let name = match template.remove("name") {
+                Some(toml::Value::String(name)) if !name.trim().is_empty() => name,
+                _ => return Err(format!("machine_templates[{}]: name が空です", i)),
+            };
+            if templates.insert(name.clone(), template).is_some() {
+                return Err(format!("machine_templates[{}]: テンプレート名 '{}' が重複しています", i, name));
+            }
+        }
+    }
+    for name in templates.keys() {
+        resolve_machine_template(&templates, name, &mut Vec::new()).map_err(|e| format!("machine_templates ({}): {}", name, e))?;
+    }
+
+    let Some(toml::Value::Array(machines)) = config.get_mut("machines") else {
+        return Ok(());
+    };
+    for (i, machine) in machines.iter_mut().enumerate() {
+        let Some(entry) = machine.as_table_mut() else { continue };
+        let Some(template) = entry.remove("template") else { continue };
+        let name = entry.get("name").and_then(|v| v.as_str()).unwrap_or("").to_string();
+        let template = template
+            .as_str()
+            .ok_or_else(|| format!("machines[{}] ({}): template は文字列で指定してください", i, name))?;
+        let resolved = resolve_machine_template(&templates, template, &mut Vec::new())
+            .map_err(|e| format!("machines[{}] ({}): {}", i, name, e))?;
+        merge_missing_fields(entry, &resolved);
+        eprintln!(
+            "[Nexus] Machine '{}' expanded from template '{}': {}",
+            name,
+            template,
+            serde_json::to_string(entry).unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// テンプレートの継承チェーンを解決（chain は循環検出用の訪問中リスト）
+fn resolve_machine_template(
+    templates: &std::collections::HashMap<String, toml::Table>,
+    name: &str,
+    chain: &mut Vec<String>,
+) -> Result<toml::Table, String> {
+    if chain.iter().any(|n| n == name) {
+        chain.push(name.to_string());
+        return Err(format!("テンプレートが循環参照しています: {}", chain.join(" → ")));
+    }
+    let mut resolved = templates
+        .get(name)
+        .cloned()
+        .ok_or_else(|| format!("テンプレート '{}' が見つかりません", name))?;
+    chain.push(name.to_string());
+    if let Some(parent) = resolved.remove("template") {
+        let parent = parent
+            .as_str()
+            .ok_or_else(|| format!("テンプレート '{}' の template は文字列で指定してください", name))?;
+        let base = resolve_machine_template(templates, parent, chain)?;
+        merge_missing_fields(&mut resolved, &base);
+    }
+    chain.pop();
+    Ok(resolved)
+}
+
+/// target に無いキーを base から補う（テーブル同士はキー単位で再帰的に補完、配列は置き換え）
+fn merge_missing_fields(target: &mut toml::Table, base: &toml::Table) {
+    for (key, value) in base {
+        match (target.get_mut(key), value) {
+            (Some(toml::Value::Table(target)), toml::Value::Table(base)) => merge_missing_fields(target, base),
+            (Some(_), _) => {}
+            (None, _) => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// machines.tomlからマシン設定を読み込み
+fn load_machines_config() -> SshState {
+    if let Some(toml_path) = resolve_machines_toml_path() {
+        if let Ok(content) = std::fs::read_to_string(&toml_path) {
+            match parse_machines_config(&content) {
+                Ok(state) => {
+                    eprintln!("[Nexus] machines.toml loaded from: {}", toml_path.display());
+                    return state;
+                }
+                Err(e) => eprintln!("[Nexus] Warning: machines.toml invalid, using defaults: {}", e),
+            }
+        }
+    }
+    eprintln!("[Nexus] Warning: machines.toml not found, using hardcoded defaults");
+    SshState::hardcoded_defaults()
+}
+
+impl SshState {
+    fn new(mut machines: Vec<SshMachineConfig>, global_config: SshGlobalConfig) -> Self {
+        if global_config.connection_reuse {
+            assign_control_paths(&mut machines);
+        }
+        Self {
+            machines,
+            global_config,
+            notion_info: std::collections::HashMap::new(),
+            availability: std::collections::HashMap::new(),
+            rate_limits: std::collections::HashMap::new(),
+            role_policies: std::collections::BTreeMap::new(),
+        }
+    }
+
+    /// フォールバック: tomlが見つからない場合のハードコードデフォルト
+    fn hardcoded_defaults() -> Self {
+        Self::new(
+            vec![
+                SshMachineConfig {
+                    name: "OMEN".to_string(),
+                    host: "localhost".to_string(),
+                    role: "Commander".to_string(),
+                    enabled: false,
+                    os: "Windows".to_string(),
+                    notes: "メイン開発機。Nexusアプリ実行中".to_string(),
+                    notion_page_id: None,
+                    ..Default::default()
+                },
+                SshMachineConfig {
+                    name: "SIGMA".to_string(),
+                    host: "sigma".to_string(),
+                    role: "Remote".to_string(),
+                    enabled: true,
+                    os: "Windows".to_string(),
+                    notes: "LattePanda Sigma".to_string(),
+                    notion_page_id: Some("3037e628-88da-8170-9718-c8a9383d4a26".to_string()),
+                    ..Default::default()
+                },
+                SshMachineConfig {
+                    name: "Precision".to_string(),
+                    host: "precision".to_string(),
+                    role: "Remote".to_string(),
+                    enabled: true,
+                    os: "Windows".to_string(),
+                    notes: "Dell Precision 3630 ワークステーション".to_string(),
+                    notion_page_id: Some("3037e628-88da-81a4-807b-f9afc16fa752".to_string()),
+                    ..Default::default()
+                },
+            ],
+            SshGlobalConfig::default(),
+        )
+    }
+}
+
+// ========================================
+// アプリ設定（settings.toml）
+// ========================================
+
+/// OS別のコマンド実行タイムアウト（秒）
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct TimeoutProfiles {
+    /// systeminfo 等の遅いコマンドが多いため長め
+    windows: u64,
+    linux: u64,
+    /// 上記以外のOS
+    default: u64,
+    /// タイムアウト時に即失敗とせず、さらに待つか中止するかをユーザーに確認する（ツール実行のみ）
+    ask_on_timeout: bool,
+    /// 「さらに待つ」1回あたりの延長秒数
+    extend_secs: u64,
+    /// 確認を出してから応答を待つ秒数（過ぎたらタイムアウトとして打ち切る）
+    decision_wait_secs: u64,
+}
+
+impl Default for TimeoutProfiles {
+    fn default() -> Self {
+        Self {
+            windows: 60,
+            linux: REMOTE_COMMAND_TIMEOUT_SECS,
+            default: REMOTE_COMMAND_TIMEOUT_SECS,
+            ask_on_timeout: false,
+            extend_secs: 60,
+            decision_wait_secs: 120,
+        }
+    }
+}
+
+/// タイムアウト時に確認するモードの延長・待機秒数
+#[derive(Clone, Copy, Debug)]
+struct TimeoutAsk {
+    extend_secs: u64,
+    decision_wait_secs: u64,
+}
+
+impl TimeoutProfiles {
+    fn ask_policy(&self) -> Option<TimeoutAsk> {
+        self.ask_on_timeout.then_some(TimeoutAsk {
+            extend_secs: self.extend_secs,
+            decision_wait_secs: self.decision_wait_secs,
+        })
+    }
+
+    fn for_os(&self, os: &str) -> u64 {
+        match os.to_lowercase().as_str() {
+            "windows" => self.windows,
+            "linux" | "macos" | "darwin" | "unix" => self.linux,
+            _ => self.default,
+        }
+    }
+}
+
+/// ストリーミング応答の停止検知
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct StreamSettings {
+    /// ping もデルタも来ない状態がこの秒数続いたら停止とみなす
+    idle_timeout_secs: u64,
+    /// 停止・切断時の再試行回数
+    max_stall_retries: u32,
+    /// 停止・切断時に受信済みテキストを assistant prefill として渡し、続きから生成させる（ツール呼び出しの途中なら最初から）
+    resume_on_disconnect: bool,
+}
+
+impl Default for StreamSettings {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 30,
+            max_stall_retries: 2,
+            resume_on_disconnect: true,
+        }
+    }
+}
+
+/// 処理中に新しいリクエストが来たときの扱い
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum BusyMode {
+    /// busy エラーで即座に拒否
+    #[default]
+    Reject,
+    /// 順番待ちして逐次実行
+    Queue,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct RequestSettings {
+    on_busy: BusyMode,
+    /// SSHツールの同時実行数（超えた分は優先度順に待機）
+    max_concurrent_tools: usize,
+    /// 空応答・不完全な応答（ツールを呼ぶと言って呼ばない等）を1回だけ自動で再生成する
+    retry_incomplete_response: bool,
+    /// 出力上限（max_tokens）で切れた応答の続きを自動で依頼する
+    auto_continue: bool,
+    /// 1回の送信で自動継続する回数の上限
+    max_auto_continues: u32,
+}
+
+impl Default for RequestSettings {
+    fn default() -> Self {
+        Self {
+            on_busy: BusyMode::Reject,
+            max_concurrent_tools: 4,
+            retry_incomplete_response: false,
+            auto_continue: true,
+            max_auto_continues: 3,
+        }
+    }
+}
+
+/// 応答テキストの後処理（定型の前置き・後置きの除去）
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct PostprocessSettings {
+    enabled: bool,
+    /// 応答の先頭から除去する正規表現（先頭一致として扱う）
+    strip_prefixes: Vec<String>,
+    /// 応答の末尾から除去する正規表現（末尾一致として扱う）
+    strip_suffixes: Vec<String>,
+}
+
+impl Default for PostprocessSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            strip_prefixes: vec![
+                r"(承知しました|かしこまりました|了解しました|わかりました)[。！!]?\s*".to_string(),
+            ],
+            strip_suffixes: vec![
+                r"\s*(他に|ほかに)何かお手伝いできることがあれば[^\n]*".to_string(),
+            ],
+        }
+    }
+}
+
+/// 大きなツール出力の要約（二段階処理）
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct SummarizeSettings {
+    enabled: bool,
+    /// この文字数を超える出力を要約する
+    threshold_chars: usize,
+    /// 要約に使うモデル（エイリアスまたは実ID）
+    model: String,
+}
+
+impl Default for SummarizeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_chars: 8000,
+            model: "haiku".to_string(),
+        }
+    }
+}
+
+/// ツール出力の切り詰め方
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum TruncateMode {
+    /// コマンド種別（tail / journalctl 等）から自動選択
+    #[default]
+    Auto,
+    /// 先頭を残す
+    Head,
+    /// 末尾を残す
+    Tail,
+}
+
+/// Claude に渡すツール出力の上限
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct OutputSettings {
+    max_lines: usize,
+    truncate_mode: TruncateMode,
+    /// 行数で切り詰めた後の文字数上限（超えたら先頭・末尾を残して中間を省略、0 で無制限）
+    max_chars: usize,
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        Self {
+            max_lines: 200,
+            truncate_mode: TruncateMode::Auto,
+            max_chars: 8000,
+        }
+    }
+}
+
+/// settings.toml の内容（未指定項目はデフォルト値）
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+struct AppSettings {
+    language: String, // ユーザー向けメッセージの言語（"ja" / "en"、空ならja）
+    timeouts: TimeoutProfiles,
+    stream: StreamSettings,
+    output: OutputSettings,
+    summarize: SummarizeSettings,
+    requests: RequestSettings,
+    monitoring: MonitoringSettings,
+    prompts: PromptSettings,
+    postprocess: PostprocessSettings,
+    status: StatusSettings,
+    budget: BudgetSettings,
+    notifications: NotificationSettings,
+    approval: ApprovalSettings,
+    context: ContextSettings,
+    tool_cache: ToolCacheSettings,
+    sync: SyncSettings,
+    notion: NotionSettings,
+    load_monitor: LoadMonitorSettings,
+    /// モデル単価の上書き（キーはエイリアスまたはモデルID）
+    pricing: std::collections::HashMap<String, ModelPrice>,
+}
+
+const DEFAULT_CONTEXT_BUDGET_RATIO: f64 = 0.7;
+
+/// 会話履歴に使うトークン予算（超えた分は古いやり取りから落とす）
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct ContextSettings {
+    /// モデルのコンテキスト上限に対する割合（NEXUS_CONTEXT_BUDGET_RATIO があればそちらを優先）
+    budget_ratio: f64,
+    /// モデル別の割合（キーはエイリアスまたはモデルID）。指定があれば最優先
+    model_budget_ratios: std::collections::HashMap<String, f64>,
+    /// 予算を超えて落とすやり取りを要約して履歴の先頭に残す（失敗時はそのまま落とす）
+    summarize_trimmed: bool,
+    /// 履歴の要約に使うモデル（エイリアスまたは実ID）
+    summary_model: String,
+}
+
+impl Default for ContextSettings {
+    fn default() -> Self {
+        Self {
+            budget_ratio: DEFAULT_CONTEXT_BUDGET_RATIO,
+            model_budget_ratios: std::collections::HashMap::new(),
+            summarize_trimmed: true,
+            summary_model: "haiku".to_string(),
+        }
+    }
+}
+
+/// マシン生存確認
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct StatusSettings {
+    /// SSH確認の前に ICMP ping で絞り込む（ICMPが塞がれた環境では false にする）
+    ping_prefilter: bool,
+    /// SSH 接続の平均遅延がこれを超えるマシンを「接続品質: 低」とする（ミリ秒）
+    latency_threshold_ms: u64,
+}
+
+impl Default for StatusSettings {
+    fn default() -> Self {
+        Self {
+            ping_prefilter: true,
+            latency_threshold_ms: 1000,
+        }
+    }
+}
+
+/// マシン構成の差分監視
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+struct MonitoringSettings {
+    webhook_url: String, // 変更検知時にPOSTする先（空なら送らない）
+}
+
+/// settings.tomlを読み込み（なければデフォルト）
+fn load_app_settings() -> AppSettings {
+    let Some(path) = resolve_config_path("settings.toml") else {
+        eprintln!("[Nexus] settings.toml not found, using default settings");
+        return AppSettings::default();
+    };
+    match std::fs::read_to_string(&path).map(|c| toml::from_str::<AppSettings>(&c)) {
+        Ok(Ok(settings)) => {
+            eprintln!("[Nexus] settings.toml loaded from: {}", path.display());
+            settings
+        }
+        Ok(Err(e)) => {
+            eprintln!("[Nexus] Warning: settings.toml parse error, using defaults: {}", e);
+            AppSettings::default()
+        }
+        Err(e) => {
+            eprintln!("[Nexus] Warning: settings.toml read error, using defaults: {}", e);
+            AppSettings::default()
+        }
+    }
+}
+
+/// コマンドタイムアウトの決定: マシン個別設定 > OSプロファイル
+fn resolve_command_timeout(machine: &SshMachineConfig, settings: &AppSettings) -> (u64, &'static str) {
+    if let Some(secs) = machine.command_timeout_secs {
+        return (secs, "machine");
+    }
+    let os_lower = machine.os.to_lowercase();
+    if matches!(os_lower.as_str(), "windows" | "linux" | "macos" | "darwin" | "unix") {
+        (settings.timeouts.for_os(&machine.os), "os_profile")
+    } else {
+        (settings.timeouts.default, "default")
+    }
+}
+
+/// フェイルオーバーで最後に繋がったホストを優先して使う期間
+const PREFERRED_HOST_TTL_SECS: u64 = 300;
+
+/// マシン名 → (最後に繋がったホスト, 記録時刻)
+fn preferred_hosts() -> &'static Mutex<std::collections::HashMap<String, (String, std::time::Instant)>> {
+    static PREFERRED: std::sync::OnceLock<Mutex<std::collections::HashMap<String, (String, std::time::Instant)>>> =
+        std::sync::OnceLock::new();
+    PREFERRED.get_or_init(|| Mutex::new(std::collections::HashMap::new()))
+}
+
+/// 期限内に記録された、直近に繋がったホスト
+fn cached_host(machine_name: &str) -> Option<String> {
+    let cache = preferred_hosts().lock().ok()?;
+    cache
+        .get(machine_name)
+        .filter(|(_, at)| at.elapsed() < Duration::from_secs(PREFERRED_HOST_TTL_SECS))
+        .map(|(host, _)| host.clone())
+}
+
+fn remember_host(machine_name: &str, host: &str) {
+    if let Ok(mut cache) = preferred_hosts().lock() {
+        cache.insert(machine_name.to_string(), (host.to_string(), std::time::Instant::now()));
+    }
+}
+
+fn forget_cached_host(machine_name: &str) {
+    if let Ok(mut cache) = preferred_hosts().lock() {
+        cache.remove(machine_name);
+    }
+}
+
+/// 接続候補を試す順に並べる（直近に繋がったホストを先頭、残りは設定の優先順）
+fn host_candidates(machine: &SshMachineConfig) -> Vec<String> {
+    let mut hosts = if machine.hosts.is_empty() { vec![machine.host.clone()] } else { machine.hosts.clone() };
+    if let Some(preferred) = cached_host(&machine.name) {
+        if let Some(pos) = hosts.iter().position(|h| *h == preferred) {
+            let host = hosts.remove(pos);
+            hosts.insert(0, host);
+        }
+    }
+    hosts
+}
+
+/// 接続先を指定ホストに差し替えた設定（以降の ssh 引数・フック等はこのホストを使う）
+fn with_host(machine: &SshMachineConfig, host: &str) -> SshMachineConfig {
+    SshMachineConfig {
+        host: host.to_string(),
+        ..machine.clone()
+    }
+}
+
+/// 接続候補を順に試し、最初に繋がったホストを記録して返す
+async fn find_reachable_host(machine: &SshMachineConfig) -> Option<String> {
+    ssh_check_alive(machine).await.map(|(host, _)| host)
+}
+
+/// SSH接続テスト（接続候補を順に試し、最初に繋がったホストとその接続にかかった時間（ミリ秒）を返す）
+async fn ssh_check_alive(machine: &SshMachineConfig) -> Option<(String, u64)> {
+    for host in host_candidates(machine) {
+        if let Some(latency_ms) = ssh_probe(&with_host(machine, &host)).await {
+            remember_host(&machine.name, &host);
+            return Some((host, latency_ms));
+        }
+        eprintln!("[Nexus] {}: host '{}' unreachable, trying next candidate", machine.name, host);
+    }
+    forget_cached_host(&machine.name);
+    None
+}
+
+/// 1ホストへの SSH 接続テスト（ssh.exe経由、軽量）。成功時は接続確立から応答までの所要時間（ミリ秒）
+async fn ssh_probe(machine: &SshMachineConfig) -> Option<u64> {
+    if check_identity_file(machine, "ja").is_err() {
+        return None;
+    }
+    let mut command = ssh_command("ssh");
+    command.args([
+        "-o", "BatchMode=yes",
+        "-o", "ConnectTimeout=3",
+        "-o", "StrictHostKeyChecking=accept-new",
+        "-o", "ServerAliveInterval=30",
+        "-o", "ServerAliveCountMax=3",
+    ]);
+    command.args(ssh_connection_args(machine));
+    command.args(["echo", "nexus-ping"]);
+    let started = std::time::Instant::now();
+    let result = timeout(Duration::from_secs(SSH_TIMEOUT_SECS), command.output()).await;
+    let latency_ms = started.elapsed().as_millis() as u64;
+
+    match result {
+        Ok(Ok(output))
+            if output.status.success() && String::from_utf8_lossy(&output.stdout).contains("nexus-ping") =>
+        {
+            Some(latency_ms)
+        }
+        _ => None,
+    }
+}
+
+/// ssh_config のエイリアスを実ホスト名に解決（ssh -G、失敗時はそのまま）
+async fn resolve_ssh_hostname(host: &str) -> String {
+    let result = timeout(
+        Duration::from_secs(SSH_TIMEOUT_SECS),
+        ssh_command("ssh").args(["-G", host]).output(),
+    )
+    .await;
+    if let Ok(Ok(output)) = result {
+        if output.status.success() {
+            let config = String::from_utf8_lossy(&output.stdout);
+            if let Some(hostname) = config.lines().find_map(|l| l.strip_prefix("hostname ")) {
+                return hostname.trim().to_string();
+            }
+        }
+    }
+    host.to_string()
+}
+
+/// ICMP ping による到達確認（候補ホストのいずれかが応答すれば到達可能）
+async fn ping_check_alive(machine: &SshMachineConfig) -> bool {
+    let checks = host_candidates(machine)
+        .into_iter()
+        .map(|host| async move { ping_host(machine, &host).await });
+    futures_util::future::join_all(checks).await.into_iter().any(|alive| alive)
+}
+
+/// 1ホストへの ping（1発・約1秒で打ち切り、bind_address があればその送信元から）
+async fn ping_host(machine: &SshMachineConfig, host: &str) -> bool {
+    let target = resolve_ssh_hostname(host).await;
+    let (count_args, source_flag): ([&str; 4], &str) = if cfg!(windows) {
+        (["-n", "1", "-w", "1000"], "-S")
+    } else {
+        (["-c", "1", "-W", "1"], "-I")
+    };
+    let mut command = TokioCommand::new("ping");
+    command.args(count_args);
+    if let Some(addr) = machine.bind_address.as_deref() {
+        command.args([source_flag, addr.trim()]);
+    }
+    let result = timeout(Duration::from_secs(SSH_TIMEOUT_SECS), command.arg(&target).output()).await;
+
+    match result {
+        // Windows の ping は「宛先ホストに到達できません」でも成功を返すことがあるため応答行も確認
+        Ok(Ok(output)) => {
+            output.status.success()
+                && String::from_utf8_lossy(&output.stdout).to_lowercase().contains("ttl=")
+        }
+        _ => false,
+    }
+}
+
+/// 全マシンのステータスを実SSH接続で取得
+#[tauri::command]
+async fn get_machine_status(
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<MachineStatus>, String> {
+    let machines = {
+        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        state.machines.clone()
+    };
+    let ping_prefilter = {
+        let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        settings.status.ping_prefilter
+    };
+
+    // 前段: ping を並列実行し、応答のないマシンは SSH を試さずオフライン扱い
+    let reachable: Vec<bool> = if ping_prefilter {
+        futures_util::future::join_all(machines.iter().map(|m| async move {
+            m.role == "Commander" || !m.enabled || ping_check_alive(m).await
+        }))
+        .await
+    } else {
+        vec![true; machines.len()]
+    };
+
+    let mut statuses = Vec::new();
+
+    for (machine, reachable) in machines.iter().zip(reachable) {
+        let alive = if machine.role == "Commander" {
+            None
+        } else if machine.enabled && reachable {
+            ssh_check_alive(machine).await
+        } else {
+            None
+        };
+        // OMEN（自分自身）は常にオンライン
+        let online = machine.role == "Commander" || alive.is_some();
+        let (connected_host, latency_ms) = alive.unzip();
+
+        // 接続品質の集計用に遅延を時系列で残す（label は接続できたホスト）
+        if let (Some(host), Some(latency)) = (&connected_host, latency_ms) {
+            append_metric_samples(&app_handle, &machine.name, vec![(LATENCY_METRIC.to_string(), host.clone(), latency as f64)]);
+        }
+
+        statuses.push(MachineStatus {
+            name: machine.name.clone(),
+            role: machine.role.clone(),
+            online,
+            connected_host,
+            latency_ms,
+        });
+    }
+
+    // デバウンス後に確定した状態変化のみ availability.jsonl に記録
+    let transitions = {
+        let mut state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        statuses
+            .iter()
+            .filter_map(|s| {
+                state
+                    .availability
+                    .entry(s.name.clone())
+                    .or_default()
+                    .observe(s.online)
+                    .map(|(old_state, new_state)| AvailabilityRecord {
+                        timestamp: now_unix_secs(),
+                        machine: s.name.clone(),
+                        old_state: old_state.to_string(),
+                        new_state: new_state.to_string(),
+                    })
+            })
+            .collect::<Vec<_>>()
+    };
+    if !transitions.is_empty() {
+        match app_data_path(&app_handle, AVAILABILITY_LOG_FILE) {
+            Ok(path) => {
+                for record in &transitions {
+                    eprintln!(
+                        "[Nexus] {} availability: {} -> {}",
+                        record.machine, record.old_state, record.new_state
+                    );
+                    if let Err(e) = append_jsonl(&path, record) {
+                        eprintln!("[Nexus] Failed to record availability: {}", e);
+                    }
+                }
+            }
+            Err(e) => eprintln!("[Nexus] Failed to record availability: {}", e),
+        }
+    }
+
+    Ok(statuses)
+}
+
+/// リモート実行可能なマシンを名前で解決（存在・Commander・有効フラグを検査）
+fn resolve_remote_machine(
+    machines: &[SshMachineConfig],
+    machine_name: &str,
+    lang: &str,
+) -> Result<SshMachineConfig, String> {
+    let machine = machines
+        .iter()
+        .find(|m| m.name == machine_name)
+        .cloned()
+        .ok_or_else(|| tr(lang, "machine_not_found", &[("machine", machine_name)]))?;
+
+    if machine.role == "Commander" {
+        return Err(tr(lang, "commander_not_supported", &[]));
+    }
+
+    if !machine.enabled {
+        return Err(tr(lang, "machine_disabled", &[("machine", machine_name)]));
+    }
+
+    Ok(machine)
+}
+
+/// リモートPCでコマンドを実行
+#[tauri::command]
+async fn execute_remote_command(
+    machine_name: String,
+    command: String,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+) -> Result<RemoteCommandResult, String> {
+    let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+    let machine = {
+        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        resolve_remote_machine(&state.machines, &machine_name, &settings.language)?
+    };
+    let (timeout_secs, _) = resolve_command_timeout(&machine, &settings);
+
+    run_ssh_command(&machine, &command, timeout_secs, &settings.language).await
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct RemoteCommandResult {
+    success: bool,
+    stdout: String,
+    stderr: String,
+    exit_code: i32,
+}
+
+/// コマンド実行のデフォルトタイムアウト（接続テストより長め）
+const REMOTE_COMMAND_TIMEOUT_SECS: u64 = 30;
+
+/// リモートコマンドの実行オプション（作業ディレクトリ・環境変数）
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+struct CommandOptions {
+    working_dir: Option<String>,
+    env_vars: std::collections::BTreeMap<String, String>,
+}
+
+/// コマンドを実行するシェル
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ShellKind {
+    Cmd,
+    PowerShell,
+    Bash,
+}
+
+impl ShellKind {
+    fn name(self) -> &'static str {
+        match self {
+            ShellKind::Cmd => "cmd",
+            ShellKind::PowerShell => "powershell",
+            ShellKind::Bash => "bash",
+        }
+    }
+}
+
+/// SSH接続時のログインシェル（Windows の OpenSSH は cmd.exe、それ以外は POSIX シェル）
+fn login_shell(machine: &SshMachineConfig) -> ShellKind {
+    if machine.os.eq_ignore_ascii_case("windows") {
+        ShellKind::Cmd
+    } else {
+        ShellKind::Bash
+    }
+}
+
+/// コマンドを解釈させるシェル（未指定ならログインシェル）
+fn machine_shell(machine: &SshMachineConfig) -> ShellKind {
+    machine.shell.unwrap_or_else(|| login_shell(machine))
+}
+
+/// bash / POSIX シェル用: シングルクォートで囲み、内部の ' は '\'' に置換
+fn escape_for_bash(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// PowerShell 用: シングルクォート文字列（$ や ` は展開されない）。' と PowerShell が引用符とみなす ‘ ’ ‚ ‛ は二重化
+fn escape_for_powershell(value: &str) -> String {
+    let mut escaped = String::from("'");
+    for c in value.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            escaped.push(c);
+        }
+        escaped.push(c);
+    }
+    escaped.push('\'');
+    escaped
+}
+
+/// cmd.exe 経由で起動するプログラムへ1つの引数として渡す用
+/// CommandLineToArgvW の規則で " と直前の \ をエスケープして引用し、
+/// さらに cmd のメタ文字（" % ^ & | < > ( ) !）をすべて ^ でエスケープする（%VAR% の展開も防ぐ）
+fn escape_for_cmd(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in value.chars() {
+        match c {
+            '\\' => {
+                backslashes += 1;
+                continue;
+            }
+            '"' => quoted.push_str(&"\\".repeat(backslashes * 2 + 1)),
+            _ => quoted.push_str(&"\\".repeat(backslashes)),
+        }
+        backslashes = 0;
+        quoted.push(c);
+    }
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+
+    let mut escaped = String::with_capacity(quoted.len() * 2);
+    for c in quoted.chars() {
+        if "\"%^&|<>()!".contains(c) {
+            escaped.push('^');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// 作業ディレクトリ・環境変数を反映した、リモートで実際に実行されるコマンド文字列
+/// マシンのシェル（cmd / PowerShell / bash）の構文で組み立て、
+/// ログインシェルと異なるシェルを指定した場合はログインシェル向けにエスケープして起動する
+fn wrap_remote_command(machine: &SshMachineConfig, command: &str, options: &CommandOptions, lang: &str) -> Result<String, String> {
+    let shell = machine_shell(machine);
+    let mut parts = Vec::new();
+
+    if let Some(dir) = options.working_dir.as_deref().filter(|d| !d.is_empty()) {
+        parts.push(match shell {
+            ShellKind::Cmd => {
+                if dir.contains('"') {
+                    return Err(tr(lang, "wrap_working_dir_quote", &[("dir", dir)]));
+                }
+                format!("cd /d \"{}\"", dir)
+            }
+            ShellKind::PowerShell => format!("Set-Location -LiteralPath {}", escape_for_powershell(dir)),
+            ShellKind::Bash => format!("cd {}", escape_for_bash(dir)),
+        });
+    }
+    for (key, value) in &options.env_vars {
+        let valid_key = key.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_key {
+            return Err(tr(lang, "wrap_invalid_env_name", &[("name", key)]));
+        }
+        parts.push(match shell {
+            ShellKind::Cmd => {
+                if value.contains('"') {
+                    return Err(tr(lang, "wrap_env_value_quote", &[("name", key)]));
+                }
+                format!("set \"{}={}\"", key, value)
+            }
+            ShellKind::PowerShell => format!("$env:{} = {}", key, escape_for_powershell(value)),
+            ShellKind::Bash => format!("export {}={}", key, escape_for_bash(value)),
+        });
+    }
+
+    parts.push(command.to_string());
+    // Windows PowerShell 5.1 は && 非対応のため ; で連結
+    let script = parts.join(if shell == ShellKind::PowerShell { "; " } else { " && " });
+
+    let login = login_shell(machine);
+    if shell == login {
+        return Ok(script);
+    }
+    let launcher = match shell {
+        ShellKind::Cmd => "cmd /d /s /c",
+        ShellKind::PowerShell => "powershell -NoProfile -NonInteractive -Command",
+        ShellKind::Bash => "bash -c",
+    };
+    let argument = match login {
+        ShellKind::Cmd => {
+            if script.contains(['\n', '\r']) {
+                return Err(tr(lang, "wrap_cmd_newline", &[]));
+            }
+            escape_for_cmd(&script)
+        }
+        ShellKind::PowerShell => escape_for_powershell(&script),
+        ShellKind::Bash => escape_for_bash(&script),
+    };
+    Ok(format!("{} {}", launcher, argument))
+}
+
+/// ssh に渡す引数列（実行とプレビューで共通）
+fn build_ssh_args(machine: &SshMachineConfig, remote_command: &str) -> Vec<String> {
+    let mut args: Vec<String> = [
+        "-o", "BatchMode=yes",
+        "-o", "ConnectTimeout=5",
+        "-o", "ServerAliveInterval=30",
+        "-o", "ServerAliveCountMax=3",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect();
+    // 同じマシンへの連続実行でハンドシェイクを省く（SshState が割り当てた場合のみ）
+    if let Some(path) = machine.control_path.as_deref() {
+        args.extend([
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            format!("ControlPath={}", path),
+            "-o".to_string(),
+            format!("ControlPersist={}", SSH_CONTROL_PERSIST_SECS),
+        ]);
+    }
+    args.extend(ssh_connection_args(machine));
+    args.push(remote_command.to_string());
+    args
+}
+
+/// 接続先の指定（-b 接続元アドレス・-p ポート・[user@]host）。未指定の項目は ~/.ssh/config に任せる
+fn ssh_connection_args(machine: &SshMachineConfig) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(addr) = machine.bind_address.as_deref() {
+        args.extend(["-b".to_string(), addr.trim().to_string()]);
+    }
+    if let Some(port) = machine.port {
+        args.extend(["-p".to_string(), port.to_string()]);
+    }
+    if let Some(path) = machine.identity_file.as_deref() {
+        args.extend(["-i".to_string(), expand_tilde(path).to_string_lossy().into_owned()]);
+    }
+    args.push(ssh_destination(machine));
+    args
+}
+
+/// 先頭の ~ をホームディレクトリに展開（~user 形式は展開しない）
+fn expand_tilde(path: &std::path::Path) -> PathBuf {
+    let Ok(rest) = path.strip_prefix("~") else {
+        return path.to_path_buf();
+    };
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    match home {
+        Some(home) => PathBuf::from(home).join(rest),
+        None => path.to_path_buf(),
+    }
+}
+
+/// identity_file 指定時、鍵ファイルが存在するか（ssh は存在しない鍵を警告だけで無視するため事前に確認）
+fn check_identity_file(machine: &SshMachineConfig, lang: &str) -> Result<(), String> {
+    match machine.identity_file.as_deref() {
+        Some(path) if !expand_tilde(path).is_file() => Err(tr(lang, "identity_file_missing", &[
+            ("machine", &machine.name),
+            ("path", &expand_tilde(path).display().to_string()),
+        ])),
+        _ => Ok(()),
+    }
+}
+
+/// user 指定があれば user@host、なければ host をそのまま
+fn ssh_destination(machine: &SshMachineConfig) -> String {
+    match machine.user.as_deref() {
+        Some(user) => format!("{}@{}", user.trim(), machine.host),
+        None => machine.host.clone(),
+    }
+}
+
+/// 出力の種別（tool-output-line イベント用）
+#[derive(Clone, Copy, Debug)]
+enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    fn name(self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+/// ストリーミング実行中の通知
+enum StreamEvent<'a> {
+    Line(&'a str, OutputStream),
+    /// タイムアウトに達した（ask 指定時のみ）。decision_wait_secs 以内に判断がなければ打ち切る
+    TimeoutPending { elapsed_secs: u64, decision_wait_secs: u64 },
+}
+
+/// SSH経由でコマンドを実行し、出力を1行ずつ on_event に渡す（ツール実行用）
+/// タイムアウト・キャンセル時はエラーにせず、プロセスを止めてそれまでの出力を残した失敗結果を返す
+/// ask 指定時はタイムアウトしてもプロセスを生かしたまま、run.timeout_decisions で延長か中止かを待つ
+async fn run_ssh_command_streaming<F>(
+    machine: &SshMachineConfig,
+    command: &str,
+    timeout_secs: u64,
+    ask: Option<TimeoutAsk>,
+    lang: &str,
+    run: &mut ToolRun,
+    on_event: F,
+) -> Result<RemoteCommandResult, String>
+where
+    F: FnMut(StreamEvent),
+{
+    check_identity_file(machine, lang)?;
+    let remote_command = wrap_remote_command(machine, command, &CommandOptions::default(), lang)?;
+    let mut process = ssh_command("ssh");
+    process.args(build_ssh_args(machine, &remote_command));
+    run_process_streaming(process, "ssh_exec_error", timeout_secs, ask, lang, run, on_event).await
+}
+
+/// 子プロセスを起動し、出力を1行ずつ on_event に渡しながら終了・タイムアウト・キャンセルを待つ（SSH・ローカル実行共通）
+/// error_key は起動・待機に失敗したときのメッセージ（ssh_exec_error / local_exec_error）
+async fn run_process_streaming<F>(
+    mut process: TokioCommand,
+    error_key: &str,
+    timeout_secs: u64,
+    ask: Option<TimeoutAsk>,
+    lang: &str,
+    run: &mut ToolRun,
+    mut on_event: F,
+) -> Result<RemoteCommandResult, String>
+where
+    F: FnMut(StreamEvent),
+{
+    use tokio::io::AsyncBufReadExt;
+
+    let mut child = process
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| tr(lang, error_key, &[("error", &e.to_string())]))?;
+    let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        return Err(tr(lang, error_key, &[("error", "stdout/stderr を取得できません")]));
+    };
+    let mut stdout_lines = tokio::io::BufReader::new(stdout).split(b'\n');
+    let mut stderr_lines = tokio::io::BufReader::new(stderr).split(b'\n');
+    let mut stdout_bytes: Vec<u8> = Vec::new();
+    let mut stderr_bytes: Vec<u8> = Vec::new();
+    let (mut stdout_open, mut stderr_open) = (true, true);
+
+    let started = std::time::Instant::now();
+    let deadline = tokio::time::sleep(Duration::from_secs(timeout_secs));
+    tokio::pin!(deadline);
+    let timeout_message = |started: std::time::Instant| {
+        tr(lang, "command_timeout", &[("secs", &started.elapsed().as_secs().to_string())])
+    };
+    // 送信側が消えたらキャンセル・延長の待ち受けはやめる
+    let (mut cancel_open, mut decisions_open) = (true, true);
+    let mut awaiting_decision = false;
+    let mut stopped: Option<String> = None;
+    let mut status = None;
+    // 出力を読み切ってから終了を待つ（先に wait すると未読の出力を取りこぼす）
+    while stopped.is_none() && status.is_none() {
+        let pipes_closed = !stdout_open && !stderr_open;
+        tokio::select! {
+            segment = stdout_lines.next_segment(), if stdout_open => match segment {
+                Ok(Some(line)) => {
+                    on_event(StreamEvent::Line(decode_bytes(&line).trim_end_matches('\r'), OutputStream::Stdout));
+                    stdout_bytes.extend_from_slice(&line);
+                    stdout_bytes.push(b'\n');
+                }
+                _ => stdout_open = false,
+            },
+            segment = stderr_lines.next_segment(), if stderr_open => match segment {
+                Ok(Some(line)) => {
+                    on_event(StreamEvent::Line(decode_bytes(&line).trim_end_matches('\r'), OutputStream::Stderr));
+                    stderr_bytes.extend_from_slice(&line);
+                    stderr_bytes.push(b'\n');
+                }
+                _ => stderr_open = false,
+            },
+            result = child.wait(), if pipes_closed => match result {
+                Ok(exit) => status = Some(exit),
+                Err(e) => return Err(tr(lang, error_key, &[("error", &e.to_string())])),
+            },
+            _ = &mut deadline => match ask {
+                Some(ask) if !awaiting_decision => {
+                    awaiting_decision = true;
+                    on_event(StreamEvent::TimeoutPending {
+                        elapsed_secs: started.elapsed().as_secs(),
+                        decision_wait_secs: ask.decision_wait_secs,
+                    });
+                    deadline.as_mut().reset(tokio::time::Instant::now() + Duration::from_secs(ask.decision_wait_secs));
+                }
+                _ => stopped = Some(timeout_message(started)),
+            },
+            requested = run.cancel.wait_for(|c| *c), if cancel_open => match requested {
+                Ok(_) => stopped = Some(tr(lang, "tool_cancelled", &[])),
+                Err(_) => cancel_open = false,
+            },
+            decision = run.timeout_decisions.recv(), if decisions_open => match (decision, ask) {
+                (Some(true), Some(ask)) => {
+                    awaiting_decision = false;
+                    deadline.as_mut().reset(tokio::time::Instant::now() + Duration::from_secs(ask.extend_secs));
+                }
+                (Some(false), _) => stopped = Some(timeout_message(started)),
+                (Some(true), None) => {}
+                (None, _) => decisions_open = false,
+            },
+        }
+    }
+    let Some(status) = status else {
+        let _ = child.kill().await;
+        let mut stderr = decode_bytes(&stderr_bytes);
+        if !stderr.is_empty() && !stderr.ends_with('\n') {
+            stderr.push('\n');
+        }
+        stderr.push_str(&stopped.unwrap_or_default());
+        return Ok(RemoteCommandResult {
+            success: false,
+            stdout: decode_bytes(&stdout_bytes),
+            stderr,
+            exit_code: -1,
+        });
+    };
+    Ok(RemoteCommandResult {
+        success: status.success(),
+        stdout: decode_bytes(&stdout_bytes),
+        stderr: decode_bytes(&stderr_bytes),
+        exit_code: status.code().unwrap_or(-1),
+    })
+}
+
+/// SSH経由でコマンドを実行（ツール実行・手動実行・トランザクション共通）
+async fn run_ssh_command(
+    machine: &SshMachineConfig,
+    command: &str,
+    timeout_secs: u64,
+    lang: &str,
+) -> Result<RemoteCommandResult, String> {
+    check_identity_file(machine, lang)?;
+    let remote_command = wrap_remote_command(machine, command, &CommandOptions::default(), lang)?;
+    let result = timeout(
+        Duration::from_secs(timeout_secs),
+        ssh_command("ssh")
+            .args(build_ssh_args(machine, &remote_command))
+            .output(),
+    )
+    .await;
+
+    match result {
+        Ok(Ok(output)) => Ok(RemoteCommandResult {
+            success: output.status.success(),
+            stdout: decode_bytes(&output.stdout),
+            stderr: decode_bytes(&output.stderr),
+            exit_code: output.status.code().unwrap_or(-1),
+        }),
+        Ok(Err(e)) => Err(tr(lang, "ssh_exec_error", &[("error", &e.to_string())])),
+        Err(_) => Err(tr(lang, "command_timeout", &[("secs", &timeout_secs.to_string())])),
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct ToolCommandPreview {
+    machine_name: String,
+    program: String,
+    args: Vec<String>,
+    /// ラップ後にリモートで実行されるコマンド文字列
+    remote_command: String,
+    /// 表示用に1行へ連結したもの
+    display: String,
+    timeout_secs: u64,
+    timeout_source: String,
+    /// 実行前シンタックスチェックの指摘（問題なければ None）
+    syntax_warning: Option<String>,
+}
+
+/// 実行せずに、実際に ssh に渡る引数列とラップ後のコマンドを返す
+#[tauri::command]
+fn preview_tool_command(
+    machine_name: String,
+    command: String,
+    options: Option<CommandOptions>,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+) -> Result<ToolCommandPreview, String> {
+    let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+    let machine = {
+        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        resolve_remote_machine(&state.machines, &machine_name, &settings.language)?
+    };
+    let (timeout_secs, timeout_source) = resolve_command_timeout(&machine, &settings);
+    let remote_command = wrap_remote_command(&machine, &command, &options.unwrap_or_default(), &settings.language)?;
+    let args = build_ssh_args(&machine, &remote_command);
+    let display = std::iter::once("ssh".to_string())
+        .chain(args.iter().map(|a| {
+            if a.is_empty() || a.contains(|c: char| c.is_whitespace() || "'\"$`\\|&;<>()".contains(c)) {
+                escape_for_bash(a)
+            } else {
+                a.clone()
+            }
+        }))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let syntax_warning = check_command_syntax(&command, &machine.os)
+        .err()
+        .map(|(key, ch)| tr(&settings.language, key, &[("char", &ch.to_string())]));
+
+    Ok(ToolCommandPreview {
+        machine_name: machine.name.clone(),
+        program: "ssh".to_string(),
+        args,
+        remote_command,
+        display,
+        timeout_secs,
+        timeout_source: timeout_source.to_string(),
+        syntax_warning,
+    })
+}
+
+/// machines.toml の全文を取得（アプリ内エディタ用）
+#[tauri::command]
+fn get_raw_config() -> Result<String, String> {
+    let path = resolve_machines_toml_path().ok_or("machines.toml が見つかりません")?;
+    std::fs::read_to_string(&path).map_err(|e| format!("machines.toml 読み込みエラー: {}", e))
+}
+
+/// machines.toml を検証してから保存し、即座に反映する
+/// 検証に失敗した場合は保存しない。保存前の内容はアプリデータにバックアップする
+/// 保存前に差分・警告を確認するには preview_config_change を使う
+#[tauri::command]
+fn set_raw_config(
+    content: String,
+    ssh_state: State<'_, Mutex<SshState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let mut state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let (count, backup_path) = write_machines_config(&content, &mut state, &app_handle)?;
+    Ok(format!(
+        "machines.toml を保存して反映しました（{}台、バックアップ: {}）",
+        count,
+        backup_path.display()
+    ))
+}
+
+/// 検証済みの内容で machines.toml を書き換えて反映する（保存前の内容はバックアップ）。マシン数とバックアップのパスを返す
+/// 呼び出し側は内容の確認から反映までロックを持ち続けること（同時の追加・削除で片方の変更が消えないように）
+fn write_machines_config(
+    content: &str,
+    state: &mut SshState,
+    app_handle: &tauri::AppHandle,
+) -> Result<(usize, PathBuf), String> {
+    let new_state = parse_machines_config(content)?;
+    let path = resolve_machines_toml_path().ok_or("machines.toml が見つかりません")?;
+
+    let backup_path = app_data_path(app_handle, &format!("machines.toml.{}.bak", now_unix_secs()))?;
+    std::fs::copy(&path, &backup_path).map_err(|e| format!("バックアップ作成エラー: {}", e))?;
+    std::fs::write(&path, content).map_err(|e| format!("machines.toml 書き込みエラー: {}", e))?;
+
+    let notion_targets = replace_machines_state(state, new_state);
+    fetch_notion_info_for(app_handle, notion_targets);
+
+    eprintln!("[Nexus] machines.toml updated (backup: {})", backup_path.display());
+    Ok((state.machines.len(), backup_path))
+}
+
+/// 読み直した設定に差し替える。実行時に集めた情報（Notion・可用性・レート制限）は引き継ぐ
+/// Notion情報がまだないマシン（新規・ページ変更）を返す
+fn replace_machines_state(state: &mut SshState, new_state: SshState) -> Vec<SshMachineConfig> {
+    let previous = std::mem::replace(state, new_state);
+    state.notion_info = previous.notion_info;
+    state.availability = previous.availability;
+    state.rate_limits = previous.rate_limits;
+
+    state
+        .machines
+        .iter()
+        .filter(|m| {
+            let Some(page_id) = &m.notion_page_id else {
+                return false;
+            };
+            let same_page = previous.machines.iter().any(|p| p.name == m.name && p.notion_page_id.as_ref() == Some(page_id));
+            !same_page || !state.notion_info.contains_key(&m.name)
+        })
+        .cloned()
+        .collect()
+}
+
+/// 指定マシンのNotion情報だけをバックグラウンドで取得して反映する（キャッシュがあればそこにも追加）
+fn fetch_notion_info_for(app_handle: &tauri::AppHandle, machines: Vec<SshMachineConfig>) {
+    if machines.is_empty() {
+        return;
+    }
+    let app_handle = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        let info = match fetch_all_notion_info(&machines).await {
+            Ok(info) if !info.is_empty() => info,
+            Ok(_) => return,
+            Err(e) => {
+                eprintln!("[Nexus] Notion fetch for new machines skipped: {}", e);
+                return;
+            }
+        };
+        // キャッシュがないときは作らない（新規分だけのキャッシュだと次回起動で他のマシンを取得しなくなる）
+        if let Ok(path) = app_data_path(&app_handle, NOTION_CACHE_FILE) {
+            if let Some(mut cache) = load_notion_cache(&path) {
+                cache.info.extend(info.clone());
+                if let Err(e) = save_notion_cache(&path, &cache) {
+                    eprintln!("[Nexus] Warning: Notion cache save failed: {}", e);
+                }
+            }
+        }
+        let ssh_state = app_handle.state::<Mutex<SshState>>();
+        if let Ok(mut state) = ssh_state.lock() {
+            state.notion_info.extend(info);
+        };
+    });
+}
+
+/// machines.toml を読み直して反映する（再起動不要）
+/// 読み込み・検証に失敗した場合は現行の設定のまま Err を返す。新しくNotionページが設定されたマシンの分だけ取得する
+#[tauri::command]
+fn reload_machines_config(
+    ssh_state: State<'_, Mutex<SshState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let path = resolve_machines_toml_path().ok_or("machines.toml が見つかりません")?;
+    let content = std::fs::read_to_string(&path).map_err(|e| format!("machines.toml 読み込みエラー: {}", e))?;
+    let new_state = parse_machines_config(&content)
+        .map_err(|e| format!("machines.toml の再読み込みに失敗しました（現在の設定のままです）: {}", e))?;
+
+    let mut state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let before: std::collections::HashSet<String> = state.machines.iter().map(|m| m.name.clone()).collect();
+    let notion_targets = replace_machines_state(&mut state, new_state);
+    fetch_notion_info_for(&app_handle, notion_targets);
+
+    let added: Vec<&str> = state.machines.iter().map(|m| m.name.as_str()).filter(|n| !before.contains(*n)).collect();
+    let removed: Vec<&str> = before
+        .iter()
+        .map(String::as_str)
+        .filter(|n| !state.machines.iter().any(|m| m.name == *n))
+        .collect();
+    eprintln!("[Nexus] machines.toml reloaded from: {}", path.display());
+    let mut message = format!("machines.toml を再読み込みしました（{}台", state.machines.len());
+    if !added.is_empty() {
+        message.push_str(&format!("、追加: {}", added.join(", ")));
+    }
+    if !removed.is_empty() {
+        message.push_str(&format!("、削除: {}", removed.join(", ")));
+    }
+    message.push('）');
+    Ok(message)
+}
+
+/// machines.toml の変更プレビュー（保存前の確認用）
+#[derive(Serialize, Debug)]
+struct ConfigChangePreview {
+    /// 現行 → 変更後のユニファイド diff（変更なしなら空）
+    diff: String,
+    /// 変更後の全文（確認後にそのまま set_raw_config に渡す）
+    content: String,
+    added: Vec<String>,
+    removed: Vec<String>,
+    /// 設定が変わったマシン（テンプレート・[ssh] の既定値の変更による間接的な変化も含む）
+    changed: Vec<String>,
+    /// 危険な変更（Commander の削除・全マシンの無効化）
+    warnings: Vec<String>,
+}
+
+/// 現行と変更後の machines.toml を比べる（変更後が検証に通らなければエラー、純粋関数）
+fn build_config_change_preview(current: &str, proposed: &str) -> Result<ConfigChangePreview, String> {
+    let after = parse_machines_config(proposed)?.machines;
+    // 現行のファイルが壊れていても差分は出せるよう、マシン比較は空とみなす
+    let before = parse_machines_config(current).map(|s| s.machines).unwrap_or_default();
+    let find = |machines: &[SshMachineConfig], name: &str| machines.iter().find(|m| m.name == name).cloned();
+    let as_json = |m: &SshMachineConfig| serde_json::to_string(m).unwrap_or_default();
+
+    let added = after.iter().filter(|m| find(&before, &m.name).is_none()).map(|m| m.name.clone()).collect();
+    let removed = before.iter().filter(|m| find(&after, &m.name).is_none()).map(|m| m.name.clone()).collect();
+    let changed = after
+        .iter()
+        .filter(|m| find(&before, &m.name).is_some_and(|b| as_json(&b) != as_json(m)))
+        .map(|m| m.name.clone())
+        .collect();
+
+    let mut warnings = Vec::new();
+    for commander in before.iter().filter(|m| m.role == "Commander") {
+        match find(&after, &commander.name) {
+            None => warnings.push(format!("Commander（{}）が削除されます。ローカル実行ができなくなります", commander.name)),
+            Some(m) if m.role != "Commander" => {
+                warnings.push(format!("{} の role が Commander から {} に変わります", commander.name, m.role))
+            }
+            Some(_) => {}
+        }
+    }
+    let enabled_remote = |machines: &[SshMachineConfig]| machines.iter().filter(|m| m.enabled && m.role != "Commander").count();
+    if enabled_remote(&after) == 0 && enabled_remote(&before) > 0 {
+        warnings.push("有効なリモートマシンがなくなります（すべて無効化または削除）".to_string());
+    }
+
+    Ok(ConfigChangePreview {
+        diff: if current == proposed { String::new() } else { unified_diff(current, proposed, "machines.toml") },
+        content: proposed.to_string(),
+        added,
+        removed,
+        changed,
+        warnings,
+    })
+}
+
+/// machines.toml の変更をドライランで確認する（まだ保存しない）
+/// 問題なければ content を set_raw_config に渡すと、バックアップを取ってから保存・反映される
+#[tauri::command]
+fn preview_config_change(content: String) -> Result<ConfigChangePreview, String> {
+    let current = get_raw_config()?;
+    build_config_change_preview(&current, &content)
+}
+
+/// マシン設定を machines.toml の [[machines]] の1件に変換（未指定・空の項目は書かない）
+fn machine_to_toml(machine: &SshMachineConfig) -> Result<toml::Table, String> {
+    let mut entry = toml::Table::try_from(machine).map_err(|e| format!("TOML変換エラー: {}", e))?;
+    // 接続候補が1つなら host だけで足りる
+    if machine.hosts.len() <= 1 {
+        entry.remove("hosts");
+    }
+    entry.retain(|_, value| match value {
+        toml::Value::String(s) => !s.is_empty(),
+        toml::Value::Array(items) => !items.is_empty(),
+        _ => true,
+    });
+    Ok(entry)
+}
+
+/// machines.toml の [[machines]] を編集した全文（[ssh]・テンプレート・role_policies 等はそのまま残る）
+/// toml::to_string で書き直すため、コメントは保持されない（元の内容は書き込み時にバックアップされる）
+fn edit_machines_toml(content: &str, edit: impl FnOnce(&mut Vec<toml::Value>) -> Result<(), String>) -> Result<String, String> {
+    let mut table = toml::from_str::<toml::Table>(content).map_err(|e| format!("TOML構文エラー: {}", e))?;
+    let machines = table
+        .entry("machines")
+        .or_insert_with(|| toml::Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or("machines.toml の machines が配列ではありません")?;
+    edit(machines)?;
+    toml::to_string(&table).map_err(|e| format!("TOML変換エラー: {}", e))
+}
+
+/// マシンを追加して machines.toml に書き戻す
+#[tauri::command]
+fn add_machine(
+    machine: SshMachineConfig,
+    ssh_state: State<'_, Mutex<SshState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let name = machine.name.trim().to_string();
+    if name.is_empty() {
+        return Err("マシン名が空です".to_string());
+    }
+    // 重複の確認から書き込み・反映まで同じロックで行う
+    let mut state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+    if state.machines.iter().any(|m| m.name == name) {
+        return Err(format!("マシン '{}' は既に登録されています", name));
+    }
+    let entry = machine_to_toml(&SshMachineConfig { name: name.clone(), ..machine })?;
+    let content = edit_machines_toml(&get_raw_config()?, |machines| {
+        machines.push(toml::Value::Table(entry));
+        Ok(())
+    })?;
+    let (count, backup_path) = write_machines_config(&content, &mut state, &app_handle)?;
+    Ok(format!(
+        "マシン '{}' を追加しました（{}台）。machines.toml は書き直したためコメントは消えています（元の内容のバックアップ: {}）",
+        name,
+        count,
+        backup_path.display()
+    ))
+}
+
+/// マシンを削除して machines.toml に書き戻す（Commander は削除できない）
+#[tauri::command]
+fn remove_machine(
+    name: String,
+    ssh_state: State<'_, Mutex<SshState>>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    // 存在の確認から書き込み・反映まで同じロックで行う
+    let mut state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let machine = state
+        .machines
+        .iter()
+        .find(|m| m.name == name)
+        .ok_or_else(|| format!("マシン '{}' が見つかりません", name))?;
+    if machine.role == "Commander" {
+        return Err(format!("'{}' は Commander のため削除できません", name));
+    }
+    let content = edit_machines_toml(&get_raw_config()?, |machines| {
+        let before = machines.len();
+        machines.retain(|m| m.get("name").and_then(|n| n.as_str()) != Some(name.as_str()));
+        if machines.len() == before {
+            return Err(format!("machines.toml に '{}' の定義が見つかりません", name));
+        }
+        Ok(())
+    })?;
+    let (count, backup_path) = write_machines_config(&content, &mut state, &app_handle)?;
+    Ok(format!(
+        "マシン '{}' を削除しました（{}台）。machines.toml は書き直したためコメントは消えています（元の内容のバックアップ: {}）",
+        name,
+        count,
+        backup_path.display()
+    ))
+}
+
+/// SSH設定一覧を取得
+#[tauri::command]
+fn get_ssh_config(
+    ssh_state: State<'_, Mutex<SshState>>,
+) -> Result<Vec<SshMachineConfig>, String> {
+    let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+    Ok(state.machines.clone())
+}
+
+/// SSH設定を更新（マシンのhost/enabled変更）
+#[tauri::command]
+fn update_ssh_config(
+    machine_name: String,
+    host: Option<String>,
+    enabled: Option<bool>,
+    ssh_state: State<'_, Mutex<SshState>>,
+) -> Result<String, String> {
+    let mut state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let machine = state
+        .machines
+        .iter_mut()
+        .find(|m| m.name == machine_name)
+        .ok_or_else(|| format!("マシン '{}' が見つかりません", machine_name))?;
+
+    if let Some(h) = host {
+        machine.hosts = vec![h.clone()];
+        machine.host = h;
+    }
+    if let Some(e) = enabled {
+        machine.enabled = e;
+    }
+
+    Ok(format!("マシン '{}' の設定を更新しました", machine_name))
+}
+
+// ========================================
+// Structured Output（JSONモード）
+// ========================================
+
+/// send_message_structured の応答
+#[derive(Serialize)]
+struct StructuredResponse {
+    data: serde_json::Value,
+    retried: bool,
+    token_stats: TokenStats,
+}
+
+/// JSON出力を強制するシステムプロンプト
+fn build_structured_system_prompt(json_schema: &serde_json::Value) -> String {
+    format!(
+        "あなたはJSON生成器です。ユーザーの入力に対して、以下のJSON Schemaに厳密に従うJSONのみを出力してください。\n\
+         - 前置き・説明・コードフェンス（```）は一切付けない\n\
+         - 出力全体が1つのJSON値としてパース可能であること\n\
+         - スキーマの required フィールドは必ず含めること\n\n\
+         JSON Schema:\n{}",
+        serde_json::to_string_pretty(json_schema).unwrap_or_default()
+    )
+}
+
+/// 応答テキストからJSONを取り出してパース（コードフェンス付きの応答も許容）
+fn parse_structured_text(text: &str) -> Result<serde_json::Value, String> {
+    let trimmed = text.trim();
+    let body = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|t| t.strip_suffix("```"))
+        .unwrap_or(trimmed)
+        .trim();
+    serde_json::from_str(body).map_err(|e| format!("JSONパースエラー: {}", e))
+}
+
+/// 呼び出し元の json_schema をコンパイル（不正なスキーマはAPI呼び出し前に弾く）
+fn compile_structured_schema(json_schema: &serde_json::Value) -> Result<jsonschema::Validator, String> {
+    jsonschema::validator_for(json_schema).map_err(|e| format!("json_schema が不正です: {}", e))
+}
+
+/// 応答JSONをスキーマで検証し、違反箇所をパス付きで列挙する（再試行プロンプトにそのまま渡す）
+fn check_structured_value(value: &serde_json::Value, validator: &jsonschema::Validator) -> Result<(), String> {
+    let errors: Vec<String> = validator
+        .iter_errors(value)
+        .map(|e| {
+            let path = e.instance_path.to_string();
+            format!("- {}: {}", if path.is_empty() { "(root)" } else { path.as_str() }, e)
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("スキーマ違反:\n{}", errors.join("\n")))
+    }
+}
+
+/// スキーマに沿ったJSONをClaudeに生成させる（通常の会話履歴とは分離、tool_useなし）
+#[tauri::command]
+async fn send_message_structured(
+    message: String,
+    json_schema: serde_json::Value,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<StructuredResponse, String> {
+    let validator = compile_structured_schema(&json_schema)?;
+    let session = sessions.get(session_id.as_deref())?;
+    let (lang, pricing) = {
+        let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        (settings.language.clone(), settings.pricing.clone())
+    };
+    check_budget_limit(&session, &pricing)?;
+    let state = &session.chat;
+    let api_key = std::env::var("ANTHROPIC_API_KEY")
+        .map_err(|_| "ANTHROPIC_API_KEY 環境変数が設定されていません".to_string())?;
+
+    let (model, max_tokens) = {
+        let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        (chat.model.clone(), clamp_max_tokens(chat.max_tokens, &chat.model))
+    };
+
+    let system_prompt = build_structured_system_prompt(&json_schema);
+    let mut api_messages = vec![serde_json::json!({ "role": "user", "content": message })];
+    let trace_id = new_trace_id();
+    let mut total_usage = UsageInfo::default();
+    let mut last_call_input_tokens: u64 = 0;
+    let mut retried = false;
+
+    // 初回 + パース失敗時の再試行1回
+    let result = loop {
+        let caller = ApiCaller { api_key: &api_key, lang: &lang, app_handle: &app_handle, session_id: &session.id, trace_id: &trace_id };
+        let api_resp = call_anthropic(&caller, &model, &system_prompt, &[], &api_messages, CallLimits { max_tokens, ..CallLimits::default() }).await?;
+        if let Some(usage) = &api_resp.usage {
+            total_usage.add(usage);
+            last_call_input_tokens = usage.prompt_tokens();
+        }
+
+        let text: String = api_resp
+            .content
+            .iter()
+            .filter_map(|b| b.get("text").and_then(|t| t.as_str()))
+            .collect();
+
+        let parsed = parse_structured_text(&text)
+            .and_then(|v| check_structured_value(&v, &validator).map(|_| v));
+
+        match parsed {
+            Ok(value) => break Ok(value),
+            Err(e) if !retried => {
+                eprintln!("[Nexus] Structured output invalid, retrying once: {}", e);
+                retried = true;
+                api_messages.push(serde_json::json!({ "role": "assistant", "content": text }));
+                api_messages.push(serde_json::json!({
+                    "role": "user",
+                    "content": format!("出力がスキーマに従っていません。\n{}\nスキーマに従って、JSONのみを再出力してください。", e)
+                }));
+            }
+            Err(e) => break Err(format!("構造化出力の取得に失敗しました: {}", e)),
+        }
+    };
+
+    // コストは通常の会話と同じ統計に計上（履歴には残さない）
+    let token_stats = {
+        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        chat.token_stats.last_input_tokens = last_call_input_tokens;
+        chat.token_stats.last_output_tokens = total_usage.output_tokens;
+        chat.token_stats.total_input_tokens += total_usage.input_tokens;
+        chat.token_stats.total_output_tokens += total_usage.output_tokens;
+        chat.token_stats.total_cache_creation_input_tokens += total_usage.cache_creation_input_tokens;
+        chat.token_stats.total_cache_read_input_tokens += total_usage.cache_read_input_tokens;
+        chat.token_stats.request_count += 1;
+        chat.token_stats.add_model_usage(&model, &total_usage);
+        notify_budget_warning(&app_handle, &session.id, &mut chat, &pricing);
+        chat.token_stats.clone()
+    };
+    notify_token_stats(&app_handle, &session.id, &token_stats, "request");
+
+    Ok(StructuredResponse {
+        data: result?,
+        retried,
+        token_stats,
+    })
+}
+
+// ========================================
+// 秘匿情報マスク
+// ========================================
+
+/// 秘匿情報パターン（APIキー・トークン・パスワード・秘密鍵）
+fn secret_patterns() -> &'static [(regex::Regex, &'static str)] {
+    static PATTERNS: std::sync::OnceLock<Vec<(regex::Regex, &'static str)>> = std::sync::OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        [
+            (r"-----BEGIN [A-Z ]*PRIVATE KEY-----[\s\S]*?-----END [A-Z ]*PRIVATE KEY-----", "[MASKED PRIVATE KEY]"),
+            (r"sk-ant-[A-Za-z0-9_\-]{8,}", "sk-ant-[MASKED]"),
+            (r"sk-[A-Za-z0-9_\-]{16,}", "sk-[MASKED]"),
+            (r"\b(?:secret|ntn)_[A-Za-z0-9]{16,}", "[MASKED NOTION KEY]"),
+            (r"AIza[0-9A-Za-z_\-]{20,}", "[MASKED GOOGLE KEY]"),
+            (r"(?i)\b(password|passwd|pwd|token|api[_-]?key|secret)(\s*[=:]\s*)\S+", "${1}${2}[MASKED]"),
+        ]
+        .iter()
+        .filter_map(|(p, r)| regex::Regex::new(p).ok().map(|re| (re, *r)))
+        .collect()
+    })
+}
+
+/// テキスト中の秘匿情報をマスク（エクスポート・コピー・ログ記録前に通す）
+fn mask_secrets(text: &str) -> String {
+    let mut masked = text.to_string();
+    // 実際に読み込まれているキーの値は形式に関係なく確実に伏せる
+    for var in ["ANTHROPIC_API_KEY", "NOTION_API_KEY"] {
+        if let Ok(value) = std::env::var(var) {
+            if value.len() >= 8 {
+                masked = masked.replace(&value, "[MASKED]");
+            }
+        }
+    }
+    for (re, replacement) in secret_patterns() {
+        masked = re.replace_all(&masked, *replacement).into_owned();
+    }
+    masked
+}
+
+// ========================================
+// 会話エクスポート（自己完結HTML）
+// ========================================
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// 言語別のキーワード一覧（簡易ハイライト用）
+fn highlight_keywords(lang: &str) -> &'static [&'static str] {
+    match lang {
+        "bash" | "sh" | "shell" | "zsh" => &[
+            "if", "then", "else", "elif", "fi", "for", "while", "do", "done", "case", "esac",
+            "function", "return", "export", "local", "echo", "sudo",
+        ],
+        "powershell" | "ps1" | "pwsh" => &[
+            "if", "else", "elseif", "foreach", "for", "while", "function", "return", "param",
+            "try", "catch", "finally", "Get-ChildItem", "Get-Content", "Get-Process", "Select-Object",
+            "Where-Object", "Write-Output", "Write-Host",
+        ],
+        "bat" | "cmd" | "batch" => &["if", "else", "for", "goto", "call", "set", "echo", "exit", "rem", "dir"],
+        "rust" | "rs" => &[
+            "fn", "let", "mut", "pub", "struct", "enum", "impl", "trait", "match", "if", "else",
+            "for", "while", "loop", "return", "use", "mod", "async", "await", "Some", "None", "Ok", "Err",
+        ],
+        "python" | "py" => &[
+            "def", "class", "import", "from", "return", "if", "elif", "else", "for", "while", "in",
+            "with", "as", "try", "except", "None", "True", "False",
+        ],
+        "javascript" | "js" | "typescript" | "ts" => &[
+            "function", "const", "let", "var", "return", "if", "else", "for", "while", "async",
+            "await", "import", "export", "class", "new", "null", "true", "false",
+        ],
+        "json" | "toml" | "yaml" | "yml" => &["true", "false", "null"],
+        _ => &[],
+    }
+}
+
+/// コードブロックの言語別ハイライト（コメント・文字列・キーワード・数値）
+fn highlight_code(code: &str, lang: &str) -> String {
+    let lang = lang.to_lowercase();
+    let keywords = highlight_keywords(&lang);
+    let line_comment = match lang.as_str() {
+        "rust" | "rs" | "javascript" | "js" | "typescript" | "ts" => Some("//"),
+        "bat" | "cmd" | "batch" => Some("::"),
+        "" => None,
+        _ => Some("#"),
+    };
+
+    let chars: Vec<char> = code.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+
+        if let Some(marker) = line_comment {
+            if rest.starts_with(marker) {
+                let end = chars[i..].iter().position(|&ch| ch == '\n').map_or(chars.len(), |p| i + p);
+                let comment: String = chars[i..end].iter().collect();
+                out.push_str(&format!("<span class=\"hl-comment\">{}</span>", escape_html(&comment)));
+                i = end;
+                continue;
+            }
+        }
+
+        if c == '"' || c == '\'' {
+            let mut end = i + 1;
+            while end < chars.len() && chars[end] != c && chars[end] != '\n' {
+                if chars[end] == '\\' {
+                    end += 1;
+                }
+                end += 1;
+            }
+            let end = (end + 1).min(chars.len());
+            let literal: String = chars[i..end].iter().collect();
+            out.push_str(&format!("<span class=\"hl-string\">{}</span>", escape_html(&literal)));
+            i = end;
+            continue;
+        }
+
+        if c.is_ascii_digit() {
+            let end = chars[i..].iter().position(|ch| !ch.is_ascii_alphanumeric() && *ch != '.').map_or(chars.len(), |p| i + p);
+            let number: String = chars[i..end].iter().collect();
+            out.push_str(&format!("<span class=\"hl-number\">{}</span>", escape_html(&number)));
+            i = end;
+            continue;
+        }
+
+        if c.is_alphanumeric() || c == '_' || c == '-' {
+            let end = chars[i..]
+                .iter()
+                .position(|ch| !(ch.is_alphanumeric() || *ch == '_' || *ch == '-'))
+                .map_or(chars.len(), |p| i + p);
+            let word: String = chars[i..end].iter().collect();
+            if keywords.contains(&word.as_str()) {
+                out.push_str(&format!("<span class=\"hl-keyword\">{}</span>", escape_html(&word)));
+            } else {
+                out.push_str(&escape_html(&word));
+            }
+            i = end;
+            continue;
+        }
+
+        out.push_str(&escape_html(&c.to_string()));
+        i += 1;
+    }
+    out
+}
+
+/// インライン要素（`code` と **bold**）の変換
+fn render_inline_markdown(text: &str) -> String {
+    static INLINE_CODE: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    static BOLD: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let inline_code = INLINE_CODE.get_or_init(|| regex::Regex::new(r"`([^`]+)`").unwrap());
+    let bold = BOLD.get_or_init(|| regex::Regex::new(r"\*\*([^*]+)\*\*").unwrap());
+
+    let escaped = escape_html(text);
+    let with_code = inline_code.replace_all(&escaped, "<code>$1</code>");
+    bold.replace_all(&with_code, "<strong>$1</strong>").into_owned()
+}
+
+/// Markdownの最小限レンダリング（見出し・リスト・コードブロック・段落）
+fn render_markdown_html(text: &str) -> String {
+    let mut html = String::new();
+    let mut paragraph: Vec<String> = Vec::new();
+    let mut list_tag: Option<&str> = None;
+    let mut code_block: Option<(String, Vec<String>)> = None;
+
+    fn flush_paragraph(html: &mut String, paragraph: &mut Vec<String>) {
+        if !paragraph.is_empty() {
+            html.push_str(&format!("<p>{}</p>\n", paragraph.join("<br>")));
+            paragraph.clear();
+        }
+    }
+    fn close_list(html: &mut String, list_tag: &mut Option<&str>) {
+        if let Some(tag) = list_tag.take() {
+            html.push_str(&format!("</{}>\n", tag));
+        }
+    }
+
+    for line in text.lines() {
+        if let Some((lang, lines)) = code_block.as_mut() {
+            if line.trim_start().starts_with("```") {
+                html.push_str(&format!(
+                    "<pre class=\"code-block\"><div class=\"code-lang\">{}</div><code>{}</code></pre>\n",
+                    escape_html(lang),
+                    highlight_code(&lines.join("\n"), lang)
+                ));
+                code_block = None;
+            } else {
+                lines.push(line.to_string());
+            }
+            continue;
+        }
+
+        let trimmed = line.trim_start();
+        if let Some(lang) = trimmed.strip_prefix("```") {
+            flush_paragraph(&mut html, &mut paragraph);
+            close_list(&mut html, &mut list_tag);
+            code_block = Some((lang.trim().to_string(), Vec::new()));
+            continue;
+        }
+
+        if trimmed.is_empty() {
+            flush_paragraph(&mut html, &mut paragraph);
+            close_list(&mut html, &mut list_tag);
+            continue;
+        }
+
+        let heading = [("### ", "h4"), ("## ", "h3"), ("# ", "h2")]
+            .iter()
+            .find_map(|(prefix, tag)| trimmed.strip_prefix(prefix).map(|t| (*tag, t)));
+        if let Some((tag, content)) = heading {
+            flush_paragraph(&mut html, &mut paragraph);
+            close_list(&mut html, &mut list_tag);
+            html.push_str(&format!("<{0}>{1}</{0}>\n", tag, render_inline_markdown(content)));
+            continue;
+        }
+
+        let bullet = trimmed.strip_prefix("- ").or_else(|| trimmed.strip_prefix("* "));
+        let numbered = trimmed
+            .split_once(". ")
+            .filter(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+            .map(|(_, t)| t);
+        if let Some((tag, item)) = bullet.map(|t| ("ul", t)).or(numbered.map(|t| ("ol", t))) {
+            flush_paragraph(&mut html, &mut paragraph);
+            if list_tag != Some(tag) {
+                close_list(&mut html, &mut list_tag);
+                html.push_str(&format!("<{}>\n", tag));
+                list_tag = Some(tag);
+            }
+            html.push_str(&format!("<li>{}</li>\n", render_inline_markdown(item)));
+            continue;
+        }
+
+        close_list(&mut html, &mut list_tag);
+        paragraph.push(render_inline_markdown(line));
+    }
+
+    // 閉じられていないコードブロックもそのまま出力
+    if let Some((lang, lines)) = code_block {
+        html.push_str(&format!(
+            "<pre class=\"code-block\"><div class=\"code-lang\">{}</div><code>{}</code></pre>\n",
+            escape_html(&lang),
+            highlight_code(&lines.join("\n"), &lang)
+        ));
+    }
+    flush_paragraph(&mut html, &mut paragraph);
+    close_list(&mut html, &mut list_tag);
+    html
+}
+
+const EXPORT_CSS: &str = "\
+body{margin:0;padding:24px;background:#1a1b1e;color:#e4e5e7;font-family:'Segoe UI',-apple-system,sans-serif;font-size:14px;line-height:1.6}\
+.container{max-width:860px;margin:0 auto}\
+header{border-bottom:1px solid #2c2d33;margin-bottom:20px;padding-bottom:12px}\
+header h1{font-size:20px;margin:0 0 4px}\
+header .meta{color:#909296;font-size:12px}\
+.message{margin:14px 0;padding:12px 16px;border-radius:10px}\
+.message.user{background:#2b4a7a;margin-left:15%}\
+.message.assistant{background:#25262b;margin-right:5%}\
+.sender{font-size:11px;color:#909296;margin-bottom:6px;text-transform:uppercase;letter-spacing:.5px}\
+.sender .tag{text-transform:none;letter-spacing:0;color:#4dabf7;margin-left:4px}\
+.content p{margin:6px 0}\
+.content h2,.content h3,.content h4{margin:12px 0 6px}\
+code{background:#141517;padding:1px 5px;border-radius:4px;font-family:Consolas,monospace;font-size:13px}\
+pre.code-block{background:#141517;border:1px solid #2c2d33;border-radius:6px;padding:10px 12px;overflow-x:auto}\
+pre.code-block code{background:none;padding:0}\
+.code-lang{color:#5c5f66;font-size:11px;margin-bottom:4px}\
+.hl-keyword{color:#c678dd}.hl-string{color:#98c379}.hl-comment{color:#5c6370;font-style:italic}.hl-number{color:#d19a66}\
+details.tools{margin-top:10px;border:1px solid #2c2d33;border-radius:6px;background:#1e1f23}\
+details.tools summary{cursor:pointer;padding:6px 10px;color:#909296;font-size:12px}\
+.exec{padding:6px 10px;border-top:1px solid #2c2d33}\
+.exec.ok .exec-head{color:#51cf66}.exec.ng .exec-head{color:#ff6b6b}\
+.exec pre{margin:4px 0 0;white-space:pre-wrap;word-break:break-all;font-size:12px;color:#c1c2c5}";
+
+/// ツール実行の折りたたみ表示
+fn render_tool_executions_html(executions: &[ToolExecution]) -> String {
+    let success_count = executions.iter().filter(|e| e.success).count();
+    let items: String = executions
+        .iter()
+        .map(|e| {
+            let output = if e.stdout.is_empty() && e.stderr.is_empty() {
+                "(出力なし)".to_string()
+            } else {
+                format!("{}{}", e.stdout, e.stderr)
+            };
+            format!(
+                "<div class=\"exec {}\"><div class=\"exec-head\">{} {}: <code>{}</code></div><pre>{}</pre></div>",
+                if e.success { "ok" } else { "ng" },
+                if e.success { "✓" } else { "✗" },
+                escape_html(&e.machine_name),
+                escape_html(&e.command),
+                escape_html(&output)
+            )
+        })
+        .collect();
+    format!(
+        "<details class=\"tools\"><summary>🔧 {}件のコマンド実行（{}/{} 成功）</summary>{}</details>",
+        executions.len(),
+        success_count,
+        executions.len(),
+        items
+    )
+}
+
+/// 会話全体を自己完結HTMLとして生成（外部依存なし）
+fn render_conversation_html(history: &[HistoryMessage], model: &str) -> String {
+    let messages: String = history
+        .iter()
+        .map(|m| {
+            let sender = if m.role == "user" { "You" } else { "Claude" };
+            let mut marks = String::new();
+            if m.starred {
+                marks.push_str(" ★");
+            }
+            for tag in &m.tags {
+                marks.push_str(&format!(" <span class=\"tag\">#{}</span>", escape_html(tag)));
+            }
+            let tools = if m.tool_executions.is_empty() {
+                String::new()
+            } else {
+                render_tool_executions_html(&m.tool_executions)
+            };
+            format!(
+                "<div class=\"message {}\"><div class=\"sender\">{}{}</div><div class=\"content\">{}</div>{}</div>\n",
+                escape_html(&m.role),
+                sender,
+                marks,
+                render_markdown_html(&m.content),
+                tools
+            )
+        })
+        .collect();
+
+    let exported_at = now_unix_secs();
+
+    format!(
+        "<!doctype html>\n<html lang=\"ja\">\n<head>\n<meta charset=\"UTF-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1.0\">\n\
+         <title>Project Nexus — Conversation Export</title>\n<style>{}</style>\n</head>\n<body>\n\
+         <div class=\"container\">\n<header><h1>⬡ Project Nexus — 会話エクスポート</h1>\
+         <div class=\"meta\">Model: {} / Messages: {} / Exported: <span id=\"exported-at\" data-ts=\"{}\"></span></div></header>\n\
+         {}</div>\n<script>(function(){{var e=document.getElementById('exported-at');\
+         e.textContent=new Date(Number(e.dataset.ts)*1000).toLocaleString();}})();</script>\n</body>\n</html>\n",
+        EXPORT_CSS,
+        escape_html(model),
+        history.len(),
+        exported_at,
+        messages
+    )
+}
+
+/// 会話を自己完結型HTMLファイルに書き出す（秘匿情報はマスク済み）
+/// starred_only: true ならお気に入りのメッセージのみ
+#[tauri::command]
+fn export_conversation_html(
+    path: String,
+    starred_only: Option<bool>,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<String, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let (history, model) = {
+        let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        (chat.history.clone(), chat.model.clone())
+    };
+
+    if history.is_empty() {
+        return Err("エクスポートする会話がありません".to_string());
+    }
+    let history: Vec<HistoryMessage> = if starred_only.unwrap_or(false) {
+        let starred: Vec<HistoryMessage> = history.into_iter().filter(|m| m.starred).collect();
+        if starred.is_empty() {
+            return Err("お気に入りのメッセージがありません".to_string());
+        }
+        starred
+    } else {
+        history
+    };
+
+    // 本文・コマンド・出力のすべてをマスクしてからレンダリング
+    let masked: Vec<HistoryMessage> = history
+        .into_iter()
+        .map(|m| HistoryMessage {
+            role: m.role,
+            content: mask_secrets(&m.content),
+            tool_executions: m
+                .tool_executions
+                .into_iter()
+                .map(|e| ToolExecution {
+                    command: mask_secrets(&e.command),
+                    stdout: mask_secrets(&e.stdout),
+                    stderr: mask_secrets(&e.stderr),
+                    ..e
+                })
+                .collect(),
+            raw_content: None,
+            pinned: m.pinned,
+            starred: m.starred,
+            tags: m.tags,
+            estimated_tokens: 0,
+        })
+        .collect();
+
+    let html = render_conversation_html(&masked, &model);
+    std::fs::write(&path, html).map_err(|e| format!("HTML書き出しエラー: {}", e))?;
+    Ok(format!("会話を {} に書き出しました", path))
+}
+
+// ========================================
+// 実行コマンドのスクリプト書き出し
+// ========================================
+
+/// シェルごとの拡張子・ヘッダ・コメント記法
+fn script_format(shell: ShellKind) -> (&'static str, &'static str, &'static str) {
+    match shell {
+        ShellKind::Bash => ("sh", "#!/usr/bin/env bash", "#"),
+        ShellKind::PowerShell => ("ps1", "#Requires -Version 5.1", "#"),
+        ShellKind::Cmd => ("bat", "@echo off", "REM"),
+    }
+}
+
+/// マシンに対して実行されたコマンドを順に並べたスクリプト本文（失敗したコマンドはコメントアウト）
+fn render_command_script(
+    machine: &SshMachineConfig,
+    session_id: &str,
+    executions: &[&ToolExecution],
+    generated_at: &str,
+) -> String {
+    let (_, header, comment) = script_format(machine_shell(machine));
+    let mut lines = vec![
+        header.to_string(),
+        format!("{} Project Nexus: {} ({}) で実行したコマンド", comment, machine.name, machine.host),
+        format!("{} 生成日時: {}", comment, generated_at),
+        format!("{} セッション: {}", comment, session_id),
+        format!("{} 失敗したコマンドはコメントアウトしています。内容を確認してから実行してください", comment),
+    ];
+    for exec in executions {
+        lines.push(String::new());
+        let purpose = exec.purpose.map(|p| format!(" [{}]", p.name())).unwrap_or_default();
+        if exec.success {
+            lines.push(format!("{} #{}{}", comment, exec.sequence, purpose));
+            lines.extend(exec.command.lines().map(str::to_string));
+        } else {
+            let reason = if exec.cancelled { "キャンセル" } else { "失敗" };
+            lines.push(format!("{} #{}{} {}（終了時の出力は会話履歴を参照）", comment, exec.sequence, purpose, reason));
+            lines.extend(exec.command.lines().map(|line| format!("{} {}", comment, line)));
+        }
+    }
+    // バッチファイルは CRLF でないと行の解釈が崩れることがある
+    let newline = if machine_shell(machine) == ShellKind::Cmd { "\r\n" } else { "\n" };
+    let mut script = lines.join(newline);
+    script.push_str(newline);
+    script
+}
+
+/// 会話中にマシンへ実行したコマンドをシェルスクリプト（.sh / .ps1 / .bat）に書き出す
+/// 形式はマシンのシェル設定で決まる。拡張子がなければ付ける。書き出したパスを返す
+#[tauri::command]
+fn export_session_as_script(
+    machine_name: String,
+    path: String,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    ssh_state: State<'_, Mutex<SshState>>,
+) -> Result<String, String> {
+    let machine = {
+        let ssh = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        ssh.machines
+            .iter()
+            .find(|m| m.name == machine_name)
+            .cloned()
+            .ok_or_else(|| format!("マシン '{}' が見つかりません", machine_name))?
+    };
+    let session = sessions.get(session_id.as_deref())?;
+    let history = {
+        let chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+        chat.history.clone()
+    };
+
+    let executions: Vec<&ToolExecution> = history
+        .iter()
+        .flat_map(|m| {
+            let mut execs: Vec<&ToolExecution> =
+                m.tool_executions.iter().filter(|e| e.machine_name == machine_name).collect();
+            execs.sort_by_key(|e| e.sequence);
+            execs
+        })
+        .collect();
+    if executions.is_empty() {
+        return Err(format!("このセッションでは {} にコマンドを実行していません", machine_name));
+    }
+
+    let (extension, _, _) = script_format(machine_shell(&machine));
+    let mut path = PathBuf::from(path);
+    if path.extension().is_none() {
+        path.set_extension(extension);
+    }
+    let generated_at = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let script = render_command_script(&machine, &session.id, &executions, &generated_at);
+    std::fs::write(&path, script).map_err(|e| format!("スクリプト書き出しエラー: {}", e))?;
+
+    let succeeded = executions.iter().filter(|e| e.success).count();
+    Ok(format!(
+        "{} のコマンド {} 件（成功 {} 件）を {} に書き出しました",
+        machine_name,
+        executions.len(),
+        succeeded,
+        path.display()
+    ))
+}
+
+// ========================================
+// 応答内のコードブロックの抽出・保存
+// ========================================
+
+/// 応答から取り出したコードブロック
+#[derive(Serialize, Clone, Debug, PartialEq)]
+struct CodeBlock {
+    /// メッセージ内での順番（0 始まり。save_code_block の index）
+    index: usize,
+    /// フェンスの言語指定（```bash の bash）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    /// 本文中の「# filename: xxx」等のヒント
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    /// 既定の保存名（ヒントのファイル名部分、なければ snippet-N.拡張子）
+    suggested_name: String,
+    content: String,
+}
+
+/// 言語指定から拡張子を推定（不明なら txt）
+fn code_block_extension(language: Option<&str>) -> &'static str {
+    match language.map(|l| l.to_lowercase()).as_deref() {
+        Some("bash" | "sh" | "shell" | "zsh") => "sh",
+        Some("powershell" | "ps1" | "pwsh") => "ps1",
+        Some("bat" | "batch" | "cmd") => "bat",
+        Some("python" | "py") => "py",
+        Some("javascript" | "js") => "js",
+        Some("typescript" | "ts") => "ts",
+        Some("rust" | "rs") => "rs",
+        Some("json") => "json",
+        Some("yaml" | "yml") => "yml",
+        Some("toml") => "toml",
+        Some("ini") => "ini",
+        Some("xml") => "xml",
+        Some("html") => "html",
+        Some("css") => "css",
+        Some("sql") => "sql",
+        Some("dockerfile") => "dockerfile",
+        Some("nginx" | "conf" | "apache") => "conf",
+        _ => "txt",
+    }
+}
+
+/// 先頭付近の行の「filename: xxx」ヒント（# // -- ; REM <!-- のコメント内のみ）
+fn code_block_filename_hint(content: &str) -> Option<String> {
+    content.lines().filter(|l| !l.trim().is_empty()).take(3).find_map(|line| {
+        let line = line.trim();
+        let body = ["<!--", "#", "//", "--", ";", "REM ", "rem "]
+            .iter()
+            .find_map(|marker| line.strip_prefix(marker))?
+            .trim()
+            .trim_end_matches("-->")
+            .trim();
+        let name = body
+            .strip_prefix("filename:")
+            .or_else(|| body.strip_prefix("File:"))
+            .or_else(|| body.strip_prefix("file:"))?
+            .trim();
+        (!name.is_empty()).then(|| name.to_string())
+    })
+}
+
+/// 保存名として安全な形（ディレクトリ部分を落とし、使えない文字を _ に置き換える）
+fn safe_code_file_name(name: &str) -> Option<String> {
+    let base = name.rsplit(['/', '\\']).next()?.trim();
+    let safe: String = base
+        .chars()
+        .map(|c| if c.is_control() || r#"<>:"|?*"#.contains(c) { '_' } else { c })
+        .collect();
+    (!safe.is_empty() && safe != "." && safe != "..").then_some(safe)
+}
+
+/// Markdown のフェンス（``` / ~~~）で囲まれたコードブロックを順に取り出す（閉じていないブロックは末尾まで）
+fn extract_code_blocks_from(text: &str) -> Vec<CodeBlock> {
+    // 開いているブロック: フェンス文字・フェンスの長さ・言語・本文の行
+    let mut open: Option<(char, usize, Option<String>, Vec<&str>)> = None;
+    let mut blocks = Vec::new();
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let fence_char = trimmed.chars().next().filter(|&c| c == '`' || c == '~');
+        let fence_len = fence_char.map_or(0, |c| trimmed.chars().take_while(|&x| x == c).count());
+        match open.as_mut() {
+            None if fence_len >= 3 => {
+                let language = trimmed[fence_len..].split_whitespace().next().map(str::to_string);
+                open = fence_char.map(|c| (c, fence_len, language, Vec::new()));
+            }
+            None => {}
+            // 閉じフェンスは開きと同じ文字で同じ長さ以上、後ろに何も付かない
+            Some((c, len, _, _)) if fence_char == Some(*c) && fence_len >= *len && trimmed[fence_len..].trim().is_empty() => {
+                if let Some((_, _, language, lines)) = open.take() {
+                    blocks.push(new_code_block(blocks.len(), language, lines.join("\n")));
+                }
+            }
+            Some((_, _, _, lines)) => lines.push(line),
+        }
+    }
+    if let Some((_, _, language, lines)) = open {
+        blocks.push(new_code_block(blocks.len(), language, lines.join("\n")));
+    }
+    blocks
+}
+
+fn new_code_block(index: usize, language: Option<String>, mut content: String) -> CodeBlock {
+    content.push('\n');
+    let filename = code_block_filename_hint(&content);
+    let suggested_name = filename
+        .as_deref()
+        .and_then(safe_code_file_name)
+        .unwrap_or_else(|| format!("snippet-{}.{}", index + 1, code_block_extension(language.as_deref())));
+    CodeBlock { index, language, filename, suggested_name, content }
+}
+
+/// 履歴のメッセージからコードブロックを取り出す
+fn message_code_blocks(sessions: &Sessions, session_id: Option<&str>, message_index: usize) -> Result<Vec<CodeBlock>, String> {
+    let session = sessions.get(session_id)?;
+    let chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+    let message = chat
+        .history
+        .get(message_index)
+        .ok_or_else(|| format!("メッセージ #{} が見つかりません", message_index))?;
+    Ok(extract_code_blocks_from(&message.content))
+}
+
+/// 既存のファイルと重ならない保存先（name-2.ext, name-3.ext ...）
+fn unused_path(dir: &std::path::Path, name: &str, taken: &std::collections::HashSet<PathBuf>) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() && !taken.contains(&path) {
+        return path;
+    }
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, format!(".{}", ext)),
+        _ => (name, String::new()),
+    };
+    (2..)
+        .map(|n| dir.join(format!("{}-{}{}", stem, n, ext)))
+        .find(|p| !p.exists() && !taken.contains(p))
+        .unwrap_or(path)
+}
+
+/// 応答内のコードブロックを言語・ファイル名ヒント付きで返す
+#[tauri::command]
+fn extract_code_blocks(
+    message_index: usize,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<Vec<CodeBlock>, String> {
+    message_code_blocks(&sessions, session_id.as_deref(), message_index)
+}
+
+/// コードブロックを1つ保存する。path が既存のディレクトリなら既定の保存名でその中に保存。保存したパスを返す
+#[tauri::command]
+fn save_code_block(
+    message_index: usize,
+    index: usize,
+    path: String,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<String, String> {
+    let blocks = message_code_blocks(&sessions, session_id.as_deref(), message_index)?;
+    let block = blocks
+        .get(index)
+        .ok_or_else(|| format!("メッセージ #{} にコードブロック #{} はありません（{}件）", message_index, index, blocks.len()))?;
+    let mut path = expand_tilde(std::path::Path::new(&path));
+    if path.is_dir() {
+        path = path.join(&block.suggested_name);
+    }
+    std::fs::write(&path, &block.content).map_err(|e| format!("コード保存エラー: {}", e))?;
+    Ok(path.display().to_string())
+}
+
+/// 複数のコードブロックをディレクトリにまとめて保存（indices 未指定なら全ブロック）
+/// 保存名は既定の保存名で、既存のファイルは上書きせず -2, -3 ... を付ける。保存したパスを順に返す
+#[tauri::command]
+fn save_code_blocks(
+    message_index: usize,
+    dir: String,
+    indices: Option<Vec<usize>>,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<Vec<String>, String> {
+    let blocks = message_code_blocks(&sessions, session_id.as_deref(), message_index)?;
+    if blocks.is_empty() {
+        return Err(format!("メッセージ #{} にコードブロックはありません", message_index));
+    }
+    let selected: Vec<&CodeBlock> = match &indices {
+        Some(indices) => indices
+            .iter()
+            .map(|&i| blocks.get(i).ok_or_else(|| format!("コードブロック #{} はありません（{}件）", i, blocks.len())))
+            .collect::<Result<_, _>>()?,
+        None => blocks.iter().collect(),
+    };
+
+    let dir = expand_tilde(std::path::Path::new(&dir));
+    std::fs::create_dir_all(&dir).map_err(|e| format!("保存先の作成エラー: {}", e))?;
+    let mut taken = std::collections::HashSet::new();
+    let mut saved = Vec::new();
+    for block in selected {
+        let path = unused_path(&dir, &block.suggested_name, &taken);
+        std::fs::write(&path, &block.content).map_err(|e| format!("コード保存エラー（{}）: {}", path.display(), e))?;
+        saved.push(path.display().to_string());
+        taken.insert(path);
+    }
+    Ok(saved)
+}
+
+// ========================================
+// 可用性記録（オンライン/オフライン遷移）
+// ========================================
+
+const AVAILABILITY_LOG_FILE: &str = "availability.jsonl";
+/// 状態変化を確定させるまでに必要な連続観測回数（瞬断ノイズ除去）
+const AVAILABILITY_DEBOUNCE_COUNT: u32 = 2;
+
+/// マシンごとの接続状態デバウンス
+#[derive(Default)]
+struct AvailabilityTracker {
+    confirmed: Option<bool>,
+    candidate: Option<bool>,
+    candidate_count: u32,
+}
+
+fn availability_label(online: Option<bool>) -> &'static str {
+    match online {
+        Some(true) => "online",
+        Some(false) => "offline",
+        None => "unknown",
+    }
+}
+
+impl AvailabilityTracker {
+    /// 観測結果を反映し、確定状態が変わった場合のみ (old, new) を返す
+    fn observe(&mut self, online: bool) -> Option<(&'static str, &'static str)> {
+        if self.confirmed == Some(online) {
+            self.candidate = None;
+            self.candidate_count = 0;
+            return None;
+        }
+        if self.candidate == Some(online) {
+            self.candidate_count += 1;
+        } else {
+            self.candidate = Some(online);
+            self.candidate_count = 1;
+        }
+        if self.candidate_count < AVAILABILITY_DEBOUNCE_COUNT {
+            return None;
+        }
+        let old = availability_label(self.confirmed);
+        self.confirmed = Some(online);
+        self.candidate = None;
+        self.candidate_count = 0;
+        Some((old, availability_label(Some(online))))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct AvailabilityRecord {
+    timestamp: u64,
+    machine: String,
+    old_state: String,
+    new_state: String,
+}
+
+#[derive(Serialize)]
+struct AvailabilityReport {
+    machine: String,
+    from: u64,
+    to: u64,
+    /// 状態が判明していた時間に対するオンライン時間の割合（記録なしなら None）
+    uptime_percent: Option<f64>,
+    online_secs: u64,
+    offline_secs: u64,
+    unknown_secs: u64,
+    transitions: usize,
+    current_state: String,
+}
+
+/// 遷移記録から期間内の稼働率を集計
+fn build_availability_report(
+    machine: &str,
+    records: &[AvailabilityRecord],
+    from: Option<u64>,
+    to: Option<u64>,
+) -> AvailabilityReport {
+    let mut records: Vec<&AvailabilityRecord> = records.iter().filter(|r| r.machine == machine).collect();
+    records.sort_by_key(|r| r.timestamp);
+
+    let to = to.unwrap_or_else(now_unix_secs);
+    let from = from.unwrap_or_else(|| records.first().map_or(to, |r| r.timestamp));
+
+    // 期間開始時点の状態 = 開始以前の最後の遷移先
+    let mut state = records
+        .iter()
+        .rev()
+        .find(|r| r.timestamp <= from)
+        .map(|r| r.new_state.clone())
+        .unwrap_or_else(|| "unknown".to_string());
+    let mut cursor = from;
+    let (mut online_secs, mut offline_secs, mut unknown_secs) = (0u64, 0u64, 0u64);
+    let mut transitions = 0;
+
+    let mut accumulate = |state: &str, secs: u64| match state {
+        "online" => online_secs += secs,
+        "offline" => offline_secs += secs,
+        _ => unknown_secs += secs,
+    };
+
+    for record in records.iter().filter(|r| r.timestamp > from && r.timestamp <= to) {
+        accumulate(&state, record.timestamp - cursor);
+        cursor = record.timestamp;
+        state = record.new_state.clone();
+        transitions += 1;
+    }
+    accumulate(&state, to.saturating_sub(cursor));
+
+    let known = online_secs + offline_secs;
+    AvailabilityReport {
+        machine: machine.to_string(),
+        from,
+        to,
+        uptime_percent: if known > 0 {
+            Some((online_secs as f64 / known as f64 * 10000.0).round() / 100.0)
+        } else {
+            None
+        },
+        online_secs,
+        offline_secs,
+        unknown_secs,
+        transitions,
+        current_state: state,
+    }
+}
+
+/// マシンの稼働率レポート（from/to は UNIX 秒、省略時は記録開始〜現在）
+#[tauri::command]
+fn get_availability_report(
+    machine: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    app_handle: tauri::AppHandle,
+) -> Result<AvailabilityReport, String> {
+    let path = app_data_path(&app_handle, AVAILABILITY_LOG_FILE)?;
+    let records: Vec<AvailabilityRecord> = read_jsonl(&path);
+    Ok(build_availability_report(&machine, &records, from, to))
+}
+
+// ========================================
+// SSH トランザクション（多段実行＋ロールバック）
+// ========================================
+
+#[derive(Deserialize, Clone, Debug)]
+struct TransactionStep {
+    machine: String,
+    apply_cmd: String,
+    rollback_cmd: String,
+}
+
+/// トランザクション内の1コマンドの実行結果
+#[derive(Serialize, Clone, Debug)]
+struct TransactionCommandResult {
+    step_index: usize,
+    machine: String,
+    command: String,
+    success: bool,
+    stdout: String,
+    stderr: String,
+    exit_code: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+struct TransactionResult {
+    /// 全ステップの apply が成功したか
+    committed: bool,
+    /// 失敗したステップ（0始まり）
+    failed_step: Option<usize>,
+    applied: Vec<TransactionCommandResult>,
+    /// ロールバック結果（実行順 = 適用の逆順）
+    rollbacks: Vec<TransactionCommandResult>,
+    /// ロールバック自体が失敗し、手動での復旧が必要
+    needs_manual_intervention: bool,
+    message: String,
+}
+
+async fn run_transaction_command(
+    step_index: usize,
+    machine: &SshMachineConfig,
+    command: &str,
+    settings: &AppSettings,
+) -> TransactionCommandResult {
+    let (timeout_secs, _) = resolve_command_timeout(machine, settings);
+    match run_ssh_command(machine, command, timeout_secs, &settings.language).await {
+        Ok(output) => TransactionCommandResult {
+            step_index,
+            machine: machine.name.clone(),
+            command: command.to_string(),
+            success: output.success,
+            stdout: output.stdout,
+            stderr: output.stderr,
+            exit_code: Some(output.exit_code),
+        },
+        Err(e) => TransactionCommandResult {
+            step_index,
+            machine: machine.name.clone(),
+            command: command.to_string(),
+            success: false,
+            stdout: String::new(),
+            stderr: e,
+            exit_code: None,
+        },
+    }
+}
+
+/// 複数マシンへの設定変更を順に適用し、失敗時は適用済みステップを逆順にロールバック
+#[tauri::command]
+async fn execute_transaction(
+    steps: Vec<TransactionStep>,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+) -> Result<TransactionResult, String> {
+    if steps.is_empty() {
+        return Err("ステップが指定されていません".to_string());
+    }
+    let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+
+    // 何か適用する前に全ステップの対象マシンを検証
+    let resolved: Vec<(TransactionStep, SshMachineConfig)> = {
+        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        steps
+            .into_iter()
+            .enumerate()
+            .map(|(i, step)| {
+                resolve_remote_machine(&state.machines, &step.machine, &settings.language)
+                    .map(|m| (step, m))
+                    .map_err(|e| format!("ステップ{}: {}", i + 1, e))
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    let mut applied = Vec::new();
+    let mut failed_step = None;
+
+    for (i, (step, machine)) in resolved.iter().enumerate() {
+        let result = run_transaction_command(i, machine, &step.apply_cmd, &settings).await;
+        let success = result.success;
+        applied.push(result);
+        if !success {
+            failed_step = Some(i);
+            break;
+        }
+    }
+
+    let Some(failed) = failed_step else {
+        return Ok(TransactionResult {
+            committed: true,
+            failed_step: None,
+            applied,
+            rollbacks: Vec::new(),
+            needs_manual_intervention: false,
+            message: format!("{}ステップすべて適用しました", resolved.len()),
+        });
+    };
+
+    // 失敗したステップ自体も途中まで適用された可能性があるため、ロールバック対象に含める
+    let mut rollbacks = Vec::new();
+    for i in (0..=failed).rev() {
+        let (step, machine) = &resolved[i];
+        if step.rollback_cmd.trim().is_empty() {
+            continue;
+        }
+        let result = run_transaction_command(i, machine, &step.rollback_cmd, &settings).await;
+        if !result.success {
+            eprintln!(
+                "[Nexus] Transaction rollback failed at step {} on {}: {}",
+                i + 1,
+                machine.name,
+                result.stderr
+            );
+        }
+        rollbacks.push(result);
+    }
+
+    let rollback_failures: Vec<String> = rollbacks
+        .iter()
+        .filter(|r| !r.success)
+        .map(|r| format!("ステップ{}({})", r.step_index + 1, r.machine))
+        .collect();
+    let needs_manual_intervention = !rollback_failures.is_empty();
+
+    let message = if needs_manual_intervention {
+        format!(
+            "ステップ{}で失敗しました。ロールバックに失敗したステップがあります（{}）。手動で状態を確認してください",
+            failed + 1,
+            rollback_failures.join(", ")
+        )
+    } else {
+        format!("ステップ{}で失敗したため、適用済みのステップをロールバックしました", failed + 1)
+    };
+
+    Ok(TransactionResult {
+        committed: false,
+        failed_step: Some(failed),
+        applied,
+        rollbacks,
+        needs_manual_intervention,
+        message,
+    })
+}
+
+// ========================================
+// クリップボード連携
+// ========================================
+
+/// これを超えるとコピーはするが警告を返す
+const CLIPBOARD_WARN_CHARS: usize = 100_000;
+/// これを超えるテキストはコピーしない
+const CLIPBOARD_MAX_CHARS: usize = 1_000_000;
+
+#[derive(Serialize)]
+struct CopyResult {
+    copied_chars: usize,
+    masked: bool,
+    warning: Option<String>,
+}
+
+fn copy_text_to_clipboard(app_handle: &tauri::AppHandle, text: &str, mask: bool) -> Result<CopyResult, String> {
+    let text = if mask { mask_secrets(text) } else { text.to_string() };
+    let chars = text.chars().count();
+
+    if chars > CLIPBOARD_MAX_CHARS {
+        return Err(format!(
+            "テキストが大きすぎるためコピーしませんでした（{}文字 / 上限{}文字）",
+            chars, CLIPBOARD_MAX_CHARS
+        ));
+    }
+
+    app_handle
+        .clipboard()
+        .write_text(text)
+        .map_err(|e| format!("クリップボード書き込みエラー: {}", e))?;
+
+    Ok(CopyResult {
+        copied_chars: chars,
+        masked: mask,
+        warning: (chars > CLIPBOARD_WARN_CHARS)
+            .then(|| format!("大きなテキスト（{}文字）をコピーしました。貼り付け先によっては扱えない場合があります", chars)),
+    })
+}
+
+/// 直近のアシスタント応答
+fn last_assistant_response(chat: &ChatState) -> Option<String> {
+    chat.history.iter().rev().find(|m| m.role == "assistant").map(|m| m.content.clone())
+}
+
+/// 直近のツール実行出力（stdout、なければstderr）
+fn last_tool_output(chat: &ChatState) -> Option<String> {
+    chat.history
+        .iter()
+        .rev()
+        .find_map(|m| m.tool_executions.last())
+        .map(|e| if e.stdout.is_empty() { e.stderr.clone() } else { e.stdout.clone() })
+}
+
+/// 任意のテキストをクリップボードにコピー
+#[tauri::command]
+fn copy_to_clipboard(text: String, mask: Option<bool>, app_handle: tauri::AppHandle) -> Result<CopyResult, String> {
+    copy_text_to_clipboard(&app_handle, &text, mask.unwrap_or(false))
+}
+
+/// 直近のアシスタント応答をコピー
+#[tauri::command]
+fn copy_last_response(
+    mask: Option<bool>,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<CopyResult, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let text = {
+        let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        last_assistant_response(&chat).ok_or("コピーできる応答がありません")?
+    };
+    copy_text_to_clipboard(&app_handle, &text, mask.unwrap_or(false))
+}
+
+/// 直近のツール実行出力をコピー
+#[tauri::command]
+fn copy_last_tool_output(
+    mask: Option<bool>,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<CopyResult, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let state = &session.chat;
+    let text = {
+        let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+        last_tool_output(&chat).ok_or("コピーできるツール出力がありません")?
+    };
+    copy_text_to_clipboard(&app_handle, &text, mask.unwrap_or(false))
+}
+
+// ========================================
+// マシン構成の差分監視
+// ========================================
+
+const MACHINE_SNAPSHOTS_FILE: &str = "machine_snapshots.json";
+const MACHINE_CHANGES_LOG_FILE: &str = "machine_changes.jsonl";
+const DEFAULT_WATCH_INTERVAL_SECS: u64 = 3600;
+/// 監視ループの確認周期（各マシンの間隔はこれ単位で判定）
+const WATCH_TICK_SECS: u64 = 60;
+
+/// マシン名 → 監視コマンド → 前回の出力
+type MachineSnapshots = std::collections::HashMap<String, std::collections::HashMap<String, String>>;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MachineChange {
+    machine_name: String,
+    command: String,
+    before: String,
+    after: String,
+    diff: String, // ユニファイド diff
+    detected_at: u64,
+}
+
+fn load_machine_snapshots(path: &std::path::Path) -> MachineSnapshots {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save_machine_snapshots(path: &std::path::Path, snapshots: &MachineSnapshots) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(snapshots).map_err(|e| format!("JSON変換エラー: {}", e))?;
+    std::fs::write(path, json).map_err(|e| format!("書き込みエラー: {}", e))
+}
+
+fn unified_diff(before: &str, after: &str, label: &str) -> String {
+    similar::TextDiff::from_lines(before, after)
+        .unified_diff()
+        .context_radius(3)
+        .header(&format!("{} (before)", label), &format!("{} (after)", label))
+        .to_string()
+}
+
+/// 出力を前回と比較（初回はベースラインとして記録し差分なし）
+fn compare_snapshot(
+    snapshots: &mut MachineSnapshots,
+    machine_name: &str,
+    command: &str,
+    output: &str,
+) -> Option<MachineChange> {
+    let after = output.trim_end().to_string();
+    let previous = snapshots
+        .entry(machine_name.to_string())
+        .or_default()
+        .insert(command.to_string(), after.clone());
+
+    match previous {
+        Some(before) if before != after => Some(MachineChange {
+            machine_name: machine_name.to_string(),
+            command: command.to_string(),
+            diff: unified_diff(&before, &after, &format!("{}: {}", machine_name, command)),
+            before,
+            after,
+            detected_at: now_unix_secs(),
+        }),
+        _ => None,
+    }
+}
+
+/// 変更をイベント・ログ・webhook に通知
+async fn notify_machine_change(app_handle: &tauri::AppHandle, change: &MachineChange, webhook_url: &str) {
+    eprintln!("[Nexus] Machine changed: {} ({})", change.machine_name, change.command);
+    let _ = app_handle.emit("machine-changed", change);
+
+    match app_data_path(app_handle, MACHINE_CHANGES_LOG_FILE) {
+        Ok(path) => {
+            if let Err(e) = append_jsonl(&path, change) {
+                eprintln!("[Nexus] Warning: failed to record machine change: {}", e);
+            }
+        }
+        Err(e) => eprintln!("[Nexus] Warning: {}", e),
+    }
+
+    if webhook_url.is_empty() {
+        return;
+    }
+    let result = reqwest::Client::new()
+        .post(webhook_url)
+        .timeout(Duration::from_secs(10))
+        .json(change)
+        .send()
+        .await;
+    match result {
+        Ok(resp) if !resp.status().is_success() => {
+            eprintln!("[Nexus] Warning: machine change webhook returned {}", resp.status());
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("[Nexus] Warning: machine change webhook failed: {}", e),
+    }
+}
+
+/// マシンの監視コマンドを実行し、前回との差分を返す（取得に失敗したコマンドは比較しない）
+async fn check_machine_changes(
+    machine: &SshMachineConfig,
+    settings: &AppSettings,
+    snapshots: &mut MachineSnapshots,
+    queue: &ToolQueue,
+    app_handle: &tauri::AppHandle,
+) -> Vec<MachineChange> {
+    let (timeout_secs, _) = resolve_command_timeout(machine, settings);
+    let mut changes = Vec::new();
+    for command in &machine.watch_commands {
+        let _permit = queue.acquire(ToolPriority::Low, &machine.name, command).await;
+        match run_ssh_command(machine, command, timeout_secs, &settings.language).await {
+            Ok(output) if output.success => {
+                record_command_metrics(app_handle, &machine.name, command, &output.stdout);
+                changes.extend(compare_snapshot(snapshots, &machine.name, command, &output.stdout));
+            }
+            Ok(output) => eprintln!(
+                "[Nexus] Watch command failed on {} ({}): {}",
+                machine.name,
+                command,
+                output.stderr.trim()
+            ),
+            Err(e) => eprintln!("[Nexus] Watch command failed on {} ({}): {}", machine.name, command, e),
+        }
+    }
+    changes
+}
+
+/// 差分監視ループ（setup から起動、watch_commands のあるマシンを間隔ごとに確認）
+async fn run_machine_watch_loop(app_handle: tauri::AppHandle) {
+    let snapshots_path = match app_data_path(&app_handle, MACHINE_SNAPSHOTS_FILE) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("[Nexus] Machine watch disabled: {}", e);
+            return;
+        }
+    };
+    let mut snapshots = load_machine_snapshots(&snapshots_path);
+    let mut last_checked: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+    let queue = app_handle.state::<ToolQueue>().inner().clone();
+
+    loop {
+        let machines: Vec<SshMachineConfig> = app_handle
+            .state::<Mutex<SshState>>()
+            .lock()
+            .map(|state| {
+                state
+                    .machines
+                    .iter()
+                    .filter(|m| m.enabled && m.role != "Commander" && !m.watch_commands.is_empty())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let settings = app_handle
+            .state::<Mutex<AppSettings>>()
+            .lock()
+            .map(|s| s.clone())
+            .unwrap_or_default();
+
+        let now = now_unix_secs();
+        let mut checked_any = false;
+        for machine in &machines {
+            let interval = machine.watch_interval_secs.unwrap_or(DEFAULT_WATCH_INTERVAL_SECS);
+            if last_checked
+                .get(&machine.name)
+                .is_some_and(|t| now.saturating_sub(*t) < interval)
+            {
+                continue;
+            }
+            last_checked.insert(machine.name.clone(), now);
+            checked_any = true;
+
+            for change in check_machine_changes(machine, &settings, &mut snapshots, &queue, &app_handle).await {
+                notify_machine_change(&app_handle, &change, &settings.monitoring.webhook_url).await;
+            }
+        }
+
+        if checked_any {
+            if let Err(e) = save_machine_snapshots(&snapshots_path, &snapshots) {
+                eprintln!("[Nexus] Warning: failed to save machine snapshots: {}", e);
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(WATCH_TICK_SECS)).await;
+    }
+}
+
+// ========================================
+// 読み取り系ツール結果のキャッシュ
+// ========================================
+
+/// 読み取り系ツール結果のキャッシュ（同じマシン・同じコマンドの連続実行を省く）
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct ToolCacheSettings {
+    enabled: bool,
+    /// キャッシュの有効期間（秒）
+    ttl_secs: u64,
+    /// 書き込み系とみなすコマンドの正規表現（部分一致）。実行されたらそのマシンのキャッシュをすべて無効化する
+    write_patterns: Vec<String>,
+    /// 個別の依存関係（command に一致するコマンドを実行したら、そのマシンの invalidates に一致するキャッシュを無効化）
+    invalidation_rules: Vec<CacheInvalidationRule>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+struct CacheInvalidationRule {
+    command: String,
+    invalidates: Vec<String>,
+}
+
+impl Default for ToolCacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 60,
+            write_patterns: [
+                r"(?i)\bsystemctl\s+(start|stop|restart|reload|enable|disable|mask|unmask|daemon-reload)\b",
+                r"(?i)\bservice\s+\S+\s+(start|stop|restart|reload)\b",
+                r"(?i)\b(apt|apt-get|yum|dnf|zypper|pacman|snap|winget|choco|pip|npm)\s+(\S+\s+)*(install|remove|purge|upgrade|uninstall)\b",
+                r"\b(rm|mv|cp|mkdir|rmdir|touch|chmod|chown|ln|truncate|kill|pkill|killall|tee)\b",
+                r"\bsed\s+(-\S+\s+)*-i",
+                r">",
+                r"(?i)\b(Set|New|Remove|Stop|Start|Restart|Rename|Move|Copy|Clear|Add|Install|Uninstall|Enable|Disable)-[A-Za-z]+",
+                r"(?i)\b(shutdown|reboot|poweroff|halt)\b",
+                r"(?i)\b(reg\s+(add|delete)|sc(\.exe)?\s+(start|stop|config|delete)|net\s+(start|stop|user))\b",
+                r"(?i)\bdocker\s+(run|start|stop|restart|rm|rmi|pull|compose)\b",
+            ]
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+            invalidation_rules: Vec::new(),
+        }
+    }
+}
+
+/// キャッシュから見たコマンドの扱い
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ToolCacheUse {
+    /// 期限内の結果があれば返し、なければ実行して保存する
+    Read,
+    /// 常に実行し、保存もしない（変更後の確認 purpose=verify）
+    Fresh,
+    /// 実行前後にキャッシュを無効化（whole_machine: そのマシンの全件、false なら invalidation_rules の対象のみ）
+    Write { whole_machine: bool },
+}
+
+/// 正規表現に部分一致するか（不正なパターンは警告して不一致扱い）
+fn tool_cache_pattern_matches(command: &str, pattern: &str) -> bool {
+    match regex::Regex::new(pattern) {
+        Ok(re) => re.is_match(command),
+        Err(e) => {
+            eprintln!("[Nexus] Warning: invalid tool cache pattern '{}': {}", pattern, e);
+            false
+        }
+    }
+}
+
+fn classify_for_tool_cache(command: &str, purpose: Option<ToolPurpose>, config: &ToolCacheSettings) -> ToolCacheUse {
+    if purpose.is_some_and(ToolPurpose::is_change)
+        || config.write_patterns.iter().any(|p| tool_cache_pattern_matches(command, p))
+    {
+        ToolCacheUse::Write { whole_machine: true }
+    } else if config.invalidation_rules.iter().any(|r| tool_cache_pattern_matches(command, &r.command)) {
+        ToolCacheUse::Write { whole_machine: false }
+    } else if purpose == Some(ToolPurpose::Verify) {
+        ToolCacheUse::Fresh
+    } else {
+        ToolCacheUse::Read
+    }
+}
+
+/// (マシン名, コマンド) → (実行結果, 保存時刻)
+type ToolCacheMap = std::collections::HashMap<(String, String), (ToolExecution, std::time::Instant)>;
+
+fn tool_result_cache() -> &'static Mutex<ToolCacheMap> {
+    static CACHE: std::sync::OnceLock<Mutex<ToolCacheMap>> = std::sync::OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(std::collections::HashMap::new()))
+}
+
+/// 期限内の結果を新しい実行IDで返す（ヒット・ミスはログに出す）
+fn lookup_tool_cache(machine_name: &str, command: &str, config: &ToolCacheSettings) -> Option<ToolExecution> {
+    let cache = tool_result_cache().lock().ok()?;
+    let key = (machine_name.to_string(), command.to_string());
+    match cache.get(&key).filter(|(_, at)| at.elapsed() < Duration::from_secs(config.ttl_secs)) {
+        Some((exec, at)) => {
+            eprintln!("[Nexus] Tool cache hit: {} `{}` ({}s old)", machine_name, command, at.elapsed().as_secs());
+            Some(ToolExecution {
+                execution_id: next_execution_id(),
+                cached: true,
+                ..exec.clone()
+            })
+        }
+        None => {
+            eprintln!("[Nexus] Tool cache miss: {} `{}`", machine_name, command);
+            None
+        }
+    }
+}
+
+/// 成功した読み取り結果を保存（期限切れのものはついでに捨てる）
+fn store_tool_cache(machine_name: &str, command: &str, exec: &ToolExecution, config: &ToolCacheSettings) {
+    if let Ok(mut cache) = tool_result_cache().lock() {
+        let ttl = Duration::from_secs(config.ttl_secs);
+        cache.retain(|_, (_, at)| at.elapsed() < ttl);
+        cache.insert((machine_name.to_string(), command.to_string()), (exec.clone(), std::time::Instant::now()));
+    }
+}
+
+/// 書き込み系コマンドの実行に伴い、そのマシンの読み取りキャッシュを無効化する
+fn invalidate_tool_cache(machine_name: &str, command: &str, whole_machine: bool, config: &ToolCacheSettings) {
+    let targets: Vec<&String> = config
+        .invalidation_rules
+        .iter()
+        .filter(|r| tool_cache_pattern_matches(command, &r.command))
+        .flat_map(|r| r.invalidates.iter())
+        .collect();
+    let Ok(mut cache) = tool_result_cache().lock() else {
+        return;
+    };
+    let before = cache.len();
+    cache.retain(|(machine, cached_command), _| {
+        machine != machine_name
+            || !(whole_machine || targets.iter().any(|p| tool_cache_pattern_matches(cached_command, p)))
+    });
+    let removed = before - cache.len();
+    if removed > 0 {
+        eprintln!(
+            "[Nexus] Tool cache invalidated: {} entr{} on {} by `{}` ({})",
+            removed,
+            if removed == 1 { "y" } else { "ies" },
+            machine_name,
+            command,
+            if whole_machine { "write command" } else { "invalidation rule" }
+        );
+    }
+}
+
+// ========================================
+// ツール呼び出しのレート制限（マシン保護）
+// ========================================
+
+/// 上限超過時の扱い
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum RateLimitAction {
+    /// トークンが貯まるまで待つ（max_wait_secs を超えるなら拒否）
+    #[default]
+    Wait,
+    /// 即座に拒否
+    Reject,
+}
+
+/// マシンごとのレート制限（per_secs 秒あたり最大 max_calls 回、max_calls = 0 で無制限）
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct RateLimitConfig {
+    max_calls: u32,
+    per_secs: u64,
+    on_exceed: RateLimitAction,
+    max_wait_secs: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_calls: 20,
+            per_secs: 60,
+            on_exceed: RateLimitAction::Wait,
+            max_wait_secs: 30,
+        }
+    }
+}
+
+/// トークンバケット（容量 max_calls、per_secs で満タンまで補充）
+struct TokenBucket {
+    tokens: f64,
+    last_refill: std::time::Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: &RateLimitConfig) -> Self {
+        Self {
+            tokens: limit.max_calls as f64,
+            last_refill: std::time::Instant::now(),
+        }
+    }
+
+    /// トークンを1つ消費。足りなければ次の1つが貯まるまでの時間を返す
+    fn try_acquire(&mut self, limit: &RateLimitConfig) -> Result<(), Duration> {
+        let capacity = limit.max_calls as f64;
+        let rate = capacity / limit.per_secs.max(1) as f64;
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+/// レート制限を通過するまで待つ（拒否すべき場合は false）
+async fn acquire_rate_limit(machine: &SshMachineConfig, app_handle: &tauri::AppHandle) -> bool {
+    let limit = machine.rate_limit.clone().unwrap_or_default();
+    if limit.max_calls == 0 {
+        return true;
+    }
+    let mut waited = Duration::ZERO;
+    loop {
+        let wait = {
+            let ssh_state = app_handle.state::<Mutex<SshState>>();
+            let Ok(mut state) = ssh_state.lock() else {
+                return true;
+            };
+            let bucket = state
+                .rate_limits
+                .entry(machine.name.clone())
+                .or_insert_with(|| TokenBucket::new(&limit));
+            match bucket.try_acquire(&limit) {
+                Ok(()) => return true,
+                Err(wait) => wait,
+            }
+        };
+        if limit.on_exceed == RateLimitAction::Reject
+            || waited + wait > Duration::from_secs(limit.max_wait_secs)
+        {
+            eprintln!("[Nexus] Rate limit exceeded on {}, rejecting command", machine.name);
+            return false;
+        }
+        eprintln!("[Nexus] Rate limit on {}, waiting {:.1}s", machine.name, wait.as_secs_f64());
+        tokio::time::sleep(wait).await;
+        waited += wait;
+    }
+}
+
+// ========================================
+// 起動時の自己診断
+// ========================================
+
+#[derive(Serialize, Clone, Debug)]
+struct DiagnosticItem {
+    name: String,
+    /// "ok" | "warning" | "error"
+    status: &'static str,
+    message: String,
+    /// 問題がある場合の修正方法
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+}
+
+impl DiagnosticItem {
+    fn ok(name: &str, message: String) -> Self {
+        Self { name: name.to_string(), status: "ok", message, hint: None }
+    }
+
+    fn problem(name: &str, status: &'static str, message: String, hint: &str) -> Self {
+        Self { name: name.to_string(), status, message, hint: Some(hint.to_string()) }
+    }
+}
+
+/// 外部コマンドが PATH 上にあるか（起動できれば終了コードは問わない）
+async fn diagnose_program(program: &str, args: &[&str], hint: &str) -> DiagnosticItem {
+    let result = timeout(Duration::from_secs(5), TokioCommand::new(program).args(args).output()).await;
+    match result {
+        Ok(Ok(output)) => {
+            // ssh -V はバージョンを stderr に出す
+            let version = decode_bytes(&output.stderr);
+            let version = version.lines().next().unwrap_or("").trim();
+            DiagnosticItem::ok(program, if version.is_empty() { "利用可能".to_string() } else { version.to_string() })
+        }
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+            DiagnosticItem::problem(program, "error", format!("{} が PATH に見つかりません", program), hint)
+        }
+        Ok(Err(e)) => DiagnosticItem::problem(program, "error", format!("{} を起動できません: {}", program, e), hint),
+        Err(_) => DiagnosticItem::problem(program, "warning", format!("{} の応答がありません", program), hint),
+    }
+}
+
+/// APIキーの有無と有効性（モデル一覧APIで確認）
+async fn diagnose_api_key() -> DiagnosticItem {
+    const NAME: &str = "ANTHROPIC_API_KEY";
+    let Ok(api_key) = std::env::var(NAME) else {
+        return DiagnosticItem::problem(
+            NAME,
+            "error",
+            "APIキーが設定されていません".to_string(),
+            ".env に ANTHROPIC_API_KEY=sk-ant-... を記載してアプリを再起動してください",
+        );
+    };
+    let result = reqwest::Client::new()
+        .get("https://api.anthropic.com/v1/models")
+        .header("x-api-key", api_key)
+        .header("anthropic-version", "2023-06-01")
+        .timeout(Duration::from_secs(10))
+        .send()
+        .await;
+    match result {
+        Ok(resp) if resp.status().is_success() => DiagnosticItem::ok(NAME, "有効なAPIキーです".to_string()),
+        Ok(resp) if resp.status() == reqwest::StatusCode::UNAUTHORIZED => DiagnosticItem::problem(
+            NAME,
+            "error",
+            "APIキーが無効です（401）".to_string(),
+            "Anthropic Console でキーを再発行し、.env を更新してください",
+        ),
+        Ok(resp) => DiagnosticItem::problem(
+            NAME,
+            "warning",
+            format!("APIキーを検証できませんでした（HTTP {}）", resp.status()),
+            "しばらく待ってから再度診断してください",
+        ),
+        Err(e) => DiagnosticItem::problem(
+            NAME,
+            "warning",
+            format!("APIに接続できませんでした: {}", e),
+            "ネットワーク接続・プロキシ設定を確認してください",
+        ),
+    }
+}
+
+fn diagnose_machines_toml() -> DiagnosticItem {
+    const NAME: &str = "machines.toml";
+    let Some(path) = resolve_machines_toml_path() else {
+        return DiagnosticItem::problem(
+            NAME,
+            "warning",
+            "machines.toml が見つからないため組み込みのデフォルト設定で動作しています".to_string(),
+            "実行ファイルと同じフォルダ（開発時は nexus-app/ 直下）に machines.toml を配置してください",
+        );
+    };
+    match std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|c| parse_machines_config(&c)) {
+        Ok(state) => DiagnosticItem::ok(NAME, format!("{} を読み込めます（{}台）", path.display(), state.machines.len())),
+        Err(e) => DiagnosticItem::problem(
+            NAME,
+            "error",
+            format!("{} を読み込めません: {}", path.display(), e),
+            "エラー箇所を修正するか、アプリ内の設定エディタで検証してから保存してください",
+        ),
+    }
+}
+
+fn diagnose_notion_key() -> DiagnosticItem {
+    const NAME: &str = "NOTION_API_KEY";
+    match std::env::var(NAME) {
+        Ok(key) if !key.trim().is_empty() => DiagnosticItem::ok(NAME, "設定済み".to_string()),
+        _ => DiagnosticItem::problem(
+            NAME,
+            "warning",
+            "未設定のため Notion のソフトウェア情報は読み込まれません".to_string(),
+            "Notion連携を使う場合は .env に NOTION_API_KEY=secret_... を記載してください",
+        ),
+    }
+}
+
+/// 環境の自己診断（ssh/scp・APIキー・machines.toml・Notionキー）
+#[tauri::command]
+async fn run_diagnostics() -> Result<Vec<DiagnosticItem>, String> {
+    let (ssh, scp, api_key) = tokio::join!(
+        diagnose_program("ssh", &["-V"], "OpenSSH クライアントをインストールし、PATH に追加してください（Windows: 設定 > オプション機能 > OpenSSH クライアント）"),
+        diagnose_program("scp", &[], "OpenSSH クライアントに含まれる scp が PATH にあるか確認してください"),
+        diagnose_api_key(),
+    );
+    Ok(vec![ssh, scp, api_key, diagnose_machines_toml(), diagnose_notion_key()])
+}
+
+/// 起動直後の自動診断。問題があれば diagnostics-warning で通知
+async fn run_startup_diagnostics(app_handle: tauri::AppHandle) {
+    // フロントのイベントリスナー登録を待つ
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let Ok(items) = run_diagnostics().await else {
+        return;
+    };
+    let problems: Vec<&DiagnosticItem> = items.iter().filter(|i| i.status != "ok").collect();
+    for item in &problems {
+        eprintln!("[Nexus] Diagnostics {}: {} — {}", item.status, item.name, item.message);
+    }
+    if !problems.is_empty() {
+        let _ = app_handle.emit("diagnostics-warning", &problems);
+    }
+}
+
+// ========================================
+// ランタイム情報（サポート・issue 報告用）
+// ========================================
+
+/// 値を出さずに有無だけ報告する環境変数
+const RUNTIME_INFO_SECRET_VARS: &[&str] = &["ANTHROPIC_API_KEY", "NOTION_API_KEY"];
+
+#[derive(Serialize)]
+struct SecretPresence {
+    name: &'static str,
+    configured: bool,
+}
+
+#[derive(Serialize)]
+struct RuntimeTimeouts {
+    /// SSH 接続タイムアウト（machines.toml の [ssh] timeout_secs）
+    ssh_connect_secs: u64,
+    ssh_keepalive_interval: u32,
+    ssh_keepalive_count_max: u32,
+    /// リモートコマンドの OS 別タイムアウト（settings.toml の [timeouts]）
+    command_windows_secs: u64,
+    command_linux_secs: u64,
+    command_default_secs: u64,
+    stream_idle_secs: u64,
+    default_hook_secs: u64,
+}
+
+#[derive(Serialize)]
+struct RuntimeInfo {
+    app_version: &'static str,
+    os: &'static str,
+    arch: &'static str,
+    model: String,
+    machines_toml: Option<String>,
+    settings_toml: Option<String>,
+    machine_count: usize,
+    enabled_machine_count: usize,
+    ssh_version: String,
+    scp_version: String,
+    api_keys: Vec<SecretPresence>,
+    timeouts: RuntimeTimeouts,
+    /// settings.toml の有効値（秘匿になりうる値は伏せ字）
+    settings: serde_json::Value,
+    /// issue にそのまま貼れる Markdown
+    report: String,
+}
+
+/// settings の JSON から秘匿になりうる値を伏せる（Webhook URL はトークンを含むため有無のみ）
+fn redact_settings(settings: &AppSettings) -> serde_json::Value {
+    let mut value = serde_json::to_value(settings).unwrap_or(serde_json::Value::Null);
+    if let Some(url) = value.pointer_mut("/monitoring/webhook_url") {
+        let configured = url.as_str().is_some_and(|u| !u.is_empty());
+        *url = serde_json::json!(if configured { "(設定あり)" } else { "(未設定)" });
+    }
+    value
+}
+
+fn format_runtime_report(info: &RuntimeInfo) -> String {
+    let path_or_none = |path: &Option<String>| path.clone().unwrap_or_else(|| "(見つかりません)".to_string());
+    let keys = info
+        .api_keys
+        .iter()
+        .map(|k| format!("{}: {}", k.name, if k.configured { "設定あり" } else { "未設定" }))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let t = &info.timeouts;
+    let report = format!(
+        "### Project Nexus ランタイム情報\n\
+         - アプリ: {} ({} / {})\n\
+         - モデル: {}\n\
+         - machines.toml: {}（{} 台、有効 {} 台）\n\
+         - settings.toml: {}\n\
+         - ssh: {}\n\
+         - scp: {}\n\
+         - APIキー: {}\n\
+         - タイムアウト: SSH接続 {}秒 / keepalive {}秒×{} / コマンド windows {}秒・linux {}秒・その他 {}秒 / ストリーム無応答 {}秒 / フック {}秒\n\
+         \n\
+         <details><summary>settings</summary>\n\n```json\n{}\n```\n</details>\n",
+        info.app_version,
+        info.os,
+        info.arch,
+        info.model,
+        path_or_none(&info.machines_toml),
+        info.machine_count,
+        info.enabled_machine_count,
+        path_or_none(&info.settings_toml),
+        info.ssh_version,
+        info.scp_version,
+        keys,
+        t.ssh_connect_secs,
+        t.ssh_keepalive_interval,
+        t.ssh_keepalive_count_max,
+        t.command_windows_secs,
+        t.command_linux_secs,
+        t.command_default_secs,
+        t.stream_idle_secs,
+        t.default_hook_secs,
+        serde_json::to_string_pretty(&info.settings).unwrap_or_default(),
+    );
+    // 念のため貼り付け用テキスト全体もマスクを通す
+    mask_secrets(&report)
+}
+
+/// サポート対応用に実行環境をまとめて返す（APIキー等の値は含めない）
+#[tauri::command]
+async fn get_runtime_info(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+) -> Result<RuntimeInfo, String> {
+    let model = {
+        let session = sessions.get(session_id.as_deref())?;
+        let chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+        chat.model.clone()
+    };
+    let (machine_count, enabled_machine_count, ssh_timeouts) = {
+        let ssh = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        let global = &ssh.global_config;
+        (
+            ssh.machines.len(),
+            ssh.machines.iter().filter(|m| m.enabled).count(),
+            (global.timeout_secs, global.keepalive_interval, global.keepalive_count_max),
+        )
+    };
+    let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+
+    let (ssh, scp) = tokio::join!(diagnose_program("ssh", &["-V"], ""), diagnose_program("scp", &[], ""));
+    Ok(build_runtime_info(
+        model,
+        (machine_count, enabled_machine_count),
+        ssh_timeouts,
+        &settings,
+        ssh.message,
+        scp.message,
+    ))
+}
+
+/// 収集済みの値から RuntimeInfo を組み立てる（APIキーは有無だけを環境変数から見る）
+fn build_runtime_info(
+    model: String,
+    (machine_count, enabled_machine_count): (usize, usize),
+    ssh_timeouts: (u64, u32, u32),
+    settings: &AppSettings,
+    ssh_version: String,
+    scp_version: String,
+) -> RuntimeInfo {
+    let path_string = |path: Option<PathBuf>| path.map(|p| p.display().to_string());
+    let mut info = RuntimeInfo {
+        app_version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        arch: std::env::consts::ARCH,
+        model,
+        machines_toml: path_string(resolve_machines_toml_path()),
+        settings_toml: path_string(resolve_config_path("settings.toml")),
+        machine_count,
+        enabled_machine_count,
+        ssh_version,
+        scp_version,
+        api_keys: RUNTIME_INFO_SECRET_VARS
+            .iter()
+            .map(|name| SecretPresence {
+                name,
+                configured: std::env::var(name).is_ok_and(|v| !v.trim().is_empty()),
+            })
+            .collect(),
+        timeouts: RuntimeTimeouts {
+            ssh_connect_secs: ssh_timeouts.0,
+            ssh_keepalive_interval: ssh_timeouts.1,
+            ssh_keepalive_count_max: ssh_timeouts.2,
+            command_windows_secs: settings.timeouts.windows,
+            command_linux_secs: settings.timeouts.linux,
+            command_default_secs: settings.timeouts.default,
+            stream_idle_secs: settings.stream.idle_timeout_secs,
+            default_hook_secs: DEFAULT_HOOK_TIMEOUT_SECS,
+        },
+        settings: redact_settings(settings),
+        report: String::new(),
+    };
+    info.report = format_runtime_report(&info);
+    info
+}
+
+// ========================================
+// ssh-agent 連携
+// ========================================
+
+/// Windows の OpenSSH agent（ssh.exe は SSH_AUTH_SOCK 未設定ならこのパイプを使う）
+const SSH_AGENT_WINDOWS_PIPE: &str = r"\\.\pipe\openssh-ssh-agent";
+
+/// ssh に引き継ぐ agent のソケット
+/// デスクトップから起動するとログインシェルの SSH_AUTH_SOCK が渡らないことがあるため、
+/// 未設定なら systemd / GNOME Keyring / gcr の既定ソケットを探す（Windows は名前付きパイプに任せる）
+fn ssh_agent_socket() -> Option<&'static str> {
+    static SOCKET: std::sync::OnceLock<Option<String>> = std::sync::OnceLock::new();
+    SOCKET
+        .get_or_init(|| {
+            if let Some(sock) = std::env::var("SSH_AUTH_SOCK").ok().filter(|s| !s.trim().is_empty()) {
+                return Some(sock);
+            }
+            if cfg!(windows) {
+                return None;
+            }
+            let runtime_dir = PathBuf::from(std::env::var_os("XDG_RUNTIME_DIR")?);
+            ["ssh-agent.socket", "openssh_agent", "gcr/ssh", "keyring/ssh"]
+                .iter()
+                .map(|name| runtime_dir.join(name))
+                .find(|path| path.exists())
+                .map(|path| {
+                    eprintln!("[Nexus] SSH_AUTH_SOCK not set, using agent socket {}", path.display());
+                    path.to_string_lossy().into_owned()
+                })
+        })
+        .as_deref()
+}
+
+/// ssh / ssh-add を起動するコマンド（agent のソケットを確実に引き継ぐ）
+fn ssh_command(program: &str) -> TokioCommand {
+    let mut command = TokioCommand::new(program);
+    if let Some(sock) = ssh_agent_socket() {
+        command.env("SSH_AUTH_SOCK", sock);
+    }
+    command
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SshAgentStatus {
+    /// agent に接続できた
+    available: bool,
+    /// 登録済みの鍵の数
+    key_count: usize,
+    /// 使用したソケット（Windows の名前付きパイプ含む）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socket: Option<String>,
+    message: String,
+}
+
+/// ssh-add -l で agent の状態を確認（終了コード 0: 鍵あり / 1: 鍵なし / 2: agent に接続できない）
+async fn check_ssh_agent() -> SshAgentStatus {
+    let socket = ssh_agent_socket()
+        .map(str::to_string)
+        .or_else(|| cfg!(windows).then(|| SSH_AGENT_WINDOWS_PIPE.to_string()));
+    let result = timeout(Duration::from_secs(SSH_TIMEOUT_SECS), ssh_command("ssh-add").arg("-l").output()).await;
+    let (available, key_count, message) = match result {
+        Ok(Ok(output)) => match output.status.code() {
+            Some(0) => {
+                let count = decode_bytes(&output.stdout).lines().filter(|l| !l.trim().is_empty()).count();
+                (true, count, format!("ssh-agent に鍵が{}個登録されています", count))
+            }
+            Some(1) => (true, 0, "ssh-agent に鍵が登録されていません".to_string()),
+            _ => (false, 0, "ssh-agent に接続できません".to_string()),
+        },
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => (false, 0, "ssh-add が PATH に見つかりません".to_string()),
+        Ok(Err(e)) => (false, 0, format!("ssh-add を起動できません: {}", e)),
+        Err(_) => (false, 0, "ssh-agent の応答がありません".to_string()),
+    };
+    SshAgentStatus { available, key_count, socket, message }
+}
+
+/// agent が使えない・鍵がないときの対処（使える状態なら None）
+fn ssh_agent_hint(status: &SshAgentStatus, lang: &str) -> Option<String> {
+    if !status.available {
+        Some(tr(lang, "ssh_agent_unavailable", &[]))
+    } else if status.key_count == 0 {
+        Some(tr(lang, "ssh_agent_no_keys", &[]))
+    } else {
+        None
+    }
+}
+
+/// ssh -v の出力から、認証に agent の鍵が使われたか（多重化で認証を省いた等、判定できなければ None）
+fn auth_via_agent(verbose_stderr: &str) -> Option<bool> {
+    let accepted = verbose_stderr
+        .lines()
+        .find(|l| l.contains("Server accepts key:"))?;
+    Some(accepted.trim_end().ends_with(" agent"))
+}
+
+/// ssh-agent の状態（鍵の登録数・使用するソケット）
+#[tauri::command]
+async fn get_ssh_agent_status() -> SshAgentStatus {
+    check_ssh_agent().await
+}
+
+// ========================================
+// 接続テストのバッチ実行
+// ========================================
+
+/// 接続テストで実行するプローブ（bash / cmd.exe の両方で動き、OSも判別できる）
+const CONNECTION_PROBE_COMMAND: &str = "echo nexus-ping && (uname -s || ver)";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ConnectionResult {
+    machine: String,
+    host: String,
+    success: bool,
+    /// 失敗分類: "auth" | "agent_unavailable" | "agent_no_keys" | "host_unknown" | "refused" | "timeout" | "unreachable" | "host_key" | "ssh_missing" | "unknown"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure_kind: Option<String>,
+    /// 認証に ssh-agent の鍵を使ったか（接続の再利用等で判定できなければ None）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth_via_agent: Option<bool>,
+    /// プローブ結果から判別したOS（"Windows" | "Linux" | "Darwin" 等）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detected_os: Option<String>,
+    response_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cause: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ConnectionReport {
+    generated_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssh_agent: Option<SshAgentStatus>,
+    total: usize,
+    succeeded: usize,
+    failed: usize,
+    results: Vec<ConnectionResult>,
+}
+
+/// ssh の stderr から失敗を分類し、推定原因と対処ヒントを返す
+fn classify_ssh_failure(stderr: &str) -> (&'static str, &'static str, &'static str) {
+    let lower = stderr.to_lowercase();
+    if lower.contains("cannot assign requested address") || lower.contains("bind: ") {
+        ("bind_address", "接続元アドレス（bind_address）から接続できません", "bind_address がこのPCのNIC（VPN含む）に割り当てられたIPアドレスか、ipconfig で確認してください")
+    } else if lower.contains("permission denied") {
+        ("auth", "公開鍵認証に失敗しました（鍵がない、または未登録）", "ssh-keygen で鍵を作成し、リモートの authorized_keys（Windows は administrators_authorized_keys）に公開鍵を登録してください")
+    } else if lower.contains("could not resolve hostname") || lower.contains("name or service not known") {
+        ("host_unknown", "ホスト名を解決できません", "~/.ssh/config の Host 定義、または machines.toml の host を確認してください")
+    } else if lower.contains("host key verification failed") || lower.contains("remote host identification has changed") {
+        ("host_key", "ホスト鍵の検証に失敗しました", "再インストール等でホスト鍵が変わった場合は ssh-keygen -R <host> で known_hosts を更新してください")
+    } else if lower.contains("connection refused") {
+        ("refused", "接続を拒否されました（sshd が停止している可能性）", "リモートで sshd（Windows は OpenSSH Server サービス）が起動しているか、ポート番号を確認してください")
+    } else if lower.contains("no route to host") || lower.contains("network is unreachable") {
+        ("unreachable", "ホストに到達できません", "マシンの電源・ネットワーク接続・IPアドレスを確認してください")
+    } else if lower.contains("timed out") {
+        ("timeout", "接続がタイムアウトしました", "ファイアウォールで22番ポートが塞がれていないか、マシンが起動しているか確認してください")
+    } else {
+        ("unknown", "原因を特定できませんでした", "ターミナルで ssh -v <host> を実行して詳細を確認してください")
+    }
+}
+
+fn detect_os(stdout: &str) -> Option<String> {
+    let lower = stdout.to_lowercase();
+    if lower.contains("microsoft windows") {
+        Some("Windows".to_string())
+    } else if lower.contains("linux") {
+        Some("Linux".to_string())
+    } else if lower.contains("darwin") {
+        Some("Darwin".to_string())
+    } else {
+        None
+    }
+}
+
+/// 1台の接続テスト（応答時間・OS判別・失敗分類・agent 経由の認証か）
+async fn test_connection(machine: &SshMachineConfig, agent: &SshAgentStatus) -> ConnectionResult {
+    let started = std::time::Instant::now();
+    // -v の出力（stderr）から、どの鍵で認証されたかを読む
+    let result = timeout(
+        Duration::from_secs(SSH_TIMEOUT_SECS * 2),
+        ssh_command("ssh")
+            .arg("-v")
+            .args(build_ssh_args(machine, CONNECTION_PROBE_COMMAND))
+            .output(),
+    )
+    .await;
+    let response_ms = started.elapsed().as_millis() as u64;
+
+    let mut report = ConnectionResult {
+        machine: machine.name.clone(),
+        host: machine.host.clone(),
+        success: false,
+        failure_kind: None,
+        auth_via_agent: None,
+        detected_os: None,
+        response_ms,
+        cause: None,
+        hint: None,
+    };
+    let (kind, cause, hint) = match result {
+        Ok(Ok(output)) => {
+            let stdout = decode_bytes(&output.stdout);
+            let stderr = decode_bytes(&output.stderr);
+            if output.status.success() && stdout.contains("nexus-ping") {
+                report.success = true;
+                report.detected_os = detect_os(&stdout);
+                report.auth_via_agent = auth_via_agent(&stderr);
+                return report;
+            }
+            match classify_ssh_failure(&stderr) {
+                // 公開鍵認証の失敗は、agent が使えない・鍵が未登録ならそちらを原因として示す
+                ("auth", ..) if !agent.available => (
+                    "agent_unavailable",
+                    "公開鍵認証に失敗しました（ssh-agent に接続できません）",
+                    "パスフレーズ付きの鍵は ssh-agent 経由で使います。ssh-agent を起動し ssh-add で鍵を追加してください（Windows: Start-Service ssh-agent）",
+                ),
+                ("auth", ..) if agent.key_count == 0 => (
+                    "agent_no_keys",
+                    "公開鍵認証に失敗しました（ssh-agent に鍵が登録されていません）",
+                    "ssh-add <鍵ファイル> で鍵を ssh-agent に追加してください",
+                ),
+                classified => classified,
+            }
+        }
+        Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => (
+            "ssh_missing",
+            "ssh コマンドが見つかりません",
+            "OpenSSH クライアントをインストールし、PATH に追加してください",
+        ),
+        Ok(Err(_)) => classify_ssh_failure(""),
+        Err(_) => classify_ssh_failure("timed out"),
+    };
+    report.failure_kind = Some(kind.to_string());
+    report.cause = Some(cause.to_string());
+    report.hint = Some(hint.to_string());
+    report
+}
+
+/// 有効な全リモートマシンの接続テストを並列実行してレポートにまとめる
+#[tauri::command]
+async fn test_all_connections(ssh_state: State<'_, Mutex<SshState>>) -> Result<ConnectionReport, String> {
+    let machines: Vec<SshMachineConfig> = {
+        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        state
+            .machines
+            .iter()
+            .filter(|m| m.enabled && m.role != "Commander")
+            .cloned()
+            .collect()
+    };
+
+    let agent = check_ssh_agent().await;
+    let results = futures_util::future::join_all(machines.iter().map(|m| test_connection(m, &agent))).await;
+    let succeeded = results.iter().filter(|r| r.success).count();
+    Ok(ConnectionReport {
+        generated_at: now_unix_secs(),
+        ssh_agent: Some(agent),
+        total: results.len(),
+        succeeded,
+        failed: results.len() - succeeded,
+        results,
+    })
+}
+
+fn render_connection_report_markdown(report: &ConnectionReport) -> String {
+    let mut md = format!(
+        "# 接続テストレポート\n\n生成: {} (UNIX秒)  \n結果: {}/{} 台成功\n\n| マシン | ホスト | 結果 | OS | 応答時間 | 原因 |\n|---|---|---|---|---|---|\n",
+        report.generated_at, report.succeeded, report.total
+    );
+    for r in &report.results {
+        md.push_str(&format!(
+            "| {} | {} | {} | {} | {} ms | {} |\n",
+            r.machine,
+            r.host,
+            if r.success { "✓ 成功" } else { "✗ 失敗" },
+            r.detected_os.as_deref().unwrap_or("-"),
+            r.response_ms,
+            r.cause.as_deref().unwrap_or("-"),
+        ));
+    }
+    let failures: Vec<&ConnectionResult> = report.results.iter().filter(|r| !r.success).collect();
+    if !failures.is_empty() {
+        md.push_str("\n## 対処ヒント\n\n");
+        for r in failures {
+            md.push_str(&format!(
+                "- **{}** ({}): {}\n",
+                r.machine,
+                r.failure_kind.as_deref().unwrap_or("unknown"),
+                r.hint.as_deref().unwrap_or("-")
+            ));
+        }
+    }
+    md
+}
+
+/// 接続テストレポートを Markdown（"markdown"）または JSON（"json"）で書き出す
+#[tauri::command]
+fn export_connection_report(report: ConnectionReport, format: String, path: String) -> Result<String, String> {
+    let content = match format.to_lowercase().as_str() {
+        "markdown" | "md" => render_connection_report_markdown(&report),
+        "json" => serde_json::to_string_pretty(&report).map_err(|e| format!("JSON変換エラー: {}", e))?,
+        other => return Err(format!("未対応の形式: {}（markdown / json）", other)),
+    };
+    std::fs::write(&path, content).map_err(|e| format!("レポート書き出しエラー: {}", e))?;
+    Ok(format!("接続テストレポートを {} に書き出しました", path))
+}
+
+// ========================================
+// OS差を吸収したシステム情報
+// ========================================
+
+/// Windows（PowerShell）用: key=value 形式で1行ずつ出力（cmd 経由でも渡せるよう1行にまとめる）
+const SYSTEM_INFO_SCRIPT_WINDOWS: &str = "$os = Get-CimInstance Win32_OperatingSystem; \
+$cs = Get-CimInstance Win32_ComputerSystem; \
+$d = Get-CimInstance Win32_LogicalDisk -Filter 'DriveType=3'; \
+\"os_name=$($os.Caption)\"; \
+\"os_version=$($os.Version)\"; \
+\"cpu_cores=$($cs.NumberOfLogicalProcessors)\"; \
+\"total_memory_kb=$($os.TotalVisibleMemorySize)\"; \
+\"uptime_secs=$([int64]((Get-Date) - $os.LastBootUpTime).TotalSeconds)\"; \
+\"disk_total_bytes=$(($d | Measure-Object Size -Sum).Sum)\"; \
+\"disk_free_bytes=$(($d | Measure-Object FreeSpace -Sum).Sum)\"";
+
+/// Linux 等（bash）用: ディスクは疑似ファイルシステムを除いたローカルのものをデバイス単位で合計
+const SYSTEM_INFO_SCRIPT_POSIX: &str = "(. /etc/os-release 2>/dev/null; \
+echo \"os_name=${NAME:-$(uname -s)}\"; echo \"os_version=${VERSION_ID:-$(uname -r)}\"); \
+echo \"cpu_cores=$(nproc 2>/dev/null || getconf _NPROCESSORS_ONLN)\"; \
+echo \"total_memory_kb=$(awk '/^MemTotal:/{print $2}' /proc/meminfo 2>/dev/null)\"; \
+echo \"uptime_secs=$(cut -d. -f1 /proc/uptime 2>/dev/null)\"; \
+df -P -k -l -x tmpfs -x devtmpfs -x overlay -x squashfs 2>/dev/null \
+| awk 'NR>1 && !seen[$1]++ {t+=$2; f+=$4} END {print \"disk_total_kb=\" t; print \"disk_free_kb=\" f}'";
+
+/// OS によらない共通スキーマのシステム情報（取得・解析できなかった項目は null）
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+struct NormalizedSystemInfo {
+    machine_name: String,
+    os_name: Option<String>,
+    os_version: Option<String>,
+    /// 論理プロセッサ数
+    cpu_cores: Option<u32>,
+    total_memory_mb: Option<u64>,
+    uptime_secs: Option<u64>,
+    /// ローカルの固定ディスクの合計
+    disk_total_gb: Option<f64>,
+    disk_free_gb: Option<f64>,
+    /// 取得自体に失敗した理由（バッチ版のみ。このとき他の項目はすべて null）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// バイト数を GB（小数2桁）に換算
+fn bytes_to_gb(bytes: f64) -> f64 {
+    (bytes / 1024.0 / 1024.0 / 1024.0 * 100.0).round() / 100.0
+}
+
+/// 情報取得スクリプトの出力（key=value）を共通スキーマに変換（純粋関数）
+fn parse_system_info(machine_name: &str, stdout: &str) -> NormalizedSystemInfo {
+    let values: std::collections::HashMap<&str, &str> = stdout
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .filter(|(_, value)| !value.is_empty())
+        .collect();
+    let text = |key: &str| values.get(key).map(|v| v.to_string());
+    let number = |key: &str| values.get(key).and_then(|v| v.parse::<f64>().ok()).filter(|n| n.is_finite() && *n >= 0.0);
+    // Linux は KB、Windows はバイトで返る
+    let disk_gb = |kb_key: &str, bytes_key: &str| {
+        number(kb_key).map(|kb| kb * 1024.0).or_else(|| number(bytes_key)).map(bytes_to_gb)
+    };
+
+    NormalizedSystemInfo {
+        machine_name: machine_name.to_string(),
+        os_name: text("os_name"),
+        os_version: text("os_version"),
+        cpu_cores: values.get("cpu_cores").and_then(|v| v.parse().ok()).filter(|n| *n > 0),
+        total_memory_mb: number("total_memory_kb").map(|kb| (kb / 1024.0).round() as u64),
+        uptime_secs: values.get("uptime_secs").and_then(|v| v.parse().ok()),
+        disk_total_gb: disk_gb("disk_total_kb", "disk_total_bytes").filter(|gb| *gb > 0.0),
+        disk_free_gb: disk_gb("disk_free_kb", "disk_free_bytes"),
+        error: None,
+    }
+}
+
+/// 1台分を取得（machines.toml の shell 指定によらず、OS に合ったシェルでスクリプトを実行する）
+async fn collect_system_info(machine: &SshMachineConfig, settings: &AppSettings) -> Result<NormalizedSystemInfo, String> {
+    let mut machine = machine.clone();
+    let script = if login_shell(&machine) == ShellKind::Cmd {
+        machine.shell = Some(ShellKind::PowerShell);
+        SYSTEM_INFO_SCRIPT_WINDOWS
+    } else {
+        machine.shell = Some(ShellKind::Bash);
+        SYSTEM_INFO_SCRIPT_POSIX
+    };
+    let (timeout_secs, _) = resolve_command_timeout(&machine, settings);
+    let result = run_ssh_command(&machine, script, timeout_secs, &settings.language).await?;
+    if !result.success && result.stdout.trim().is_empty() {
+        return Err(tr(&settings.language, "system_info_failed", &[
+            ("code", &result.exit_code.to_string()),
+            ("stderr", result.stderr.trim()),
+        ]));
+    }
+    Ok(parse_system_info(&machine.name, &result.stdout))
+}
+
+/// マシンのシステム情報を OS 差を吸収した共通スキーマで返す
+#[tauri::command]
+async fn get_normalized_system_info(
+    machine_name: String,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+) -> Result<NormalizedSystemInfo, String> {
+    let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+    let machine = {
+        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        resolve_remote_machine(&state.machines, &machine_name, &settings.language)?
+    };
+    collect_system_info(&machine, &settings).await
+}
+
+/// 有効な全リモートマシンのシステム情報（並列取得、失敗したマシンは error に理由を入れて返す）
+#[tauri::command]
+async fn get_all_normalized_system_info(
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+) -> Result<Vec<NormalizedSystemInfo>, String> {
+    let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+    let machines: Vec<SshMachineConfig> = {
+        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        state.machines.iter().filter(|m| m.enabled && m.role != "Commander").cloned().collect()
+    };
+    let settings = &settings;
+    Ok(futures_util::future::join_all(machines.iter().map(|machine| async move {
+        collect_system_info(machine, settings).await.unwrap_or_else(|e| NormalizedSystemInfo {
+            machine_name: machine.name.clone(),
+            error: Some(e),
+            ..Default::default()
+        })
+    }))
+    .await)
+}
+
+// ========================================
+// ツール実行中のマシン負荷モニタリング
+// ========================================
+
+/// 長時間コマンドの実行中に対象マシンの CPU・メモリ使用率を定期取得して tool-machine-load で通知（opt-in）
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct LoadMonitorSettings {
+    enabled: bool,
+    /// 取得間隔（秒）。コマンドがこれより早く終われば一度も取得しない
+    interval_secs: u64,
+}
+
+impl Default for LoadMonitorSettings {
+    fn default() -> Self {
+        Self { enabled: false, interval_secs: 5 }
+    }
+}
+
+/// Windows（PowerShell）用: Get-Counter のカウンタ名は表示言語で変わるため CIM から求める
+const LOAD_SCRIPT_WINDOWS: &str = "$os = Get-CimInstance Win32_OperatingSystem; \
+\"cpu_percent=$((Get-CimInstance Win32_Processor | Measure-Object LoadPercentage -Average).Average)\"; \
+\"memory_percent=$([math]::Round(100 - $os.FreePhysicalMemory * 100 / $os.TotalVisibleMemorySize, 1))\"";
+
+/// Linux 等（bash）用: top のサマリー行（CPU・メモリ）を解析する
+const LOAD_SCRIPT_POSIX: &str = "LC_ALL=C top -bn1 | head -n 5";
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+struct MachineLoad {
+    cpu_percent: Option<f64>,
+    memory_percent: Option<f64>,
+}
+
+fn round_percent(value: f64) -> Option<f64> {
+    value.is_finite().then(|| ((value * 10.0).round() / 10.0).clamp(0.0, 100.0))
+}
+
+/// "98.3 id" / "98.3%id" / "15890.1 total" / "16331312k total" のような項目から数値を取り出す
+fn top_field(line: &str, label: &str) -> Option<f64> {
+    line.split([',', ':']).find_map(|part| {
+        let value = part.trim().strip_suffix(label)?.trim_end().trim_end_matches(['%', 'k', 'K']);
+        value.trim().parse::<f64>().ok()
+    })
+}
+
+/// 負荷取得スクリプトの出力を解析（Windows は key=value、Linux は top のサマリー行。純粋関数）
+fn parse_machine_load(stdout: &str) -> MachineLoad {
+    let mut load = MachineLoad::default();
+    for line in stdout.lines().map(str::trim) {
+        if let Some((key, value)) = line.split_once('=') {
+            let value = value.trim().parse::<f64>().ok().and_then(round_percent);
+            match key.trim() {
+                "cpu_percent" => load.cpu_percent = value,
+                "memory_percent" => load.memory_percent = value,
+                _ => {}
+            }
+        } else if line.starts_with("%Cpu") || line.starts_with("Cpu(s)") {
+            load.cpu_percent = top_field(line, "id").and_then(|idle| round_percent(100.0 - idle));
+        } else if line.contains("Mem") && !line.contains("Swap") {
+            if let (Some(total), Some(used)) = (top_field(line, "total"), top_field(line, "used")) {
+                load.memory_percent = (total > 0.0).then(|| used * 100.0 / total).and_then(round_percent);
+            }
+        }
+    }
+    load
+}
+
+/// 1回分の負荷を取得する。コマンド実行中の接続を塞がないよう ControlMaster を使わず別接続で行う
+async fn fetch_machine_load(machine: &SshMachineConfig, lang: &str) -> Result<MachineLoad, String> {
+    let mut machine = SshMachineConfig { control_path: None, ..machine.clone() };
+    let script = if login_shell(&machine) == ShellKind::Cmd {
+        machine.shell = Some(ShellKind::PowerShell);
+        LOAD_SCRIPT_WINDOWS
+    } else {
+        machine.shell = Some(ShellKind::Bash);
+        LOAD_SCRIPT_POSIX
+    };
+    let result = run_ssh_command(&machine, script, SSH_TIMEOUT_SECS, lang).await?;
+    let load = parse_machine_load(&result.stdout);
+    if load == MachineLoad::default() {
+        return Err(tr(lang, "machine_load_failed", &[
+            ("code", &result.exit_code.to_string()),
+            ("stderr", result.stderr.trim()),
+        ]));
+    }
+    Ok(load)
+}
+
+/// コマンド実行中、interval_secs ごとに負荷を取得して emit し続ける（終了しない。呼び出し側がコマンド完了で打ち切る）
+async fn monitor_machine_load(
+    machine: &SshMachineConfig,
+    execution_id: &str,
+    sequence: u64,
+    ctx: &ToolContext,
+) -> std::convert::Infallible {
+    let interval = Duration::from_secs(ctx.settings.load_monitor.interval_secs.max(1));
+    loop {
+        tokio::time::sleep(interval).await;
+        match fetch_machine_load(machine, &ctx.settings.language).await {
+            Ok(load) => ctx.emit("tool-machine-load", serde_json::json!({
+                "execution_id": execution_id,
+                "sequence": sequence,
+                "machine_name": machine.name,
+                "cpu_percent": load.cpu_percent,
+                "memory_percent": load.memory_percent
+            })),
+            Err(e) => eprintln!("[Nexus] Load monitor failed for {}: {}", machine.name, e),
+        }
+    }
+}
+
+// ========================================
+// マシングループへの一括実行と結果の集約
+// ========================================
+
+/// 集約表示で1グループあたりに載せる出力の上限（文字数、超えた分は中略）
+const GROUP_REPORT_MAX_OUTPUT_CHARS: usize = 2000;
+
+/// 出力が同じだったマシンのまとまり（失敗したマシンはエラー内容ごとにまとめる）
+#[derive(Serialize, Clone, Debug)]
+struct GroupOutputCluster {
+    machines: Vec<String>,
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct GroupExecutionSummary {
+    total: usize,
+    succeeded: usize,
+    failed: usize,
+    all_succeeded: bool,
+    /// 成功したマシンの出力がすべて同一
+    identical_output: bool,
+    /// 最多のまとまりと結果が異なるマシン（多数派と違う挙動の候補）
+    outliers: Vec<String>,
+}
+
+/// execute_on_group の結果（clusters は多い順、report は人にも Claude にも渡せる集約テキスト）
+#[derive(Serialize, Clone, Debug)]
+struct GroupExecutionReport {
+    group: String,
+    command: String,
+    summary: GroupExecutionSummary,
+    clusters: Vec<GroupOutputCluster>,
+    report: String,
+}
+
+/// グループ名に該当する有効なリモートマシン（ロール名またはタグ、"*" / "all" で全台）
+fn machines_in_group<'a>(machines: &'a [SshMachineConfig], group: &str) -> Vec<&'a SshMachineConfig> {
+    let group = group.trim();
+    let all = group == "*" || group.eq_ignore_ascii_case("all");
+    machines
+        .iter()
+        .filter(|m| m.enabled && m.role != "Commander")
+        .filter(|m| all || m.role.eq_ignore_ascii_case(group) || m.tags.iter().any(|t| t.eq_ignore_ascii_case(group)))
+        .collect()
+}
+
+/// 出力の比較用の正規化（改行コードと行末・末尾の空白の違いは同一とみなす）
+fn normalize_group_output(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim_end()
+        .to_string()
+}
+
+/// マシンごとの結果を同一出力ごとにまとめる
+fn cluster_group_results(results: Vec<(String, Result<RemoteCommandResult, String>)>) -> Vec<GroupOutputCluster> {
+    let mut clusters: Vec<GroupOutputCluster> = Vec::new();
+    for (machine, result) in results {
+        let cluster = match result {
+            Ok(r) => GroupOutputCluster {
+                machines: vec![machine],
+                success: r.success,
+                exit_code: Some(r.exit_code),
+                stdout: normalize_group_output(&r.stdout),
+                stderr: normalize_group_output(&r.stderr),
+                error: None,
+            },
+            Err(e) => GroupOutputCluster {
+                machines: vec![machine],
+                success: false,
+                exit_code: None,
+                stdout: String::new(),
+                stderr: String::new(),
+                error: Some(e),
+            },
+        };
+        let same = clusters.iter_mut().find(|c| {
+            c.success == cluster.success
+                && c.exit_code == cluster.exit_code
+                && c.stdout == cluster.stdout
+                && c.stderr == cluster.stderr
+                && c.error == cluster.error
+        });
+        match same {
+            Some(existing) => existing.machines.extend(cluster.machines),
+            None => clusters.push(cluster),
+        }
+    }
+    // 多数派を先頭に（同数なら成功を優先）
+    clusters.sort_by(|a, b| b.machines.len().cmp(&a.machines.len()).then(b.success.cmp(&a.success)));
+    clusters
+}
+
+fn summarize_group_clusters(clusters: &[GroupOutputCluster]) -> GroupExecutionSummary {
+    let count = |success: bool| -> usize {
+        clusters.iter().filter(|c| c.success == success).map(|c| c.machines.len()).sum()
+    };
+    let (succeeded, failed) = (count(true), count(false));
+    let outliers = if clusters.len() > 1 {
+        clusters.iter().skip(1).flat_map(|c| c.machines.iter().cloned()).collect()
+    } else {
+        Vec::new()
+    };
+    GroupExecutionSummary {
+        total: succeeded + failed,
+        succeeded,
+        failed,
+        all_succeeded: failed == 0 && succeeded > 0,
+        identical_output: clusters.iter().filter(|c| c.success).count() <= 1,
+        outliers,
+    }
+}
+
+/// 集約テキスト（同一出力のマシンはまとめて1回だけ載せ、異なるものだけ個別に載せる）
+fn format_group_report(group: &str, command: &str, summary: &GroupExecutionSummary, clusters: &[GroupOutputCluster]) -> String {
+    let mut lines = vec![format!(
+        "グループ '{}'（{}台）で `{}` を実行: 成功 {} / 失敗 {}",
+        group, summary.total, command, summary.succeeded, summary.failed
+    )];
+    if summary.all_succeeded && summary.identical_output {
+        lines.push("全台成功・出力はすべて同一".to_string());
+    } else if summary.identical_output && summary.succeeded > 0 {
+        lines.push(format!("成功{}台の出力は同一", summary.succeeded));
+    }
+    if !summary.outliers.is_empty() {
+        lines.push(format!("多数派と結果が異なるマシン: {}", summary.outliers.join(", ")));
+    }
+    for cluster in clusters {
+        let status = match (&cluster.error, cluster.exit_code) {
+            (Some(_), _) => "実行エラー".to_string(),
+            (None, Some(code)) => format!("exit {}", code),
+            (None, None) => "不明".to_string(),
+        };
+        lines.push(String::new());
+        lines.push(format!("[{}]（{}台・{}）", cluster.machines.join(", "), cluster.machines.len(), status));
+        if let Some(e) = &cluster.error {
+            lines.push(e.clone());
+        }
+        if !cluster.stdout.is_empty() {
+            lines.push(elide_middle(&cluster.stdout, GROUP_REPORT_MAX_OUTPUT_CHARS));
+        }
+        if !cluster.stderr.is_empty() {
+            lines.push(format!("stderr: {}", elide_middle(&cluster.stderr, GROUP_REPORT_MAX_OUTPUT_CHARS)));
+        }
+        if cluster.error.is_none() && cluster.stdout.is_empty() && cluster.stderr.is_empty() {
+            lines.push("(出力なし)".to_string());
+        }
+    }
+    lines.join("\n")
+}
+
+/// ロール名またはタグが一致する全マシンで同じコマンドを並列実行し、結果を出力ごとに集約して返す
+#[tauri::command]
+async fn execute_on_group(
+    group: String,
+    command: String,
+    ssh_state: State<'_, Mutex<SshState>>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+) -> Result<GroupExecutionReport, String> {
+    let settings = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+    let machines: Vec<SshMachineConfig> = {
+        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+        machines_in_group(&state.machines, &group).into_iter().cloned().collect()
+    };
+    if machines.is_empty() {
+        return Err(tr(&settings.language, "group_no_machines", &[("group", &group)]));
+    }
+
+    let settings = &settings;
+    let command_ref = command.as_str();
+    let results = futures_util::future::join_all(machines.iter().map(|machine| async move {
+        let (timeout_secs, _) = resolve_command_timeout(machine, settings);
+        let result = run_ssh_command(machine, command_ref, timeout_secs, &settings.language).await;
+        (machine.name.clone(), result)
+    }))
+    .await;
+
+    let clusters = cluster_group_results(results);
+    let summary = summarize_group_clusters(&clusters);
+    let report = format_group_report(&group, &command, &summary, &clusters);
+    eprintln!(
+        "[Nexus] Group '{}' executed on {} machine(s): {} ok, {} failed, {} distinct result(s)",
+        group, summary.total, summary.succeeded, summary.failed, clusters.len()
+    );
+    Ok(GroupExecutionReport { group, command, summary, clusters, report })
+}
+
+// ========================================
+// サービス管理ツール（manage_service）
+// ========================================
+
+/// manage_service の操作（status 以外は変更系として承認を求める）
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ServiceAction {
+    Status,
+    Start,
+    Stop,
+    Restart,
+}
+
+impl ServiceAction {
+    const ALL: [ServiceAction; 4] = [ServiceAction::Status, ServiceAction::Start, ServiceAction::Stop, ServiceAction::Restart];
+
+    fn name(self) -> &'static str {
+        match self {
+            ServiceAction::Status => "status",
+            ServiceAction::Start => "start",
+            ServiceAction::Stop => "stop",
+            ServiceAction::Restart => "restart",
+        }
+    }
+
+    fn from_input(input: &serde_json::Value) -> Option<Self> {
+        input.get("action").and_then(|v| serde_json::from_value(v.clone()).ok())
+    }
+}
+
+/// サービスの状態を取得するコマンド（systemctl status / sc query。出力は OUTPUT_PARSERS で構造化される）
+fn service_status_command(machine: &SshMachineConfig, service: &str) -> String {
+    match machine_shell(machine) {
+        ShellKind::Bash => format!("systemctl status --no-pager --lines=10 -- {}", escape_for_bash(service)),
+        // PowerShell では sc が Set-Content の別名になるため sc.exe と書く
+        ShellKind::PowerShell => format!("sc.exe query {}", escape_for_powershell(service)),
+        ShellKind::Cmd => format!("sc query {}", escape_for_cmd(service)),
+    }
+}
+
+/// サービスを操作するコマンド（Windows の net start / stop は完了まで待つので、直後の状態取得で結果が分かる）
+fn service_action_command(machine: &SshMachineConfig, service: &str, action: ServiceAction) -> String {
+    match (machine_shell(machine), action) {
+        (_, ServiceAction::Status) => service_status_command(machine, service),
+        (ShellKind::Bash, action) => format!("systemctl {} -- {}", action.name(), escape_for_bash(service)),
+        (ShellKind::PowerShell, action) => {
+            let cmdlet = match action {
+                ServiceAction::Start => "Start-Service",
+                ServiceAction::Stop => "Stop-Service",
+                _ => "Restart-Service",
+            };
+            format!("{} -Name {} -ErrorAction Stop", cmdlet, escape_for_powershell(service))
+        }
+        (ShellKind::Cmd, ServiceAction::Restart) => {
+            let service = escape_for_cmd(service);
+            format!("net stop {} && net start {}", service, service)
+        }
+        (ShellKind::Cmd, action) => format!("net {} {}", action.name(), escape_for_cmd(service)),
+    }
+}
+
+/// 承認フローで表示する理由（danger_patterns の代わりに「一致したパターン」として出す）
+fn service_approval_reason(action: ServiceAction) -> String {
+    format!("{} {}", SERVICE_TOOL, action.name())
+}
+
+/// manage_service の変更系呼び出しなら (マシン名, 実行するコマンド, 承認理由)。バッチ承認の項目作成に使う
+fn service_change_request(
+    input: &serde_json::Value,
+    machines: &[SshMachineConfig],
+) -> Option<(String, String, String)> {
+    let action = ServiceAction::from_input(input).filter(|a| *a != ServiceAction::Status)?;
+    let machine_name = input.get("machine_name").and_then(|v| v.as_str())?;
+    let service = input.get("service_name").and_then(|v| v.as_str())?;
+    let machine = machines.iter().find(|m| m.name == machine_name)?;
+    Some((machine_name.to_string(), service_action_command(machine, service, action), service_approval_reason(action)))
+}
+
+/// manage_service を実行する。変更系は操作（承認が必要）→ 状態取得の2回に分け、状態は構造化して返す
+/// 状態取得は停止中のサービスでも非ゼロ終了になる（systemctl status は 3）ため、パースできれば成功とみなす
+async fn run_service_tool(tool_id: &str, input: &serde_json::Value, ctx: &ToolContext) -> (serde_json::Value, Vec<ToolExecution>) {
+    let machine_name = input.get("machine_name").and_then(|v| v.as_str()).unwrap_or("unknown");
+    let service = input.get("service_name").and_then(|v| v.as_str()).unwrap_or("");
+    let action = ServiceAction::from_input(input).unwrap_or(ServiceAction::Status);
+    let Some(machine) = ctx.machines.iter().find(|m| m.name == machine_name && m.enabled && m.role != "Commander") else {
+        let tool_result = serde_json::json!({
+            "type": "tool_result",
+            "tool_use_id": tool_id,
+            "content": tr(&ctx.settings.language, "machine_unavailable", &[("machine", machine_name)]),
+            "is_error": true
+        });
+        return (tool_result, Vec::new());
+    };
+
+    let mut sections: Vec<String> = Vec::new();
+    let mut executions: Vec<ToolExecution> = Vec::new();
+    let mut action_failed = false;
+    if action != ServiceAction::Status {
+        let command = service_action_command(machine, service, action);
+        let options = MachineCommandOptions { approval_reason: Some(service_approval_reason(action)), ..Default::default() };
+        let sequence = ctx.next_tool_sequence();
+        let (content, exec) =
+            run_machine_command(machine_name, &command, true, Some(ToolPurpose::Change), sequence, options, ctx).await;
+        eprintln!("[Nexus] Service {} {} on {}: {}", action.name(), service, machine_name, if exec.success { "ok" } else { "failed" });
+        sections.push(format!("## {} {}\n{}", action.name(), if exec.success { "✓" } else { "✗" }, content));
+        action_failed = !exec.success;
+        let cancelled = exec.cancelled;
+        executions.push(exec);
+        if cancelled {
+            let tool_result = serde_json::json!({
+                "type": "tool_result",
+                "tool_use_id": tool_id,
+                "content": sections.join("\n\n"),
+                "is_error": true
+            });
+            return (tool_result, executions);
+        }
+    }
+
+    let command = service_status_command(machine, service);
+    let sequence = ctx.next_tool_sequence();
+    let (content, exec) = run_machine_command(
+        machine_name,
+        &command,
+        true,
+        Some(ToolPurpose::Investigate),
+        sequence,
+        MachineCommandOptions::default(),
+        ctx,
+    )
+    .await;
+    let status_failed = !exec.success && exec.parsed.is_none();
+    sections.push(if sections.is_empty() { content } else { format!("## status\n{}", content) });
+    executions.push(exec);
+
+    let tool_result = serde_json::json!({
+        "type": "tool_result",
+        "tool_use_id": tool_id,
+        "content": sections.join("\n\n"),
+        "is_error": action_failed || status_failed
+    });
+    (tool_result, executions)
+}
+
+// ========================================
+// ローカル（Commander）でのコマンド実行
+// ========================================
+
+/// アプリが動いているマシン（role = "Commander"）。SSH の対象ではないため enabled は見ない
+fn local_machine(machines: &[SshMachineConfig]) -> Option<&SshMachineConfig> {
+    machines.iter().find(|m| m.role == "Commander")
+}
+
+/// 構文チェック・許可リストの判定に使うローカルシェルの OS（machines.toml の os ではなく実際の起動シェルに合わせる）
+const LOCAL_SHELL_OS: &str = if cfg!(windows) { "windows" } else { "linux" };
+
+/// ローカルシェルでコマンドを起動する（Windows は cmd /C、それ以外は sh -c）
+fn local_shell_command(command: &str) -> TokioCommand {
+    #[cfg(windows)]
+    let process = {
+        let mut process = TokioCommand::new("cmd");
+        process.args(["/d", "/s", "/c"]).raw_arg(format!("\"{}\"", command));
+        process
+    };
+    #[cfg(not(windows))]
+    let process = {
+        let mut process = TokioCommand::new("sh");
+        process.arg("-c").arg(command);
+        process
+    };
+    process
+}
+
+/// Commander 上でコマンドを実行（execute_local_command）。SSH は介さず、結果は execute_tool_ssh と同じ形で返す
+/// タイムアウト・許可リスト・構文チェック・同時実行枠・キャンセルはリモート実行と同じ扱い
+async fn execute_tool_local(
+    machine: &SshMachineConfig,
+    command: &str,
+    skip_syntax_check: bool,
+    mut run: ToolRun,
+    ctx: &ToolContext,
+) -> ToolExecution {
+    let lang = ctx.settings.language.as_str();
+    let machine_name = machine.name.as_str();
+    let (timeout_secs, timeout_source) = resolve_command_timeout(machine, &ctx.settings);
+
+    if let Some(prefixes) = &machine.allowed_command_prefixes {
+        if !is_command_allowed(command, prefixes, LOCAL_SHELL_OS) {
+            let allowed = if prefixes.is_empty() { "(なし)".to_string() } else { prefixes.join(", ") };
+            let stderr = tr(lang, "command_not_allowed", &[("machine", machine_name), ("prefixes", &allowed)]);
+            return ToolExecution::failed(machine_name, command, stderr, timeout_secs, timeout_source);
+        }
+    }
+    if !skip_syntax_check {
+        if let Err((key, ch)) = check_command_syntax(command, LOCAL_SHELL_OS) {
+            let detail = tr(lang, key, &[("char", &ch.to_string())]);
+            let stderr = tr(lang, "syntax_check_failed", &[("detail", &detail)]);
+            return ToolExecution::failed(machine_name, command, stderr, timeout_secs, timeout_source);
+        }
+    }
+
+    let _permit = ctx
+        .app_handle
+        .state::<ToolQueue>()
+        .acquire(ToolPriority::High, machine_name, command)
+        .await;
+    if *run.cancel.borrow() {
+        return ToolExecution::failed(machine_name, command, tr(lang, "tool_cancelled", &[]), timeout_secs, timeout_source);
+    }
+
+    eprintln!("[Nexus] Local command on {}: {}", machine_name, command);
+    let sequence = run.sequence;
+    let on_event = |event: StreamEvent| {
+        if let StreamEvent::Line(line, stream) = event {
+            ctx.emit("tool-output-line", serde_json::json!({
+                "sequence": sequence,
+                "machine_name": machine_name,
+                "command": command,
+                "line": line,
+                "stream": stream.name()
+            }));
+        }
+    };
+    let process = local_shell_command(command);
+    match run_process_streaming(process, "local_exec_error", timeout_secs, None, lang, &mut run, on_event).await {
+        Ok(output) => ToolExecution {
+            execution_id: next_execution_id(),
+            machine_name: machine_name.to_string(),
+            command: command.to_string(),
+            parsed: output.success.then(|| parse_tool_output(command, &output.stdout)).flatten(),
+            stdout: output.stdout,
+            stderr: output.stderr,
+            success: output.success,
+            timeout_secs,
+            timeout_source: timeout_source.to_string(),
+            summary: None,
+            purpose: None,
+            sequence: 0,
+            cancelled: false,
+            connected_host: None,
+            cached: false,
+            truncated: false,
+        },
+        Err(e) => ToolExecution::failed(machine_name, command, e, timeout_secs, timeout_source),
+    }
+}
+
+// ========================================
+// ツール実行キュー（優先度付き同時実行制限）
+// ========================================
+
+/// ツール実行の優先度（空きが出たら高い順、同順位は到着順）
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum ToolPriority {
+    /// 定期ジョブ・バックグラウンド監視
+    Low,
+    #[default]
+    Normal,
+    /// ユーザー起点の対話ツール
+    High,
+}
+
+/// キュー内のツール実行（get_tool_queue 用）
+#[derive(Serialize, Clone, Debug)]
+struct ToolQueueEntry {
+    id: u64,
+    priority: ToolPriority,
+    machine_name: String,
+    command: String,
+    enqueued_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started_at: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct ToolQueueSnapshot {
+    max_concurrent: usize,
+    running: Vec<ToolQueueEntry>,
+    waiting: Vec<ToolQueueEntry>,
+}
+
+#[derive(Default)]
+struct ToolQueueInner {
+    max_concurrent: usize,
+    next_id: u64,
+    running: Vec<ToolQueueEntry>,
+    waiting: Vec<(ToolQueueEntry, tokio::sync::oneshot::Sender<()>)>,
+}
+
+impl ToolQueueInner {
+    /// 空きスロットに待機中の最優先エントリを流し込む
+    fn dispatch(&mut self) {
+        while self.running.len() < self.max_concurrent {
+            let Some(index) = self
+                .waiting
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, (e, _))| (e.priority, std::cmp::Reverse(e.id)))
+                .map(|(i, _)| i)
+            else {
+                return;
+            };
+            let (mut entry, tx) = self.waiting.remove(index);
+            if tx.send(()).is_ok() {
+                entry.started_at = Some(now_unix_secs());
+                self.running.push(entry);
+            }
+        }
+    }
+}
+
+/// SSH実行の同時実行数を制限し、解放時に優先度順で次を走らせる
+#[derive(Clone)]
+struct ToolQueue {
+    inner: std::sync::Arc<Mutex<ToolQueueInner>>,
+}
+
+impl ToolQueue {
+    fn new(max_concurrent: usize) -> Self {
+        Self {
+            inner: std::sync::Arc::new(Mutex::new(ToolQueueInner {
+                max_concurrent: max_concurrent.max(1),
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// 実行枠を取得するまで待つ（Permit を drop すると枠を返す）
+    async fn acquire(&self, priority: ToolPriority, machine_name: &str, command: &str) -> ToolQueuePermit {
+        let (id, rx) = {
+            let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+            inner.next_id += 1;
+            let now = now_unix_secs();
+            let mut entry = ToolQueueEntry {
+                id: inner.next_id,
+                priority,
+                machine_name: machine_name.to_string(),
+                command: command.to_string(),
+                enqueued_at: now,
+                started_at: None,
+            };
+            if inner.running.len() < inner.max_concurrent {
+                entry.started_at = Some(now);
+                inner.running.push(entry);
+                (inner.next_id, None)
+            } else {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                inner.waiting.push((entry, tx));
+                (inner.next_id, Some(rx))
+            }
+        };
+
+        // 待機中にキャンセルされた場合も Permit の drop でキューから外れる
+        let permit = ToolQueuePermit {
+            inner: self.inner.clone(),
+            id,
+        };
+        if let Some(rx) = rx {
+            let _ = rx.await;
+        }
+        permit
+    }
+
+    fn snapshot(&self) -> ToolQueueSnapshot {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut waiting: Vec<ToolQueueEntry> = inner.waiting.iter().map(|(e, _)| e.clone()).collect();
+        waiting.sort_by_key(|e| (std::cmp::Reverse(e.priority), e.id));
+        ToolQueueSnapshot {
+            max_concurrent: inner.max_concurrent,
+            running: inner.running.clone(),
+            waiting,
+        }
+    }
+}
+
+struct ToolQueuePermit {
+    inner: std::sync::Arc<Mutex<ToolQueueInner>>,
+    id: u64,
+}
+
+impl Drop for ToolQueuePermit {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(index) = inner.waiting.iter().position(|(e, _)| e.id == self.id) {
+            inner.waiting.remove(index);
+            return;
+        }
+        inner.running.retain(|e| e.id != self.id);
+        inner.dispatch();
+    }
+}
+
+/// 実行中・待機中のツール実行一覧
+#[tauri::command]
+fn get_tool_queue(queue: State<'_, ToolQueue>) -> ToolQueueSnapshot {
+    queue.snapshot()
+}
+
+// ========================================
+// ツール出力の構造化パーサ
+// ========================================
+
+/// 定番コマンドの出力パーサ（新しいパーサは OUTPUT_PARSERS に登録する）
+struct OutputParser {
+    name: &'static str,
+    /// 対象プログラム名（パス・先頭の sudo は無視して比較）
+    program: &'static str,
+    /// 対象サブコマンド（systemctl status 等、None ならプログラム名のみで判定）
+    subcommand: Option<&'static str>,
+    /// パースできなければ None（生の出力のまま扱う）
+    parse: fn(&str) -> Option<serde_json::Value>,
+}
+
+const OUTPUT_PARSERS: &[OutputParser] = &[
+    OutputParser { name: "df", program: "df", subcommand: None, parse: parse_df_output },
+    OutputParser { name: "free", program: "free", subcommand: None, parse: parse_free_output },
+    OutputParser {
+        name: "systemctl-status",
+        program: "systemctl",
+        subcommand: Some("status"),
+        parse: parse_systemctl_status_output,
+    },
+    OutputParser { name: "sc-query", program: "sc", subcommand: Some("query"), parse: parse_sc_query_output },
+    OutputParser { name: "sc-query", program: "sc.exe", subcommand: Some("query"), parse: parse_sc_query_output },
+];
+
+/// コマンドに対応するパーサを探す（パイプ・リダイレクト・複文は出力形式が変わるため対象外）
+fn find_output_parser(command: &str) -> Option<&'static OutputParser> {
+    if command.contains(['|', ';', '&', '>', '<', '`', '$', '\n']) {
+        return None;
+    }
+    let mut tokens = command.split_whitespace().skip_while(|t| *t == "sudo");
+    let program = tokens.next()?.rsplit(['/', '\\']).next()?;
+    let subcommand = tokens.find(|t| !t.starts_with('-'));
+    OUTPUT_PARSERS
+        .iter()
+        .find(|p| p.program == program && p.subcommand.is_none_or(|s| subcommand == Some(s)))
+}
+
+/// 対応するパーサがあれば出力を構造化する
+fn parse_tool_output(command: &str, stdout: &str) -> Option<serde_json::Value> {
+    find_output_parser(command).and_then(|parser| (parser.parse)(stdout))
+}
+
+/// 見出しをキー名に正規化（"Use%" → "use_percent", "buff/cache" → "buff_cache"）
+fn normalize_column(name: &str) -> String {
+    let key: String = name
+        .to_lowercase()
+        .replace('%', "_percent")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    key.trim_matches('_').to_string()
+}
+
+/// 数値（"23%" 等も含む）は数値に、それ以外は文字列のまま
+fn column_value(value: &str) -> serde_json::Value {
+    match value.trim_end_matches('%').parse::<u64>() {
+        Ok(n) => serde_json::json!(n),
+        Err(_) => serde_json::json!(value),
+    }
+}
+
+/// df: 見出し行の列名で各行をオブジェクト化（最後の列 "Mounted on" は空白を含みうる）
+fn parse_df_output(stdout: &str) -> Option<serde_json::Value> {
+    let mut lines = stdout.lines().filter(|l| !l.trim().is_empty());
+    let header = lines.next()?;
+    let mut columns: Vec<String> = header.split_whitespace().map(normalize_column).collect();
+    if columns.ends_with(&["mounted".to_string(), "on".to_string()]) {
+        columns.truncate(columns.len() - 2);
+        columns.push("mounted_on".to_string());
+    }
+    if columns.len() < 2 || columns[0] != "filesystem" {
+        return None;
+    }
+
+    let filesystems: Vec<serde_json::Value> = lines
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < columns.len() {
+                return None;
+            }
+            let (head, tail) = fields.split_at(columns.len() - 1);
+            let mut row: serde_json::Map<String, serde_json::Value> = columns
+                .iter()
+                .zip(head)
+                .map(|(col, val)| (col.clone(), column_value(val)))
+                .collect();
+            row.insert(columns[columns.len() - 1].clone(), serde_json::json!(tail.join(" ")));
+            Some(serde_json::Value::Object(row))
+        })
+        .collect();
+    if filesystems.is_empty() {
+        return None;
+    }
+    Some(serde_json::json!({ "filesystems": filesystems }))
+}
+
+/// free: "Mem:" / "Swap:" 行を見出しの列名で対応付け
+fn parse_free_output(stdout: &str) -> Option<serde_json::Value> {
+    let mut lines = stdout.lines().filter(|l| !l.trim().is_empty());
+    let columns: Vec<String> = lines.next()?.split_whitespace().map(normalize_column).collect();
+    if columns.first().map(String::as_str) != Some("total") {
+        return None;
+    }
+
+    let mut result = serde_json::Map::new();
+    for line in lines {
+        let mut fields = line.split_whitespace();
+        let Some(label) = fields.next().and_then(|l| l.strip_suffix(':')) else {
+            continue;
+        };
+        let values: serde_json::Map<String, serde_json::Value> = columns
+            .iter()
+            .zip(fields)
+            .map(|(col, val)| (col.clone(), column_value(val)))
+            .collect();
+        result.insert(normalize_column(label), serde_json::Value::Object(values));
+    }
+    if result.is_empty() {
+        return None;
+    }
+    Some(serde_json::Value::Object(result))
+}
+
+/// systemctl status: ユニットごとに状態行（Loaded / Active / Main PID 等）と直近ログを抽出
+fn parse_systemctl_status_output(stdout: &str) -> Option<serde_json::Value> {
+    let mut units: Vec<serde_json::Value> = Vec::new();
+    let mut lines = stdout.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        // 見出し行: "● nginx.service - 説明"（状態により ○ × * 等）
+        let Some(title) = line
+            .strip_prefix(['●', '○', '×', '*', '↻'])
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+        else {
+            continue;
+        };
+        let (unit, description) = title.split_once(" - ").unwrap_or((title, ""));
+
+        let mut properties = serde_json::Map::new();
+        let mut last_key = String::new();
+        while let Some(line) = lines.next_if(|l| !l.trim().is_empty() && !l.starts_with(['●', '○', '×', '*', '↻'])) {
+            match line.trim().split_once(": ") {
+                Some((key, value)) if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphabetic() || c == ' ') => {
+                    last_key = normalize_column(key);
+                    properties.insert(last_key.clone(), serde_json::json!(value.trim()));
+                }
+                // CGroup のプロセスツリー等の継続行は直前の項目に追記
+                _ => {
+                    if let Some(serde_json::Value::String(value)) = properties.get_mut(&last_key) {
+                        value.push('\n');
+                        value.push_str(line.trim());
+                    }
+                }
+            }
+        }
+        let mut recent_logs: Vec<String> = Vec::new();
+        while let Some(line) = lines.next_if(|l| !l.starts_with(['●', '○', '×', '*', '↻'])) {
+            if !line.trim().is_empty() {
+                recent_logs.push(line.trim().to_string());
+            }
+        }
+
+        let property = |key: &str| properties.get(key).and_then(|v| v.as_str()).unwrap_or("");
+        let loaded = property("loaded");
+        let active = property("active");
+        let unit_file_state = loaded
+            .split_once('(')
+            .and_then(|(_, rest)| rest.split(';').nth(1))
+            .map(str::trim);
+        let sub_state = active
+            .split_once('(')
+            .and_then(|(_, rest)| rest.split_once(')'))
+            .map(|(s, _)| s);
+        let since = active
+            .split_once(" since ")
+            .map(|(_, rest)| rest.split(';').next().unwrap_or(rest).trim());
+        let main_pid = property("main_pid")
+            .split_whitespace()
+            .next()
+            .and_then(|pid| pid.parse::<u64>().ok());
+
+        units.push(serde_json::json!({
+            "unit": unit,
+            "description": description,
+            "loaded": loaded.split_whitespace().next(),
+            "unit_file_state": unit_file_state,
+            "active": active.split_whitespace().next(),
+            "sub_state": sub_state,
+            "since": since,
+            "main_pid": main_pid,
+            "properties": properties,
+            "recent_logs": recent_logs,
+        }));
+    }
+    if units.is_empty() {
+        return None;
+    }
+    Some(serde_json::json!({ "units": units }))
+}
+
+/// sc query: "SERVICE_NAME: x" ごとに "KEY : 値" を集め、STATE を状態名（running / stopped 等）と番号に分ける
+fn parse_sc_query_output(stdout: &str) -> Option<serde_json::Value> {
+    let mut services: Vec<serde_json::Value> = Vec::new();
+    let mut current: Option<(String, serde_json::Map<String, serde_json::Value>)> = None;
+    let mut last_key = String::new();
+    let finish = |current: Option<(String, serde_json::Map<String, serde_json::Value>)>, services: &mut Vec<serde_json::Value>| {
+        let Some((name, properties)) = current else {
+            return;
+        };
+        let state = properties.get("state").and_then(|v| v.as_str()).unwrap_or("");
+        let mut fields = state.split_whitespace();
+        let state_code = fields.next().and_then(|code| code.parse::<u64>().ok());
+        let state_name = fields.next().map(str::to_lowercase);
+        let exit_code = properties
+            .get("win32_exit_code")
+            .and_then(|v| v.as_str())
+            .and_then(|v| v.split_whitespace().next())
+            .and_then(|code| code.parse::<i64>().ok());
+        services.push(serde_json::json!({
+            "service_name": name,
+            "state": state_name,
+            "state_code": state_code,
+            "win32_exit_code": exit_code,
+            "properties": properties,
+        }));
+    };
+
+    for line in stdout.lines() {
+        let trimmed = line.trim();
+        if let Some(name) = trimmed.strip_prefix("SERVICE_NAME:") {
+            finish(current.take(), &mut services);
+            current = Some((name.trim().to_string(), serde_json::Map::new()));
+            continue;
+        }
+        let Some((_, properties)) = current.as_mut() else {
+            continue;
+        };
+        match trimmed.split_once(" : ") {
+            Some((key, value)) => {
+                last_key = normalize_column(key);
+                properties.insert(last_key.clone(), serde_json::json!(value.trim()));
+            }
+            // STATE の次行の "(STOPPABLE, ...)" 等は直前の項目に追記
+            None if !trimmed.is_empty() => {
+                if let Some(serde_json::Value::String(value)) = properties.get_mut(&last_key) {
+                    value.push(' ');
+                    value.push_str(trimmed);
+                }
+            }
+            None => {}
+        }
+    }
+    finish(current, &mut services);
+    if services.is_empty() {
+        return None;
+    }
+    Some(serde_json::json!({ "services": services }))
+}
+
+// ========================================
+// メトリクスの時系列蓄積
+// ========================================
+
+const METRICS_LOG_FILE: &str = "metrics.jsonl";
+/// 保持期間（これより古いサンプルは間引きの際に捨てる）
+const METRICS_RETENTION_SECS: u64 = 30 * 24 * 3600;
+/// 保持するサンプル数の上限（保持期間内でも超えた分は古い順に捨てる）
+const METRICS_MAX_SAMPLES: usize = 100_000;
+/// ファイルの間引きを行う最短間隔
+const METRICS_PRUNE_INTERVAL_SECS: u64 = 3600;
+
+/// 1件の計測値（metric: "disk_use_percent" | "memory_use_percent" | "swap_use_percent" | "ssh_latency_ms"）
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MetricSample {
+    timestamp: u64,
+    machine: String,
+    metric: String,
+    /// 系列の区別（disk_use_percent はマウントポイント、ssh_latency_ms は接続先ホスト、それ以外は空）
+    #[serde(default)]
+    label: String,
+    value: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct MetricPoint {
+    timestamp: u64,
+    value: f64,
+}
+
+/// グラフ1本分（label ごと、時刻順）
+#[derive(Serialize, Clone, Debug)]
+struct MetricSeries {
+    label: String,
+    points: Vec<MetricPoint>,
+}
+
+/// 構造化パース結果（df / free）から計測値を取り出す
+fn extract_metrics(parser: &str, parsed: &serde_json::Value) -> Vec<(String, String, f64)> {
+    let mut metrics = Vec::new();
+    match parser {
+        "df" => {
+            for fs in parsed.get("filesystems").and_then(|v| v.as_array()).into_iter().flatten() {
+                // GNU は Use%、BSD / macOS は Capacity
+                let percent = fs.get("use_percent").or_else(|| fs.get("capacity")).and_then(|v| v.as_f64());
+                let mount = fs.get("mounted_on").and_then(|v| v.as_str());
+                if let (Some(percent), Some(mount)) = (percent, mount) {
+                    metrics.push(("disk_use_percent".to_string(), mount.to_string(), percent));
+                }
+            }
+        }
+        "free" => {
+            for (row, metric) in [("mem", "memory_use_percent"), ("swap", "swap_use_percent")] {
+                let value = |key: &str| parsed.get(row).and_then(|r| r.get(key)).and_then(|v| v.as_f64());
+                if let (Some(used), Some(total)) = (value("used"), value("total")) {
+                    if total > 0.0 {
+                        let percent = (used / total * 1000.0).round() / 10.0;
+                        metrics.push((metric.to_string(), String::new(), percent));
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+    metrics
+}
+
+/// コマンド出力に対応するパーサがあれば計測値を metrics.jsonl に追記（ツール実行・定期監視の双方から呼ぶ）
+fn record_command_metrics(app_handle: &tauri::AppHandle, machine: &str, command: &str, stdout: &str) {
+    let Some(parser) = find_output_parser(command) else {
+        return;
+    };
+    let Some(parsed) = (parser.parse)(stdout) else {
+        return;
+    };
+    append_metric_samples(app_handle, machine, extract_metrics(parser.name, &parsed));
+}
+
+/// 計測値 (metric, label, value) を metrics.jsonl に追記
+fn append_metric_samples(app_handle: &tauri::AppHandle, machine: &str, metrics: Vec<(String, String, f64)>) {
+    if metrics.is_empty() {
+        return;
+    }
+    let path = match app_data_path(app_handle, METRICS_LOG_FILE) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("[Nexus] Warning: metrics not recorded: {}", e);
+            return;
+        }
+    };
+    let timestamp = now_unix_secs();
+    for (metric, label, value) in metrics {
+        let sample = MetricSample { timestamp, machine: machine.to_string(), metric, label, value };
+        if let Err(e) = append_jsonl(&path, &sample) {
+            eprintln!("[Nexus] Warning: metrics not recorded: {}", e);
+            return;
+        }
+    }
+    prune_metrics(&path, timestamp);
+}
+
+/// 保持期間・件数の上限を超えたサンプルを捨てる（ファイル全体を書き直すため一定間隔でのみ行う）
+fn prune_metrics(path: &std::path::Path, now: u64) {
+    static LAST_PRUNED: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let last = LAST_PRUNED.load(std::sync::atomic::Ordering::Relaxed);
+    if now.saturating_sub(last) < METRICS_PRUNE_INTERVAL_SECS
+        || LAST_PRUNED
+            .compare_exchange(last, now, std::sync::atomic::Ordering::Relaxed, std::sync::atomic::Ordering::Relaxed)
+            .is_err()
+    {
+        return;
+    }
+    let samples: Vec<MetricSample> = read_jsonl(path);
+    let total = samples.len();
+    let cutoff = now.saturating_sub(METRICS_RETENTION_SECS);
+    let mut kept: Vec<MetricSample> = samples.into_iter().filter(|s| s.timestamp >= cutoff).collect();
+    if kept.len() > METRICS_MAX_SAMPLES {
+        kept.drain(..kept.len() - METRICS_MAX_SAMPLES);
+    }
+    if kept.len() == total {
+        return;
+    }
+    let content: String = kept
+        .iter()
+        .filter_map(|s| serde_json::to_string(s).ok())
+        .map(|line| line + "\n")
+        .collect();
+    match std::fs::write(path, content) {
+        Ok(()) => eprintln!("[Nexus] Metrics pruned: {} -> {} samples", total, kept.len()),
+        Err(e) => eprintln!("[Nexus] Warning: metrics prune failed: {}", e),
+    }
+}
+
+/// サンプルを label ごとの時系列にまとめる（from/to は UNIX 秒、両端を含む）
+fn build_metric_series(
+    samples: &[MetricSample],
+    machine: &str,
+    metric: &str,
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Vec<MetricSeries> {
+    let mut by_label: std::collections::BTreeMap<&str, Vec<MetricPoint>> = std::collections::BTreeMap::new();
+    for sample in samples.iter().filter(|s| {
+        s.machine == machine
+            && s.metric == metric
+            && from.is_none_or(|from| s.timestamp >= from)
+            && to.is_none_or(|to| s.timestamp <= to)
+    }) {
+        by_label
+            .entry(sample.label.as_str())
+            .or_default()
+            .push(MetricPoint { timestamp: sample.timestamp, value: sample.value });
+    }
+    by_label
+        .into_iter()
+        .map(|(label, mut points)| {
+            points.sort_by_key(|p| p.timestamp);
+            MetricSeries { label: label.to_string(), points }
+        })
+        .collect()
+}
+
+/// メトリクスの推移（グラフ描画用）。metric: disk_use_percent / memory_use_percent / swap_use_percent / ssh_latency_ms
+/// from/to は UNIX 秒、省略時は保持している全期間
+#[tauri::command]
+fn get_metric_series(
+    machine: String,
+    metric: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    app_handle: tauri::AppHandle,
+) -> Result<Vec<MetricSeries>, String> {
+    let path = app_data_path(&app_handle, METRICS_LOG_FILE)?;
+    let samples: Vec<MetricSample> = read_jsonl(&path);
+    Ok(build_metric_series(&samples, &machine, &metric, from, to))
+}
+
+// ========================================
+// SSH 接続プール（ControlMaster）の状態確認・切断
+// ========================================
+
+/// ネットワーク（既定経路の送信元アドレス）の変化を確認する間隔
+const NETWORK_WATCH_INTERVAL_SECS: u64 = 10;
+
+/// マスター接続1本分の状態
+#[derive(Serialize, Clone, Debug)]
+struct PoolConnection {
+    machine_name: String,
+    host: String,
+    /// ControlMaster による接続再利用の対象か（Windows・connection_reuse = false・Commander は対象外）
+    reuse_enabled: bool,
+    /// マスター接続が生きている（ControlPersist 秒使われなければ ssh 側で自動的に閉じる）
+    connected: bool,
+}
+
+/// マスター接続の単位（接続候補ごとにソケットが分かれるため、ホストごとの設定に展開する）
+fn pool_targets(machine: &SshMachineConfig) -> Vec<SshMachineConfig> {
+    let hosts = if machine.hosts.is_empty() { vec![machine.host.clone()] } else { machine.hosts.clone() };
+    hosts.iter().map(|host| with_host(machine, host)).collect()
+}
+
+/// ssh -O でマスター接続を操作（"check" / "exit"）。成功（終了コード 0）なら true
+/// ControlPath の %C は接続先・ポート・ユーザーから決まるため、実行時と同じ接続指定を渡す
+async fn control_master(machine: &SshMachineConfig, operation: &str) -> bool {
+    let Some(path) = machine.control_path.as_deref() else {
+        return false;
+    };
+    let mut args = vec!["-o".to_string(), format!("ControlPath={}", path), "-O".to_string(), operation.to_string()];
+    args.extend(ssh_connection_args(machine));
+    let output = timeout(
+        Duration::from_secs(SSH_TIMEOUT_SECS),
+        ssh_command("ssh").args(&args).stdin(std::process::Stdio::null()).kill_on_drop(true).output(),
+    )
+    .await;
+    matches!(output, Ok(Ok(out)) if out.status.success())
+}
+
+/// マシンのマスター接続をすべて閉じる（閉じた本数を返す）。直近に繋がったホストの記録も消す
+async fn disconnect_machine_connections(machine: &SshMachineConfig) -> usize {
+    forget_cached_host(&machine.name);
+    let results = futures_util::future::join_all(
+        pool_targets(machine).iter().map(|target| async move { control_master(target, "exit").await }),
+    )
+    .await;
+    results.into_iter().filter(|closed| *closed).count()
+}
+
+async fn disconnect_all_connections(machines: &[SshMachineConfig]) -> usize {
+    futures_util::future::join_all(machines.iter().map(disconnect_machine_connections)).await.into_iter().sum()
+}
+
+fn pooled_machines(ssh_state: &Mutex<SshState>) -> Result<Vec<SshMachineConfig>, String> {
+    let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
+    Ok(state.machines.iter().filter(|m| m.role != "Commander").cloned().collect())
+}
+
+/// マシンのマスター接続を明示的に切る（次の実行で接続し直す）
+#[tauri::command]
+async fn disconnect_machine(machine_name: String, ssh_state: State<'_, Mutex<SshState>>) -> Result<String, String> {
+    let machine = pooled_machines(&ssh_state)?
+        .into_iter()
+        .find(|m| m.name == machine_name)
+        .ok_or_else(|| format!("マシン '{}' が見つかりません", machine_name))?;
+    if machine.control_path.is_none() {
+        return Ok(format!("{} は接続を再利用していません（切断する接続はありません）", machine_name));
+    }
+    let closed = disconnect_machine_connections(&machine).await;
+    eprintln!("[Nexus] Disconnected {} ({} connection(s))", machine_name, closed);
+    Ok(format!("{} の接続を {} 本切断しました", machine_name, closed))
+}
+
+/// すべてのマスター接続を切る
+#[tauri::command]
+async fn disconnect_all(ssh_state: State<'_, Mutex<SshState>>) -> Result<String, String> {
+    let machines = pooled_machines(&ssh_state)?;
+    let closed = disconnect_all_connections(&machines).await;
+    eprintln!("[Nexus] Disconnected all SSH connections ({} connection(s))", closed);
+    Ok(format!("SSH 接続を {} 本切断しました", closed))
+}
+
+/// マスター接続の状態（マシン・接続候補ごと）
+#[tauri::command]
+async fn get_connection_pool_status(ssh_state: State<'_, Mutex<SshState>>) -> Result<Vec<PoolConnection>, String> {
+    let targets: Vec<SshMachineConfig> = pooled_machines(&ssh_state)?.iter().flat_map(pool_targets).collect();
+    let checks = futures_util::future::join_all(targets.iter().map(|target| async move {
+        PoolConnection {
+            machine_name: target.name.clone(),
+            host: target.host.clone(),
+            reuse_enabled: target.control_path.is_some(),
+            connected: control_master(target, "check").await,
+        }
+    }))
+    .await;
+    Ok(checks)
+}
+
+/// 既定経路で使われる送信元アドレス（パケットは送らない。VPN の接続・切断で変わる）
+fn primary_local_addr() -> Option<std::net::IpAddr> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("8.8.8.8:53").ok()?;
+    socket.local_addr().ok().map(|addr| addr.ip())
+}
+
+/// ネットワークの切り替え（VPN の接続・切断等）を検知したら全接続をリセットする（setup から起動）
+/// 古い経路に張られたマスター接続が残ると、切り替え後の実行が応答しなくなるため
+async fn run_network_watch_loop(app_handle: tauri::AppHandle) {
+    let mut last = primary_local_addr();
+    loop {
+        tokio::time::sleep(Duration::from_secs(NETWORK_WATCH_INTERVAL_SECS)).await;
+        let current = primary_local_addr();
+        if current == last {
+            continue;
+        }
+        let describe = |addr: Option<std::net::IpAddr>| addr.map_or("(なし)".to_string(), |a| a.to_string());
+        let machines = pooled_machines(&app_handle.state::<Mutex<SshState>>()).unwrap_or_default();
+        let closed = disconnect_all_connections(&machines).await;
+        eprintln!(
+            "[Nexus] Network changed ({} -> {}), reset {} SSH connection(s)",
+            describe(last),
+            describe(current),
+            closed
+        );
+        let _ = app_handle.emit("network-changed", serde_json::json!({
+            "previous_address": describe(last),
+            "address": describe(current),
+            "closed_connections": closed
+        }));
+        last = current;
+    }
+}
+
+// ========================================
+// 接続品質（SSH 接続の遅延）
+// ========================================
+
+/// 生存確認のたびに記録する SSH 接続の所要時間（metrics.jsonl の metric 名）
+const LATENCY_METRIC: &str = "ssh_latency_ms";
+/// get_connection_quality の既定の集計期間
+const CONNECTION_QUALITY_DEFAULT_HOURS: u64 = 24;
+
+#[derive(Serialize, Clone, Debug)]
+struct ConnectionQuality {
+    machine: String,
+    from: u64,
+    to: u64,
+    samples: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avg_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_ms: Option<f64>,
+    /// 連続する計測値の差の平均（ミリ秒）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jitter_ms: Option<f64>,
+    threshold_ms: u64,
+    /// "good" | "low"（平均が閾値超え） | "unknown"（記録なし）
+    quality: &'static str,
+    /// 表示用（例: "接続品質: 低"）
+    label: String,
+}
+
+/// 期間内の遅延サンプルから平均・最大・ジッタを集計し、閾値で品質を分類する
+fn build_connection_quality(machine: &str, samples: &[MetricSample], from: u64, to: u64, threshold_ms: u64) -> ConnectionQuality {
+    let mut points: Vec<&MetricSample> = samples
+        .iter()
+        .filter(|s| s.machine == machine && s.metric == LATENCY_METRIC && s.timestamp >= from && s.timestamp <= to)
+        .collect();
+    points.sort_by_key(|s| s.timestamp);
+    let values: Vec<f64> = points.iter().map(|s| s.value).collect();
+
+    let round = |v: f64| (v * 10.0).round() / 10.0;
+    let avg_ms = (!values.is_empty()).then(|| round(values.iter().sum::<f64>() / values.len() as f64));
+    let max_ms = values.iter().copied().reduce(f64::max);
+    let jitter_ms = (values.len() >= 2).then(|| {
+        let diffs: f64 = values.windows(2).map(|w| (w[1] - w[0]).abs()).sum();
+        round(diffs / (values.len() - 1) as f64)
+    });
+    let quality = match avg_ms {
+        None => "unknown",
+        Some(avg) if avg > threshold_ms as f64 => "low",
+        Some(_) => "good",
+    };
+    let label = match quality {
+        "low" => "接続品質: 低",
+        "good" => "接続品質: 良好",
+        _ => "接続品質: 記録なし",
+    };
+    ConnectionQuality {
+        machine: machine.to_string(),
+        from,
+        to,
+        samples: values.len(),
+        avg_ms,
+        max_ms,
+        jitter_ms,
+        threshold_ms,
+        quality,
+        label: label.to_string(),
+    }
+}
+
+/// マシンへの SSH 接続の遅延（平均・最大・ジッタ）と品質分類。hours は集計期間（省略時 24 時間）
+#[tauri::command]
+fn get_connection_quality(
+    machine: String,
+    hours: Option<u64>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<ConnectionQuality, String> {
+    let threshold_ms = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.status.latency_threshold_ms;
+    let path = app_data_path(&app_handle, METRICS_LOG_FILE)?;
+    let samples: Vec<MetricSample> = read_jsonl(&path);
+    let to = now_unix_secs();
+    let from = to.saturating_sub(hours.unwrap_or(CONNECTION_QUALITY_DEFAULT_HOURS) * 3600);
+    Ok(build_connection_quality(&machine, &samples, from, to, threshold_ms))
+}
+
+// ========================================
+// リクエスト単位のトークン予算
+// ========================================
+
+/// 1リクエスト（ツールループ全体）のトークン予算
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct BudgetSettings {
+    /// 入出力トークンの合計上限（0 で無制限）
+    request_max_tokens: u64,
+    /// この割合を消化したらツールを止めて結論を出させる
+    wrap_up_ratio: f64,
+    /// 月間の予算上限（USD、0 で未設定）。コスト見込みの到達予測に使う
+    monthly_limit_usd: f64,
+}
+
+impl Default for BudgetSettings {
+    fn default() -> Self {
+        Self {
+            request_max_tokens: 0,
+            wrap_up_ratio: 0.8,
+            monthly_limit_usd: 0.0,
+        }
+    }
+}
+
+const DEFAULT_CALL_MAX_TOKENS: u32 = 4096;
+const MIN_CALL_MAX_TOKENS: u32 = 512; // 予算が残り少なくても結論を書ける最低限
+
+const BUDGET_WRAP_UP_NOTE: &str = "このリクエストのトークン予算が残り少なくなっています。\
+これ以上ツールは使わず、ここまでに得た情報だけで結論をまとめて回答してください。\
+確認しきれなかった点があれば、その旨を明記してください。";
+
+/// 1回のAPI呼び出しの出力上限とツール使用可否
+#[derive(Clone, Copy, Debug)]
+struct CallLimits {
+    max_tokens: u32,
+    allow_tools: bool,
+}
+
+impl Default for CallLimits {
+    fn default() -> Self {
+        Self {
+            max_tokens: DEFAULT_CALL_MAX_TOKENS,
+            allow_tools: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BudgetPhase {
+    Normal,
+    /// 予算の wrap_up_ratio を超えた: ツールを止めて結論を促す
+    WrapUp,
+    /// 予算を使い切った: ループを打ち切る
+    Exhausted,
+}
+
+/// ツールループの継続可否と各呼び出しの上限を、消化済みトークンから判断する
+#[derive(Clone, Copy, Debug)]
+struct RequestBudget {
+    limit: u64,
+    wrap_up_ratio: f64,
+    /// 1回の呼び出しの出力上限（セッションの max_tokens をモデルの範囲に収めたもの）
+    call_max_tokens: u32,
+}
+
+impl RequestBudget {
+    fn new(settings: &BudgetSettings, call_max_tokens: u32) -> Self {
+        Self {
+            limit: settings.request_max_tokens,
+            wrap_up_ratio: settings.wrap_up_ratio.clamp(0.0, 1.0),
+            call_max_tokens,
+        }
+    }
+
+    fn phase(&self, used: u64) -> BudgetPhase {
+        if self.limit == 0 {
+            BudgetPhase::Normal
+        } else if used >= self.limit {
+            BudgetPhase::Exhausted
+        } else if used as f64 >= self.limit as f64 * self.wrap_up_ratio {
+            BudgetPhase::WrapUp
+        } else {
+            BudgetPhase::Normal
+        }
+    }
+
+    /// 次の呼び出しの上限（出力は残り予算まで、結論段階ではツールを使わせない）
+    /// 拡張思考を使う場合も、その budget_tokens はこの max_tokens の内側に収める
+    fn call_limits(&self, used: u64) -> CallLimits {
+        if self.limit == 0 {
+            return CallLimits { max_tokens: self.call_max_tokens, ..CallLimits::default() };
+        }
+        let remaining = self.limit.saturating_sub(used);
+        CallLimits {
+            max_tokens: (remaining.min(self.call_max_tokens as u64) as u32).max(MIN_CALL_MAX_TOKENS),
+            allow_tools: self.phase(used) == BudgetPhase::Normal,
+        }
+    }
+
+    /// 結論段階ではシステムプロンプトに打ち切りの指示を足す
+    fn system_prompt(&self, system: &str, used: u64) -> String {
+        if self.phase(used) == BudgetPhase::Normal {
+            system.to_string()
+        } else {
+            format!("{}\n\n{}", system, BUDGET_WRAP_UP_NOTE)
+        }
+    }
+
+    /// 予算消化状況を budget-progress で通知（無制限なら何もしない）
+    fn emit_progress(&self, ctx: &ToolContext, used: u64, loop_count: usize) {
+        if self.limit == 0 {
+            return;
+        }
+        let phase = match self.phase(used) {
+            BudgetPhase::Normal => "normal",
+            BudgetPhase::WrapUp => "wrap_up",
+            BudgetPhase::Exhausted => "exhausted",
+        };
+        ctx.emit("budget-progress", serde_json::json!({
+            "used_tokens": used,
+            "limit_tokens": self.limit,
+            "ratio": used as f64 / self.limit as f64,
+            "loop": loop_count + 1,
+            "phase": phase
+        }));
+    }
+}
+
+// ========================================
+// 使用量履歴とコスト見込み
+// ========================================
+
+const USAGE_HISTORY_FILE: &str = "usage_history.jsonl";
+const FORECAST_DEFAULT_WINDOW: usize = 20; // 1リクエストあたりコストの移動平均に使う件数
+const FORECAST_PACE_DAYS: u64 = 7; // リクエスト頻度を測る期間
+
+/// 1回のAPI利用（送信1回、または要約1回）の記録
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct UsageRecord {
+    timestamp: u64,
+    model: String,
+    input_tokens: u64,
+    output_tokens: u64,
+    #[serde(default)]
+    cache_creation_input_tokens: u64,
+    #[serde(default)]
+    cache_read_input_tokens: u64,
+    cost_usd: f64,
+}
+
+/// モデル単価（USD / 100万トークン）
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+struct ModelPrice {
+    input_usd_per_mtok: f64,
+    output_usd_per_mtok: f64,
+}
+
+/// モデルの単価（settings.toml の [pricing] → 組み込みの単価の順、どちらもなければ None）
+fn model_price(model: &str, overrides: &std::collections::HashMap<String, ModelPrice>) -> Option<ModelPrice> {
+    let spec = find_model(model);
+    overrides
+        .iter()
+        .find(|(key, _)| key.as_str() == model || find_model(key).zip(spec).is_some_and(|(a, b)| a.id == b.id))
+        .map(|(_, price)| *price)
+        .or_else(|| {
+            spec.map(|m| ModelPrice {
+                input_usd_per_mtok: m.input_usd_per_mtok,
+                output_usd_per_mtok: m.output_usd_per_mtok,
+            })
+        })
+}
+
+/// プロンプトキャッシュの入力単価に対する倍率（書き込みは割増、読み取りは割引）
+const CACHE_WRITE_PRICE_RATIO: f64 = 1.25;
+const CACHE_READ_PRICE_RATIO: f64 = 0.1;
+
+/// モデル単価からコストを計算（単価不明のモデルは 0）
+fn usage_cost_usd(model_id: &str, usage: &UsageInfo, pricing: &std::collections::HashMap<String, ModelPrice>) -> f64 {
+    model_price(model_id, pricing).map_or(0.0, |p| {
+        let input = usage.input_tokens as f64
+            + usage.cache_creation_input_tokens as f64 * CACHE_WRITE_PRICE_RATIO
+            + usage.cache_read_input_tokens as f64 * CACHE_READ_PRICE_RATIO;
+        (input * p.input_usd_per_mtok + usage.output_tokens as f64 * p.output_usd_per_mtok) / 1_000_000.0
+    })
+}
+
+/// usage_history.jsonl に1件追記（失敗しても送信処理は止めない）
+fn record_usage(app_handle: &tauri::AppHandle, model_id: &str, usage: &UsageInfo) {
+    if usage.prompt_tokens() == 0 && usage.output_tokens == 0 {
+        return;
+    }
+    let pricing = app_handle
+        .state::<Mutex<AppSettings>>()
+        .lock()
+        .map(|settings| settings.pricing.clone())
+        .unwrap_or_default();
+    let record = UsageRecord {
+        timestamp: now_unix_secs(),
+        model: model_id.to_string(),
+        input_tokens: usage.input_tokens,
+        output_tokens: usage.output_tokens,
+        cache_creation_input_tokens: usage.cache_creation_input_tokens,
+        cache_read_input_tokens: usage.cache_read_input_tokens,
+        cost_usd: usage_cost_usd(model_id, usage, &pricing),
+    };
+    let result = app_data_path(app_handle, USAGE_HISTORY_FILE).and_then(|path| append_jsonl(&path, &record));
+    if let Err(e) = result {
+        eprintln!("[Nexus] Failed to record usage: {}", e);
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct CostForecast {
+    /// 予測の前提（概算であることの明示）
+    note: String,
+    sample_requests: usize,
+    avg_cost_per_request_usd: f64,
+    requests_per_day: f64,
+    month_to_date_usd: f64,
+    projected_month_end_usd: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    monthly_limit_usd: Option<f64>,
+    /// このペースで予算に達するまでの残りリクエスト数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    requests_until_limit: Option<u64>,
+    /// 予算到達の予測日（YYYY-MM-DD、到達済みなら今日）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    projected_limit_date: Option<String>,
+}
+
+/// 使用量履歴から月末の着地見込みと予算到達予測を計算（純粋関数）
+fn build_cost_forecast(
+    records: &[UsageRecord],
+    now: chrono::DateTime<chrono::Local>,
+    window: usize,
+    monthly_limit_usd: f64,
+) -> CostForecast {
+    use chrono::{Datelike, TimeZone};
+
+    let month_start = chrono::Local
+        .with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .earliest()
+        .unwrap_or(now);
+    let next_month_start = chrono::Local
+        .with_ymd_and_hms(
+            if now.month() == 12 { now.year() + 1 } else { now.year() },
+            now.month() % 12 + 1,
+            1,
+            0,
+            0,
+            0,
+        )
+        .earliest()
+        .unwrap_or(now);
+    let now_secs = now.timestamp().max(0) as u64;
+
+    let month_to_date_usd: f64 = records
+        .iter()
+        .filter(|r| r.timestamp >= month_start.timestamp().max(0) as u64)
+        .map(|r| r.cost_usd)
+        .sum();
+
+    // 1リクエストあたりのコストは直近 window 件の移動平均
+    let recent = &records[records.len().saturating_sub(window.max(1))..];
+    let avg_cost = if recent.is_empty() {
+        0.0
+    } else {
+        recent.iter().map(|r| r.cost_usd).sum::<f64>() / recent.len() as f64
+    };
+
+    // 頻度は直近7日（履歴がそれより短ければ最初の記録から）の件数で見る
+    let pace_start = now_secs.saturating_sub(FORECAST_PACE_DAYS * 86_400);
+    let first = records.first().map_or(now_secs, |r| r.timestamp).max(pace_start);
+    let pace_days = ((now_secs.saturating_sub(first)) as f64 / 86_400.0).max(1.0);
+    let requests_per_day = records.iter().filter(|r| r.timestamp >= pace_start).count() as f64 / pace_days;
+    let daily_cost = requests_per_day * avg_cost;
+
+    let remaining_days = (next_month_start - now).num_seconds().max(0) as f64 / 86_400.0;
+    let projected_month_end_usd = month_to_date_usd + daily_cost * remaining_days;
+
+    let (monthly_limit, requests_until_limit, projected_limit_date) = if monthly_limit_usd > 0.0 {
+        let remaining = (monthly_limit_usd - month_to_date_usd).max(0.0);
+        let requests = (avg_cost > 0.0).then(|| (remaining / avg_cost).floor() as u64);
+        let date = if remaining == 0.0 {
+            Some(now.format("%Y-%m-%d").to_string())
+        } else if daily_cost > 0.0 {
+            let secs = (remaining / daily_cost * 86_400.0) as i64;
+            Some((now + chrono::Duration::seconds(secs)).format("%Y-%m-%d").to_string())
+        } else {
+            None
+        };
+        (Some(monthly_limit_usd), requests, date)
+    } else {
+        (None, None, None)
+    };
+
+    CostForecast {
+        note: format!(
+            "直近{}件の平均コストと過去{}日間の利用ペースから算出した概算です。実際の請求額とは異なる場合があります",
+            recent.len(),
+            FORECAST_PACE_DAYS
+        ),
+        sample_requests: recent.len(),
+        avg_cost_per_request_usd: avg_cost,
+        requests_per_day,
+        month_to_date_usd,
+        projected_month_end_usd,
+        monthly_limit_usd: monthly_limit,
+        requests_until_limit,
+        projected_limit_date,
+    }
+}
+
+/// このペースで使い続けた場合のコスト着地見込み（概算）
+#[tauri::command]
+fn get_cost_forecast(
+    window: Option<usize>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<CostForecast, String> {
+    let monthly_limit_usd = settings_state
+        .lock()
+        .map_err(|e| format!("Lock error: {}", e))?
+        .budget
+        .monthly_limit_usd;
+    let path = app_data_path(&app_handle, USAGE_HISTORY_FILE)?;
+    let records: Vec<UsageRecord> = read_jsonl(&path);
+    Ok(build_cost_forecast(
+        &records,
+        chrono::Local::now(),
+        window.unwrap_or(FORECAST_DEFAULT_WINDOW),
+        monthly_limit_usd,
+    ))
+}
+
+/// モデル別のコスト内訳
+#[derive(Serialize, Clone, Debug)]
+struct ModelCost {
+    model: String,
+    input_tokens: u64,
+    output_tokens: u64,
+    /// 単価が分からないモデルは None（コストは 0 として合計する）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price: Option<ModelPrice>,
+    cost_usd: f64,
+}
+
+/// セッションの累計コスト（概算）
+#[derive(Serialize, Clone, Debug)]
+struct CostEstimate {
+    /// 現在のモデル（内訳のないトークンはこの単価で計算）
+    model: String,
+    total_usd: f64,
+    by_model: Vec<ModelCost>,
+}
+
+/// トークン統計からモデル別のコストを計算（純粋関数）
+/// モデル別の内訳がない分（内訳を持たない古い統計など）は現在のモデルの単価で計算する
+fn build_cost_estimate(
+    stats: &TokenStats,
+    current_model: &str,
+    pricing: &std::collections::HashMap<String, ModelPrice>,
+) -> CostEstimate {
+    let mut usage: Vec<(String, UsageInfo)> = stats.by_model.iter().map(|(m, u)| (m.clone(), u.clone())).collect();
+    let attributed_input: u64 = usage.iter().map(|(_, u)| u.input_tokens).sum();
+    let attributed_output: u64 = usage.iter().map(|(_, u)| u.output_tokens).sum();
+    let rest = UsageInfo {
+        input_tokens: (stats.total_input_tokens + stats.summary_input_tokens).saturating_sub(attributed_input),
+        output_tokens: (stats.total_output_tokens + stats.summary_output_tokens).saturating_sub(attributed_output),
+        ..Default::default()
+    };
+    if rest.input_tokens > 0 || rest.output_tokens > 0 {
+        match usage.iter_mut().find(|(m, _)| m == current_model) {
+            Some((_, u)) => {
+                u.input_tokens += rest.input_tokens;
+                u.output_tokens += rest.output_tokens;
+            }
+            None => usage.push((current_model.to_string(), rest)),
+        }
+    }
+
+    let mut by_model: Vec<ModelCost> = usage
+        .into_iter()
+        .map(|(model, u)| ModelCost {
+            price: model_price(&model, pricing),
+            cost_usd: usage_cost_usd(&model, &u, pricing),
+            input_tokens: u.input_tokens,
+            output_tokens: u.output_tokens,
+            model,
+        })
+        .collect();
+    by_model.sort_by(|a, b| b.cost_usd.total_cmp(&a.cost_usd).then_with(|| a.model.cmp(&b.model)));
+    CostEstimate {
+        model: current_model.to_string(),
+        total_usd: by_model.iter().map(|c| c.cost_usd).sum(),
+        by_model,
+    }
+}
+
+/// セッションの累計コスト（USD、モデル別の内訳付き）
+#[tauri::command]
+fn get_cost_estimate(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+) -> Result<CostEstimate, String> {
+    let pricing = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.pricing.clone();
+    let session = sessions.get(session_id.as_deref())?;
+    let chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+    Ok(build_cost_estimate(&chat.token_stats, &chat.model, &pricing))
+}
+
+// ========================================
+// セッションの予算上限
+// ========================================
+
+/// 上限に対してこの割合に達したら budget-warning で知らせる
+const BUDGET_WARNING_RATIO: f64 = 0.8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BudgetLimitLevel {
+    Within,
+    /// 上限の BUDGET_WARNING_RATIO 以上
+    Warning,
+    /// 上限に達した（ちょうど上限も含む）: 以降の送信を止める
+    Reached,
+}
+
+fn budget_limit_level(cost_usd: f64, limit_usd: f64) -> BudgetLimitLevel {
+    if cost_usd >= limit_usd {
+        BudgetLimitLevel::Reached
+    } else if cost_usd >= limit_usd * BUDGET_WARNING_RATIO {
+        BudgetLimitLevel::Warning
+    } else {
+        BudgetLimitLevel::Within
+    }
+}
+
+/// 送信前の確認: 累計推定コストが上限に達していれば API を呼ばずにエラーを返す
+fn check_budget_limit(session: &Session, pricing: &std::collections::HashMap<String, ModelPrice>) -> Result<(), String> {
+    let chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+    let Some(limit) = chat.budget_limit_usd else {
+        return Ok(());
+    };
+    let cost = build_cost_estimate(&chat.token_stats, &chat.model, pricing).total_usd;
+    if budget_limit_level(cost, limit) == BudgetLimitLevel::Reached {
+        return Err(format!(
+            "予算上限に達しました（累計 ${:.4} / 上限 ${:.2}）。上限を変更するか、コスト累計をリセットしてください",
+            cost, limit
+        ));
+    }
+    Ok(())
+}
+
+/// 使用量の加算後に呼ぶ: 上限の BUDGET_WARNING_RATIO に初めて達したとき budget-warning を通知
+fn notify_budget_warning(
+    app_handle: &tauri::AppHandle,
+    session_id: &str,
+    chat: &mut ChatState,
+    pricing: &std::collections::HashMap<String, ModelPrice>,
+) {
+    let Some(limit) = chat.budget_limit_usd else {
+        return;
+    };
+    let cost = build_cost_estimate(&chat.token_stats, &chat.model, pricing).total_usd;
+    let level = budget_limit_level(cost, limit);
+    if level == BudgetLimitLevel::Within || chat.budget_warned {
+        return;
+    }
+    chat.budget_warned = true;
+    let _ = app_handle.emit("budget-warning", serde_json::json!({
+        "session_id": session_id,
+        "cost_usd": cost,
+        "limit_usd": limit,
+        "ratio": cost / limit,
+        "reached": level == BudgetLimitLevel::Reached
+    }));
+}
+
+/// セッションの予算上限を設定（None で解除）。すでに警告の割合を超えていればすぐに通知する
+#[tauri::command]
+fn set_budget_limit(
+    limit_usd: Option<f64>,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    settings_state: State<'_, Mutex<AppSettings>>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    if limit_usd.is_some_and(|limit| !limit.is_finite() || limit <= 0.0) {
+        return Err("予算上限は0より大きい金額（USD）で指定してください".to_string());
+    }
+    let pricing = settings_state.lock().map_err(|e| format!("Lock error: {}", e))?.pricing.clone();
+    let session = sessions.get(session_id.as_deref())?;
+    {
+        let mut chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+        chat.budget_limit_usd = limit_usd;
+        chat.budget_warned = false;
+        notify_budget_warning(&app_handle, &session.id, &mut chat, &pricing);
+    }
+    if let Err(e) = save_session_to_disk(&app_handle, &session) {
+        eprintln!("[Nexus] Warning: session save failed: {}", e);
+    }
+    Ok(())
+}
+
+// ========================================
+// コマンド実行フック（machines.toml の pre_command_hook / post_command_hook）
+// ========================================
+
+const HOOK_AUDIT_LOG_FILE: &str = "hook_audit.jsonl";
+const DEFAULT_HOOK_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HookPhase {
+    Pre,
+    Post,
+}
+
+impl HookPhase {
+    fn name(self) -> &'static str {
+        match self {
+            HookPhase::Pre => "pre",
+            HookPhase::Post => "post",
+        }
+    }
+}
+
+/// フック1回分の実行記録（hook_audit.jsonl に1行ずつ追記）
+#[derive(Serialize, Clone, Debug)]
+struct HookAuditRecord {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    trace_id: String,
+    phase: &'static str,
+    machine_name: String,
+    command: String,
+    purpose: &'static str,
+    hook: String, // プレースホルダ展開後のフックコマンド
+    exit_code: Option<i32>,
+    timed_out: bool,
+    stdout: String,
+    stderr: String,
+    executed_at: u64,
+}
+
+impl HookAuditRecord {
+    fn succeeded(&self) -> bool {
+        !self.timed_out && self.exit_code == Some(0)
+    }
+}
+
+/// ローカル（このPC）のシェル用のクォート
+fn escape_for_local_shell(value: &str) -> String {
+    if cfg!(windows) {
+        escape_for_cmd(value)
+    } else {
+        escape_for_bash(value)
+    }
+}
+
+/// {machine} {host} {purpose} {status} {command} を展開（値はローカルシェル用にクォート済みで埋め込む）
+/// {status} は post フックのみ "success" / "failure"、pre フックでは空文字
+/// コマンド本文に含まれる {...} を展開しないよう {command} は最後に置換する
+fn expand_hook(template: &str, machine: &SshMachineConfig, command: &str, purpose: &str, success: Option<bool>) -> String {
+    let status = match success {
+        Some(true) => "success",
+        Some(false) => "failure",
+        None => "",
+    };
+    template
+        .replace("{machine}", &escape_for_local_shell(&machine.name))
+        .replace("{host}", &escape_for_local_shell(&machine.host))
+        .replace("{purpose}", &escape_for_local_shell(purpose))
+        .replace("{status}", &escape_for_local_shell(status))
+        .replace("{command}", &escape_for_local_shell(command))
+}
+
+/// フックをローカルで実行（未設定なら None）。値は環境変数 NEXUS_* でも渡す
+async fn run_command_hook(
+    phase: HookPhase,
+    machine: &SshMachineConfig,
+    command: &str,
+    purpose: Option<ToolPurpose>,
+    success: Option<bool>,
+    app_handle: &tauri::AppHandle,
+    trace_id: &str,
+) -> Option<HookAuditRecord> {
+    let template = match phase {
+        HookPhase::Pre => machine.pre_command_hook.as_deref(),
+        HookPhase::Post => machine.post_command_hook.as_deref(),
+    }?;
+    let purpose = purpose.map_or("", ToolPurpose::name);
+    let hook = expand_hook(template, machine, command, purpose, success);
+    let timeout_secs = machine.hook_timeout_secs.unwrap_or(DEFAULT_HOOK_TIMEOUT_SECS);
+
+    #[cfg(windows)]
+    let mut process = {
+        let mut process = TokioCommand::new("cmd");
+        process.args(["/d", "/s", "/c"]).raw_arg(format!("\"{}\"", hook));
+        process
+    };
+    #[cfg(not(windows))]
+    let mut process = {
+        let mut process = TokioCommand::new("sh");
+        process.arg("-c").arg(&hook);
+        process
+    };
+    process
+        .env("NEXUS_HOOK_PHASE", phase.name())
+        .env("NEXUS_MACHINE", &machine.name)
+        .env("NEXUS_HOST", &machine.host)
+        .env("NEXUS_COMMAND", command)
+        .env("NEXUS_PURPOSE", purpose)
+        .env("NEXUS_TRACE_ID", trace_id)
+        .env("NEXUS_STATUS", success.map_or("", |s| if s { "success" } else { "failure" }))
+        .stdin(std::process::Stdio::null())
+        .kill_on_drop(true);
+
+    let mut record = HookAuditRecord {
+        trace_id: trace_id.to_string(),
+        phase: phase.name(),
+        machine_name: machine.name.clone(),
+        command: command.to_string(),
+        purpose,
+        hook,
+        exit_code: None,
+        timed_out: false,
+        stdout: String::new(),
+        stderr: String::new(),
+        executed_at: now_unix_secs(),
+    };
+    match timeout(Duration::from_secs(timeout_secs), process.output()).await {
+        Ok(Ok(output)) => {
+            record.exit_code = output.status.code();
+            record.stdout = decode_bytes(&output.stdout);
+            record.stderr = decode_bytes(&output.stderr);
+        }
+        Ok(Err(e)) => record.stderr = format!("フックを起動できません: {}", e),
+        Err(_) => {
+            record.timed_out = true;
+            record.stderr = format!("フックが{}秒以内に終了しませんでした", timeout_secs);
+        }
+    }
+
+    if !record.succeeded() {
+        eprintln!(
+            "[Nexus] {}_command_hook failed on {} (exit {:?}, timed_out {})",
+            record.phase, machine.name, record.exit_code, record.timed_out
+        );
+    }
+    match app_data_path(app_handle, HOOK_AUDIT_LOG_FILE) {
+        Ok(path) => {
+            if let Err(e) = append_jsonl(&path, &record) {
+                eprintln!("[Nexus] Warning: failed to record hook audit: {}", e);
+            }
+        }
+        Err(e) => eprintln!("[Nexus] Warning: {}", e),
+    }
+    Some(record)
+}
+
+/// pre フック失敗時にモデルへ返す理由（タイムアウト / 終了コード + stderr 先頭行）
+fn pre_hook_failure_detail(record: &HookAuditRecord) -> String {
+    let first_line = record.stderr.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or("");
+    match record.exit_code {
+        Some(code) if first_line.is_empty() => format!("exit {}", code),
+        Some(code) => format!("exit {}: {}", code, first_line),
+        None => first_line.to_string(),
+    }
+}
+
+// ========================================
+// 応答テキストの注釈（URL・パス・マシン名・コマンドの検出）
+// ========================================
+
+/// 応答テキスト中の検出箇所（フロントでボタン等に変換する）
+/// 範囲は文字単位（Unicode スカラー値）の [start, end)
+#[derive(Serialize, Clone, Debug)]
+struct TextAnnotation {
+    kind: &'static str, // "url" | "path" | "machine" | "command"
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+/// コマンドとして扱うコードブロックの言語名
+const SHELL_FENCE_LANGS: &[&str] = &["bash", "sh", "shell", "console", "zsh", "powershell", "ps1", "pwsh", "cmd", "bat"];
+
+struct AnnotationPatterns {
+    url: regex::Regex,
+    windows_path: regex::Regex,
+    unix_path: regex::Regex,
+    fence: regex::Regex,
+    inline_code: regex::Regex,
+}
+
+/// 誤検出を避けるため保守的なパターンのみ（パスはASCIIかつ2階層以上、インラインコードは空白を含むもののみコマンド扱い）
+fn annotation_patterns() -> &'static AnnotationPatterns {
+    static PATTERNS: std::sync::OnceLock<AnnotationPatterns> = std::sync::OnceLock::new();
+    PATTERNS.get_or_init(|| AnnotationPatterns {
+        url: regex::Regex::new(r#"https?://[A-Za-z0-9\-._~:/?#\[\]@!$&'*+,;=%]+"#).unwrap(),
+        windows_path: regex::Regex::new(r"(?:^|[^A-Za-z0-9_])([A-Za-z]:\\(?:[A-Za-z0-9_.\-$~]+\\)*[A-Za-z0-9_.\-$~]+\\?)").unwrap(),
+        unix_path: regex::Regex::new(r#"(?:^|[\s(`'"「（])(~?/[A-Za-z0-9_.\-]+(?:/[A-Za-z0-9_.\-]+)+/?)"#).unwrap(),
+        fence: regex::Regex::new(r"(?ms)^```[ \t]*([A-Za-z0-9]*)[ \t]*\n(.*?)^```").unwrap(),
+        inline_code: regex::Regex::new(r"`([^`\n]+)`").unwrap(),
+    })
+}
+
+/// 応答テキストから URL・パス・マシン名・コマンドを検出
+/// 重なる場合は url > command > path > machine の優先順で1つだけ残す
+fn annotate_response(text: &str, machine_names: &[&str]) -> Vec<TextAnnotation> {
+    let patterns = annotation_patterns();
+    // (kind, バイト範囲)
+    let mut candidates: Vec<(&'static str, std::ops::Range<usize>)> = Vec::new();
+
+    for m in patterns.url.find_iter(text) {
+        // 文末の句読点・閉じ括弧はURLに含めない
+        let trimmed = m.as_str().trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '\'']);
+        candidates.push(("url", m.start()..m.start() + trimmed.len()));
+    }
+
+    let fences: Vec<std::ops::Range<usize>> = patterns.fence.captures_iter(text).map(|c| c.get(0).unwrap().range()).collect();
+    for caps in patterns.fence.captures_iter(text) {
+        let lang = caps.get(1).map_or("", |m| m.as_str()).to_lowercase();
+        if !SHELL_FENCE_LANGS.contains(&lang.as_str()) {
+            continue;
+        }
+        let body = caps.get(2).unwrap();
+        let mut offset = body.start();
+        for line in body.as_str().split_inclusive('\n') {
+            let content = line.trim_end();
+            let stripped = ["$ ", "PS> ", "> "]
+                .iter()
+                .find_map(|prompt| content.trim_start().strip_prefix(prompt))
+                .unwrap_or(content.trim_start());
+            let command = stripped.trim();
+            if !command.is_empty() && !command.starts_with('#') && !command.starts_with("REM ") {
+                let start = offset + (content.len() - stripped.len()) + (stripped.len() - stripped.trim_start().len());
+                candidates.push(("command", start..start + command.len()));
+            }
+            offset += line.len();
+        }
+    }
+
+    for caps in patterns.inline_code.captures_iter(text) {
+        let inner = caps.get(1).unwrap();
+        if fences.iter().any(|f| f.contains(&inner.start())) {
+            continue;
+        }
+        let code = inner.as_str();
+        let starts_like_command = code.starts_with(|c: char| c.is_ascii_alphabetic() || c == '.' || c == '/');
+        if starts_like_command && code.trim() == code && code.contains(' ') {
+            candidates.push(("command", inner.range()));
+        }
+    }
+
+    for re in [&patterns.windows_path, &patterns.unix_path] {
+        for caps in re.captures_iter(text) {
+            let path = caps.get(1).unwrap();
+            let trimmed = path.as_str().trim_end_matches('.');
+            candidates.push(("path", path.start()..path.start() + trimmed.len()));
+        }
+    }
+
+    for name in machine_names.iter().filter(|n| !n.is_empty()) {
+        let pattern = format!(r"(?:^|[^A-Za-z0-9_\-])({})(?:[^A-Za-z0-9_\-]|$)", regex::escape(name));
+        let Ok(re) = regex::Regex::new(&pattern) else { continue };
+        let mut from = 0;
+        // 区切り文字を消費するため、連続出現に備えて1つずつ位置をずらして探す
+        while let Some(caps) = re.captures_at(text, from) {
+            let m = caps.get(1).unwrap();
+            candidates.push(("machine", m.range()));
+            from = m.end();
+        }
+    }
 
-    for loop_count in 0..MAX_TOOL_LOOPS {
-        let api_resp =
-            call_anthropic(&api_key, &model, &system_prompt, &tools, &api_messages).await?;
+    let priority = |kind: &str| ["url", "command", "path", "machine"].iter().position(|k| *k == kind).unwrap_or(usize::MAX);
+    candidates.sort_by_key(|(kind, range)| (priority(kind), range.start));
+    let mut accepted: Vec<(&'static str, std::ops::Range<usize>)> = Vec::new();
+    for (kind, range) in candidates {
+        if range.is_empty() || accepted.iter().any(|(_, r)| r.start < range.end && range.start < r.end) {
+            continue;
+        }
+        accepted.push((kind, range));
+    }
+    accepted.sort_by_key(|(_, range)| range.start);
+
+    accepted
+        .into_iter()
+        .map(|(kind, range)| TextAnnotation {
+            kind,
+            start: text[..range.start].chars().count(),
+            end: text[..range.end].chars().count(),
+            text: text[range].to_string(),
+        })
+        .collect()
+}
 
-        // トークン使用量を累積
-        if let Some(usage) = &api_resp.usage {
-            total_usage.input_tokens += usage.input_tokens;
-            total_usage.output_tokens += usage.output_tokens;
-            last_call_input_tokens = usage.input_tokens; // 最新のAPIコールのinput_tokensを記録
+// ========================================
+// デスクトップ通知（ウィンドウ非表示中の完了・失敗）
+// ========================================
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum NotifyMode {
+    Always,   // 応答完了・失敗のどちらも通知
+    Failures, // 失敗（応答エラー・ツール実行失敗）のみ
+}
+
+/// デスクトップ通知（ウィンドウ表示中は出さない）
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct NotificationSettings {
+    enabled: bool,
+    mode: NotifyMode,
+    /// この間隔内に続いた通知は1件にまとめて出す
+    min_interval_secs: u64,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mode: NotifyMode::Always,
+            min_interval_secs: 30,
         }
+    }
+}
 
-        // レスポンスのcontentブロックを解析
-        let mut tool_uses: Vec<(String, String, serde_json::Value)> = Vec::new(); // (id, name, input)
+/// 通知のまとめ送り状態
+#[derive(Default)]
+struct NotificationQueue {
+    last_sent: Option<std::time::Instant>,
+    pending: Vec<String>,
+    flush_scheduled: bool,
+    /// 通知後まだウィンドウを開いていない（トレイのシングルクリックで表示する）
+    unread: bool,
+}
 
-        for block in &api_resp.content {
-            if let Some(block_type) = block.get("type").and_then(|t| t.as_str()) {
-                match block_type {
-                    "text" => {
-                        if let Some(text) = block.get("text").and_then(|t| t.as_str()) {
-                            all_text_parts.push(text.to_string());
-                        }
-                    }
-                    "tool_use" => {
-                        let id = block
-                            .get("id")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("")
-                            .to_string();
-                        let name = block
-                            .get("name")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("")
-                            .to_string();
-                        let input = block
-                            .get("input")
-                            .cloned()
-                            .unwrap_or(serde_json::json!({}));
-                        tool_uses.push((id, name, input));
-                    }
-                    _ => {}
-                }
+#[derive(Default)]
+struct NotificationState {
+    queue: Mutex<NotificationQueue>,
+}
+
+fn main_window_visible(app_handle: &tauri::AppHandle) -> bool {
+    app_handle
+        .get_webview_window("main")
+        .is_some_and(|w| w.is_visible().unwrap_or(true))
+}
+
+fn show_notification(app_handle: &tauri::AppHandle, body: &str) {
+    if let Err(e) = app_handle.notification().builder().title("Project Nexus").body(body).show() {
+        eprintln!("[Nexus] Warning: notification failed: {}", e);
+    }
+}
+
+/// ウィンドウ非表示中のみ通知する。min_interval_secs 以内の後続はまとめて間隔明けに1件で出す
+fn notify_if_hidden(app_handle: &tauri::AppHandle, failure: bool, body: String) {
+    let settings = match app_handle.state::<Mutex<AppSettings>>().lock() {
+        Ok(settings) => settings.notifications.clone(),
+        Err(_) => return,
+    };
+    if !settings.enabled || (!failure && settings.mode == NotifyMode::Failures) || main_window_visible(app_handle) {
+        return;
+    }
+
+    let state = app_handle.state::<NotificationState>();
+    let Ok(mut queue) = state.queue.lock() else { return };
+    queue.unread = true;
+    let interval = Duration::from_secs(settings.min_interval_secs);
+    let elapsed = queue.last_sent.map(|last| last.elapsed());
+    match elapsed {
+        Some(elapsed) if elapsed < interval => {
+            queue.pending.push(body);
+            if !queue.flush_scheduled {
+                queue.flush_scheduled = true;
+                let app_handle = app_handle.clone();
+                let wait = interval - elapsed;
+                tauri::async_runtime::spawn(async move {
+                    tokio::time::sleep(wait).await;
+                    flush_notifications(&app_handle);
+                });
             }
         }
+        _ => {
+            queue.last_sent = Some(std::time::Instant::now());
+            drop(queue);
+            show_notification(app_handle, &body);
+        }
+    }
+}
+
+/// 間隔内に溜まった通知を1件にまとめて出す
+fn flush_notifications(app_handle: &tauri::AppHandle) {
+    let pending = {
+        let state = app_handle.state::<NotificationState>();
+        let Ok(mut queue) = state.queue.lock() else { return };
+        queue.flush_scheduled = false;
+        queue.last_sent = Some(std::time::Instant::now());
+        std::mem::take(&mut queue.pending)
+    };
+    // 待っている間にウィンドウが開かれていれば不要
+    if pending.is_empty() || main_window_visible(app_handle) {
+        return;
+    }
+    let body = if pending.len() == 1 {
+        pending[0].clone()
+    } else {
+        let mut lines: Vec<String> = pending.iter().take(3).cloned().collect();
+        if pending.len() > 3 {
+            lines.push(format!("ほか {} 件", pending.len() - 3));
+        }
+        format!("{} 件の通知\n{}", pending.len(), lines.join("\n"))
+    };
+    show_notification(app_handle, &body);
+}
 
-        // アシスタント応答をメッセージ配列に追加（tool_useブロック含む）
-        api_messages.push(serde_json::json!({
-            "role": "assistant",
-            "content": api_resp.content
-        }));
+/// 未読の通知があれば既読にして true（トレイのシングルクリックで表示するかの判定）
+fn take_unread_notification(app_handle: &tauri::AppHandle) -> bool {
+    let state = app_handle.state::<NotificationState>();
+    let Ok(mut queue) = state.queue.lock() else { return false };
+    std::mem::take(&mut queue.unread)
+}
 
-        // ツール呼び出しがなければ終了
-        if tool_uses.is_empty() || api_resp.stop_reason.as_deref() != Some("tool_use") {
-            break;
+fn notification_excerpt(text: &str) -> String {
+    const MAX_CHARS: usize = 80;
+    let line = text.lines().map(str::trim).find(|l| !l.is_empty()).unwrap_or("");
+    if line.chars().count() > MAX_CHARS {
+        format!("{}…", line.chars().take(MAX_CHARS).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
+
+/// 送信完了（部分応答・エラーを含む）の通知
+fn notify_send_result(app_handle: &tauri::AppHandle, result: Result<&SendMessageResponse, &str>) {
+    match result {
+        Ok(response) => match &response.error {
+            Some(error) => notify_if_hidden(app_handle, true, format!("応答が途中で中断しました: {}", notification_excerpt(error))),
+            None => notify_if_hidden(app_handle, false, format!("応答が完了しました: {}", notification_excerpt(&response.text))),
+        },
+        Err(error) => notify_if_hidden(app_handle, true, format!("応答に失敗しました: {}", notification_excerpt(error))),
+    }
+}
+
+// ========================================
+// ツール実行の目的タグと監査ログ
+// ========================================
+
+const TOOL_AUDIT_LOG_FILE: &str = "tool_audit.jsonl";
+
+/// ツール入力で申告させる実行目的（変更系の集計・承認の強化に使う）
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum ToolPurpose {
+    Investigate, // 調査（情報収集）
+    Change,      // 変更（設定・ファイル・サービス状態を変える）
+    Verify,      // 確認（変更後の検証）
+}
+
+impl ToolPurpose {
+    const ALL: [ToolPurpose; 3] = [ToolPurpose::Investigate, ToolPurpose::Change, ToolPurpose::Verify];
+
+    fn name(self) -> &'static str {
+        match self {
+            ToolPurpose::Investigate => "investigate",
+            ToolPurpose::Change => "change",
+            ToolPurpose::Verify => "verify",
         }
+    }
 
-        // ループ上限チェック
-        if loop_count >= MAX_TOOL_LOOPS - 1 {
-            all_text_parts
-                .push("\n⚠️ ツール実行回数が上限に達しました。".to_string());
-            break;
+    fn from_input(input: &serde_json::Value) -> Option<Self> {
+        input.get("purpose").and_then(|v| serde_json::from_value(v.clone()).ok())
+    }
+
+    /// 承認フロー等で慎重に扱うべき目的か
+    fn is_change(self) -> bool {
+        self == ToolPurpose::Change
+    }
+}
+
+/// ツール実行1件の監査記録（tool_audit.jsonl に追記）
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ToolAuditRecord {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    trace_id: String,
+    execution_id: String,
+    machine_name: String,
+    command: String,
+    purpose: Option<ToolPurpose>,
+    success: bool,
+    executed_at: u64,
+}
+
+fn record_tool_audit(app_handle: &tauri::AppHandle, exec: &ToolExecution, trace_id: &str) {
+    let record = ToolAuditRecord {
+        trace_id: trace_id.to_string(),
+        execution_id: exec.execution_id.clone(),
+        machine_name: exec.machine_name.clone(),
+        command: mask_secrets(&exec.command),
+        purpose: exec.purpose,
+        success: exec.success,
+        executed_at: now_unix_secs(),
+    };
+    match app_data_path(app_handle, TOOL_AUDIT_LOG_FILE) {
+        Ok(path) => {
+            if let Err(e) = append_jsonl(&path, &record) {
+                eprintln!("[Nexus] Warning: failed to record tool audit: {}", e);
+            }
         }
+        Err(e) => eprintln!("[Nexus] Warning: {}", e),
+    }
+}
 
-        // ツール実行
-        let mut tool_results: Vec<serde_json::Value> = Vec::new();
+#[derive(Serialize, Clone, Debug, Default)]
+struct PurposeCount {
+    total: usize,
+    failed: usize,
+}
 
-        for (tool_id, tool_name, tool_input) in &tool_uses {
-            let machine_name = tool_input
-                .get("machine_name")
-                .and_then(|v| v.as_str())
-                .unwrap_or("unknown");
-            let command = tool_input
-                .get("command")
-                .and_then(|v| v.as_str())
-                .unwrap_or("");
-
-            // フロントエンドに実行中イベントを送信
-            let _ = app_handle.emit(
-                "tool-executing",
-                ToolExecutingEvent {
-                    machine_name: machine_name.to_string(),
-                    command: command.to_string(),
-                },
-            );
+#[derive(Serialize, Clone, Debug)]
+struct ToolPurposeStats {
+    since: u64,
+    total: usize,
+    /// 目的 → 件数（申告なしは "unspecified"）
+    by_purpose: std::collections::BTreeMap<String, PurposeCount>,
+    /// マシン名 → 変更系の件数
+    changes_by_machine: std::collections::BTreeMap<String, usize>,
+}
+
+fn aggregate_tool_purposes(records: &[ToolAuditRecord], since: u64) -> ToolPurposeStats {
+    let mut by_purpose: std::collections::BTreeMap<String, PurposeCount> = ToolPurpose::ALL
+        .iter()
+        .map(|p| (p.name().to_string(), PurposeCount::default()))
+        .collect();
+    let mut changes_by_machine = std::collections::BTreeMap::new();
+    let mut total = 0;
+    for record in records.iter().filter(|r| r.executed_at >= since) {
+        total += 1;
+        let key = record.purpose.map_or("unspecified", ToolPurpose::name);
+        let count = by_purpose.entry(key.to_string()).or_default();
+        count.total += 1;
+        if !record.success {
+            count.failed += 1;
+        }
+        if record.purpose.is_some_and(ToolPurpose::is_change) {
+            *changes_by_machine.entry(record.machine_name.clone()).or_insert(0) += 1;
+        }
+    }
+    ToolPurposeStats { since, total, by_purpose, changes_by_machine }
+}
 
-            if tool_name == "execute_remote_command" {
-                let exec_result = execute_tool_ssh(machine_name, command, &machines).await;
+/// 目的別のツール実行件数（days 日前から、未指定なら全期間）
+#[tauri::command]
+fn get_tool_purpose_stats(days: Option<u64>, app_handle: tauri::AppHandle) -> Result<ToolPurposeStats, String> {
+    let path = app_data_path(&app_handle, TOOL_AUDIT_LOG_FILE)?;
+    let records: Vec<ToolAuditRecord> = read_jsonl(&path);
+    let since = days.map_or(0, |d| now_unix_secs().saturating_sub(d * 86_400));
+    Ok(aggregate_tool_purposes(&records, since))
+}
 
-                // 実行完了イベント
-                let _ = app_handle.emit(
-                    "tool-completed",
-                    ToolCompletedEvent {
-                        machine_name: machine_name.to_string(),
-                        command: command.to_string(),
-                        success: exec_result.success,
-                    },
-                );
+// ========================================
+// Claude のツール選択の記録と分析
+// ========================================
 
-                // tool_resultの content を構築
-                let result_text = if exec_result.success {
-                    if exec_result.stdout.is_empty() {
-                        "(コマンド成功・出力なし)".to_string()
-                    } else {
-                        exec_result.stdout.clone()
-                    }
-                } else {
-                    format!(
-                        "エラー: {}{}",
-                        exec_result.stderr,
-                        if !exec_result.stdout.is_empty() {
-                            format!("\nstdout: {}", exec_result.stdout)
-                        } else {
-                            String::new()
-                        }
-                    )
-                };
+const TOOL_DECISIONS_LOG_FILE: &str = "tool_decisions.jsonl";
+/// 失敗率の一覧に載せる最小の呼び出し回数（1回だけの失敗で 100% と出ないように）
+const TOOL_FAILURE_MIN_CALLS: usize = 3;
+/// 記録するエラー内容の最大文字数
+const TOOL_DECISION_ERROR_MAX_CHARS: usize = 300;
 
-                tool_results.push(serde_json::json!({
-                    "type": "tool_result",
-                    "tool_use_id": tool_id,
-                    "content": result_text,
-                    "is_error": !exec_result.success
-                }));
+/// tool_use 1件の記録（tool_decisions.jsonl に追記。入力・エラーは秘匿情報をマスク済み）
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ToolDecisionRecord {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    trace_id: String,
+    tool_name: String,
+    input: serde_json::Value,
+    /// 実行したコマンド（入力の command、なければアプリが組み立てたコマンド）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+    success: bool,
+    /// 失敗時に Claude に返した内容の先頭（入力検証エラー等、よく間違えるパターンの分析用）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    timestamp: u64,
+}
 
-                all_tool_executions.push(exec_result);
-            } else {
-                // 未知のツール
-                tool_results.push(serde_json::json!({
-                    "type": "tool_result",
-                    "tool_use_id": tool_id,
-                    "content": format!("未知のツール: {}", tool_name),
-                    "is_error": true
-                }));
+/// JSON 内の文字列をすべてマスク（全体を文字列としてマスクすると JSON が壊れることがあるため値ごとに通す）
+fn mask_json_strings(value: &serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(s) => serde_json::Value::String(mask_secrets(s)),
+        serde_json::Value::Array(items) => serde_json::Value::Array(items.iter().map(mask_json_strings).collect()),
+        serde_json::Value::Object(map) => {
+            serde_json::Value::Object(map.iter().map(|(k, v)| (k.clone(), mask_json_strings(v))).collect())
+        }
+        other => other.clone(),
+    }
+}
+
+fn record_tool_decision(
+    app_handle: &tauri::AppHandle,
+    tool_name: &str,
+    input: &serde_json::Value,
+    tool_result: &serde_json::Value,
+    executions: &[ToolExecution],
+    trace_id: &str,
+) {
+    let success = tool_result["is_error"].as_bool() != Some(true);
+    let command = input["command"].as_str().or_else(|| executions.first().map(|e| e.command.as_str()));
+    let error = (!success).then(|| {
+        let content = tool_result["content"].as_str().unwrap_or_default();
+        mask_secrets(&content.chars().take(TOOL_DECISION_ERROR_MAX_CHARS).collect::<String>())
+    });
+    let record = ToolDecisionRecord {
+        trace_id: trace_id.to_string(),
+        tool_name: tool_name.to_string(),
+        input: mask_json_strings(input),
+        command: command.map(mask_secrets),
+        success,
+        error,
+        timestamp: now_unix_secs(),
+    };
+    match app_data_path(app_handle, TOOL_DECISIONS_LOG_FILE) {
+        Ok(path) => {
+            if let Err(e) = append_jsonl(&path, &record) {
+                eprintln!("[Nexus] Warning: failed to record tool decision: {}", e);
             }
         }
+        Err(e) => eprintln!("[Nexus] Warning: {}", e),
+    }
+}
 
-        // ツール結果をuserメッセージとして追加
-        api_messages.push(serde_json::json!({
-            "role": "user",
-            "content": tool_results
-        }));
+/// コマンドの集計キー（先頭のプログラム名。パス・拡張子・大文字小文字の違いはまとめる）
+fn command_usage_key(command: &str) -> Option<String> {
+    let program = command.split_whitespace().next()?;
+    let base = program.rsplit(['/', '\\']).next().unwrap_or(program).to_lowercase();
+    let base = base.strip_suffix(".exe").unwrap_or(&base);
+    (!base.is_empty()).then(|| base.to_string())
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+struct CommandUsage {
+    /// 集計キー（コマンドの先頭のプログラム名）
+    command: String,
+    total: usize,
+    failed: usize,
+    failure_rate: f64,
+    /// 直近の呼び出し例（失敗の一覧では直近の失敗例）
+    example: String,
+}
+
+#[derive(Serialize, Clone, Debug)]
+struct ToolUsageAnalytics {
+    since: u64,
+    total: usize,
+    /// ツール名 → 件数
+    by_tool: std::collections::BTreeMap<String, PurposeCount>,
+    /// よく使われるコマンド（回数の多い順）
+    top_commands: Vec<CommandUsage>,
+    /// 失敗率の高いコマンド（TOOL_FAILURE_MIN_CALLS 回以上呼ばれたもの、失敗率・回数の高い順）
+    most_failed_commands: Vec<CommandUsage>,
+}
+
+fn aggregate_tool_decisions(records: &[ToolDecisionRecord], since: u64, limit: usize) -> ToolUsageAnalytics {
+    let mut by_tool: std::collections::BTreeMap<String, PurposeCount> = std::collections::BTreeMap::new();
+    let mut commands: std::collections::HashMap<String, (CommandUsage, String)> = std::collections::HashMap::new();
+    let mut total = 0;
+    for record in records.iter().filter(|r| r.timestamp >= since) {
+        total += 1;
+        let count = by_tool.entry(record.tool_name.clone()).or_default();
+        count.total += 1;
+        if !record.success {
+            count.failed += 1;
+        }
+        let Some(command) = record.command.as_deref() else {
+            continue;
+        };
+        let Some(key) = command_usage_key(command) else {
+            continue;
+        };
+        let (usage, last_failure) = commands.entry(key.clone()).or_insert_with(|| {
+            (CommandUsage { command: key, ..Default::default() }, String::new())
+        });
+        usage.total += 1;
+        usage.example = command.to_string();
+        if !record.success {
+            usage.failed += 1;
+            *last_failure = command.to_string();
+        }
     }
 
-    // 最終テキスト
-    let final_text = all_text_parts.join("");
-    let final_text = if final_text.is_empty() {
-        "(空の応答が返されました)".to_string()
-    } else {
-        final_text
+    let usages: Vec<(CommandUsage, String)> = commands
+        .into_values()
+        .map(|(mut usage, last_failure)| {
+            usage.failure_rate = (usage.failed as f64 / usage.total as f64 * 1000.0).round() / 1000.0;
+            (usage, last_failure)
+        })
+        .collect();
+    let mut top_commands: Vec<CommandUsage> = usages.iter().map(|(u, _)| u.clone()).collect();
+    top_commands.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.command.cmp(&b.command)));
+    top_commands.truncate(limit);
+    let mut most_failed_commands: Vec<CommandUsage> = usages
+        .into_iter()
+        .filter(|(u, _)| u.failed > 0 && u.total >= TOOL_FAILURE_MIN_CALLS)
+        .map(|(usage, last_failure)| CommandUsage { example: last_failure, ..usage })
+        .collect();
+    most_failed_commands.sort_by(|a, b| {
+        b.failure_rate
+            .total_cmp(&a.failure_rate)
+            .then_with(|| b.total.cmp(&a.total))
+            .then_with(|| a.command.cmp(&b.command))
+    });
+    most_failed_commands.truncate(limit);
+
+    ToolUsageAnalytics { since, total, by_tool, top_commands, most_failed_commands }
+}
+
+/// Claude のツール選択の傾向（よく使われるコマンド・失敗率の高いコマンド）
+/// days 日前から（未指定なら全期間）、各一覧は limit 件まで（未指定なら 10）
+#[tauri::command]
+fn get_tool_usage_analytics(
+    days: Option<u64>,
+    limit: Option<usize>,
+    app_handle: tauri::AppHandle,
+) -> Result<ToolUsageAnalytics, String> {
+    let path = app_data_path(&app_handle, TOOL_DECISIONS_LOG_FILE)?;
+    let records: Vec<ToolDecisionRecord> = read_jsonl(&path);
+    let since = days.map_or(0, |d| now_unix_secs().saturating_sub(d * 86_400));
+    Ok(aggregate_tool_decisions(&records, since, limit.unwrap_or(10)))
+}
+
+// ========================================
+// 破壊的コマンドの承認
+// ========================================
+
+/// danger_patterns に一致したコマンドは実行前にユーザーの承認を待つ
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+struct ApprovalSettings {
+    enabled: bool,
+    /// 承認が必要なコマンドの正規表現（部分一致）
+    danger_patterns: Vec<String>,
+    /// この秒数内に応答がなければ拒否扱い
+    timeout_secs: u64,
+    /// 同じターンで承認が必要なコマンドが複数あれば、実行前にまとめて1回で承認を求める
+    batch: bool,
+}
+
+impl Default for ApprovalSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            danger_patterns: [
+                r"\brm\s+(-[a-zA-Z]*[rf][a-zA-Z]*\s+)+",
+                r"(?i)\bRemove-Item\b",
+                r"(?i)\b(del|erase)\s+(/[a-z]\s+)*/s\b",
+                r"(?i)\b(rmdir|rd)\s+(/[a-z]\s+)*/s\b",
+                r"(?i)\bformat(\.com)?\s+[a-z]:",
+                r"\bmkfs(\.\w+)?\b",
+                r"\bdd\s+.*\bof=/dev/",
+                r"(?i)\b(shutdown|reboot|poweroff|halt)\b",
+                r"(?i)\b(Stop|Restart)-Computer\b",
+                r"(?i)\bdrop\s+(table|database|schema)\b",
+                r"(?i)\btruncate\s+table\b",
+            ]
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+            timeout_secs: 300,
+            batch: false,
+        }
+    }
+}
+
+/// 承認待ちへのユーザーの応答
+#[derive(Debug)]
+struct ApprovalDecision {
+    approved: bool,
+    reason: Option<String>,
+}
+
+/// コマンドが一致した最初の danger_pattern（不正なパターンは警告して無視）
+fn find_danger_pattern<'a>(command: &str, patterns: &'a [String]) -> Option<&'a str> {
+    patterns.iter().map(String::as_str).find(|pattern| match regex::Regex::new(pattern) {
+        Ok(re) => re.is_match(command),
+        Err(e) => {
+            eprintln!("[Nexus] Warning: invalid danger pattern '{}': {}", pattern, e);
+            false
+        }
+    })
+}
+
+/// 破壊的コマンド（または required_reason 指定）なら tool-approval-required を通知して承認を待つ（該当しなければ即 Ok）
+/// 拒否・タイムアウト・キャンセル時は Claude に返す理由を Err で返す
+async fn await_tool_approval(
+    run: &ToolRun,
+    machine_name: &str,
+    command: &str,
+    purpose: Option<ToolPurpose>,
+    required_reason: Option<&str>,
+    ctx: &ToolContext,
+) -> Result<(), String> {
+    let config = &ctx.settings.approval;
+    if !config.enabled {
+        return Ok(());
+    }
+    let Some(pattern) = required_reason.or_else(|| find_danger_pattern(command, &config.danger_patterns)) else {
+        return Ok(());
     };
+    // このターンのバッチ承認で既に承認・拒否されていればそれに従う
+    if let Some(result) = batch_approval_result(ctx, machine_name, command) {
+        return result;
+    }
+    request_tool_approval(run, machine_name, command, purpose, ApprovalKind::Danger, pattern, ctx).await
+}
 
-    // 履歴とトークン統計を更新（最終テキストのみ保存）
-    let current_stats = {
-        let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
+/// 承認を求める理由の種類（フロントの表示を切り替える）
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ApprovalKind {
+    /// danger_patterns に一致した、またはツールが承認を必須とした（detail は一致したパターン・理由）
+    Danger,
+    /// 実行ユーザーが expected_user と異なる（detail は不一致の内容）
+    UserMismatch,
+}
 
-        chat.token_stats.last_input_tokens = last_call_input_tokens; // コンテキスト%用: 最後のAPIコールのみ
-        chat.token_stats.last_output_tokens = total_usage.output_tokens;
-        chat.token_stats.total_input_tokens += total_usage.input_tokens; // コスト計算用: 全ループ合計
-        chat.token_stats.total_output_tokens += total_usage.output_tokens;
-        chat.token_stats.request_count += 1;
+/// tool-approval-required を通知してユーザーの応答を待つ（バッチ承認の結果は見ない）
+async fn request_tool_approval(
+    run: &ToolRun,
+    machine_name: &str,
+    command: &str,
+    purpose: Option<ToolPurpose>,
+    kind: ApprovalKind,
+    detail: &str,
+    ctx: &ToolContext,
+) -> Result<(), String> {
+    let config = &ctx.settings.approval;
+    let (execution_id, sequence, mut cancel) = (run.execution_id.as_str(), run.sequence, run.cancel.clone());
+    let (decision_tx, decision_rx) = tokio::sync::oneshot::channel();
+    ctx.session
+        .pending_approvals
+        .lock()
+        .map_err(|e| format!("Lock error: {}", e))?
+        .insert(execution_id.to_string(), decision_tx);
+    eprintln!("[Nexus] Approval required for {} on {}: {}", execution_id, machine_name, command);
+    ctx.emit("tool-approval-required", serde_json::json!({
+        "execution_id": execution_id,
+        "sequence": sequence,
+        "machine_name": machine_name,
+        "command": command,
+        "kind": kind,
+        "pattern": detail,
+        "purpose": purpose,
+        "timeout_secs": config.timeout_secs
+    }));
+    notify_if_hidden(
+        &ctx.app_handle,
+        true,
+        format!("{} でのコマンド実行に承認が必要です: {}", machine_name, notification_excerpt(command)),
+    );
 
-        // アシスタント応答を履歴に追加（テキストのみ）
-        chat.history.push(HistoryMessage {
-            role: "assistant".to_string(),
-            content: final_text.clone(),
-        });
+    let outcome = wait_for_approval_decision(decision_rx, config.timeout_secs, &mut cancel, &ctx.settings.language).await;
+    if let Ok(mut pending) = ctx.session.pending_approvals.lock() {
+        pending.remove(execution_id);
+    }
+    outcome
+}
 
-        chat.token_stats.clone()
+/// 承認・拒否の応答を待つ。拒否・タイムアウト・キャンセル時は Claude に返す理由を Err で返す
+/// 待機中もストリーム処理自体は止めない（このツール呼び出しの future だけが待つ）
+async fn wait_for_approval_decision(
+    decision_rx: tokio::sync::oneshot::Receiver<ApprovalDecision>,
+    timeout_secs: u64,
+    cancel: &mut tokio::sync::watch::Receiver<bool>,
+    lang: &str,
+) -> Result<(), String> {
+    let outcome = tokio::select! {
+        decision = timeout(Duration::from_secs(timeout_secs), decision_rx) => match decision {
+            Ok(Ok(decision)) => Ok(decision),
+            _ => Err(tr(lang, "tool_approval_timeout", &[("secs", &timeout_secs.to_string())])),
+        },
+        _ = cancel.wait_for(|c| *c) => Err(tr(lang, "tool_cancelled", &[])),
     };
 
-    Ok(SendMessageResponse {
-        text: final_text,
-        token_stats: current_stats,
-        tool_executions: all_tool_executions,
-    })
+    match outcome? {
+        ApprovalDecision { approved: true, .. } => Ok(()),
+        ApprovalDecision { reason, .. } => {
+            let reason = reason.filter(|r| !r.trim().is_empty()).unwrap_or_else(|| "理由の指定なし".to_string());
+            Err(tr(lang, "tool_rejected", &[("reason", &reason)]))
+        }
+    }
+}
+
+/// 承認待ちのツール実行を再開（approved=true）または拒否する。拒否理由は tool_result で Claude に伝わる
+#[tauri::command]
+fn approve_tool_execution(
+    execution_id: String,
+    approved: bool,
+    reason: Option<String>,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<(), String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let decision_tx = session
+        .pending_approvals
+        .lock()
+        .map_err(|e| format!("Lock error: {}", e))?
+        .remove(&execution_id)
+        .ok_or_else(|| format!("承認待ちのツール '{}' が見つかりません（タイムアウトした可能性があります）", execution_id))?;
+    eprintln!("[Nexus] Tool execution {} {}", execution_id, if approved { "approved" } else { "rejected" });
+    decision_tx
+        .send(ApprovalDecision { approved, reason })
+        .map_err(|_| format!("承認待ちのツール '{}' は既に終了しています", execution_id))
+}
+
+/// バッチ承認の1項目（同じターンで提案された、承認が必要なコマンド1台分）
+#[derive(Serialize, Clone, Debug)]
+struct BatchApprovalItem {
+    /// approve_tool_batch で承認する項目を指す ID（"tool_use_id/マシン名"）
+    item_id: String,
+    tool_use_id: String,
+    machine_name: String,
+    command: String,
+    pattern: String,
+    purpose: Option<ToolPurpose>,
+}
+
+/// バッチ承認へのユーザーの応答（approved_items に含まれない項目は拒否）
+#[derive(Debug)]
+struct BatchApprovalReply {
+    approved_items: std::collections::HashSet<String>,
+    reason: Option<String>,
+}
+
+/// ターン内のツール呼び出しから承認が必要なコマンドを抜き出す（read_remote_file はアプリが組み立てる読み取りなので対象外）
+fn collect_batch_approval_items(
+    tool_calls: &[(String, String, serde_json::Value)],
+    danger_patterns: &[String],
+    machines: &[SshMachineConfig],
+) -> Vec<BatchApprovalItem> {
+    let mut items = Vec::new();
+    let local_machine_name = local_machine(machines).map_or("local", |m| m.name.as_str());
+    for (tool_use_id, tool_name, input) in tool_calls {
+        if tool_name == READ_FILE_TOOL {
+            continue;
+        }
+        // manage_service の変更系は実際に送るコマンドで照合する
+        if tool_name == SERVICE_TOOL {
+            if let Some((machine_name, command, reason)) = service_change_request(input, machines) {
+                items.push(BatchApprovalItem {
+                    item_id: format!("{}/{}", tool_use_id, machine_name),
+                    tool_use_id: tool_use_id.clone(),
+                    machine_name,
+                    command,
+                    pattern: reason,
+                    purpose: Some(ToolPurpose::Change),
+                });
+            }
+            continue;
+        }
+        let command = input.get("command").and_then(|v| v.as_str()).unwrap_or("");
+        let Some(pattern) = find_danger_pattern(command, danger_patterns) else {
+            continue;
+        };
+        let machine_names: Vec<&str> = if tool_name == MULTI_MACHINE_TOOL {
+            input
+                .get("machine_names")
+                .and_then(|v| v.as_array())
+                .map(|names| names.iter().filter_map(|n| n.as_str()).collect())
+                .unwrap_or_default()
+        } else if tool_name == LOCAL_COMMAND_TOOL {
+            vec![local_machine_name]
+        } else {
+            vec![input.get("machine_name").and_then(|v| v.as_str()).unwrap_or("unknown")]
+        };
+        for machine_name in machine_names {
+            items.push(BatchApprovalItem {
+                item_id: format!("{}/{}", tool_use_id, machine_name),
+                tool_use_id: tool_use_id.clone(),
+                machine_name: machine_name.to_string(),
+                command: command.to_string(),
+                pattern: pattern.to_string(),
+                purpose: ToolPurpose::from_input(input),
+            });
+        }
+    }
+    items
 }
 
-/// Clear conversation history (コスト累計は保持)
-#[tauri::command]
-fn clear_history(state: State<'_, Mutex<ChatState>>) -> Result<(), String> {
-    let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
-    chat.history.clear();
-    // コンテキスト関連のみリセット、コスト累計は保持
-    chat.token_stats.last_input_tokens = 0;
-    chat.token_stats.last_output_tokens = 0;
-    Ok(())
+/// バッチ承認の応答（またはタイムアウト・中断）を項目ごとの実行可否に展開する
+/// 同じマシン・同じコマンドが複数あって判断が割れた場合は拒否を優先する
+fn resolve_batch_approval(
+    items: &[BatchApprovalItem],
+    outcome: &Result<BatchApprovalReply, String>,
+    lang: &str,
+) -> std::collections::HashMap<(String, String), Result<(), String>> {
+    let mut results: std::collections::HashMap<(String, String), Result<(), String>> = std::collections::HashMap::new();
+    for item in items {
+        let result = match outcome {
+            Err(message) => Err(message.clone()),
+            Ok(reply) if reply.approved_items.contains(&item.item_id) => Ok(()),
+            Ok(reply) => {
+                let reason = reply.reason.clone().filter(|r| !r.trim().is_empty()).unwrap_or_else(|| "理由の指定なし".to_string());
+                Err(tr(lang, "tool_rejected", &[("reason", &reason)]))
+            }
+        };
+        let key = (item.machine_name.clone(), item.command.clone());
+        match results.get(&key) {
+            Some(Err(_)) => {}
+            _ => {
+                results.insert(key, result);
+            }
+        }
+    }
+    results
 }
 
-/// コスト累計をリセット
-#[tauri::command]
-fn reset_cost(state: State<'_, Mutex<ChatState>>) -> Result<(), String> {
-    let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
-    chat.token_stats = TokenStats::default();
-    Ok(())
+/// バッチ承認済みの結果（このターンで扱っていないコマンドなら None で個別承認に回す）
+fn batch_approval_result(ctx: &ToolContext, machine_name: &str, command: &str) -> Option<Result<(), String>> {
+    let results = ctx.session.batch_approval_results.lock().ok()?;
+    results.get(&(machine_name.to_string(), command.to_string())).cloned()
 }
 
-/// Get current token usage statistics
-#[tauri::command]
-fn get_token_stats(state: State<'_, Mutex<ChatState>>) -> Result<TokenStats, String> {
-    let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
-    Ok(chat.token_stats.clone())
+/// ターンのツール実行が終わったらバッチ承認の結果を捨てる（次のターンの同じコマンドは改めて承認を求める）
+fn clear_batch_approvals(ctx: &ToolContext) {
+    if let Ok(mut results) = ctx.session.batch_approval_results.lock() {
+        results.clear();
+    }
 }
 
-/// Switch model
-#[tauri::command]
-fn set_model(model_id: String, state: State<'_, Mutex<ChatState>>) -> Result<String, String> {
-    let valid_models = [
-        "claude-sonnet-4-5-20250929",
-        "claude-haiku-4-5-20251001",
-    ];
+/// 承認が必要なコマンドが同じターンに2件以上あれば、実行前に tool-approval-batch-required でまとめて承認を求める
+/// 結果は batch_approval_results に置き、各ツールの await_tool_approval がそれに従う（1件以下なら個別承認のまま）
+async fn preflight_batch_approval(tool_calls: &[(String, String, serde_json::Value)], ctx: &ToolContext) {
+    let config = &ctx.settings.approval;
+    let lang = ctx.settings.language.as_str();
+    if !config.enabled || !config.batch || ctx.session.stop_requested() {
+        return;
+    }
+    let items = collect_batch_approval_items(tool_calls, &config.danger_patterns, &ctx.machines);
+    if items.len() < 2 {
+        return;
+    }
 
-    if !valid_models.contains(&model_id.as_str()) {
-        return Err(format!("無効なモデル: {}", model_id));
+    let batch_id = next_execution_id().replacen("exec-", "batch-", 1);
+    let (reply_tx, reply_rx) = tokio::sync::oneshot::channel();
+    match ctx.session.pending_batch_approvals.lock() {
+        Ok(mut pending) => {
+            pending.insert(batch_id.clone(), reply_tx);
+        }
+        Err(e) => {
+            eprintln!("[Nexus] Lock error: {}", e);
+            return;
+        }
     }
+    eprintln!("[Nexus] Batch approval required for {} command(s): {}", items.len(), batch_id);
+    ctx.emit("tool-approval-batch-required", serde_json::json!({
+        "batch_id": batch_id,
+        "items": items,
+        "timeout_secs": config.timeout_secs
+    }));
+    notify_if_hidden(&ctx.app_handle, true, format!("{}件のコマンド実行に承認が必要です", items.len()));
 
-    let mut chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
-    chat.model = model_id.clone();
-    Ok(format!("モデルを {} に変更しました", model_id))
-}
+    let outcome = tokio::select! {
+        reply = timeout(Duration::from_secs(config.timeout_secs), reply_rx) => match reply {
+            Ok(Ok(reply)) => Ok(reply),
+            _ => Err(tr(lang, "tool_approval_timeout", &[("secs", &config.timeout_secs.to_string())])),
+        },
+        _ = ctx.session.stop_notify.notified() => Err(tr(lang, "tool_cancelled", &[])),
+    };
+    if let Ok(mut pending) = ctx.session.pending_batch_approvals.lock() {
+        pending.remove(&batch_id);
+    }
 
-/// Get current model info
-#[tauri::command]
-fn get_current_model(state: State<'_, Mutex<ChatState>>) -> Result<String, String> {
-    let chat = state.lock().map_err(|e| format!("State lock error: {}", e))?;
-    Ok(chat.model.clone())
+    let results = resolve_batch_approval(&items, &outcome, lang);
+    eprintln!(
+        "[Nexus] Batch {} resolved: {}/{} approved",
+        batch_id,
+        results.values().filter(|r| r.is_ok()).count(),
+        results.len()
+    );
+    if let Ok(mut stored) = ctx.session.batch_approval_results.lock() {
+        *stored = results;
+    }
 }
 
-#[derive(Serialize)]
-struct MachineStatus {
-    name: String,
-    role: String,
-    online: bool,
+/// バッチ承認に応答する。approved_items に含めた項目だけ実行し、残りは拒否理由とともに Claude に返す
+/// 全承認は全項目、全拒否は空配列を渡す
+#[tauri::command]
+fn approve_tool_batch(
+    batch_id: String,
+    approved_items: Vec<String>,
+    reason: Option<String>,
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<(), String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let reply_tx = session
+        .pending_batch_approvals
+        .lock()
+        .map_err(|e| format!("Lock error: {}", e))?
+        .remove(&batch_id)
+        .ok_or_else(|| format!("承認待ちのバッチ '{}' が見つかりません（タイムアウトした可能性があります）", batch_id))?;
+    eprintln!("[Nexus] Tool batch {}: {} item(s) approved", batch_id, approved_items.len());
+    reply_tx
+        .send(BatchApprovalReply { approved_items: approved_items.into_iter().collect(), reason })
+        .map_err(|_| format!("承認待ちのバッチ '{}' は既に終了しています", batch_id))
 }
 
 // ========================================
-// SSH Remote Management (Phase 3-A)
-// ========================================
-// machines.toml 設定構造体
+// 会話の undo / redo
 // ========================================
 
-#[derive(Deserialize, Debug)]
-struct MachinesFileConfig {
-    ssh: Option<SshFileConfig>,
-    machines: Vec<MachineEntry>,
-}
+/// セッションごとに保持するスナップショットの上限（履歴を丸ごと複製するためメモリを抑える）
+const UNDO_MAX_SNAPSHOTS: usize = 20;
 
-#[derive(Deserialize, Debug, Clone)]
-struct SshFileConfig {
-    timeout_secs: Option<u64>,
-    keepalive_interval: Option<u32>,
-    keepalive_count_max: Option<u32>,
+/// 操作前の会話の状態（履歴・トークン統計・モデル）
+#[derive(Clone, Debug)]
+struct ChatSnapshot {
+    /// 取り消す操作: "send" | "clear" | "reset_cost" | "model"
+    action: &'static str,
+    history: Vec<HistoryMessage>,
+    model: String,
+    token_stats: TokenStats,
 }
 
-#[derive(Deserialize, Debug)]
-struct MachineEntry {
-    name: String,
-    host: String,
-    role: String,
-    enabled: bool,
-    os: String,
-    notes: Option<String>,
-    notion_page_id: Option<String>,
+impl ChatState {
+    fn snapshot(&self, action: &'static str) -> ChatSnapshot {
+        ChatSnapshot {
+            action,
+            history: self.history.clone(),
+            model: self.model.clone(),
+            token_stats: self.token_stats.clone(),
+        }
+    }
+
+    /// 操作前の状態を undo に積む（新しい操作をしたら redo は捨てる）
+    fn record_undo(&mut self, snapshot: ChatSnapshot) {
+        self.undo_stack.push(snapshot);
+        if self.undo_stack.len() > UNDO_MAX_SNAPSHOTS {
+            self.undo_stack.remove(0);
+        }
+        self.redo_stack.clear();
+    }
+
+    /// スナップショットの状態に戻し、戻す前の状態を同じ操作名で返す（反対側のスタックに積む用）
+    fn restore(&mut self, snapshot: ChatSnapshot) -> ChatSnapshot {
+        let current = self.snapshot(snapshot.action);
+        self.history = snapshot.history;
+        self.model = snapshot.model;
+        self.token_stats = snapshot.token_stats;
+        current
+    }
+
+    fn undo_status(&self) -> UndoStatus {
+        UndoStatus {
+            can_undo: !self.undo_stack.is_empty(),
+            can_redo: !self.redo_stack.is_empty(),
+            undo_actions: self.undo_stack.iter().rev().map(|s| s.action.to_string()).collect(),
+            redo_actions: self.redo_stack.iter().rev().map(|s| s.action.to_string()).collect(),
+        }
+    }
 }
 
-/// SSH接続維持設定（グローバル）
-struct SshGlobalConfig {
-    timeout_secs: u64,
-    keepalive_interval: u32,
-    keepalive_count_max: u32,
+#[derive(Serialize, Clone, Debug)]
+struct UndoStatus {
+    can_undo: bool,
+    can_redo: bool,
+    /// 取り消せる操作（新しい順、この件数まで遡れる）
+    undo_actions: Vec<String>,
+    redo_actions: Vec<String>,
 }
 
-impl Default for SshGlobalConfig {
-    fn default() -> Self {
-        Self {
-            timeout_secs: 5,
-            keepalive_interval: 30,
-            keepalive_count_max: 3,
+/// undo / redo の共通処理（応答中は履歴が書き換わるため拒否）
+fn step_chat_history(session: &Session, redo: bool, app_handle: &tauri::AppHandle) -> Result<UndoStatus, String> {
+    if session.gate.lock.try_lock().is_err() {
+        return Err("処理中は取り消し・やり直しできません。完了またはキャンセルしてから操作してください".to_string());
+    }
+    let (action, status) = {
+        let mut chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+        let popped = if redo { chat.redo_stack.pop() } else { chat.undo_stack.pop() };
+        let snapshot = popped.ok_or(if redo { "やり直せる操作がありません" } else { "取り消せる操作がありません" })?;
+        let action = snapshot.action;
+        let previous = chat.restore(snapshot);
+        if redo {
+            chat.undo_stack.push(previous);
+        } else {
+            chat.redo_stack.push(previous);
         }
+        notify_token_stats(app_handle, &session.id, &chat.token_stats, "restore");
+        (action, chat.undo_status())
+    };
+    let _ = app_handle.emit("history-restored", serde_json::json!({
+        "session_id": session.id,
+        "action": action,
+        "direction": if redo { "redo" } else { "undo" }
+    }));
+    if let Err(e) = save_session_to_disk(app_handle, session) {
+        eprintln!("[Nexus] Warning: session save failed: {}", e);
     }
+    Ok(status)
 }
 
-// ========================================
+/// 直前の操作（送信・クリア・コストリセット・モデル変更）を取り消す
+#[tauri::command]
+fn undo(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<UndoStatus, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    step_chat_history(&session, false, &app_handle)
+}
 
-const SSH_TIMEOUT_SECS: u64 = 5;
+/// undo で取り消した操作をやり直す（undo 後に新しい操作をすると、やり直せる操作は破棄される）
+#[tauri::command]
+fn redo(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<UndoStatus, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    step_chat_history(&session, true, &app_handle)
+}
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct SshMachineConfig {
-    name: String,
-    host: String,       // ~/.ssh/config の Host名 or IPアドレス
-    role: String,       // "Commander" | "Remote"
-    enabled: bool,      // 接続試行するか
-    os: String,         // "Windows" | "Linux"
-    notes: String,      // マシン用途・特記事項
-    #[serde(default)]
-    notion_page_id: Option<String>,  // Notionページ（ソフトウェア情報）
+/// どこまで取り消し・やり直しできるか
+#[tauri::command]
+fn can_undo(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+) -> Result<UndoStatus, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+    Ok(chat.undo_status())
 }
 
-impl Default for SshMachineConfig {
-    fn default() -> Self {
-        Self {
-            name: String::new(),
-            host: String::new(),
-            role: "Remote".to_string(),
-            enabled: true,
-            os: "Windows".to_string(),
-            notes: String::new(),
-            notion_page_id: None,
-        }
-    }
+// ========================================
+// 会話セッションの管理
+// ========================================
+
+/// セッション一覧の1件
+#[derive(Serialize, Clone, Debug)]
+struct SessionInfo {
+    id: String,
+    title: String,
+    created_at: u64,
+    message_count: usize,
+    token_stats: TokenStats,
+    active: bool,
+    busy: bool,
 }
 
-struct SshState {
-    machines: Vec<SshMachineConfig>,
-    global_config: SshGlobalConfig,
-    /// Notion APIから取得したソフトウェア情報（マシン名 → 情報テキスト）
-    notion_info: std::collections::HashMap<String, String>,
+fn session_info(session: &Session, active_id: &str) -> Result<SessionInfo, String> {
+    let title = session.title.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+    let chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+    Ok(SessionInfo {
+        id: session.id.clone(),
+        title,
+        created_at: session.created_at,
+        message_count: chat.history.len(),
+        token_stats: chat.token_stats.clone(),
+        active: session.id == active_id,
+        busy: session.gate.lock.try_lock().is_err(),
+    })
 }
 
-/// machines.tomlのパスを解決（実行ファイルからの相対パス対応）
-fn resolve_machines_toml_path() -> Option<PathBuf> {
-    // 1. カレントディレクトリ
-    let cwd_path = std::path::Path::new("machines.toml");
-    if cwd_path.exists() {
-        return Some(cwd_path.to_path_buf());
+/// 新しいセッションIDを採番（起動時刻＋連番）
+fn next_session_id() -> String {
+    static SEQ: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(1);
+    let seq = SEQ.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    format!("chat-{}-{}", now_unix_secs(), seq)
+}
+
+/// 新しいセッションを作成してアクティブにする（title 未指定なら「会話 N」）
+#[tauri::command]
+fn create_session(
+    title: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<SessionInfo, String> {
+    let id = next_session_id();
+    let session = std::sync::Arc::new(Session::new(&id));
+    {
+        let mut map = sessions.map.lock().map_err(|e| format!("Lock error: {}", e))?;
+        let title = title
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| format!("会話 {}", map.len() + 1));
+        *session.title.lock().map_err(|e| format!("Lock error: {}", e))? = title;
+        map.insert(id.clone(), session.clone());
     }
-    // 2. 実行ファイルの親ディレクトリ
-    if let Ok(exe_path) = std::env::current_exe() {
-        if let Some(exe_dir) = exe_path.parent() {
-            let path = exe_dir.join("machines.toml");
-            if path.exists() {
-                return Some(path);
-            }
-            // 3. 開発時: src-tauri/../../machines.toml (nexus-app/直下)
-            let dev_path = exe_dir
-                .join("..")
-                .join("..")
-                .join("..")
-                .join("machines.toml");
-            if dev_path.exists() {
-                return Some(dev_path);
-            }
-        }
+    *sessions.active.lock().map_err(|e| format!("Lock error: {}", e))? = id.clone();
+    // まだ発言のないセッションも再起動後の一覧に残す
+    if let Err(e) = save_session_to_disk(&app_handle, &session) {
+        eprintln!("[Nexus] Warning: session save failed: {}", e);
     }
-    None
+    session_info(&session, &id)
 }
 
-/// machines.tomlからマシン設定を読み込み
-fn load_machines_config() -> SshState {
-    if let Some(toml_path) = resolve_machines_toml_path() {
-        if let Ok(content) = std::fs::read_to_string(&toml_path) {
-            if let Ok(config) = toml::from_str::<MachinesFileConfig>(&content) {
-                let global = config.ssh.as_ref().map_or(SshGlobalConfig::default(), |s| {
-                    SshGlobalConfig {
-                        timeout_secs: s.timeout_secs.unwrap_or(5),
-                        keepalive_interval: s.keepalive_interval.unwrap_or(30),
-                        keepalive_count_max: s.keepalive_count_max.unwrap_or(3),
-                    }
-                });
+/// アクティブセッションを切り替える（session_id 未指定のコマンドの対象が変わる）
+#[tauri::command]
+fn switch_session(
+    session_id: String,
+    sessions: State<'_, Sessions>,
+) -> Result<SessionInfo, String> {
+    let session = sessions.get(Some(&session_id))?;
+    *sessions.active.lock().map_err(|e| format!("Lock error: {}", e))? = session.id.clone();
+    session_info(&session, &session.id)
+}
 
-                let machines = config
-                    .machines
-                    .into_iter()
-                    .map(|m| SshMachineConfig {
-                        name: m.name,
-                        host: m.host,
-                        role: m.role,
-                        enabled: m.enabled,
-                        os: m.os,
-                        notes: m.notes.unwrap_or_default(),
-                        notion_page_id: m.notion_page_id,
-                    })
-                    .collect();
+/// セッション一覧（作成順）
+#[tauri::command]
+fn list_sessions(sessions: State<'_, Sessions>) -> Result<Vec<SessionInfo>, String> {
+    let active = sessions.key(None)?;
+    let all: Vec<std::sync::Arc<Session>> = {
+        let map = sessions.map.lock().map_err(|e| format!("Lock error: {}", e))?;
+        map.values().cloned().collect()
+    };
+    let mut infos = all
+        .iter()
+        .map(|s| session_info(s, &active))
+        .collect::<Result<Vec<_>, _>>()?;
+    infos.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+    Ok(infos)
+}
 
-                eprintln!("[Nexus] machines.toml loaded from: {}", toml_path.display());
-                return SshState {
-                    machines,
-                    global_config: global,
-                    notion_info: std::collections::HashMap::new(),
-                };
-            } else {
-                eprintln!("[Nexus] Warning: machines.toml parse error, using defaults");
-            }
-        }
+/// セッションを削除（保存ファイルも削除）。default セッションと処理中のセッションは削除できない
+/// アクティブセッションを削除した場合は default に戻る。削除後のアクティブセッションIDを返す
+#[tauri::command]
+fn delete_session(
+    session_id: String,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let session = sessions.get(Some(&session_id))?;
+    if session.id == DEFAULT_SESSION_ID {
+        return Err("既定のセッションは削除できません（履歴のクリアを使ってください）".to_string());
     }
-    eprintln!("[Nexus] Warning: machines.toml not found, using hardcoded defaults");
-    SshState::hardcoded_defaults()
-}
+    if session.gate.lock.try_lock().is_err() {
+        return Err("処理中のセッションは削除できません。完了またはキャンセルしてから削除してください".to_string());
+    }
+    sessions.map.lock().map_err(|e| format!("Lock error: {}", e))?.remove(&session.id);
 
-impl SshState {
-    /// フォールバック: tomlが見つからない場合のハードコードデフォルト
-    fn hardcoded_defaults() -> Self {
-        Self {
-            machines: vec![
-                SshMachineConfig {
-                    name: "OMEN".to_string(),
-                    host: "localhost".to_string(),
-                    role: "Commander".to_string(),
-                    enabled: false,
-                    os: "Windows".to_string(),
-                    notes: "メイン開発機。Nexusアプリ実行中".to_string(),
-                    notion_page_id: None,
-                },
-                SshMachineConfig {
-                    name: "SIGMA".to_string(),
-                    host: "sigma".to_string(),
-                    role: "Remote".to_string(),
-                    enabled: true,
-                    os: "Windows".to_string(),
-                    notes: "LattePanda Sigma".to_string(),
-                    notion_page_id: Some("3037e628-88da-8170-9718-c8a9383d4a26".to_string()),
-                },
-                SshMachineConfig {
-                    name: "Precision".to_string(),
-                    host: "precision".to_string(),
-                    role: "Remote".to_string(),
-                    enabled: true,
-                    os: "Windows".to_string(),
-                    notes: "Dell Precision 3630 ワークステーション".to_string(),
-                    notion_page_id: Some("3037e628-88da-81a4-807b-f9afc16fa752".to_string()),
-                },
-            ],
-            global_config: SshGlobalConfig::default(),
-            notion_info: std::collections::HashMap::new(),
-        }
+    let path = app_data_path(&app_handle, &session_file_name(&session.id))?;
+    if path.exists() {
+        std::fs::remove_file(&path).map_err(|e| format!("保存ファイル削除エラー: {}", e))?;
+    }
+
+    let mut active = sessions.active.lock().map_err(|e| format!("Lock error: {}", e))?;
+    if *active == session.id {
+        *active = DEFAULT_SESSION_ID.to_string();
     }
+    Ok(active.clone())
 }
 
-/// SSH接続テスト（ssh.exe経由、軽量）
-async fn ssh_check_alive(host: &str) -> bool {
-    let result = timeout(
-        Duration::from_secs(SSH_TIMEOUT_SECS),
-        TokioCommand::new("ssh")
-            .args([
-                "-o", "BatchMode=yes",
-                "-o", "ConnectTimeout=3",
-                "-o", "StrictHostKeyChecking=accept-new",
-                "-o", "ServerAliveInterval=30",
-                "-o", "ServerAliveCountMax=3",
-                host,
-                "echo", "nexus-ping",
-            ])
-            .output(),
-    )
-    .await;
+// ========================================
+// 会話履歴の永続化
+// ========================================
 
-    match result {
-        Ok(Ok(output)) => {
-            output.status.success()
-                && String::from_utf8_lossy(&output.stdout).contains("nexus-ping")
-        }
-        _ => false,
+/// 保存ファイルのスキーマバージョン（形式を変えたら上げ、migrate_saved_session に移行処理を足す）
+const SESSION_SCHEMA_VERSION: u32 = 1;
+const SESSION_FILE_PREFIX: &str = "session-";
+
+/// ディスクに保存するセッション（履歴・モデル・トークン統計）
+#[derive(Serialize, Deserialize, Debug)]
+struct SavedSession {
+    version: u32,
+    session_id: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    created_at: u64,
+    model: String,
+    history: Vec<HistoryMessage>,
+    token_stats: TokenStats,
+    #[serde(default)]
+    budget_limit_usd: Option<f64>,
+    #[serde(default)]
+    max_tokens: Option<u32>,
+    #[serde(default)]
+    max_tool_loops: Option<u32>,
+    saved_at: u64,
+}
+
+/// セッションIDからファイル名を作る（パス区切り等はファイル名に使える文字へ置換）
+fn session_file_name(session_id: &str) -> String {
+    let safe: String = session_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    format!("{}{}.json", SESSION_FILE_PREFIX, safe)
+}
+
+/// 古いバージョンのファイルを現行形式に読み替える（未知の新しいバージョンは読まない）
+fn migrate_saved_session(value: serde_json::Value) -> Result<SavedSession, String> {
+    let version = value.get("version").and_then(|v| v.as_u64()).ok_or("version がありません")?;
+    if version > SESSION_SCHEMA_VERSION as u64 {
+        return Err(format!("未対応のバージョンです（{} > {}）", version, SESSION_SCHEMA_VERSION));
     }
+    serde_json::from_value(value).map_err(|e| format!("形式エラー: {}", e))
 }
 
-/// 全マシンのステータスを実SSH接続で取得
-#[tauri::command]
-async fn get_machine_status(
-    ssh_state: State<'_, Mutex<SshState>>,
-) -> Result<Vec<MachineStatus>, String> {
-    let machines = {
-        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
-        state.machines.clone()
+/// セッションの履歴・統計をアプリデータディレクトリに保存（書きかけで壊れないよう一時ファイル経由）
+fn save_session_to_disk(app_handle: &tauri::AppHandle, session: &Session) -> Result<PathBuf, String> {
+    let title = session.title.lock().map_err(|e| format!("Lock error: {}", e))?.clone();
+    let saved = {
+        let chat = session.chat.lock().map_err(|e| format!("State lock error: {}", e))?;
+        SavedSession {
+            version: SESSION_SCHEMA_VERSION,
+            session_id: session.id.clone(),
+            title,
+            created_at: session.created_at,
+            model: chat.model.clone(),
+            history: chat.history.clone(),
+            token_stats: chat.token_stats.clone(),
+            budget_limit_usd: chat.budget_limit_usd,
+            max_tokens: Some(chat.max_tokens),
+            max_tool_loops: Some(chat.max_tool_loops),
+            saved_at: now_unix_secs(),
+        }
     };
+    let path = app_data_path(app_handle, &session_file_name(&session.id))?;
+    write_saved_session(&path, &saved)?;
+    Ok(path)
+}
 
-    let mut statuses = Vec::new();
+/// 保存形式のセッションを書き込む（書きかけで壊れないよう一時ファイル経由。同期の取り込み・書き出しでも使う）
+fn write_saved_session(path: &std::path::Path, saved: &SavedSession) -> Result<(), String> {
+    let json = serde_json::to_string(saved).map_err(|e| format!("JSON変換エラー: {}", e))?;
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, json).map_err(|e| format!("書き込みエラー: {}", e))?;
+    std::fs::rename(&tmp, path).map_err(|e| format!("書き込みエラー: {}", e))
+}
 
-    for machine in &machines {
-        let online = if machine.role == "Commander" {
-            true // OMEN（自分自身）は常にオンライン
-        } else if machine.enabled {
-            ssh_check_alive(&machine.host).await
-        } else {
-            false
-        };
+/// 保存済みセッションをすべて読み込む（壊れた・未対応のファイルは警告を出して無視）
+fn load_saved_sessions(dir: &std::path::Path) -> Vec<SavedSession> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            name.starts_with(SESSION_FILE_PREFIX) && name.ends_with(".json")
+        })
+        .filter_map(|path| {
+            let loaded = std::fs::read_to_string(&path)
+                .map_err(|e| format!("読み込みエラー: {}", e))
+                .and_then(|content| serde_json::from_str(&content).map_err(|e| format!("JSON解析エラー: {}", e)))
+                .and_then(migrate_saved_session);
+            match loaded {
+                Ok(saved) => Some(saved),
+                Err(e) => {
+                    eprintln!("[Nexus] Warning: saved session ignored ({}): {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
 
-        statuses.push(MachineStatus {
-            name: machine.name.clone(),
-            role: machine.role.clone(),
-            online,
+/// 起動時に保存済みセッションを復元（setup から呼ぶ）
+fn restore_saved_sessions(app_handle: &tauri::AppHandle) {
+    let dir = match app_handle.path().app_data_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("[Nexus] Warning: session restore skipped: {}", e);
+            return;
+        }
+    };
+    let sessions = app_handle.state::<Sessions>();
+    let mut restored = 0;
+    for saved in load_saved_sessions(&dir) {
+        let session = std::sync::Arc::new(Session {
+            created_at: if saved.created_at > 0 { saved.created_at } else { saved.saved_at },
+            ..Session::new(&saved.session_id)
         });
+        if !saved.title.is_empty() {
+            if let Ok(mut title) = session.title.lock() {
+                *title = saved.title;
+            }
+        }
+        if let Ok(mut map) = sessions.map.lock() {
+            map.insert(saved.session_id.clone(), session.clone());
+        }
+        let Ok(mut chat) = session.chat.lock() else {
+            continue;
+        };
+        // 廃止されたモデルIDが保存されていれば既定モデルのまま
+        if find_model(&saved.model).is_some() {
+            chat.model = saved.model;
+        }
+        chat.history = saved.history;
+        chat.token_stats = saved.token_stats;
+        chat.budget_limit_usd = saved.budget_limit_usd;
+        if let Some(max_tokens) = saved.max_tokens {
+            chat.max_tokens = clamp_max_tokens(max_tokens, &chat.model);
+        }
+        if let Some(max_tool_loops) = saved.max_tool_loops {
+            chat.max_tool_loops = clamp_max_tool_loops(max_tool_loops);
+        }
+        restored += 1;
+    }
+    if restored > 0 {
+        eprintln!("[Nexus] Restored {} saved session(s)", restored);
     }
-
-    Ok(statuses)
 }
 
-/// リモートPCでコマンドを実行
+/// 会話履歴とトークン統計をディスクに保存（送信完了時にも自動で保存される）
 #[tauri::command]
-async fn execute_remote_command(
-    machine_name: String,
-    command: String,
-    ssh_state: State<'_, Mutex<SshState>>,
-) -> Result<RemoteCommandResult, String> {
-    let machine = {
-        let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
-        state
-            .machines
-            .iter()
-            .find(|m| m.name == machine_name)
-            .cloned()
-            .ok_or_else(|| format!("マシン '{}' が見つかりません", machine_name))?
-    };
+fn save_session(
+    session_id: Option<String>,
+    sessions: State<'_, Sessions>,
+    app_handle: tauri::AppHandle,
+) -> Result<String, String> {
+    let session = sessions.get(session_id.as_deref())?;
+    let path = save_session_to_disk(&app_handle, &session)?;
+    Ok(format!("会話を {} に保存しました", path.display()))
+}
 
-    if machine.role == "Commander" {
-        return Err("OMENへのリモート実行はサポートされていません".to_string());
-    }
+// ========================================
+// 共有フォルダ経由のセッション同期
+// ========================================
 
-    if !machine.enabled {
-        return Err(format!("マシン '{}' は無効化されています", machine_name));
-    }
+/// 複数の PC で同じセッションを続けるための同期（Dropbox 等の共有フォルダに session-*.json を置く）
+/// 起動時に取り込み、トレイの「終了」時に書き出す
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default)]
+struct SyncSettings {
+    enabled: bool,
+    /// 同期先フォルダ（~ はホームディレクトリに展開）
+    folder: String,
+}
 
-    let result = timeout(
-        Duration::from_secs(30), // コマンド実行は長めのタイムアウト
-        TokioCommand::new("ssh")
-            .args([
-                "-o", "BatchMode=yes",
-                "-o", "ConnectTimeout=5",
-                "-o", "ServerAliveInterval=30",
-                "-o", "ServerAliveCountMax=3",
-                &machine.host,
-                &command,
-            ])
-            .output(),
-    )
-    .await;
+/// セッションごとの最後に同期した時点の saved_at（両側の変更有無の判定基準）
+const SYNC_STATE_FILE: &str = "sync_state.json";
 
-    match result {
-        Ok(Ok(output)) => Ok(RemoteCommandResult {
-            success: output.status.success(),
-            stdout: decode_bytes(&output.stdout),
-            stderr: decode_bytes(&output.stderr),
-            exit_code: output.status.code().unwrap_or(-1),
-        }),
-        Ok(Err(e)) => Err(format!("SSH実行エラー: {}", e)),
-        Err(_) => Err("タイムアウト: コマンド実行が30秒を超えました".to_string()),
-    }
+/// 有効かつフォルダが指定されていれば同期先
+fn sync_folder(settings: &SyncSettings) -> Option<PathBuf> {
+    let folder = settings.folder.trim();
+    (settings.enabled && !folder.is_empty()).then(|| expand_tilde(std::path::Path::new(folder)))
 }
 
-#[derive(Serialize)]
-struct RemoteCommandResult {
-    success: bool,
-    stdout: String,
-    stderr: String,
-    exit_code: i32,
+fn load_sync_state(path: &std::path::Path) -> std::collections::HashMap<String, u64> {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
 }
 
-/// SSH設定一覧を取得
-#[tauri::command]
-fn get_ssh_config(
-    ssh_state: State<'_, Mutex<SshState>>,
-) -> Result<Vec<SshMachineConfig>, String> {
-    let state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
-    Ok(state.machines.clone())
+fn save_sync_state(path: &std::path::Path, state: &std::collections::HashMap<String, u64>) {
+    let result = serde_json::to_string(state)
+        .map_err(|e| format!("JSON変換エラー: {}", e))
+        .and_then(|json| std::fs::write(path, json).map_err(|e| format!("書き込みエラー: {}", e)));
+    if let Err(e) = result {
+        eprintln!("[Nexus] Warning: sync state save failed: {}", e);
+    }
 }
 
-/// SSH設定を更新（マシンのhost/enabled変更）
-#[tauri::command]
-fn update_ssh_config(
-    machine_name: String,
-    host: Option<String>,
-    enabled: Option<bool>,
-    ssh_state: State<'_, Mutex<SshState>>,
-) -> Result<String, String> {
-    let mut state = ssh_state.lock().map_err(|e| format!("Lock error: {}", e))?;
-    let machine = state
-        .machines
-        .iter_mut()
-        .find(|m| m.name == machine_name)
-        .ok_or_else(|| format!("マシン '{}' が見つかりません", machine_name))?;
+/// 取り込み時の判断
+#[derive(Debug, PartialEq)]
+enum SyncDecision {
+    /// 手元にないか、共有フォルダ側だけが更新されている
+    UseRemote,
+    /// 手元だけが更新されている（終了時に書き出す）か、同じ内容
+    KeepLocal,
+    /// 前回の同期以降に両方で更新された
+    Conflict,
+}
 
-    if let Some(h) = host {
-        machine.host = h;
+/// 最終更新（saved_at）と前回同期時点（base）から、どちらを採用するか決める
+/// 同期したことがないセッションが両側にあり内容が違う場合も衝突として両方残す
+fn decide_sync(local: Option<&SavedSession>, remote: &SavedSession, base: Option<u64>) -> SyncDecision {
+    let Some(local) = local else {
+        return SyncDecision::UseRemote;
+    };
+    if local.saved_at == remote.saved_at {
+        return SyncDecision::KeepLocal;
     }
-    if let Some(e) = enabled {
-        machine.enabled = e;
+    let same_history = serde_json::to_string(&local.history).ok() == serde_json::to_string(&remote.history).ok();
+    let base = base.unwrap_or(0);
+    match (local.saved_at > base, remote.saved_at > base) {
+        (_, true) if same_history => {
+            if remote.saved_at > local.saved_at { SyncDecision::UseRemote } else { SyncDecision::KeepLocal }
+        }
+        (true, true) => SyncDecision::Conflict,
+        (false, true) => SyncDecision::UseRemote,
+        _ => SyncDecision::KeepLocal,
     }
+}
 
-    Ok(format!("マシン '{}' の設定を更新しました", machine_name))
+/// 起動時に共有フォルダのセッションを取り込む（restore_saved_sessions の前に呼び、結果は手元の保存ファイルに反映する）
+/// 衝突したセッションは手元の版を残し、共有フォルダの版を別セッションとして追加する（どちらを残すかはユーザーが選ぶ）
+fn import_sessions_from_sync_folder(app_handle: &tauri::AppHandle) {
+    let settings = app_handle.state::<Mutex<AppSettings>>().lock().map(|s| s.sync.clone()).unwrap_or_default();
+    let Some(folder) = sync_folder(&settings) else {
+        return;
+    };
+    let (Ok(state_path), Ok(local_dir)) = (app_data_path(app_handle, SYNC_STATE_FILE), app_handle.path().app_data_dir()) else {
+        return;
+    };
+    let mut sync_state = load_sync_state(&state_path);
+    let local: std::collections::HashMap<String, SavedSession> =
+        load_saved_sessions(&local_dir).into_iter().map(|s| (s.session_id.clone(), s)).collect();
+
+    let (mut imported, mut conflicts) = (0, 0);
+    for remote in load_saved_sessions(&folder) {
+        let local_saved = local.get(&remote.session_id);
+        match decide_sync(local_saved, &remote, sync_state.get(&remote.session_id).copied()) {
+            SyncDecision::UseRemote => {
+                let path = local_dir.join(session_file_name(&remote.session_id));
+                match write_saved_session(&path, &remote) {
+                    Ok(()) => {
+                        sync_state.insert(remote.session_id.clone(), remote.saved_at);
+                        imported += 1;
+                    }
+                    Err(e) => eprintln!("[Nexus] Warning: sync import failed ({}): {}", remote.session_id, e),
+                }
+            }
+            SyncDecision::KeepLocal => {}
+            SyncDecision::Conflict => {
+                let base = local_saved.map_or(remote.saved_at, |l| l.saved_at.max(remote.saved_at));
+                let original_id = remote.session_id.clone();
+                let title = if remote.title.is_empty() { original_id.clone() } else { remote.title.clone() };
+                let copy = SavedSession {
+                    session_id: format!("{}-conflict-{}", original_id, remote.saved_at),
+                    title: format!("{}（同期の衝突: 共有フォルダ版）", title),
+                    ..remote
+                };
+                match write_saved_session(&local_dir.join(session_file_name(&copy.session_id)), &copy) {
+                    Ok(()) => {
+                        eprintln!("[Nexus] Sync conflict in session {}: kept both (copy: {})", original_id, copy.session_id);
+                        sync_state.insert(original_id, base);
+                        conflicts += 1;
+                    }
+                    Err(e) => eprintln!("[Nexus] Warning: sync conflict copy failed ({}): {}", original_id, e),
+                }
+            }
+        }
+    }
+    save_sync_state(&state_path, &sync_state);
+    eprintln!("[Nexus] Sync import from {}: {} updated, {} conflict(s)", folder.display(), imported, conflicts);
+}
+
+/// 終了時に手元で更新されたセッションを共有フォルダへ書き出す
+/// 共有フォルダ側も前回の同期以降に更新されていれば上書きせず、次回起動時の取り込みで衝突として扱う
+fn export_sessions_to_sync_folder(app_handle: &tauri::AppHandle) {
+    let settings = app_handle.state::<Mutex<AppSettings>>().lock().map(|s| s.sync.clone()).unwrap_or_default();
+    let Some(folder) = sync_folder(&settings) else {
+        return;
+    };
+    let (Ok(state_path), Ok(local_dir)) = (app_data_path(app_handle, SYNC_STATE_FILE), app_handle.path().app_data_dir()) else {
+        return;
+    };
+    if let Err(e) = std::fs::create_dir_all(&folder) {
+        eprintln!("[Nexus] Warning: sync export skipped ({}): {}", folder.display(), e);
+        return;
+    }
+    let mut sync_state = load_sync_state(&state_path);
+    let remote: std::collections::HashMap<String, u64> =
+        load_saved_sessions(&folder).into_iter().map(|s| (s.session_id, s.saved_at)).collect();
+
+    let (mut exported, mut deferred) = (0, 0);
+    for local in load_saved_sessions(&local_dir) {
+        let base = sync_state.get(&local.session_id).copied().unwrap_or(0);
+        match remote.get(&local.session_id) {
+            Some(&remote_saved_at) if remote_saved_at == local.saved_at || local.saved_at <= base => continue,
+            Some(&remote_saved_at) if remote_saved_at > base => {
+                deferred += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match write_saved_session(&folder.join(session_file_name(&local.session_id)), &local) {
+            Ok(()) => {
+                sync_state.insert(local.session_id.clone(), local.saved_at);
+                exported += 1;
+            }
+            Err(e) => eprintln!("[Nexus] Warning: sync export failed ({}): {}", local.session_id, e),
+        }
+    }
+    save_sync_state(&state_path, &sync_state);
+    eprintln!("[Nexus] Sync export to {}: {} written, {} left for next import", folder.display(), exported, deferred);
 }
 
 // ========================================
@@ -1429,28 +12314,99 @@ pub fn run() {
             }
         }
     }
+    let settings = load_app_settings();
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
-        .manage(Mutex::new(ChatState::default()))
+        .plugin(tauri_plugin_clipboard_manager::init())
+        .plugin(tauri_plugin_notification::init())
+        .manage(Sessions::default())
+        .manage(NotificationState::default())
         .manage(Mutex::new(load_machines_config()))
+        .manage(ToolQueue::new(settings.requests.max_concurrent_tools))
+        .manage(Mutex::new(settings))
         .invoke_handler(tauri::generate_handler![
             send_message,
             send_message_stream,
             clear_history,
+            get_history,
+            pin_message,
+            filter_tool_output,
+            edit_message,
             reset_cost,
             set_model,
+            toggle_model,
             get_current_model,
+            set_max_tokens,
+            set_max_tool_loops,
+            is_busy,
+            cancel_tool_execution,
+            stop_generation,
+            resolve_tool_timeout,
             get_machine_status,
             get_token_stats,
             execute_remote_command,
+            preview_tool_command,
             get_ssh_config,
             update_ssh_config,
+            get_raw_config,
+            set_raw_config,
+            export_conversation_html,
+            send_message_structured,
+            get_availability_report,
+            execute_transaction,
+            copy_to_clipboard,
+            copy_last_response,
+            copy_last_tool_output,
+            run_diagnostics,
+            test_all_connections,
+            export_connection_report,
+            get_tool_queue,
+            get_cost_forecast,
+            get_cost_estimate,
+            get_tool_purpose_stats,
+            get_runtime_info,
+            export_session_as_script,
+            approve_tool_execution,
+            approve_tool_batch,
+            tag_message,
+            star_message,
+            list_starred,
+            search_by_tag,
+            list_tags,
+            save_session,
+            get_metric_series,
+            create_session,
+            switch_session,
+            list_sessions,
+            delete_session,
+            get_ssh_agent_status,
+            get_normalized_system_info,
+            get_all_normalized_system_info,
+            set_budget_limit,
+            undo,
+            redo,
+            can_undo,
+            execute_on_group,
+            get_connection_quality,
+            refresh_notion_info,
+            preview_config_change,
+            reload_machines_config,
+            add_machine,
+            remove_machine,
+            extract_code_blocks,
+            save_code_block,
+            save_code_blocks,
+            get_tool_usage_analytics,
+            disconnect_machine,
+            disconnect_all,
+            get_connection_pool_status,
         ])
         .setup(|app| {
             // Build tray menu
             let show = MenuItemBuilder::with_id("show", "表示").build(app)?;
+            let copy_last = MenuItemBuilder::with_id("copy_last", "直近の応答をコピー").build(app)?;
             let quit = MenuItemBuilder::with_id("quit", "終了").build(app)?;
-            let menu = MenuBuilder::new(app).items(&[&show, &quit]).build()?;
+            let menu = MenuBuilder::new(app).items(&[&show, &copy_last, &quit]).build()?;
 
             // Build tray icon
             TrayIconBuilder::new()
@@ -1464,7 +12420,23 @@ pub fn run() {
                             let _ = window.set_focus();
                         }
                     }
+                    "copy_last" => {
+                        // トレイからのクイックコピーは常に秘匿マスクを通す
+                        let text = {
+                            let session = app.state::<Sessions>().get(None).ok();
+                            session.and_then(|s| s.chat.lock().ok().and_then(|chat| last_assistant_response(&chat)))
+                        };
+                        match text {
+                            Some(text) => {
+                                if let Err(e) = copy_text_to_clipboard(app, &text, true) {
+                                    eprintln!("[Nexus] Tray copy failed: {}", e);
+                                }
+                            }
+                            None => eprintln!("[Nexus] Tray copy: no response yet"),
+                        }
+                    }
                     "quit" => {
+                        export_sessions_to_sync_folder(app);
                         if let Some(w) = app.get_webview_window("main") {
                             let _ = w.hide();
                         }
@@ -1476,8 +12448,18 @@ pub fn run() {
                     _ => {}
                 })
                 .on_tray_icon_event(|tray, event| {
-                    if let tauri::tray::TrayIconEvent::DoubleClick { .. } = event {
-                        let app = tray.app_handle();
+                    let app = tray.app_handle();
+                    let show = match event {
+                        tauri::tray::TrayIconEvent::DoubleClick { .. } => true,
+                        // デスクトップ通知自体のクリックは取得できないため、通知後はトレイのシングルクリックでも表示
+                        tauri::tray::TrayIconEvent::Click {
+                            button: tauri::tray::MouseButton::Left,
+                            button_state: tauri::tray::MouseButtonState::Up,
+                            ..
+                        } => take_unread_notification(app),
+                        _ => false,
+                    };
+                    if show {
                         if let Some(window) = app.get_webview_window("main") {
                             let _ = window.show();
                             let _ = window.set_focus();
@@ -1486,28 +12468,34 @@ pub fn run() {
                 })
                 .build(app)?;
 
-            // Notion情報の非同期フェッチ（バックグラウンド、起動をブロックしない）
+            // Notion情報の読み込み（TTL 内ならキャッシュ、なければ取得。バックグラウンドで起動をブロックしない）
             {
-                let ssh_state = app.state::<Mutex<SshState>>();
-                let machines = {
-                    let state = ssh_state.lock().unwrap();
-                    state.machines.clone()
-                };
                 let app_handle = app.handle().clone();
                 tauri::async_runtime::spawn(async move {
-                    eprintln!("[Nexus] Starting Notion info fetch...");
-                    let notion_info = fetch_all_notion_info(&machines).await;
-                    if !notion_info.is_empty() {
-                        eprintln!("[Nexus] Notion info loaded for {} machine(s)", notion_info.len());
-                        let ssh_state = app_handle.state::<Mutex<SshState>>();
-                        let mut state = ssh_state.lock().unwrap();
-                        state.notion_info = notion_info;
-                    } else {
-                        eprintln!("[Nexus] No Notion info fetched (key missing or no pages configured)");
+                    match apply_notion_info(&app_handle, false).await {
+                        Ok(status) => eprintln!(
+                            "[Nexus] Notion info loaded for {} machine(s) ({})",
+                            status.machines,
+                            if status.from_cache { "cache" } else { "fetched" }
+                        ),
+                        Err(e) => eprintln!("[Nexus] No Notion info loaded: {}", e),
                     }
                 });
             }
 
+            // 共有フォルダの新しい版を取り込んでから、前回保存した会話を復元（壊れたファイルは無視して空の状態で起動）
+            import_sessions_from_sync_folder(app.handle());
+            restore_saved_sessions(app.handle());
+
+            // 環境の自己診断（問題があれば diagnostics-warning で通知）
+            tauri::async_runtime::spawn(run_startup_diagnostics(app.handle().clone()));
+
+            // マシン構成の差分監視（watch_commands 未設定なら実質何もしない）
+            tauri::async_runtime::spawn(run_machine_watch_loop(app.handle().clone()));
+
+            // ネットワーク切り替えの検知（古い経路の SSH マスター接続を捨てる）
+            tauri::async_runtime::spawn(run_network_watch_loop(app.handle().clone()));
+
             Ok(())
         })
         .on_window_event(|window, event| {
@@ -1519,3 +12507,613 @@ pub fn run() {
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drain_sse_lines_keeps_multibyte_chars_split_across_chunks() {
+        let line = "data: {\"type\":\"content_block_delta\",\"delta\":{\"type\":\"text_delta\",\"text\":\"日本語のテキスト\"}}\n";
+        let bytes = line.as_bytes();
+        // 「日」(3バイト) の途中と data 行の途中で分割する
+        let split_char = line.find('日').unwrap() + 1;
+        let chunks = [&bytes[..3], &bytes[3..split_char], &bytes[split_char..split_char + 4], &bytes[split_char + 4..]];
+
+        let mut buf = Vec::new();
+        let mut lines = Vec::new();
+        for (i, chunk) in chunks.iter().enumerate() {
+            buf.extend_from_slice(chunk);
+            let drained = drain_sse_lines(&mut buf);
+            if i + 1 < chunks.len() {
+                assert!(drained.is_empty(), "未完成の行を取り出した: {:?}", drained);
+            }
+            lines.extend(drained);
+        }
+
+        assert_eq!(lines, vec![line.trim_end().to_string()]);
+        assert!(!lines[0].contains('\u{FFFD}'));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn drain_sse_lines_strips_crlf_and_keeps_partial_line() {
+        let mut buf = "data: 一\r\ndata: 二\r\ndata: 三".as_bytes().to_vec();
+        assert_eq!(drain_sse_lines(&mut buf), vec!["data: 一", "data: 二"]);
+        assert_eq!(buf, "data: 三".as_bytes());
+    }
+
+    #[test]
+    fn check_command_syntax_accepts_balanced_commands() {
+        let ok = [
+            ("echo 'it''s'", "Linux"),
+            (r#"echo "a (b) {c}""#, "Linux"),
+            ("ls $(dirname /tmp/x) && { echo ok; }", "Linux"),
+            ("echo 'unbalanced ( inside quotes'", "Linux"),
+            (r#"echo "escaped \" quote""#, "Linux"),
+            ("echo ok # comment with ' quote", "Linux"),
+            // ヒアドキュメント・case 文は検査しない
+            ("cat <<EOF\n'unterminated\nEOF", "Linux"),
+            ("case $x in a) echo a;; b) echo b;; esac", "Linux"),
+            // PowerShell はバッククォートでクォートをエスケープする
+            ("Write-Output \"say `\"hi`\"\"", "Windows"),
+            ("Get-Process | Where-Object { $_.CPU -gt 10 }", "Windows"),
+            // バックスラッシュは PowerShell ではエスケープにならない
+            (r#"Write-Output "C:\temp\""#, "Windows"),
+        ];
+        for (command, os) in ok {
+            assert_eq!(check_command_syntax(command, os), Ok(()), "{}", command);
+        }
+    }
+
+    #[test]
+    fn check_command_syntax_reports_unbalanced_quotes_and_brackets() {
+        let cases = [
+            ("echo 'abc", "Linux", ("syntax_unclosed_quote", '\'')),
+            (r#"echo "abc"#, "Linux", ("syntax_unclosed_quote", '"')),
+            ("echo $(date", "Linux", ("syntax_unclosed_bracket", '(')),
+            ("if true; then { echo a; fi", "Linux", ("syntax_unclosed_bracket", '{')),
+            ("echo a)", "Linux", ("syntax_unexpected_closer", ')')),
+            ("echo }", "Linux", ("syntax_unexpected_closer", '}')),
+            ("(echo [a)]", "Linux", ("syntax_unexpected_closer", ')')),
+            ("Write-Output \"a`\"", "Windows", ("syntax_unclosed_quote", '"')),
+        ];
+        for (command, os, expected) in cases {
+            assert_eq!(check_command_syntax(command, os), Err(expected), "{}", command);
+        }
+    }
+
+    #[test]
+    fn shell_escaping_handles_special_characters() {
+        // (入力, bash, PowerShell, cmd)
+        let cases = [
+            ("a b", "'a b'", "'a b'", r#"^"a b^""#),
+            ("it's", r"'it'\''s'", "'it''s'", r#"^"it's^""#),
+            (r#"say "hi""#, r#"'say "hi"'"#, r#"'say "hi"'"#, r#"^"say \^"hi\^"^""#),
+            ("%PATH%", "'%PATH%'", "'%PATH%'", r#"^"^%PATH^%^""#),
+            ("`date`", "'`date`'", "'`date`'", r#"^"`date`^""#),
+            ("hi!", "'hi!'", "'hi!'", r#"^"hi^!^""#),
+            (r"C:\dir\", r"'C:\dir\'", r"'C:\dir\'", r#"^"C:\dir\\^""#),
+            (r#"a\"b"#, r#"'a\"b'"#, r#"'a\"b'"#, r#"^"a\\\^"b^""#),
+            ("‘x’", "'‘x’'", "'‘‘x’’'", r#"^"‘x’^""#),
+        ];
+        for (value, bash, powershell, cmd) in cases {
+            assert_eq!(escape_for_bash(value), bash, "bash: {}", value);
+            assert_eq!(escape_for_powershell(value), powershell, "powershell: {}", value);
+            assert_eq!(escape_for_cmd(value), cmd, "cmd: {}", value);
+        }
+    }
+
+    fn test_machine(os: &str, shell: Option<ShellKind>) -> SshMachineConfig {
+        SshMachineConfig {
+            name: "test".to_string(),
+            host: "test-host".to_string(),
+            role: "Remote".to_string(),
+            enabled: true,
+            os: os.to_string(),
+            shell,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn wrap_remote_command_launches_configured_shell_from_login_shell() {
+        // ログインシェル bash から cmd を起動
+        let machine = test_machine("Linux", Some(ShellKind::Cmd));
+        let options = CommandOptions {
+            working_dir: Some(r"C:\work".to_string()),
+            env_vars: [("FOO".to_string(), "bar".to_string())].into_iter().collect(),
+        };
+        assert_eq!(
+            wrap_remote_command(&machine, "echo %PATH%", &options, "ja").unwrap(),
+            r#"cmd /d /s /c 'cd /d "C:\work" && set "FOO=bar" && echo %PATH%'"#
+        );
+
+        // ログインシェル cmd から PowerShell を起動
+        let machine = test_machine("Windows", Some(ShellKind::PowerShell));
+        let options = CommandOptions { working_dir: Some(r"C:\a b".to_string()), ..Default::default() };
+        assert_eq!(
+            wrap_remote_command(&machine, r#"Write-Output "it's""#, &options, "ja").unwrap(),
+            r#"powershell -NoProfile -NonInteractive -Command ^"Set-Location -LiteralPath 'C:\a b'; Write-Output \^"it's\^"^""#
+        );
+    }
+
+    #[test]
+    fn wrap_remote_command_keeps_login_shell_command_as_is() {
+        let machine = test_machine("Linux", None);
+        assert_eq!(wrap_remote_command(&machine, "echo 'x'", &CommandOptions::default(), "ja").unwrap(), "echo 'x'");
+        // cmd.exe 経由では改行を渡せない
+        let machine = test_machine("Windows", Some(ShellKind::PowerShell));
+        assert_eq!(
+            wrap_remote_command(&machine, "a\nb", &CommandOptions::default(), "en"),
+            Err(tr("en", "wrap_cmd_newline", &[]))
+        );
+    }
+
+    #[test]
+    fn wrap_remote_command_errors_follow_language() {
+        let machine = test_machine("Windows", None);
+        let quoted_dir = CommandOptions { working_dir: Some(r#"C:\"x"#.to_string()), ..Default::default() };
+        let bad_env = CommandOptions { env_vars: [("1FOO".to_string(), "x".to_string())].into_iter().collect(), ..Default::default() };
+        let quoted_env = CommandOptions { env_vars: [("FOO".to_string(), "a\"b".to_string())].into_iter().collect(), ..Default::default() };
+
+        assert_eq!(wrap_remote_command(&machine, "dir", &quoted_dir, "ja").unwrap_err(), "作業ディレクトリに \" は使えません（cmd.exe）: C:\\\"x");
+        assert_eq!(wrap_remote_command(&machine, "dir", &bad_env, "en").unwrap_err(), "Invalid environment variable name: 1FOO");
+        assert_eq!(
+            wrap_remote_command(&machine, "dir", &quoted_env, "en").unwrap_err(),
+            "The value of environment variable FOO cannot contain \" (cmd.exe)"
+        );
+    }
+
+    #[test]
+    fn runtime_info_reports_api_keys_without_their_values() {
+        const ANTHROPIC_SENTINEL: &str = "sk-ant-REDACTED";
+        const NOTION_SENTINEL: &str = "secret_runtime_info_sentinel_0123456789";
+        std::env::set_var("ANTHROPIC_API_KEY", ANTHROPIC_SENTINEL);
+        std::env::set_var("NOTION_API_KEY", NOTION_SENTINEL);
+
+        let info = build_runtime_info(
+            "claude-test".to_string(),
+            (2, 1),
+            (10, 15, 3),
+            &AppSettings::default(),
+            "OpenSSH_9.6".to_string(),
+            "scp".to_string(),
+        );
+        let json = serde_json::to_string(&info).unwrap();
+
+        for sentinel in [ANTHROPIC_SENTINEL, NOTION_SENTINEL] {
+            assert!(!json.contains(sentinel), "serialized runtime info leaks {}", sentinel);
+            assert!(!info.report.contains(sentinel), "report leaks {}", sentinel);
+        }
+        assert!(info.api_keys.iter().all(|k| k.configured));
+        assert!(info.report.contains("ANTHROPIC_API_KEY: 設定あり"));
+    }
+
+    /// 入力 $1 / 100万トークン・出力無料のモデルで input_tokens 分だけ使ったセッション
+    fn session_with_input_tokens(input_tokens: u64, limit_usd: Option<f64>) -> Session {
+        let session = Session::new("budget-test");
+        {
+            let mut chat = session.chat.lock().unwrap();
+            chat.model = "budget-test-model".to_string();
+            chat.token_stats.total_input_tokens = input_tokens;
+            chat.budget_limit_usd = limit_usd;
+        }
+        session
+    }
+
+    #[test]
+    fn check_budget_limit_blocks_from_exactly_the_limit() {
+        let mut pricing = std::collections::HashMap::new();
+        pricing.insert(
+            "budget-test-model".to_string(),
+            ModelPrice { input_usd_per_mtok: 1.0, output_usd_per_mtok: 0.0 },
+        );
+
+        // $0.999999: 上限の直前は送れる
+        assert_eq!(check_budget_limit(&session_with_input_tokens(999_999, Some(1.0)), &pricing), Ok(()));
+        // ちょうど $1.00 とそれを超えたら止める
+        for tokens in [1_000_000, 1_000_001] {
+            let err = check_budget_limit(&session_with_input_tokens(tokens, Some(1.0)), &pricing).unwrap_err();
+            assert!(err.contains("予算上限に達しました"), "{}", err);
+            assert!(err.contains("上限 $1.00"), "{}", err);
+        }
+        // 上限なしならいくら使っても送れる
+        assert_eq!(check_budget_limit(&session_with_input_tokens(u32::MAX as u64, None), &pricing), Ok(()));
+    }
+
+    /// SSE の data 行を1つのチャンクにまとめる
+    fn sse_chunk(events: &[serde_json::Value]) -> Vec<u8> {
+        events.iter().map(|e| format!("event: x\ndata: {}\n\n", e)).collect::<String>().into_bytes()
+    }
+
+    fn text_delta(text: &str) -> serde_json::Value {
+        serde_json::json!({ "type": "content_block_delta", "index": 0, "delta": { "type": "text_delta", "text": text } })
+    }
+
+    #[test]
+    fn sse_parser_surfaces_overloaded_error_event_instead_of_empty_success() {
+        let mut parser = SseParser::new("");
+        let events = parser.feed(&sse_chunk(&[
+            serde_json::json!({ "type": "message_start", "message": { "usage": { "input_tokens": 12 } } }),
+            text_delta("途中まで"),
+            serde_json::json!({ "type": "error", "error": { "type": "overloaded_error", "message": "Overloaded" } }),
+            // error の後ろに何か届いても読まない
+            text_delta("続き"),
+            serde_json::json!({ "type": "message_delta", "delta": { "stop_reason": "end_turn" } }),
+        ]));
+
+        assert_eq!(
+            events,
+            vec![
+                SseEvent::TextDelta("途中まで".to_string()),
+                SseEvent::Error { error_type: "overloaded_error".to_string(), message: "Overloaded".to_string() },
+            ]
+        );
+        assert_eq!(parser.turn.text, "途中まで");
+        assert_eq!(parser.turn.stop_reason, None);
+        assert_eq!(parser.turn.usage.input_tokens, 12);
+
+        let message = describe_stream_error_event("overloaded_error", "Overloaded", "ja");
+        let (user_message, detail) = split_error_detail(&message);
+        assert_eq!(user_message, tr("ja", "api_overloaded", &[]));
+        assert_eq!(detail, Some("SSE error (overloaded_error): Overloaded"));
+    }
+
+    #[test]
+    fn sse_parser_error_event_without_details_still_reports_error() {
+        let mut parser = SseParser::new("");
+        let events = parser.feed(&sse_chunk(&[serde_json::json!({ "type": "error" })]));
+        assert_eq!(
+            events,
+            vec![SseEvent::Error { error_type: "unknown_error".to_string(), message: String::new() }]
+        );
+        assert!(parser.turn.text.is_empty());
+    }
+
+    #[test]
+    fn resumed_stream_contains_prefill_exactly_once() {
+        let body = ApiRequest {
+            model: "claude-test".to_string(),
+            max_tokens: 1024,
+            system: None,
+            messages: vec![serde_json::json!({ "role": "user", "content": "挨拶して" })],
+            tools: None,
+            tool_choice: None,
+            stream: Some(true),
+        };
+
+        // 1回目: 途中まで受信して切断
+        let mut first = SseParser::new("");
+        first.feed(&sse_chunk(&[text_delta("こんにちは、"), text_delta("世界 ")]));
+        let prefill = resume_prefill(&first.turn.text, !first.turn.tool_uses.is_empty()).unwrap();
+        assert_eq!(prefill, "こんにちは、世界");
+
+        // 再開リクエスト: prefill は末尾の assistant メッセージにだけ入る
+        let resumed = with_assistant_prefill(&body, &prefill);
+        assert_eq!(body.messages.len(), 1);
+        assert_eq!(
+            resumed.messages,
+            vec![
+                serde_json::json!({ "role": "user", "content": "挨拶して" }),
+                serde_json::json!({ "role": "assistant", "content": "こんにちは、世界" }),
+            ]
+        );
+        assert_eq!(serde_json::to_string(&resumed).unwrap().matches("こんにちは").count(), 1);
+
+        // 2回目: API は prefill の続きだけを返す
+        let mut second = SseParser::new(&prefill);
+        second.feed(&sse_chunk(&[
+            text_delta("！元気ですか"),
+            serde_json::json!({ "type": "message_delta", "delta": { "stop_reason": "end_turn" } }),
+        ]));
+        let text = join_text_parts(&[second.turn.text]);
+        assert_eq!(text, "こんにちは、世界！元気ですか");
+        assert_eq!(text.matches("こんにちは").count(), 1);
+    }
+
+    fn history_message(role: &str, content: &str, tool_executions: Vec<ToolExecution>) -> HistoryMessage {
+        HistoryMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+            tool_executions,
+            raw_content: None,
+            pinned: false,
+            starred: false,
+            tags: Vec::new(),
+            estimated_tokens: 0,
+        }
+    }
+
+    fn tool_execution(machine: &str, command: &str, stdout: &str, stderr: &str, success: bool) -> ToolExecution {
+        let mut execution = ToolExecution::failed(machine, command, stderr.to_string(), 30, "default");
+        execution.stdout = stdout.to_string();
+        execution.success = success;
+        execution
+    }
+
+    #[test]
+    fn history_keeps_text_from_every_tool_loop_in_api_messages() {
+        // ツールを2回挟んだ応答: ループごとのテキストは空の部分を除いて段落として連結される
+        let parts = vec![
+            "まず容量を確認します。\n".to_string(),
+            "次にログを見ます。".to_string(),
+            "  ".to_string(),
+            "ディスクは十分で、エラーもありません。".to_string(),
+        ];
+        let final_text = join_text_parts(&parts);
+        assert_eq!(final_text, "まず容量を確認します。\n\n次にログを見ます。\n\nディスクは十分で、エラーもありません。");
+
+        let history = [
+            history_message("user", "web01 の状態は？", Vec::new()),
+            history_message(
+                "assistant",
+                &final_text,
+                vec![
+                    tool_execution("web01", "df -h /", "/dev/sda1  50G  20G  30G  40% /\n", "", true),
+                    tool_execution("web01", "journalctl -p err -n 5", "", "Permission denied", false),
+                ],
+            ),
+            history_message("user", "ありがとう", Vec::new()),
+            history_message("assistant", "どういたしまして", Vec::new()),
+        ];
+        let api_messages: Vec<serde_json::Value> = history.iter().map(history_to_api_message).collect();
+
+        assert_eq!(
+            api_messages,
+            vec![
+                serde_json::json!({ "role": "user", "content": "web01 の状態は？" }),
+                serde_json::json!({
+                    "role": "assistant",
+                    "content": "まず容量を確認します。\n\n次にログを見ます。\n\nディスクは十分で、エラーもありません。\n\n\
+                                [このターンで実行したコマンド]\n\
+                                - web01: `df -h /` → 成功\n  /dev/sda1  50G  20G  30G  40% /\n\
+                                - web01: `journalctl -p err -n 5` → 失敗\n  Permission denied"
+                }),
+                serde_json::json!({ "role": "user", "content": "ありがとう" }),
+                serde_json::json!({ "role": "assistant", "content": "どういたしまして" }),
+            ]
+        );
+    }
+
+    #[test]
+    fn history_tool_output_preview_is_truncated_with_ellipsis() {
+        let long_output = "x".repeat(HISTORY_TOOL_OUTPUT_PREVIEW + 10);
+        let message = history_message(
+            "assistant",
+            "確認しました",
+            vec![tool_execution("db01", "cat big.log", &long_output, "", true)],
+        );
+        let content = history_to_api_message(&message)["content"].as_str().unwrap().to_string();
+        let expected_preview = format!("{}…", "x".repeat(HISTORY_TOOL_OUTPUT_PREVIEW));
+        assert!(content.ends_with(&expected_preview), "{}", content);
+        assert!(!content.contains(&long_output));
+    }
+
+    /// 必須項目と追加のキーだけの1台分の machines.toml
+    fn single_machine_toml(extra: &str) -> String {
+        format!(
+            "[[machines]]\nname = \"web01\"\nhost = \"192.168.1.20\"\nrole = \"Web\"\nenabled = true\nos = \"Linux\"\n{}\n",
+            extra
+        )
+    }
+
+    #[test]
+    fn parse_machines_config_validates_port() {
+        let err = parse_machines_config(&single_machine_toml("port = 0")).err().unwrap();
+        assert_eq!(err, "machines[0] (web01): port は 1〜65535 で指定してください");
+
+        // u16 に収まらない値は型のエラーになる
+        let err = parse_machines_config(&single_machine_toml("port = 65536")).err().unwrap();
+        assert!(err.starts_with("設定エラー: "), "{}", err);
+
+        let state = parse_machines_config(&single_machine_toml("port = 2222")).unwrap();
+        let args = ssh_connection_args(&state.machines[0]);
+        assert_eq!(args, vec!["-p", "2222", "192.168.1.20"]);
+
+        let state = parse_machines_config(&single_machine_toml("")).unwrap();
+        let args = ssh_connection_args(&state.machines[0]);
+        assert!(!args.iter().any(|a| a == "-p"), "{:?}", args);
+        assert_eq!(args, vec!["192.168.1.20"]);
+    }
+
+    #[test]
+    fn parse_machines_config_rejects_invalid_user() {
+        for user in ["admin@web01", "john doe", " "] {
+            let err = parse_machines_config(&single_machine_toml(&format!("user = \"{}\"", user))).err().unwrap();
+            assert_eq!(err, format!("machines[0] (web01): user '{}' が不正です（空白や @ は含められません）", user));
+        }
+    }
+
+    #[test]
+    fn ssh_connection_args_builds_destination_and_expands_identity_file() {
+        let state = parse_machines_config(&single_machine_toml(
+            "user = \"deploy\"\nidentity_file = \"~/.ssh/id_ed25519\"",
+        ))
+        .unwrap();
+        let machine = &state.machines[0];
+        assert_eq!(ssh_destination(machine), "deploy@192.168.1.20");
+
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")).unwrap();
+        let expanded = PathBuf::from(home).join(".ssh").join("id_ed25519");
+        assert_eq!(expand_tilde(machine.identity_file.as_deref().unwrap()), expanded);
+        assert_eq!(
+            ssh_connection_args(machine),
+            vec!["-i".to_string(), expanded.to_string_lossy().into_owned(), "deploy@192.168.1.20".to_string()]
+        );
+
+        // ~user 形式と絶対パスはそのまま
+        for path in ["~other/.ssh/id", "/etc/ssh/id"] {
+            assert_eq!(expand_tilde(std::path::Path::new(path)), PathBuf::from(path));
+        }
+        let mut no_user = machine.clone();
+        no_user.user = None;
+        assert_eq!(ssh_destination(&no_user), "192.168.1.20");
+    }
+
+    #[test]
+    fn tool_sequence_is_unique_and_increasing_across_parallel_calls() {
+        let sequence = std::sync::Arc::new(ToolSequence::default());
+        // 1回目のツールループ
+        assert_eq!(sequence.next(), 1);
+
+        // 2回目のループで複数マシンへ並列実行（ToolContext の clone は同じカウンタを共有する）
+        const WORKERS: u64 = 8;
+        const CALLS: u64 = 50;
+        let per_worker: Vec<Vec<u64>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..WORKERS)
+                .map(|_| {
+                    let sequence = sequence.clone();
+                    scope.spawn(move || (0..CALLS).map(|_| sequence.next()).collect::<Vec<u64>>())
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        for numbers in &per_worker {
+            assert!(numbers.windows(2).all(|w| w[0] < w[1]), "{:?}", numbers);
+        }
+        let mut all: Vec<u64> = per_worker.into_iter().flatten().collect();
+        all.sort_unstable();
+        assert_eq!(all, (2..=WORKERS * CALLS + 1).collect::<Vec<u64>>());
+
+        // 次のループは並列実行の分より後ろから続く
+        assert_eq!(sequence.next(), WORKERS * CALLS + 2);
+    }
+
+
+    /// ユーザーの承認・拒否を送った状態で承認待ちを実行する
+    async fn decide_approval(decision: ApprovalDecision) -> Result<(), String> {
+        let (decision_tx, decision_rx) = tokio::sync::oneshot::channel();
+        let (_cancel_tx, mut cancel) = tokio::sync::watch::channel(false);
+        decision_tx.send(decision).unwrap();
+        wait_for_approval_decision(decision_rx, 5, &mut cancel, "ja").await
+    }
+
+    #[tokio::test]
+    async fn user_mismatch_runs_only_when_user_approves() {
+        let reason = tr("ja", "user_mismatch", &[("expected", "deploy"), ("actual", "root")]);
+
+        // 承認されたら実行を続ける
+        let approved = decide_approval(ApprovalDecision { approved: true, reason: None }).await;
+        assert_eq!(user_guard_outcome(&reason, Some(approved), "ja"), Ok(()));
+
+        // 拒否されたら不一致の内容と拒否理由を返して実行しない
+        let rejected = decide_approval(ApprovalDecision { approved: false, reason: Some("root では実行しない".to_string()) }).await;
+        assert_eq!(
+            user_guard_outcome(&reason, Some(rejected), "ja"),
+            Err(format!(
+                "実行ユーザー不一致: 期待 'deploy' / 実際 'root': {}",
+                tr("ja", "tool_rejected", &[("reason", "root では実行しない")])
+            ))
+        );
+
+        // 承認機能が無効で承認を求めなかったときは実行しない
+        assert_eq!(
+            user_guard_outcome(&reason, None, "ja"),
+            Err(tr("ja", "user_guard_blocked", &[("reason", &reason)]))
+        );
+    }
+
+    #[tokio::test]
+    async fn approval_wait_ends_on_cancel() {
+        let (_decision_tx, decision_rx) = tokio::sync::oneshot::channel::<ApprovalDecision>();
+        let (cancel_tx, mut cancel) = tokio::sync::watch::channel(false);
+        cancel_tx.send_replace(true);
+        assert_eq!(
+            wait_for_approval_decision(decision_rx, 5, &mut cancel, "ja").await,
+            Err(tr("ja", "tool_cancelled", &[]))
+        );
+    }
+
+
+    /// 1回だけ接続を受け付け、body を SSE として返して切断するローカルサーバ
+    async fn serve_sse_once(body: String) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n";
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(body.as_bytes()).await.unwrap();
+            socket.shutdown().await.unwrap();
+        });
+        format!("http://{}/v1/messages", addr)
+    }
+
+    #[tokio::test]
+    async fn read_sse_stream_keeps_partial_text_when_error_event_arrives_mid_stream() {
+        let mut body = String::from_utf8(sse_chunk(&[
+            serde_json::json!({ "type": "message_start", "message": { "usage": { "input_tokens": 30 } } }),
+            text_delta("ディスク使用率は"),
+            text_delta("42%で"),
+        ]))
+        .unwrap();
+        body.push_str("event: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n");
+        let url = serve_sse_once(body).await;
+        let response = reqwest::get(&url).await.unwrap();
+
+        let session = Session::new("sse-test");
+        let events = Mutex::new(Vec::new());
+        let result = read_sse_stream(response, &session, &StreamSettings::default(), "ja", "", |event, payload| {
+            events.lock().unwrap().push((event.to_string(), payload));
+        })
+        .await;
+
+        let Err(StreamReadError::ApiError { message, partial_text, usage }) = result else {
+            panic!("error event must surface as StreamReadError::ApiError");
+        };
+        assert_eq!(partial_text, "ディスク使用率は42%で");
+        assert_eq!(usage.input_tokens, 30);
+        assert_eq!(split_error_detail(&message).0, tr("ja", "api_overloaded", &[]));
+
+        let events = events.into_inner().unwrap();
+        assert_eq!(
+            events,
+            vec![
+                ("stream-delta".to_string(), serde_json::json!({ "text": "ディスク使用率は" })),
+                ("stream-delta".to_string(), serde_json::json!({ "text": "42%で" })),
+                (
+                    "stream-error".to_string(),
+                    serde_json::json!({ "error_type": "overloaded_error", "message": "Overloaded", "partial_chars": 12 })
+                ),
+            ]
+        );
+    }
+
+
+    #[test]
+    fn check_structured_value_reports_nested_type_mismatch_with_path() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "required": ["server"],
+            "properties": {
+                "server": {
+                    "type": "object",
+                    "properties": { "disk": { "type": "object", "properties": { "used_percent": { "type": "number" } } } }
+                }
+            }
+        });
+        let validator = compile_structured_schema(&schema).unwrap();
+
+        let ok = serde_json::json!({ "server": { "disk": { "used_percent": 42.5 } } });
+        assert!(check_structured_value(&ok, &validator).is_ok());
+
+        let nested_mismatch = serde_json::json!({ "server": { "disk": { "used_percent": "42%" } } });
+        let err = check_structured_value(&nested_mismatch, &validator).unwrap_err();
+        assert!(err.contains("/server/disk/used_percent"), "{}", err);
+        assert!(err.contains("\"42%\""), "{}", err);
+
+        let invalid_schema = serde_json::json!({ "type": "objekt" });
+        assert!(compile_structured_schema(&invalid_schema).unwrap_err().starts_with("json_schema が不正です"));
+    }
+}
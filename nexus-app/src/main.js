@@ -6,6 +6,11 @@
 const { invoke } = window.__TAURI__.core;
 const { listen } = window.__TAURI__.event;
 
+// イベントが表示中のセッション宛てか（session_id のないイベントは全体向け）
+function isCurrentSession(payload) {
+  return !payload || payload.session_id === undefined || payload.session_id === currentSessionId;
+}
+
 // DOM Elements
 let messagesEl;
 let chatInputEl;
@@ -30,12 +35,14 @@ let statusPollTimer = null;
 let streamingMsgEl = null; // 現在ストリーミング中のメッセージ要素
 let streamingContentEl = null; // ストリーミング中のcontent要素
 let streamingText = ""; // 蓄積テキスト
+let currentSessionId = "default"; // 送信先の会話セッション（セッションごとに並列処理される）
 const STATUS_POLL_INTERVAL = 15000; // 15秒間隔（軽量化）
 
 // Model pricing (per million tokens)
 const MODEL_PRICING = {
   "claude-sonnet-4-5-20250929": { input: 3.0, output: 15.0, contextWindow: 200000 },
   "claude-haiku-4-5-20251001": { input: 0.80, output: 4.0, contextWindow: 200000 },
+  "claude-opus-4-1-20250805": { input: 15.0, output: 75.0, contextWindow: 200000 },
 };
 
 // Context warning thresholds
@@ -85,44 +92,102 @@ window.addEventListener("DOMContentLoaded", () => {
     autoResizeTextarea();
   });
 
+  loadSessionHistory(true);
+  refreshSessionList();
+
   // Model selector
   const modelSelect = document.getElementById("model-select");
   if (modelSelect) {
-    invoke("get_current_model").then((model) => {
+    invoke("get_current_model", { sessionId: currentSessionId }).then((model) => {
       modelSelect.value = model;
       currentModel = model;
     });
 
     modelSelect.addEventListener("change", async (e) => {
       try {
-        const result = await invoke("set_model", { modelId: e.target.value });
-        currentModel = e.target.value;
+        const result = await invoke("set_model", { modelId: e.target.value, sessionId: currentSessionId });
         addMessage("system", result);
       } catch (err) {
         addMessage("system", `Error: ${err}`);
-        const current = await invoke("get_current_model");
+        const current = await invoke("get_current_model", { sessionId: currentSessionId });
         modelSelect.value = current;
         currentModel = current;
       }
     });
   }
 
-  // New chat button
+  // モデル変更通知（セレクタ・トグル・エイリアス指定のいずれでも同期）
+  listen("model-changed", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    currentModel = event.payload.model;
+    if (modelSelect) modelSelect.value = currentModel;
+    updateContextBadge(currentTokenStats);
+  });
+
+  // Ctrl+Z / Ctrl+Y: 会話の取り消し・やり直し（入力欄の編集中はテキストの undo を優先）
+  document.addEventListener("keydown", async (e) => {
+    if (!e.ctrlKey || e.altKey) return;
+    const key = e.key.toLowerCase();
+    if (key !== "z" && key !== "y") return;
+    const tag = document.activeElement && document.activeElement.tagName;
+    if (tag === "INPUT" || tag === "TEXTAREA" || tag === "SELECT") return;
+    e.preventDefault();
+    try {
+      await invoke(key === "z" && !e.shiftKey ? "undo" : "redo", { sessionId: currentSessionId });
+    } catch (err) {
+      addMessage("system", `${err}`);
+    }
+  });
+
+  // Esc: 生成中の応答を中断（受信済みの内容は履歴に残る）
+  document.addEventListener("keydown", async (e) => {
+    if (e.key !== "Escape" || !isProcessing) return;
+    e.preventDefault();
+    try {
+      await invoke("stop_generation", { sessionId: currentSessionId });
+    } catch (err) {
+      addMessage("system", `中断できませんでした: ${err}`);
+    }
+  });
+
+  // undo / redo で履歴・モデル・統計が戻ったら表示を読み直す
+  listen("history-restored", async (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const labels = { send: "送信", clear: "履歴のクリア", reset_cost: "コストのリセット", model: "モデル変更" };
+    const { action, direction } = event.payload;
+    messagesEl.innerHTML = "";
+    messageHistory = [];
+    removeContextWarning();
+    currentModel = await invoke("get_current_model", { sessionId: currentSessionId });
+    if (modelSelect) modelSelect.value = currentModel;
+    await loadSessionHistory(false);
+    addMessage("system", `${labels[action] || action}を${direction === "redo" ? "やり直しました" : "取り消しました"}（Ctrl+Z / Ctrl+Y）`);
+  });
+
+  // Ctrl+M: Sonnet ↔ Haiku クイックトグル
+  document.addEventListener("keydown", async (e) => {
+    if (e.ctrlKey && e.key.toLowerCase() === "m") {
+      e.preventDefault();
+      try {
+        const result = await invoke("toggle_model", { sessionId: currentSessionId });
+        addMessage("system", result);
+      } catch (err) {
+        addMessage("system", `Error: ${err}`);
+      }
+    }
+  });
+
+  // New chat button: 新しいセッションを作って切り替える（元の会話はサイドバーから戻れる）
   const newChatBtn = document.getElementById("new-chat-btn");
   if (newChatBtn) {
     newChatBtn.addEventListener("click", async () => {
+      if (isProcessing) {
+        addMessage("system", "応答中は新しい会話を作成できません");
+        return;
+      }
       try {
-        await invoke("clear_history");
-        messagesEl.innerHTML = "";
-        messageHistory = [];
-        currentTokenStats = null;
-        // コンテキスト%のみリセット、コスト累計は保持
-        const textEl = contextBadgeEl.querySelector(".context-text");
-        textEl.textContent = "0%";
-        contextBadgeEl.style.color = "var(--text-secondary)";
-        contextBadgeEl.title = "";
-        removeContextWarning();
-        addMessage("system", "会話履歴をクリアしました（コスト累計は保持）");
+        const session = await invoke("create_session", {});
+        await switchToSession(session.id);
       } catch (err) {
         addMessage("system", `Error: ${err}`);
       }
@@ -142,27 +207,106 @@ window.addEventListener("DOMContentLoaded", () => {
   const costBadge = document.getElementById("cost-badge");
   if (costBadge) {
     costBadge.style.cursor = "pointer";
-    costBadge.title = "クリックでコスト累計をリセット";
+    costBadge.title = "クリックでコスト累計をリセット / 右クリックで予算上限を設定";
     costBadge.addEventListener("click", async () => {
       if (confirm("コスト累計をリセットしますか？")) {
         try {
-          await invoke("reset_cost");
-          currentTokenStats = null;
-          costBadge.textContent = "$0.00";
+          // 表示は token-stats-updated で更新される
+          await invoke("reset_cost", { sessionId: currentSessionId });
           addMessage("system", "コスト累計をリセットしました");
         } catch (err) {
           addMessage("system", `Error: ${err}`);
         }
       }
     });
+    // 右クリックでこのセッションの予算上限を設定（空欄で解除）
+    costBadge.addEventListener("contextmenu", async (e) => {
+      e.preventDefault();
+      const input = prompt("このセッションの予算上限（USD、空欄で解除）", "");
+      if (input === null) return;
+      const limitUsd = input.trim() === "" ? null : Number(input);
+      try {
+        await invoke("set_budget_limit", { limitUsd, sessionId: currentSessionId });
+        addMessage("system", limitUsd === null ? "予算上限を解除しました" : `予算上限を $${limitUsd} に設定しました`);
+      } catch (err) {
+        addMessage("system", `Error: ${err}`);
+      }
+    });
   }
 
+  // 予算上限の80%到達・上限到達
+  listen("budget-warning", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { cost_usd, limit_usd, ratio, reached } = event.payload;
+    const status = reached ? "予算上限に達したため、これ以上は送信できません" : `予算上限の ${Math.round(ratio * 100)}% に達しました`;
+    addMessage("system", `⚠️ ${status}（$${cost_usd.toFixed(4)} / $${limit_usd.toFixed(2)}）`);
+  });
+
+  // API の混雑・レート制限による自動再試行
+  listen("api-retrying", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { attempt, max_attempts, delay_ms, status } = event.payload;
+    addMessage("system", `⏳ API が応答できませんでした（HTTP ${status}）。${(delay_ms / 1000).toFixed(1)}秒後に再試行します（${attempt}/${max_attempts}）`);
+  });
+
+  // トークン統計の更新（送信完了・要約・リセット・クリアのたびにバックエンドから届く）
+  listen("token-stats-updated", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { token_stats, reason } = event.payload;
+    currentTokenStats = token_stats;
+    updateContextBadge(token_stats);
+    refreshCostEstimate();
+    if (reason === "request") {
+      checkContextWarning(token_stats);
+    }
+  });
+
+  // バックエンドの処理中状態（編集の再送信など画面外からの送信も含む）で送信ボタンを制御
+  listen("busy-changed", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    sendBtnEl.disabled = event.payload.busy || isProcessing;
+  });
+  invoke("is_busy", { sessionId: currentSessionId }).then((status) => {
+    sendBtnEl.disabled = status.busy || isProcessing;
+  });
+
+  // 1リクエストのトークン予算: 結論段階・打ち切りに入ったときだけ通知
+  let lastBudgetPhase = "normal";
+  listen("budget-progress", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { used_tokens, limit_tokens, ratio, phase } = event.payload;
+    if (event.payload.loop === 1) lastBudgetPhase = "normal";
+    if (phase === lastBudgetPhase) return;
+    lastBudgetPhase = phase;
+    const action = phase === "exhausted" ? "ツール実行を打ち切りました" : "結論をまとめるよう指示しました";
+    addMessage(
+      "system",
+      `トークン予算を${Math.round(ratio * 100)}%消化したため、${action}（${used_tokens.toLocaleString()} / ${limit_tokens.toLocaleString()}）`
+    );
+  });
+
+  // 履歴がトークン予算を超えて古いメッセージが落とされたことを通知
+  listen("context-trimmed", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { removed, summarized, saved_tokens, estimated_tokens, budget_tokens } = event.payload;
+    const action = summarized
+      ? `古いメッセージ ${removed} 件を要約に置き換えました（約 ${saved_tokens.toLocaleString()} トークン削減）`
+      : `古いメッセージを ${removed} 件省略しました`;
+    addMessage(
+      "system",
+      `コンテキスト上限に近づいたため、${action}（約 ${estimated_tokens.toLocaleString()} / ${budget_tokens.toLocaleString()} トークン）`
+    );
+  });
+
   // Tool Use: Tauri events for real-time status
   setupToolUseEvents();
 
   // Streaming response events
   setupStreamingEvents();
 
+  // Tool output filter in execution summaries
+  setupExecFilter();
+
   // Initial machine status + start polling
   refreshMachineStatus();
   statusPollTimer = setInterval(refreshMachineStatus, STATUS_POLL_INTERVAL);
@@ -184,24 +328,49 @@ async function handleSend() {
 
   try {
     // ストリーミングAPIを使用（イベント経由でリアルタイム表示）
-    const response = await invoke("send_message_stream", { message: text });
+    const response = await invoke("send_message_stream", { message: text, sessionId: currentSessionId });
     // stream-end イベントで統計更新済みだが、最終レスポンスからも反映
     currentTokenStats = response.token_stats;
     updateContextBadge(response.token_stats);
     checkContextWarning(response.token_stats);
 
+    // 実機確認済みの回答にはバッジを付けて推測回答と区別
+    if (streamingMsgEl && response.grounding === "verified") {
+      addGroundingBadge(streamingMsgEl);
+    }
+
+    // 後処理で前置き等が除去された場合は確定テキストに差し替え（原文は切り替えで表示）
+    if (streamingMsgEl && response.raw_text) {
+      applyPostprocessedText(streamingMsgEl, response.text, response.raw_text);
+    }
+
+    // 応答中のコマンド・パス・URLをアクションボタンとして追加
+    if (streamingMsgEl && response.annotations && response.annotations.length > 0) {
+      addAnnotationActions(streamingMsgEl, response.annotations);
+    }
+
     // ストリーミング完了後：ツール実行サマリーがあれば追加
     if (streamingMsgEl && response.tool_executions && response.tool_executions.length > 0) {
       const summaryHtml = buildToolExecutionSummary(response.tool_executions);
       streamingMsgEl.insertAdjacentHTML("beforeend", summaryHtml);
     }
+
+    // 途中でエラーになった場合は、ここまでの内容が履歴に保存されたことを通知
+    if (response.error) {
+      addErrorMessage(
+        `応答の途中でエラーが発生しました（ここまでの内容は履歴に保存済み）: ${response.error}`,
+        [response.error_detail, `trace_id: ${response.trace_id}`].filter(Boolean).join("\n")
+      );
+    }
   } catch (err) {
     // ストリーミング中のメッセージがあればクリーンアップ
     cleanupStreamingState();
-    addMessage("system", `Error: ${err}`);
+    const { message, detail } = splitErrorDetail(String(err));
+    addErrorMessage(`Error: ${message}`, detail);
   } finally {
     cleanupStreamingState();
     setProcessing(false);
+    refreshSessionList();
     chatInputEl.focus();
   }
 }
@@ -217,7 +386,8 @@ function cleanupStreamingState() {
 
 function setupStreamingEvents() {
   // ストリーム開始：空のassistantメッセージ要素を作成
-  listen("stream-start", () => {
+  listen("stream-start", (event) => {
+    if (!isCurrentSession(event.payload)) return;
     // タイピングインジケータ除去
     const typingEl = messagesEl.querySelector(".typing-message");
     if (typingEl) typingEl.remove();
@@ -226,6 +396,8 @@ function setupStreamingEvents() {
 
     const msgEl = document.createElement("div");
     msgEl.className = "message assistant";
+    // 送信1回分のトレースID（ログ・監査記録と突き合わせる用）
+    msgEl.dataset.traceId = event.payload.trace_id || "";
     msgEl.innerHTML = `
       <div class="message-sender">Claude</div>
       <div class="message-content streaming-content"></div>
@@ -239,6 +411,7 @@ function setupStreamingEvents() {
 
   // テキスト増分：リアルタイム表示
   listen("stream-delta", (event) => {
+    if (!isCurrentSession(event.payload)) return;
     if (!streamingContentEl) return;
     streamingText += event.payload.text;
     // シンプルなテキスト→HTML変換（改行対応）
@@ -247,22 +420,117 @@ function setupStreamingEvents() {
   });
 
   // ツール継続通知（UIは既存のtool-executingイベントで処理）
-  listen("stream-tool-continue", () => {
+  listen("stream-tool-continue", (event) => {
+    if (!isCurrentSession(event.payload)) return;
     // ストリーミングテキストをリセットせず継続
-    // ツール結果後の追加テキストも同じメッセージに蓄積
+    // ツール結果後の追加テキストも同じメッセージに蓄積（履歴と同じく段落区切り）
+    if (streamingText && !streamingText.endsWith("\n\n")) {
+      streamingText = streamingText.trimEnd() + "\n\n";
+    }
+  });
+
+  // ストリーム停止→再試行：この試行で表示した分を取り消して再受信に備える
+  listen("stream-stalled", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { discard_chars } = event.payload;
+    streamingText = streamingText.slice(0, Math.max(0, streamingText.length - discard_chars));
+    if (streamingContentEl) {
+      streamingContentEl.innerHTML = formatStreamingText(streamingText);
+    }
+  });
+
+  // ストリーム途中で API がエラーを返した（途中まで表示したテキストは部分応答として残る）
+  listen("stream-error", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { error_type, partial_chars } = event.payload;
+    if (partial_chars > 0) {
+      addMessage("system", `⚠ 応答の受信中に API がエラーを返しました（${error_type}）。ここまでの応答を残しています`);
+    }
+  });
+
+  // 切断されたストリームを続きから再開する（prefill に含めなかった末尾の空白だけ取り消す）
+  listen("stream-resuming", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { discard_chars } = event.payload;
+    streamingText = streamingText.slice(0, Math.max(0, streamingText.length - discard_chars));
+    if (streamingContentEl) {
+      streamingContentEl.innerHTML = formatStreamingText(streamingText);
+    }
+  });
+
+  // 空応答・不完全な応答を再生成する（表示済みの途中テキストは取り消す）
+  listen("response-retrying", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { discard_chars } = event.payload;
+    streamingText = streamingText.slice(0, Math.max(0, streamingText.length - discard_chars));
+    if (streamingContentEl) {
+      streamingContentEl.innerHTML = formatStreamingText(streamingText);
+    }
+    addMessage("system", "⚠️ 応答が不完全だったため、もう一度生成しています");
+  });
+
+  // 出力上限で切れた応答の続きを自動で依頼（続きは同じメッセージに連結される）
+  listen("response-continuing", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { attempt, max_attempts } = event.payload;
+    addMessage("system", `✂️ 出力上限に達したため、続きを自動で生成しています（${attempt}/${max_attempts}）`);
+  });
+
+  // 出力上限（max_tokens）で応答が途中で切れた
+  listen("response-truncated", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { max_tokens } = event.payload;
+    addMessage("system", `✂️ 出力上限（${max_tokens} トークン）に達したため応答が途中で切れました。続きが必要なら「続きをお願いします」と送信してください`);
+    if (!chatInputEl.value.trim()) {
+      chatInputEl.value = "続きをお願いします";
+    }
+  });
+
+  // 実行ユーザー不一致（承認機能が無効なため承認を求めず、コマンドは未実行）
+  listen("tool-user-mismatch", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { machine_name, command, reason } = event.payload;
+    addMessage("system", `⚠ ${machine_name}: ${reason}（未実行: ${command}）`);
+  });
+
+  // 起動時の自己診断で見つかった問題
+  listen("diagnostics-warning", (event) => {
+    const lines = event.payload.map((item) => {
+      const mark = item.status === "error" ? "✗" : "⚠";
+      return `${mark} ${item.name}: ${item.message}${item.hint ? `\n   → ${item.hint}` : ""}`;
+    });
+    addMessage("system", `環境診断で問題が見つかりました\n${lines.join("\n")}`);
+  });
+
+  // マシン構成の変化検知
+  listen("machine-changed", (event) => {
+    const { machine_name, command, diff } = event.payload;
+    addMessage("system", `🔔 ${machine_name} の構成が変化しました（${command}）\n${diff}`);
+  });
+
+  // ネットワーク切り替えの検知（SSH の再利用接続をリセット済み）
+  listen("network-changed", (event) => {
+    const { previous_address, address, closed_connections } = event.payload;
+    if (closed_connections === 0) return;
+    addMessage("system", `🌐 ネットワークが切り替わりました（${previous_address} → ${address}）。SSH 接続 ${closed_connections} 本をリセットしました`);
   });
 
   // ストリーム完了
   listen("stream-end", (event) => {
+    if (!isCurrentSession(event.payload)) return;
     // ツールステータスメッセージをクリーンアップ
     messagesEl.querySelectorAll(".tool-status-message").forEach((el) => el.remove());
 
-    const { token_stats } = event.payload;
+    const { token_stats, cancelled } = event.payload;
     if (token_stats) {
       currentTokenStats = token_stats;
       updateContextBadge(token_stats);
       checkContextWarning(token_stats);
     }
+    // 中断した場合も受信済みの内容は表示・履歴にそのまま残る
+    if (cancelled) {
+      addMessage("system", "⏹ 生成を中断しました（ここまでの内容は履歴に保存済み）");
+    }
     // ストリーミングクラス除去（カーソルアニメ停止用）
     if (streamingContentEl) {
       streamingContentEl.classList.remove("streaming-content");
@@ -311,6 +579,122 @@ function addMessage(role, content) {
   updateContextBadge();
 }
 
+// 表示中セッションの履歴とトークン統計を読み込んで表示する（起動時は復元した旨も通知）
+async function loadSessionHistory(restored) {
+  try {
+    const history = await invoke("get_history", { sessionId: currentSessionId });
+    for (const m of history) {
+      addMessage(m.role, m.content);
+    }
+    if (restored && history.length > 0) {
+      addMessage("system", `前回の会話を復元しました（${history.length}件）`);
+    }
+    const stats = await invoke("get_token_stats", { sessionId: currentSessionId });
+    currentTokenStats = stats;
+    updateContextBadge(stats);
+  } catch (err) {
+    console.error("History load error:", err);
+  }
+}
+
+// ========================================
+// Sessions
+// ========================================
+async function refreshSessionList() {
+  const listEl = document.getElementById("session-list");
+  if (!listEl) return;
+  try {
+    const sessions = await invoke("list_sessions");
+    listEl.innerHTML = "";
+    for (const s of sessions) {
+      const item = document.createElement("div");
+      item.className = `session-item${s.id === currentSessionId ? " active" : ""}`;
+      item.title = `${s.message_count}件のメッセージ`;
+      item.innerHTML = `
+        <span class="session-icon">💬</span>
+        <span class="session-name">${escapeHtml(s.title)}</span>
+        ${s.id === "default" ? "" : `<button class="session-delete-btn" title="削除">×</button>`}`;
+      item.addEventListener("click", () => switchToSession(s.id));
+      const deleteBtn = item.querySelector(".session-delete-btn");
+      if (deleteBtn) {
+        deleteBtn.addEventListener("click", (e) => {
+          e.stopPropagation();
+          deleteSession(s);
+        });
+      }
+      listEl.appendChild(item);
+    }
+  } catch (err) {
+    console.error("Session list error:", err);
+  }
+}
+
+async function switchToSession(sessionId) {
+  if (sessionId === currentSessionId) return;
+  if (isProcessing) {
+    addMessage("system", "応答中はセッションを切り替えられません");
+    return;
+  }
+  try {
+    await invoke("switch_session", { sessionId });
+  } catch (err) {
+    addMessage("system", `Error: ${err}`);
+    return;
+  }
+  currentSessionId = sessionId;
+  messagesEl.innerHTML = "";
+  messageHistory = [];
+  currentTokenStats = null;
+  removeContextWarning();
+  updateContextBadge(null);
+  const model = await invoke("get_current_model", { sessionId });
+  currentModel = model;
+  const modelSelect = document.getElementById("model-select");
+  if (modelSelect) modelSelect.value = model;
+  await loadSessionHistory(false);
+  const status = await invoke("is_busy", { sessionId });
+  sendBtnEl.disabled = status.busy || isProcessing;
+  refreshSessionList();
+  chatInputEl.focus();
+}
+
+async function deleteSession(session) {
+  if (!confirm(`「${session.title}」を削除しますか？（保存された履歴も削除されます）`)) return;
+  try {
+    const activeId = await invoke("delete_session", { sessionId: session.id });
+    if (session.id === currentSessionId) {
+      await switchToSession(activeId);
+    } else {
+      refreshSessionList();
+    }
+  } catch (err) {
+    addMessage("system", `Error: ${err}`);
+  }
+}
+
+// バックエンドが利用者向けメッセージと元エラーを区切る記号（lib.rs の ERROR_DETAIL_SEPARATOR と揃える）
+const ERROR_DETAIL_SEPARATOR = "\n--- detail ---\n";
+
+function splitErrorDetail(text) {
+  const index = text.indexOf(ERROR_DETAIL_SEPARATOR);
+  if (index < 0) return { message: text, detail: null };
+  return { message: text.substring(0, index), detail: text.substring(index + ERROR_DETAIL_SEPARATOR.length) };
+}
+
+/**
+ * エラーのシステムメッセージ（技術的な詳細は折りたたんで添える）
+ */
+function addErrorMessage(message, detail) {
+  addMessage("system", message);
+  if (!detail) return;
+  const contentEl = messagesEl.lastElementChild?.querySelector(".message-content");
+  if (!contentEl) return;
+  contentEl.insertAdjacentHTML(
+    "beforeend",
+    `<details class="error-detail"><summary>詳細</summary><pre>${escapeHtml(detail)}</pre></details>`
+  );
+}
+
 function showTypingIndicator() {
   const typingEl = document.createElement("div");
   typingEl.className = "message assistant typing-message";
@@ -360,7 +744,11 @@ function updateContextBadge(stats) {
     // Calculate session cost
     const inputCost = (stats.total_input_tokens / 1_000_000) * pricing.input;
     const outputCost = (stats.total_output_tokens / 1_000_000) * pricing.output;
-    const totalCost = inputCost + outputCost;
+    // ツール出力の要約（Haiku）分
+    const summaryPricing = MODEL_PRICING["claude-haiku-4-5-20251001"];
+    const summaryCost = ((stats.summary_input_tokens || 0) / 1_000_000) * summaryPricing.input +
+      ((stats.summary_output_tokens || 0) / 1_000_000) * summaryPricing.output;
+    const totalCost = inputCost + outputCost + summaryCost;
     costText = `$${totalCost.toFixed(4)}`;
   }
 
@@ -378,6 +766,9 @@ function updateContextBadge(stats) {
   if (stats) {
     contextBadgeEl.title = `Context: ${inputTokens.toLocaleString()} / ${contextWindow.toLocaleString()} tokens\n` +
       `累計: ${stats.total_input_tokens.toLocaleString()} in / ${stats.total_output_tokens.toLocaleString()} out\n` +
+      (stats.summary_input_tokens ? `要約: ${stats.summary_input_tokens.toLocaleString()} in / ${(stats.summary_output_tokens || 0).toLocaleString()} out\n` : "") +
+      (cacheHitText(stats)) +
+      (stats.history_summary_saved_tokens ? `履歴の要約で削減: 約 ${stats.history_summary_saved_tokens.toLocaleString()} トークン\n` : "") +
       `Requests: ${stats.request_count}\n累計コスト: ${costText}`;
   }
 
@@ -391,6 +782,29 @@ function updateContextBadge(stats) {
   }
 }
 
+// プロンプトキャッシュのヒット率（入力のうちキャッシュから読んだ割合）
+function cacheHitText(stats) {
+  const read = stats.total_cache_read_input_tokens || 0;
+  const created = stats.total_cache_creation_input_tokens || 0;
+  const total = read + created + stats.total_input_tokens;
+  if (read + created === 0 || total === 0) return "";
+  return `キャッシュ: 読み取り ${read.toLocaleString()} / 書き込み ${created.toLocaleString()}（ヒット率 ${Math.round((read / total) * 100)}%）\n`;
+}
+
+// コストバッジをバックエンドの見積もり（モデル別の単価・settings.toml の上書きを反映）で更新
+async function refreshCostEstimate() {
+  const costEl = document.getElementById("cost-badge");
+  if (!costEl) return;
+  try {
+    const estimate = await invoke("get_cost_estimate", { sessionId: currentSessionId });
+    costEl.textContent = `$${estimate.total_usd.toFixed(4)}`;
+    costEl.title = "クリックでコスト累計をリセット / 右クリックで予算上限を設定\n" +
+      estimate.by_model.map((c) => `${c.model}: $${c.cost_usd.toFixed(4)}`).join("\n");
+  } catch (err) {
+    console.error("Cost estimate error:", err);
+  }
+}
+
 function checkContextWarning(stats) {
   if (!stats || stats.last_input_tokens === 0) return;
 
@@ -467,6 +881,10 @@ function renderMachineList(statuses) {
     const isSelected = selectedRemoteMachine === m.name;
 
     div.className = `machine-item ${isOnline ? "online" : "offline"}${isRemote ? " selectable" : ""}${isSelected ? " selected" : ""}`;
+    if (m.connected_host) {
+      div.title = `接続先: ${m.connected_host}`;
+      if (m.latency_ms != null) div.title += `\n接続時間: ${m.latency_ms} ms`;
+    }
 
     div.innerHTML = `
       <span class="status-dot"></span>
@@ -545,20 +963,228 @@ async function handleRemoteExec() {
  */
 function setupToolUseEvents() {
   listen("tool-executing", (event) => {
-    const { machine_name, command } = event.payload;
-    showToolStatus(machine_name, command, "executing");
+    if (!isCurrentSession(event.payload)) return;
+    const { machine_name, command, sequence, execution_id } = event.payload;
+    showToolStatus(machine_name, command, "executing", sequence, execution_id);
   });
 
   listen("tool-completed", (event) => {
-    const { machine_name, command, success } = event.payload;
-    showToolStatus(machine_name, command, success ? "success" : "error");
+    if (!isCurrentSession(event.payload)) return;
+    const { machine_name, command, success, sequence } = event.payload;
+    showToolStatus(machine_name, command, success ? "success" : "error", sequence);
+  });
+
+  // 出力が要約・切り詰めされて Claude に渡った場合は印を付ける
+  listen("tool-result-ready", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    const { sequence, summarized, truncated } = event.payload;
+    const statusEl = messagesEl.querySelector(`.tool-status-message[data-tool-seq="${sequence}"] .tool-status-text`);
+    if (statusEl && summarized) statusEl.insertAdjacentText("beforeend", "（要約して送信）");
+    if (statusEl && truncated) statusEl.insertAdjacentText("beforeend", "（切り詰めて送信）");
+  });
+
+  // 破壊的コマンドは承認するまで実行されない
+  listen("tool-approval-required", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    showToolApproval(event.payload);
   });
+
+  // 同じターンの承認が必要なコマンドをまとめて確認する（settings.toml の approval.batch）
+  listen("tool-approval-batch-required", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    showToolApprovalBatch(event.payload);
+  });
+
+  // タイムアウトしたコマンドを待ち続けるか打ち切るか選ばせる（settings.toml の ask_on_timeout）
+  listen("tool-timeout-pending", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    showToolTimeoutPending(event.payload);
+  });
+
+  // 長時間コマンドの出力を行単位で逐次表示
+  listen("tool-output-line", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    appendToolOutputLine(event.payload);
+  });
+
+  // 実行中コマンドの対象マシンの負荷（load_monitor 有効時のみ届く）
+  listen("tool-machine-load", (event) => {
+    if (!isCurrentSession(event.payload)) return;
+    showToolMachineLoad(event.payload);
+  });
+}
+
+/**
+ * 承認待ちのツール実行に「実行」「拒否」ボタンを表示
+ */
+function showToolApproval({ execution_id, sequence, machine_name, command, kind, pattern, timeout_secs }) {
+  const statusEl = messagesEl.querySelector(`.tool-status-message[data-tool-seq="${sequence}"]`);
+  if (!statusEl) return;
+  // kind: "danger"（破壊的コマンド）/ "user_mismatch"（expected_user と実行ユーザーが異なる）
+  const userMismatch = kind === "user_mismatch";
+  const approvalEl = document.createElement("div");
+  approvalEl.className = "tool-approval";
+  approvalEl.innerHTML = `
+    <div class="tool-approval-text">⚠️ ${userMismatch ? "想定と異なるユーザーで実行されます" : "破壊的な可能性のあるコマンドです"}（${escapeHtml(machine_name)}）。${timeout_secs}秒以内に応答がなければ実行しません</div>
+    <pre class="tool-approval-command">${escapeHtml(command)}</pre>
+    <div class="tool-approval-pattern">${userMismatch ? escapeHtml(pattern) : `一致したパターン: <code>${escapeHtml(pattern)}</code>`}</div>
+    <div class="tool-approval-actions">
+      <button class="tool-approve-btn">実行する</button>
+      <button class="tool-reject-btn">拒否</button>
+    </div>
+  `;
+  statusEl.appendChild(approvalEl);
+
+  const decide = async (approved) => {
+    const reason = approved ? null : prompt("拒否の理由（Claude に伝えます。空欄可）", "") ?? "";
+    approvalEl.querySelectorAll("button").forEach((btn) => (btn.disabled = true));
+    try {
+      await invoke("approve_tool_execution", {
+        executionId: execution_id,
+        approved,
+        reason,
+        sessionId: currentSessionId,
+      });
+      approvalEl.remove();
+    } catch (error) {
+      approvalEl.remove();
+      addMessage("system", `承認の送信に失敗しました: ${error}`);
+    }
+  };
+  approvalEl.querySelector(".tool-approve-btn").addEventListener("click", () => decide(true));
+  approvalEl.querySelector(".tool-reject-btn").addEventListener("click", () => decide(false));
+  scrollToBottom();
+}
+
+/**
+ * 同じターンで提案された承認待ちコマンドの一覧に「全て承認」「選択分を承認」「全て拒否」ボタンを表示
+ */
+function showToolApprovalBatch({ batch_id, items, timeout_secs }) {
+  const approvalEl = document.createElement("div");
+  approvalEl.className = "message system tool-approval tool-approval-batch";
+  approvalEl.innerHTML = `
+    <div class="tool-approval-text">⚠️ 破壊的な可能性のあるコマンドが${items.length}件あります。${timeout_secs}秒以内に応答がなければ実行しません</div>
+    ${items
+      .map(
+        (item) => `
+      <label class="tool-approval-item">
+        <input type="checkbox" value="${escapeHtml(item.item_id)}" checked />
+        <span>${escapeHtml(item.machine_name)}</span>
+        <pre class="tool-approval-command">${escapeHtml(item.command)}</pre>
+      </label>`
+      )
+      .join("")}
+    <div class="tool-approval-actions">
+      <button class="tool-approve-btn tool-approve-all-btn">全て承認</button>
+      <button class="tool-approve-selected-btn">選択分を承認</button>
+      <button class="tool-reject-btn">全て拒否</button>
+    </div>
+  `;
+  messagesEl.appendChild(approvalEl);
+
+  const checkedItems = () =>
+    [...approvalEl.querySelectorAll(".tool-approval-item input:checked")].map((input) => input.value);
+  const decide = async (approvedItems) => {
+    const reason =
+      approvedItems.length < items.length ? prompt("拒否の理由（Claude に伝えます。空欄可）", "") ?? "" : null;
+    approvalEl.querySelectorAll("button, input").forEach((el) => (el.disabled = true));
+    try {
+      await invoke("approve_tool_batch", {
+        batchId: batch_id,
+        approvedItems,
+        reason,
+        sessionId: currentSessionId,
+      });
+      approvalEl.remove();
+    } catch (error) {
+      approvalEl.remove();
+      addMessage("system", `承認の送信に失敗しました: ${error}`);
+    }
+  };
+  approvalEl.querySelector(".tool-approve-all-btn").addEventListener("click", () => decide(items.map((item) => item.item_id)));
+  approvalEl.querySelector(".tool-approve-selected-btn").addEventListener("click", () => decide(checkedItems()));
+  approvalEl.querySelector(".tool-reject-btn").addEventListener("click", () => decide([]));
+  scrollToBottom();
+}
+
+/**
+ * タイムアウトに達した実行中ツールに「さらに待つ」「中止」ボタンを表示
+ */
+function showToolTimeoutPending({ execution_id, sequence, elapsed_secs, extend_secs, decision_wait_secs }) {
+  const statusEl = messagesEl.querySelector(`.tool-status-message[data-tool-seq="${sequence}"]`);
+  if (!statusEl) return;
+  statusEl.querySelector(".tool-timeout-pending")?.remove();
+  const pendingEl = document.createElement("div");
+  pendingEl.className = "tool-timeout-pending";
+  pendingEl.innerHTML = `
+    <span class="tool-timeout-text">⏱️ ${elapsed_secs}秒経過しても終わっていません（${decision_wait_secs}秒以内に選ばなければ中止）</span>
+    <button class="tool-wait-btn">さらに${extend_secs}秒待つ</button>
+    <button class="tool-abort-btn">中止</button>
+  `;
+  statusEl.appendChild(pendingEl);
+
+  const resolve = async (wait) => {
+    pendingEl.querySelectorAll("button").forEach((btn) => (btn.disabled = true));
+    try {
+      await invoke("resolve_tool_timeout", { executionId: execution_id, wait, sessionId: currentSessionId });
+    } catch (error) {
+      addMessage("system", `タイムアウトの操作に失敗しました: ${error}`);
+    }
+    pendingEl.remove();
+  };
+  pendingEl.querySelector(".tool-wait-btn").addEventListener("click", () => resolve(true));
+  pendingEl.querySelector(".tool-abort-btn").addEventListener("click", () => resolve(false));
+  scrollToBottom();
+}
+
+const TOOL_LIVE_OUTPUT_MAX_LINES = 20;
+
+/**
+ * 実行中ツールの出力行をステータス表示の下に追記（直近 TOOL_LIVE_OUTPUT_MAX_LINES 行のみ保持）
+ */
+function appendToolOutputLine({ sequence, line, stream }) {
+  const statusEl = messagesEl.querySelector(`.tool-status-message[data-tool-seq="${sequence}"]`);
+  if (!statusEl) return;
+  let outputEl = statusEl.querySelector(".tool-live-output");
+  if (!outputEl) {
+    outputEl = document.createElement("pre");
+    outputEl.className = "tool-live-output";
+    statusEl.appendChild(outputEl);
+  }
+  const lineEl = document.createElement("div");
+  lineEl.className = `tool-live-line ${stream}`;
+  lineEl.textContent = line;
+  outputEl.appendChild(lineEl);
+  while (outputEl.childElementCount > TOOL_LIVE_OUTPUT_MAX_LINES) {
+    outputEl.firstElementChild.remove();
+  }
+  outputEl.scrollTop = outputEl.scrollHeight;
+  scrollToBottom();
+}
+
+/**
+ * 実行中ツールのステータス表示に対象マシンの CPU・メモリ使用率を表示（最新値で上書き）
+ */
+function showToolMachineLoad({ sequence, machine_name, cpu_percent, memory_percent }) {
+  const statusEl = messagesEl.querySelector(`.tool-status-message[data-tool-seq="${sequence}"]`);
+  if (!statusEl) return;
+  let loadEl = statusEl.querySelector(".tool-machine-load");
+  if (!loadEl) {
+    loadEl = document.createElement("div");
+    loadEl.className = "tool-machine-load";
+    statusEl.appendChild(loadEl);
+  }
+  const format = (value) => (value == null ? "-" : `${value}%`);
+  loadEl.textContent = `📊 ${machine_name} CPU ${format(cpu_percent)} / メモリ ${format(memory_percent)}`;
 }
 
 /**
  * ツール実行ステータスをタイピングインジケーター領域に表示
  */
-function showToolStatus(machineName, command, status) {
+/**
+ * ツール実行状況の表示（sequence で実行順に並べる。並列実行でイベントの到着順が前後しても崩れない）
+ */
+function showToolStatus(machineName, command, status, sequence, executionId) {
   // 既存のタイピングインジケーターを除去
   const typingEl = messagesEl.querySelector(".typing-message");
   if (typingEl) typingEl.remove();
@@ -572,26 +1198,85 @@ function showToolStatus(machineName, command, status) {
       <span class="tool-status-icon">${icon}</span>
       <span class="tool-status-text">${machineName}: <code>${escapeHtml(shortCmd)}</code> ${statusText}</span>
       ${status === "executing" ? '<span class="tool-spinner"></span>' : ''}
+      ${status === "executing" && executionId ? `<button class="tool-cancel-btn" data-execution-id="${escapeHtml(executionId)}" title="このコマンドを中止">中止</button>` : ''}
     </div>
   `;
 
-  if (status === "executing") {
-    // 新規作成
+  const existing = messagesEl.querySelector(`.tool-status-message[data-tool-seq="${sequence}"]`);
+  if (existing) {
+    // 逐次出力の表示は完了後も残す
+    const liveOutput = existing.querySelector(".tool-live-output");
+    existing.innerHTML = innerHtml;
+    if (liveOutput) existing.appendChild(liveOutput);
+  } else {
+    // 後続の番号の表示があればその前に挿入
     const statusEl = document.createElement("div");
     statusEl.className = "message assistant tool-status-message";
-    statusEl.dataset.toolCmd = command; // 識別用
+    statusEl.dataset.toolSeq = sequence; // 識別用
     statusEl.innerHTML = innerHtml;
-    messagesEl.appendChild(statusEl);
-  } else {
-    // completedで最新のexecutingメッセージを上書き
-    const existing = messagesEl.querySelector(`.tool-status-message[data-tool-cmd="${CSS.escape(command)}"]`);
-    if (existing) {
-      existing.innerHTML = innerHtml;
-    }
+    const later = [...messagesEl.querySelectorAll(".tool-status-message")].find(
+      (el) => Number(el.dataset.toolSeq) > sequence
+    );
+    messagesEl.insertBefore(statusEl, later || null);
+  }
+
+  const cancelBtn = messagesEl.querySelector(`.tool-status-message[data-tool-seq="${sequence}"] .tool-cancel-btn`);
+  if (cancelBtn) {
+    cancelBtn.addEventListener("click", () => cancelToolExecution(cancelBtn));
   }
   scrollToBottom();
 }
 
+/**
+ * 実行中のツールをキャンセル（SSHプロセスを止め、Tool Use ループも打ち切られる）
+ */
+async function cancelToolExecution(button) {
+  button.disabled = true;
+  try {
+    await invoke("cancel_tool_execution", {
+      sessionId: currentSessionId,
+      executionId: button.dataset.executionId,
+    });
+  } catch (error) {
+    button.disabled = false;
+    addMessage("system", `キャンセルできませんでした: ${error}`);
+  }
+}
+
+/**
+ * 「実機確認済み」バッジを送信者ラベルの横に追加
+ */
+function addGroundingBadge(msgEl) {
+  const senderEl = msgEl.querySelector(".message-sender");
+  if (!senderEl || senderEl.querySelector(".grounding-badge")) return;
+  senderEl.insertAdjacentHTML(
+    "beforeend",
+    `<span class="grounding-badge" title="コマンドを実行して確認した回答">実機確認済み</span>`
+  );
+}
+
+/**
+ * 後処理後のテキストに差し替え、原文との切り替えボタンを追加
+ */
+function applyPostprocessedText(msgEl, text, rawText) {
+  const contentEl = msgEl.querySelector(".message-content");
+  const senderEl = msgEl.querySelector(".message-sender");
+  if (!contentEl || !senderEl) return;
+  contentEl.innerHTML = formatStreamingText(text);
+
+  const toggleEl = document.createElement("button");
+  toggleEl.className = "raw-toggle";
+  toggleEl.title = "後処理で除去する前の応答を表示";
+  toggleEl.textContent = "原文";
+  let showingRaw = false;
+  toggleEl.addEventListener("click", () => {
+    showingRaw = !showingRaw;
+    contentEl.innerHTML = formatStreamingText(showingRaw ? rawText : text);
+    toggleEl.textContent = showingRaw ? "整形後" : "原文";
+  });
+  senderEl.appendChild(toggleEl);
+}
+
 /**
  * アシスタントメッセージ表示（ツール実行サマリー付き）
  */
@@ -625,7 +1310,114 @@ function addAssistantMessage(text, toolExecutions) {
 /**
  * ツール実行サマリーHTML生成（コラプシブル）
  */
+/**
+ * ツール出力のフィルタ（サーバ側に保持された全出力を対象に絞り込み、表示のみ差し替え）
+ */
+let execFilterTimer = null;
+
+async function applyExecFilter(itemEl) {
+  const executionId = itemEl.dataset.executionId;
+  const outputEl = itemEl.querySelector(".exec-output");
+  const pattern = itemEl.querySelector(".exec-filter-input").value;
+  const invert = itemEl.querySelector(".exec-filter-invert").checked;
+  if (!executionId || !outputEl) return;
+
+  if (!pattern) {
+    outputEl.textContent = outputEl.dataset.original;
+    return;
+  }
+  try {
+    const result = await invoke("filter_tool_output", { executionId, pattern, invert, sessionId: currentSessionId });
+    const text = result.lines.map((l) => `${l.line_no}: ${l.text}`).join("\n");
+    outputEl.textContent = `${text || "(該当行なし)"}\n— ${result.lines.length}/${result.total_lines} 行`;
+  } catch (err) {
+    outputEl.textContent = `Error: ${err}`;
+  }
+}
+
+function setupExecFilter() {
+  const handler = (e) => {
+    if (!e.target.matches(".exec-filter-input, .exec-filter-invert")) return;
+    const itemEl = e.target.closest(".exec-item");
+    clearTimeout(execFilterTimer);
+    execFilterTimer = setTimeout(() => applyExecFilter(itemEl), 250);
+  };
+  messagesEl.addEventListener("input", handler);
+  messagesEl.addEventListener("change", handler);
+}
+
+/**
+ * 応答の注釈（コマンド・パス・URL）からアクションボタンを作成
+ * コマンドとパスはリモートパネルに入力するだけで、実行はユーザーが確定する
+ */
+function addAnnotationActions(msgEl, annotations) {
+  const machines = [...new Set(annotations.filter((a) => a.kind === "machine").map((a) => a.text))];
+  const seen = new Set();
+  const actions = annotations
+    .filter((a) => a.kind !== "machine" && !seen.has(a.kind + a.text) && seen.add(a.kind + a.text))
+    .slice(0, 8);
+  if (actions.length === 0) return;
+
+  const barEl = document.createElement("div");
+  barEl.className = "annotation-actions";
+  for (const a of actions) {
+    if (a.kind === "url") {
+      const linkEl = document.createElement("a");
+      linkEl.href = a.text;
+      linkEl.target = "_blank";
+      linkEl.rel = "noopener";
+      linkEl.className = "annotation-action";
+      linkEl.textContent = `🔗 ${a.text}`;
+      barEl.appendChild(linkEl);
+      continue;
+    }
+    const btnEl = document.createElement("button");
+    btnEl.className = "annotation-action";
+    if (a.kind === "command") {
+      btnEl.textContent = `▶ ${a.text}`;
+      btnEl.title = "このコマンドをリモート実行欄に入力";
+      btnEl.addEventListener("click", () => prepareRemoteCommand(a.text, machines));
+    } else {
+      btnEl.textContent = `📄 ${a.text}`;
+      btnEl.title = "このファイルを取得するコマンドをリモート実行欄に入力";
+      const readCmd = a.text.includes(":\\") ? `type "${a.text}"` : `cat '${a.text}'`;
+      btnEl.addEventListener("click", () => prepareRemoteCommand(readCmd, machines));
+    }
+    barEl.appendChild(btnEl);
+  }
+  msgEl.appendChild(barEl);
+}
+
+/**
+ * リモート実行欄にコマンドを入力（実行先は選択中のマシン、なければ応答中に1台だけ出てきたマシン）
+ */
+function prepareRemoteCommand(command, machinesInText) {
+  let target = selectedRemoteMachine;
+  if (!target && machinesInText.length === 1) {
+    const status = machineStatuses.find((m) => m.name === machinesInText[0]);
+    if (status && status.role !== "Commander") {
+      selectRemoteMachine(status.name, status.online);
+      target = status.name;
+    }
+  }
+  if (!target) {
+    addMessage("system", "実行先のマシンをマシン一覧から選択してください");
+    return;
+  }
+  remoteCmdInput.value = command;
+  remoteCmdInput.focus();
+}
+
+// ツール実行の目的タグ（Claude が申告）
+const PURPOSE_LABELS = { investigate: "調査", change: "変更", verify: "確認" };
+
+function purposeBadge(purpose) {
+  if (!PURPOSE_LABELS[purpose]) return "";
+  return ` <span class="exec-purpose purpose-${purpose}">${PURPOSE_LABELS[purpose]}</span>`;
+}
+
 function buildToolExecutionSummary(executions) {
+  executions = [...executions].sort((a, b) => (a.sequence || 0) - (b.sequence || 0));
   const count = executions.length;
   const successCount = executions.filter((e) => e.success).length;
   const label = `🔧 ${count}件のコマンド実行（${successCount}/${count} 成功）`;
@@ -638,9 +1430,13 @@ function buildToolExecutionSummary(executions) {
     // 出力が長い場合は折りたたみ内でも省略
     const shortOutput = output.length > 500 ? output.substring(0, 497) + "..." : output;
     detailsHtml += `
-      <div class="exec-item ${cls}">
-        <div class="exec-header"><span class="exec-icon">${icon}</span> ${escapeHtml(exec.machine_name)}: <code>${escapeHtml(exec.command)}</code></div>
-        <pre class="exec-output">${escapeHtml(shortOutput)}</pre>
+      <div class="exec-item ${cls}" data-execution-id="${escapeHtml(exec.execution_id || "")}">
+        <div class="exec-header"><span class="exec-icon">${icon}</span>${purposeBadge(exec.purpose)} ${escapeHtml(exec.machine_name)}${exec.connected_host ? ` (${escapeHtml(exec.connected_host)})` : ""}: <code>${escapeHtml(exec.command)}</code></div>
+        <div class="exec-filter">
+          <input type="text" class="exec-filter-input" placeholder="出力を絞り込み（正規表現）" />
+          <label><input type="checkbox" class="exec-filter-invert" /> 除外</label>
+        </div>
+        <pre class="exec-output" data-original="${escapeHtml(shortOutput)}">${escapeHtml(shortOutput)}</pre>
       </div>`;
   }
 